@@ -3,7 +3,7 @@ extern crate kasop;
 
 use clap::{ArgMatches, FromArgMatches};
 use cust::prelude::*;
-use kasop::{Plugin, Worker, WorkerSpec};
+use kasop::{NoncePartition, Plugin, Worker, WorkerSpec};
 use log::LevelFilter;
 use std::error::Error as StdError;
 
@@ -82,8 +82,10 @@ struct CudaWorkerSpec {
 }
 
 impl WorkerSpec for CudaWorkerSpec {
-    fn build(&self) -> Box<dyn Worker> {
-        Box::new(CudaGPUWorker::new(self.device_id, self.workload, self.is_absolute, self.blocking_sync).unwrap())
+    fn build(&self, partition: NoncePartition) -> Box<dyn Worker> {
+        Box::new(
+            CudaGPUWorker::new(self.device_id, self.workload, self.is_absolute, self.blocking_sync, partition).unwrap(),
+        )
     }
 }
 
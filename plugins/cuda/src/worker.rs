@@ -5,7 +5,7 @@ use cust::function::Function;
 use cust::module::{ModuleJitOption, OptLevel};
 use cust::prelude::*;
 use kaspa_miner::xoshiro256starstar::Xoshiro256StarStar;
-use kaspa_miner::Worker;
+use kaspa_miner::{NoncePartition, Worker};
 use log::{error, info};
 use rand::Fill;
 use std::ffi::CString;
@@ -60,6 +60,7 @@ pub struct CudaGPUWorker<'gpu> {
 
     device_id: u32,
     pub workload: usize,
+    partition: NoncePartition,
     _context: Context,
 }
 
@@ -83,6 +84,7 @@ impl<'gpu> Worker for CudaGPUWorker<'gpu> {
 
     #[inline(always)]
     fn calculate_hash(&mut self, _nonces: Option<&Vec<u64>>, nonce_mask: u64, nonce_fixed: u64) {
+        let (nonce_mask, nonce_fixed) = self.partition.apply(nonce_mask, nonce_fixed);
         let func = &self.heavy_hash_kernel.func;
         let stream = &self.stream;
 
@@ -124,7 +126,13 @@ impl<'gpu> Worker for CudaGPUWorker<'gpu> {
 }
 
 impl<'gpu> CudaGPUWorker<'gpu> {
-    pub fn new(device_id: u32, workload: f32, is_absolute: bool, blocking_sync: bool) -> Result<Self, Error> {
+    pub fn new(
+        device_id: u32,
+        workload: f32,
+        is_absolute: bool,
+        blocking_sync: bool,
+        partition: NoncePartition,
+    ) -> Result<Self, Error> {
         info!("Starting a CUDA worker");
         let sync_flag = match blocking_sync {
             true => ContextFlags::SCHED_BLOCKING_SYNC,
@@ -204,6 +212,7 @@ impl<'gpu> CudaGPUWorker<'gpu> {
             _context,
             _module,
             workload: chosen_workload,
+            partition,
             stream,
             rand_state,
             final_nonce_buff,
@@ -19,14 +19,50 @@ impl FromStr for NonceGenEnum {
     }
 }
 
+/// Single `--opencl-workload` value: positional (`512`, applies to the GPU at the same position) or
+/// bound to an OpenCL device index (`1=512`)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WorkloadArg {
+    pub device: Option<u16>,
+    pub value: f32,
+}
+
+impl FromStr for WorkloadArg {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_value = |v: &str| v.trim().parse::<f32>().map_err(|e| format!("invalid workload '{}': {}", v, e));
+        match s.split_once('=') {
+            Some((device, value)) => Ok(Self {
+                device: Some(device.trim().parse().map_err(|e| format!("invalid device index '{}': {}", device, e))?),
+                value: parse_value(value)?,
+            }),
+            None => Ok(Self { device: None, value: parse_value(s)? }),
+        }
+    }
+}
+
+/// Workload of the `position`-th GPU in use, which is `device` on the platform. Keyed values win,
+/// otherwise positional ones apply as before (the last one repeats for the remaining GPUs).
+pub fn workload_for(workload: &[WorkloadArg], position: usize, device: u16) -> Option<f32> {
+    if let Some(arg) = workload.iter().find(|arg| arg.device == Some(device)) {
+        return Some(arg.value);
+    }
+    let positional = workload.iter().filter(|arg| arg.device.is_none()).map(|arg| arg.value).collect::<Vec<_>>();
+    positional.get(position).or_else(|| positional.last()).copied()
+}
+
 #[derive(clap::Args, Debug)]
 pub struct OpenCLOpt {
     #[clap(long = "opencl-platform", help = "Which OpenCL platform to use (limited to one per executable)")]
     pub opencl_platform: Option<u16>,
     #[clap(long = "opencl-device", use_delimiter = true, help = "Which OpenCL GPUs to use on a specific platform")]
     pub opencl_device: Option<Vec<u16>>,
-    #[clap(long = "opencl-workload", help = "Ratio of nonces to GPU possible parrallel run in OpenCL [default: 512]")]
-    pub opencl_workload: Option<Vec<f32>>,
+    #[clap(
+        long = "opencl-workload",
+        help = "Ratio of nonces to GPU possible parrallel run in OpenCL, either by position or bound to a device as device_index=value [default: 512]"
+    )]
+    pub opencl_workload: Option<Vec<WorkloadArg>>,
     #[clap(
         long = "opencl-workload-absolute",
         help = "The values given by workload are not ratio, but absolute number of nonces in OpenCL [default: false]"
@@ -48,3 +84,42 @@ pub struct OpenCLOpt {
     )]
     pub nonce_gen: NonceGenEnum,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(values: &[&str]) -> Vec<WorkloadArg> {
+        values.iter().map(|v| v.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_positional_workload() {
+        let workload = parse(&["256", "1024"]);
+        assert_eq!(workload[0], WorkloadArg { device: None, value: 256. });
+        assert_eq!(workload_for(&workload, 0, 3), Some(256.));
+        assert_eq!(workload_for(&workload, 1, 0), Some(1024.));
+        // The last value repeats
+        assert_eq!(workload_for(&workload, 2, 1), Some(1024.));
+        assert_eq!(workload_for(&[], 0, 0), None);
+    }
+
+    #[test]
+    fn test_keyed_workload() {
+        let workload = parse(&["2=128", " 0 = 64.5"]);
+        assert_eq!(workload[0], WorkloadArg { device: Some(2), value: 128. });
+        // Bound to device regardless of its position
+        assert_eq!(workload_for(&workload, 0, 2), Some(128.));
+        assert_eq!(workload_for(&workload, 1, 0), Some(64.5));
+        assert_eq!(workload_for(&workload, 0, 1), None);
+
+        // Positional values cover the devices that aren't listed
+        let workload = parse(&["1=128", "512"]);
+        assert_eq!(workload_for(&workload, 0, 1), Some(128.));
+        assert_eq!(workload_for(&workload, 1, 3), Some(512.));
+
+        assert!("x=128".parse::<WorkloadArg>().is_err());
+        assert!("1=fast".parse::<WorkloadArg>().is_err());
+        assert!("fast".parse::<WorkloadArg>().is_err());
+    }
+}
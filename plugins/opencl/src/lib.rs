@@ -2,7 +2,7 @@
 extern crate kasop;
 
 use clap::{ArgMatches, FromArgMatches};
-use kasop::{Plugin, Worker, WorkerSpec};
+use kasop::{NoncePartition, Plugin, Worker, WorkerSpec};
 use log::LevelFilter;
 use opencl3::device::{Device, CL_DEVICE_TYPE_ALL};
 use opencl3::platform::{get_platforms, Platform};
@@ -14,7 +14,7 @@ pub type Error = Box<dyn StdError + Send + Sync + 'static>;
 mod cli;
 mod worker;
 
-use crate::cli::{NonceGenEnum, OpenCLOpt};
+use crate::cli::{workload_for, NonceGenEnum, OpenCLOpt};
 use crate::worker::OpenCLGPUWorker;
 
 const DEFAULT_WORKLOAD_SCALE: f32 = 512.;
@@ -60,23 +60,25 @@ impl Plugin for OpenCLPlugin {
         };
 
         let device_ids = _platform.get_devices(CL_DEVICE_TYPE_ALL).unwrap();
-        let gpus = match opts.opencl_device {
+        // Platform device indexes of GPUs in use, so that workloads can be bound to them
+        let device_indexes = match opts.opencl_device {
             Some(dev) => {
                 self._enabled = true;
-                dev.iter().map(|d| device_ids[*d as usize]).collect::<Vec<cl_device_id>>()
+                dev
             }
-            None => device_ids,
+            None => (0..device_ids.len() as u16).collect(),
         };
+        let gpus = device_indexes.iter().map(|d| device_ids[*d as usize]).collect::<Vec<cl_device_id>>();
 
         self.specs = (0..gpus.len())
             .map(|i| OpenCLWorkerSpec {
                 _platform,
                 device_id: Device::new(gpus[i]),
-                workload: match &opts.opencl_workload {
-                    Some(workload) if i < workload.len() => workload[i],
-                    Some(workload) if !workload.is_empty() => *workload.last().unwrap(),
-                    _ => DEFAULT_WORKLOAD_SCALE,
-                },
+                workload: opts
+                    .opencl_workload
+                    .as_ref()
+                    .and_then(|workload| workload_for(workload, i, device_indexes[i]))
+                    .unwrap_or(DEFAULT_WORKLOAD_SCALE),
                 is_absolute: opts.opencl_workload_absolute,
                 experimental_amd: opts.experimental_amd,
                 use_amd_binary: opts.opencl_amd_binary,
@@ -100,7 +102,7 @@ struct OpenCLWorkerSpec {
 }
 
 impl WorkerSpec for OpenCLWorkerSpec {
-    fn build(&self) -> Box<dyn Worker> {
+    fn build(&self, partition: NoncePartition) -> Box<dyn Worker> {
         Box::new(
             OpenCLGPUWorker::new(
                 self.device_id,
@@ -109,6 +111,7 @@ impl WorkerSpec for OpenCLWorkerSpec {
                 self.experimental_amd,
                 self.use_amd_binary,
                 &self.random,
+                partition,
             )
             .unwrap(),
         )
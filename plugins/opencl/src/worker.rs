@@ -1,7 +1,7 @@
 use crate::cli::NonceGenEnum;
 use crate::Error;
 use kasop::xoshiro256starstar::Xoshiro256StarStar;
-use kasop::Worker;
+use kasop::{NoncePartition, Worker};
 use log::info;
 use opencl3::command_queue::{CommandQueue, CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE};
 use opencl3::context::Context;
@@ -11,9 +11,10 @@ use opencl3::kernel::{ExecuteKernel, Kernel};
 use opencl3::memory::{Buffer, ClMem, CL_MAP_WRITE, CL_MEM_READ_ONLY, CL_MEM_READ_WRITE, CL_MEM_WRITE_ONLY};
 use opencl3::platform::Platform;
 use opencl3::program::{Program, CL_FINITE_MATH_ONLY, CL_MAD_ENABLE, CL_STD_2_0};
-use opencl3::types::{cl_event, cl_uchar, cl_ulong, CL_BLOCKING};
+use opencl3::types::{cl_event, cl_int, cl_uchar, cl_ulong, CL_BLOCKING};
 use rand::{thread_rng, Fill, RngCore};
 use std::borrow::Borrow;
+use std::cell::{Cell, RefCell};
 use std::ffi::c_void;
 use std::ptr;
 use std::sync::Arc;
@@ -39,6 +40,15 @@ pub struct OpenCLGPUWorker {
 
     events: Vec<cl_event>,
     experimental_amd: bool,
+    partition: NoncePartition,
+    paused: bool,
+
+    /// Name for logs that works even when the device is gone
+    name: String,
+    /// First failed OpenCL call, the worker is unusable after that
+    error: RefCell<Option<String>>,
+    /// The failure means the device was reset and the worker has to be rebuilt
+    device_lost: Cell<bool>,
 }
 
 impl Worker for OpenCLGPUWorker {
@@ -55,29 +65,32 @@ impl Worker for OpenCLGPUWorker {
                 .collect::<Vec<cl_uchar>>(),
             false => matrix.iter().flat_map(|row| row.map(|v| v as cl_uchar)).collect::<Vec<cl_uchar>>(),
         };
-        self.queue
+        let result = self
+            .queue
             .enqueue_write_buffer(&mut self.final_nonce, CL_BLOCKING, 0, &[0], &[])
-            .map_err(|e| e.to_string())
-            .unwrap()
-            .wait()
-            .unwrap();
-        self.queue
+            .and_then(|event| event.wait());
+        if self.record("writing nonce buffer", result).is_none() {
+            return;
+        }
+        let result = self
+            .queue
             .enqueue_write_buffer(&mut self.hash_header, CL_BLOCKING, 0, hash_header, &[])
-            .map_err(|e| e.to_string())
-            .unwrap()
-            .wait()
-            .unwrap();
-        self.queue
-            .enqueue_write_buffer(&mut self.matrix, CL_BLOCKING, 0, cl_uchar_matrix.as_slice(), &[])
-            .map_err(|e| e.to_string())
-            .unwrap()
-            .wait()
-            .unwrap();
-        let copy_target = self
+            .and_then(|event| event.wait());
+        if self.record("writing header buffer", result).is_none() {
+            return;
+        }
+        let result = self
             .queue
-            .enqueue_write_buffer(&mut self.target, CL_BLOCKING, 0, target, &[])
-            .map_err(|e| e.to_string())
-            .unwrap();
+            .enqueue_write_buffer(&mut self.matrix, CL_BLOCKING, 0, cl_uchar_matrix.as_slice(), &[])
+            .and_then(|event| event.wait());
+        if self.record("writing matrix buffer", result).is_none() {
+            return;
+        }
+        let result = self.queue.enqueue_write_buffer(&mut self.target, CL_BLOCKING, 0, target, &[]);
+        let copy_target = match self.record("writing target buffer", result) {
+            Some(copy_target) => copy_target,
+            None => return,
+        };
 
         self.events = vec![copy_target.get()];
         for event in &self.events {
@@ -86,19 +99,26 @@ impl Worker for OpenCLGPUWorker {
     }
 
     fn calculate_hash(&mut self, _nonces: Option<&Vec<u64>>, nonce_mask: u64, nonce_fixed: u64) {
+        // Device is gone, `sync` reports the failure
+        if self.error.borrow().is_some() {
+            return;
+        }
+        // Both nonce generators only fill the masked bits, so narrowing the mask keeps them in our slice
+        let (nonce_mask, nonce_fixed) = self.partition.apply(nonce_mask, nonce_fixed);
         if self.random == NonceGenEnum::Lean {
-            self.queue
+            let result = self
+                .queue
                 .enqueue_write_buffer(&mut self.random_state, CL_BLOCKING, 0, &[thread_rng().next_u64()], &[])
-                .map_err(|e| e.to_string())
-                .unwrap()
-                .wait()
-                .unwrap();
+                .and_then(|event| event.wait());
+            if self.record("writing random state", result).is_none() {
+                return;
+            }
         }
         let random_type: cl_uchar = match self.random {
             NonceGenEnum::Lean => 0,
             NonceGenEnum::Xoshiro => 1,
         };
-        let kernel_event = ExecuteKernel::new(&self.heavy_hash)
+        let result = ExecuteKernel::new(&self.heavy_hash)
             .set_arg(&nonce_mask)
             .set_arg(&nonce_fixed)
             .set_arg(&self.hash_header)
@@ -111,10 +131,8 @@ impl Worker for OpenCLGPUWorker {
             .set_global_work_size(self.workload)
             .set_event_wait_list(self.events.borrow())
             .enqueue_nd_range(&self.queue)
-            .map_err(|e| e.to_string())
-            .unwrap();
-
-        kernel_event.wait().unwrap();
+            .and_then(|kernel_event| kernel_event.wait());
+        self.record("running kernel", result);
 
         /*let mut nonces = [0u64; 1];
         let mut hash = [[0u64; 4]];
@@ -130,7 +148,19 @@ impl Worker for OpenCLGPUWorker {
     }
 
     fn sync(&self) -> Result<(), Error> {
-        wait_for_events(&self.events).map_err(|e| format!("waiting error code {}", e))?;
+        if self.error.borrow().is_none() {
+            let result = wait_for_events(&self.events);
+            self.record("waiting for events", result);
+        }
+        if let Some(error) = self.error.borrow().as_ref() {
+            // The worker is likely rebuilt, don't leak the events. They may be gone with the context.
+            for event in &self.events {
+                if let Err(code) = release_event(*event) {
+                    log::warn!("{}: releasing event failed with OpenCL error {}", self.name, code);
+                }
+            }
+            return Err(error.clone().into());
+        }
         for event in &self.events {
             release_event(*event).unwrap();
         }
@@ -142,19 +172,79 @@ impl Worker for OpenCLGPUWorker {
     }
 
     fn copy_output_to(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
-        self.queue
-            .enqueue_read_buffer(&self.final_nonce, CL_BLOCKING, 0, nonces, &[])
-            .map_err(|e| e.to_string())
-            .unwrap();
-        Ok(())
+        let result = self.queue.enqueue_read_buffer(&self.final_nonce, CL_BLOCKING, 0, nonces, &[]);
+        self.record("reading nonce buffer", result);
+        match self.error.borrow().as_ref() {
+            Some(error) => Err(error.clone().into()),
+            None => Ok(()),
+        }
     }
 
     fn requires_filter(&self) -> bool {
         false
     }
+
+    fn pause(&mut self) {
+        // Let kernels in flight finish, the device stays allocated
+        if let Err(e) = self.queue.finish() {
+            log::warn!("{}: failed waiting for queue before pause: {}", self.id(), e);
+        }
+        self.paused = true;
+        info!("{}: paused", self.id());
+    }
+
+    fn resume(&mut self) {
+        if self.paused {
+            self.paused = false;
+            info!("{}: resumed", self.id());
+        }
+    }
+
+    fn device_lost(&self) -> bool {
+        self.device_lost.get()
+    }
+}
+
+/// OpenCL error codes (from `cl.h`) that mean the context is gone, ie. the GPU was reset by the
+/// driver (TDR on Windows). CL_OUT_OF_RESOURCES (-5) isn't one of them: it usually means the
+/// workload is too big, a rebuilt worker would fail the same way over and over.
+const DEVICE_LOST_ERRORS: [cl_int; 4] = [
+    -2,  // CL_DEVICE_NOT_AVAILABLE
+    -33, // CL_INVALID_DEVICE
+    -34, // CL_INVALID_CONTEXT
+    -36, // CL_INVALID_COMMAND_QUEUE
+];
+
+/// Whether OpenCL call failing with `code` means the worker has to be rebuilt
+pub fn is_device_lost(code: cl_int) -> bool {
+    DEVICE_LOST_ERRORS.contains(&code)
 }
 
 impl OpenCLGPUWorker {
+    /// Remember the first failure of OpenCL call `what` (the trait calls that can't return errors
+    /// are reported by the next `sync`) and whether it means the device was lost
+    fn record<T>(&self, what: &str, result: Result<T, cl_int>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(code) => {
+                let mut error = self.error.borrow_mut();
+                if error.is_none() {
+                    let lost = is_device_lost(code);
+                    log::error!(
+                        "{}: {} failed with OpenCL error {}{}",
+                        self.name,
+                        what,
+                        code,
+                        if lost { ", device was lost" } else { "" }
+                    );
+                    self.device_lost.set(lost);
+                    *error = Some(format!("{}: {} failed with OpenCL error {}", self.name, what, code));
+                }
+                None
+            }
+        }
+    }
+
     pub fn new(
         device: Device,
         workload: f32,
@@ -162,6 +252,7 @@ impl OpenCLGPUWorker {
         experimental_amd: bool,
         use_binary: bool,
         random: &NonceGenEnum,
+        partition: NoncePartition,
     ) -> Result<Self, Error> {
         let name =
             device.board_name_amd().unwrap_or_else(|_| device.name().unwrap_or_else(|_| "Unknown Device".into()));
@@ -359,6 +450,11 @@ impl OpenCLGPUWorker {
             target,
             events: Vec::<cl_event>::new(),
             experimental_amd: ((experimental_amd | use_binary) & experimental_amd_use),
+            partition,
+            paused: false,
+            name,
+            error: RefCell::new(None),
+            device_lost: Cell::new(false),
         })
     }
 }
@@ -408,3 +504,21 @@ fn from_source(context: &Context, device: &Device, options: &str) -> Result<Prog
 
     Program::create_and_build_from_source(context, PROGRAM_SOURCE, compile_options.as_str())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_device_lost_errors() {
+        // CL_INVALID_CONTEXT, CL_DEVICE_NOT_AVAILABLE
+        assert!(is_device_lost(-34));
+        assert!(is_device_lost(-2));
+        // CL_INVALID_VALUE, CL_INVALID_KERNEL_ARGS are bugs, not resets
+        assert!(!is_device_lost(-30));
+        // CL_OUT_OF_RESOURCES is too big a workload
+        assert!(!is_device_lost(-5));
+        assert!(!is_device_lost(-52));
+        assert!(!is_device_lost(0));
+    }
+}
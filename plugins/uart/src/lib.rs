@@ -3,7 +3,7 @@ extern crate kasop;
 
 use std::cmp::min;
 use clap::{ArgMatches, FromArgMatches};
-use kasop::{Plugin, Worker, WorkerSpec};
+use kasop::{NoncePartition, Plugin, Worker, WorkerSpec};
 use std::error::Error as StdError;
 
 pub type Error = Box<dyn StdError + Send + Sync + 'static>;
@@ -79,7 +79,8 @@ struct UartWorkerSpec {
 }
 
 impl WorkerSpec for UartWorkerSpec {
-    fn build(&self) -> Box<dyn Worker> {
+    /// The hashboard iterates nonces on its own, so partition is not applicable
+    fn build(&self, _partition: NoncePartition) -> Box<dyn Worker> {
         Box::new(UartWorker::new(self.path.clone(), self.baud_rate, self.workload).unwrap())
     }
 }
@@ -0,0 +1,128 @@
+//! Kaspa address validation
+//!
+//! Addresses are `prefix:payload` where the payload is base32 encoded version byte and public key
+//! (or script hash) followed by 40 bit checksum of both prefix and payload (same scheme as
+//! CashAddr).
+
+use std::fmt;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+/// Checksum takes 8 base32 characters
+const CHECKSUM_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Simnet,
+    Devnet,
+}
+
+impl Network {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "kaspa",
+            Network::Testnet => "kaspatest",
+            Network::Simnet => "kaspasim",
+            Network::Devnet => "kaspadev",
+        }
+    }
+
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        [Network::Mainnet, Network::Testnet, Network::Simnet, Network::Devnet]
+            .iter()
+            .copied()
+            .find(|network| network.prefix() == prefix)
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.prefix())
+    }
+}
+
+fn polymod(values: impl Iterator<Item = u8>) -> u64 {
+    const GENERATOR: [u64; 5] = [0x98f2bc8e61, 0x79b76d99e2, 0xf33e5fb3c4, 0xae2eabe2a8, 0x1e4f43e470];
+    let mut c = 1u64;
+    for d in values {
+        let c0 = c >> 35;
+        c = ((c & 0x07ffffffff) << 5) ^ d as u64;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (c0 >> i) & 1 != 0 {
+                c ^= generator;
+            }
+        }
+    }
+    c ^ 1
+}
+
+/// Check that `address` is well-formed (known network prefix, valid characters, checksum and
+/// payload length) and return its network
+pub fn validate(address: &str) -> Result<Network, String> {
+    let (prefix, payload) =
+        address.split_once(':').ok_or_else(|| format!("address {} is missing network prefix", address))?;
+    let network = Network::from_prefix(prefix)
+        .ok_or_else(|| format!("address {} has unknown network prefix '{}'", address, prefix))?;
+    let data = payload
+        .bytes()
+        .map(|c| CHARSET.iter().position(|&x| x == c).map(|v| v as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| format!("address {} contains invalid characters", address))?;
+    if data.len() <= CHECKSUM_LEN {
+        return Err(format!("address {} is too short", address));
+    }
+    let prefix_values = prefix.bytes().map(|c| c & 0x1f).chain(std::iter::once(0));
+    if polymod(prefix_values.chain(data.iter().copied())) != 0 {
+        return Err(format!("address {} has invalid checksum (typo?)", address));
+    }
+
+    // 5 bit groups back to bytes (incomplete last group is padding): version byte and key
+    let groups = &data[..data.len() - CHECKSUM_LEN];
+    let key_len = (groups.len() * 5 / 8).saturating_sub(1);
+    // Top 5 bits of version are in the first group, all known versions have the lowest 3 bits 0
+    let version = groups[0] >> 2;
+    let expected_len = match version {
+        0 | 8 => 32,
+        1 => 33,
+        _ => return Err(format!("address {} has unknown version {}", address, version)),
+    };
+    if key_len != expected_len {
+        return Err(format!(
+            "address {} has {} byte payload, version {} needs {}",
+            address, key_len, version, expected_len
+        ));
+    }
+    Ok(network)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DEVFUND: &str = "kaspa:pzhh76qc82wzduvsrd9xh4zde9qhp0xc8rl7qu2mvl2e42uvdqt75zrcgpm00";
+    const MAINNET: &str = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+    const TESTNET: &str = "kaspatest:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7an5vlrr8";
+
+    #[test]
+    fn test_valid_addresses() {
+        assert_eq!(validate(DEVFUND), Ok(Network::Mainnet));
+        assert_eq!(validate(MAINNET), Ok(Network::Mainnet));
+        assert_eq!(validate(TESTNET), Ok(Network::Testnet));
+    }
+
+    #[test]
+    fn test_invalid_addresses() {
+        // Typo in payload
+        assert!(validate(&MAINNET.replace("qqqqz", "qqqpz")).unwrap_err().contains("checksum"));
+        // Payload of one network with prefix of another
+        assert!(validate(&MAINNET.replace("kaspa:", "kaspatest:")).unwrap_err().contains("checksum"));
+        assert!(validate(&MAINNET.replace("kaspa:", "kasp:")).unwrap_err().contains("unknown network prefix"));
+        assert!(validate(&MAINNET.replace("kaspa:", "")).unwrap_err().contains("missing network prefix"));
+        // `b` isn't in the charset
+        assert!(validate(&MAINNET.replace("qqqqz", "qqqbz")).unwrap_err().contains("invalid characters"));
+        assert!(validate(&MAINNET.to_uppercase()).is_err());
+        assert!(validate("kaspa:x").is_err());
+        assert!(validate("kaspa:qqqqqqqq").is_err());
+    }
+}
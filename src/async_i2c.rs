@@ -1,14 +1,20 @@
 //! Async wrapper for `I2cdev` - runs I2cDevice in a separate thread and forwards
 //! requests from async tasks.
 
+#[cfg(test)]
+pub mod test_utils;
+
 use logging::macros::*;
 
+use async_trait::async_trait;
+
 use futures::channel::mpsc;
 use futures::channel::oneshot;
 use futures::executor::block_on;
+use futures::future::{self, Either};
 use futures::stream::StreamExt;
-use async_compat::{futures, tokio};
-use tokio::task;
+use async_compat::prelude::*;
+use async_compat::futures;
 
 use embedded_hal::blocking::i2c::{Read, Write};
 use linux_embedded_hal::I2cdev;
@@ -16,44 +22,242 @@ use linux_embedded_hal::I2cdev;
 use crate::error::{self, ErrorKind};
 use failure::ResultExt;
 
+use std::collections::VecDeque;
 use std::convert::AsRef;
+use std::fmt;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{self, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for I2C request to be carried out by default
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest delay before retrying a request (see `RequestOptions::with_retries`)
+pub const MAX_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// One operation of I2C transaction
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Read { address: u8, num_bytes: usize },
+    Write { address: u8, bytes: Vec<u8> },
+}
+
+/// Priority of I2C request: queued high priority requests (ie. voltage control) are carried out
+/// before queued normal ones (ie. sensor polling)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    Normal,
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+/// How the I2C request is carried out. By default it has normal priority and it's not retried
+/// (retrying is fine for flaky sensors, but not for parts of control sequences).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RequestOptions {
+    pub priority: Priority,
+    /// How many times failed request is retried
+    pub retries: usize,
+    /// Delay before each retry
+    pub retry_delay: Duration,
+}
+
+impl RequestOptions {
+    pub fn high_priority() -> Self {
+        Self {
+            priority: Priority::High,
+            ..Default::default()
+        }
+    }
+
+    /// Retry failed request at most `retries` times, waiting `retry_delay` (capped at
+    /// `MAX_RETRY_DELAY`) before each retry. The serving thread waits along with the request, so
+    /// the delay holds up every queued request, high priority ones included. Beware that the
+    /// retries count towards the request timeout.
+    pub fn with_retries(self, retries: usize, retry_delay: Duration) -> Self {
+        Self {
+            retries,
+            retry_delay: retry_delay.min(MAX_RETRY_DELAY),
+            ..self
+        }
+    }
+
+    /// Carry out `operation`, retrying it as configured. Retrying stops once the request is
+    /// `abandoned` (ie. its originator timed out), nobody would get the result.
+    fn retry<R>(
+        &self,
+        abandoned: impl Fn() -> bool,
+        mut operation: impl FnMut() -> error::Result<R>,
+    ) -> error::Result<R> {
+        let mut retries_left = self.retries;
+        loop {
+            match operation() {
+                Err(e) if retries_left > 0 && !abandoned() => {
+                    retries_left -= 1;
+                    warn!("AsyncI2c request failed: {}, retrying", e);
+                    thread::sleep(self.retry_delay);
+                    if abandoned() {
+                        warn!("AsyncI2c request abandoned, not retrying");
+                        return Err(e);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
 
 enum Request {
     Read {
         address: u8,
         num_bytes: usize,
+        options: RequestOptions,
         /// Channel used to send back result
         reply: oneshot::Sender<error::Result<Vec<u8>>>,
     },
     Write {
         address: u8,
         bytes: Vec<u8>,
+        options: RequestOptions,
         /// Channel used to send back result
         reply: oneshot::Sender<error::Result<()>>,
     },
+    Transaction {
+        ops: Vec<Op>,
+        options: RequestOptions,
+        /// Channel used to send back result
+        reply: oneshot::Sender<error::Result<Vec<Vec<u8>>>>,
+    },
+}
+
+impl Request {
+    fn priority(&self) -> Priority {
+        match self {
+            Request::Read { options, .. }
+            | Request::Write { options, .. }
+            | Request::Transaction { options, .. } => options.priority,
+        }
+    }
+
+    /// Answer request with error instead of carrying it out
+    fn fail(self, reason: &str) {
+        let kind = ErrorKind::I2c(reason.to_string());
+        // Sending fails only when the originator is gone, there's nobody to tell then
+        match self {
+            Request::Read { reply, .. } => {
+                let _ = reply.send(Err(kind.into()));
+            }
+            Request::Write { reply, .. } => {
+                let _ = reply.send(Err(kind.into()));
+            }
+            Request::Transaction { reply, .. } => {
+                let _ = reply.send(Err(kind.into()));
+            }
+        }
+    }
+}
+
+/// Carry out operations back-to-back, stop on first error
+fn execute_ops<T, E>(i2c_device: &mut T, ops: Vec<Op>) -> error::Result<Vec<Vec<u8>>>
+where
+    T: Read<Error = E> + Write<Error = E>,
+    E: fmt::Display,
+{
+    let mut results = Vec::with_capacity(ops.len());
+    for op in ops.into_iter() {
+        match op {
+            Op::Read { address, num_bytes } => {
+                let mut bytes = vec![0; num_bytes];
+                i2c_device
+                    .read(address, &mut bytes)
+                    .map_err(|e| ErrorKind::I2c(e.to_string()))?;
+                results.push(bytes);
+            }
+            Op::Write { address, bytes } => {
+                i2c_device
+                    .write(address, &bytes)
+                    .map_err(|e| ErrorKind::I2c(e.to_string()))?;
+                results.push(Vec::new());
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Requests received by the server that wait to be carried out, by priority
+#[derive(Default)]
+struct RequestQueue {
+    high: VecDeque<Request>,
+    normal: VecDeque<Request>,
+}
+
+impl RequestQueue {
+    /// Next request to carry out, `None` when the channel is closed and everything has been taken.
+    /// All requests waiting in the channel are taken first, so that high priority ones can jump
+    /// the queue.
+    fn next(&mut self, request_rx: &mut mpsc::UnboundedReceiver<Request>) -> Option<Request> {
+        while let Ok(Some(request)) = request_rx.try_next() {
+            match request.priority() {
+                Priority::High => self.high.push_back(request),
+                Priority::Normal => self.normal.push_back(request),
+            }
+        }
+        match self.high.pop_front().or_else(|| self.normal.pop_front()) {
+            Some(request) => Some(request),
+            None => block_on(request_rx.next()),
+        }
+    }
 }
 
 /// Server for I2C read/write requests
 /// Runs in separate thread.
-/// Terminates when all request sender sides are dropped.
-fn serve_requests(
-    mut i2c_device: I2cdev,
+/// Terminates when all request sender sides are dropped or the channel is closed.
+/// Requests whose originator has already given up (ie. timed out while the bus was stuck) are
+/// skipped, so that the server catches up with the queue once the bus recovers. Requests still
+/// queued once `closing` is set are failed. High priority requests go before normal ones.
+fn serve_requests<T, E>(
+    mut i2c_device: T,
     mut request_rx: mpsc::UnboundedReceiver<Request>,
-) -> error::Result<()> {
-    while let Some(request) = block_on(request_rx.next()) {
+    closing: &AtomicBool,
+) -> error::Result<()>
+where
+    T: Read<Error = E> + Write<Error = E>,
+    E: fmt::Display,
+{
+    let mut queue = RequestQueue::default();
+    while let Some(request) = queue.next(&mut request_rx) {
+        if closing.load(Ordering::SeqCst) {
+            request.fail("I2C device closed");
+            continue;
+        }
         match request {
             Request::Read {
                 address,
                 num_bytes,
+                options,
                 reply,
             } => {
-                let mut bytes = vec![0; num_bytes];
-                let result = i2c_device
-                    .read(address, &mut bytes)
-                    .with_context(|e| ErrorKind::I2c(e.to_string()))
-                    .map(|_| bytes)
-                    .map_err(|e| e.into());
+                if reply.is_canceled() {
+                    warn!("AsyncI2c read from {:#04x} abandoned, skipping", address);
+                    continue;
+                }
+                let result = options.retry(
+                    || reply.is_canceled(),
+                    || {
+                        let mut bytes = vec![0; num_bytes];
+                        i2c_device
+                            .read(address, &mut bytes)
+                            .map(|_| bytes)
+                            .map_err(|e| ErrorKind::I2c(e.to_string()).into())
+                    },
+                );
                 if reply.send(result).is_err() {
                     warn!("AsyncI2c reply send failed - remote side may have ended");
                 }
@@ -61,12 +265,39 @@ fn serve_requests(
             Request::Write {
                 address,
                 bytes,
+                options,
                 reply,
             } => {
-                let result = i2c_device
-                    .write(address, &bytes)
-                    .with_context(|e| ErrorKind::I2c(e.to_string()))
-                    .map_err(|e| e.into());
+                if reply.is_canceled() {
+                    warn!("AsyncI2c write to {:#04x} abandoned, skipping", address);
+                    continue;
+                }
+                let result = options.retry(
+                    || reply.is_canceled(),
+                    || {
+                        i2c_device
+                            .write(address, &bytes)
+                            .map_err(|e| ErrorKind::I2c(e.to_string()).into())
+                    },
+                );
+                if reply.send(result).is_err() {
+                    warn!("AsyncI2c reply send failed - remote side may have ended");
+                }
+            }
+            Request::Transaction {
+                ops,
+                options,
+                reply,
+            } => {
+                if reply.is_canceled() {
+                    warn!("AsyncI2c transaction abandoned, skipping");
+                    continue;
+                }
+                // Whole transaction is retried
+                let result = options.retry(
+                    || reply.is_canceled(),
+                    || execute_ops(&mut i2c_device, ops.clone()),
+                );
                 if reply.send(result).is_err() {
                     warn!("AsyncI2c reply send failed - remote side may have ended");
                 }
@@ -76,57 +307,434 @@ fn serve_requests(
     Ok(())
 }
 
-/// Clonable async I2C device. I2cDevice is closed when last sender channel is dropped.
+/// Raw async I2C master: plain reads and writes addressed by 7-bit `address`
+#[async_trait]
+pub trait AsyncI2c
+where
+    Self: Sync + Send,
+{
+    /// Read `num_bytes` from device on `address`
+    async fn read(&self, address: u8, num_bytes: usize) -> error::Result<Vec<u8>>;
+
+    /// Write `bytes` to device on `address`
+    async fn write(&self, address: u8, bytes: Vec<u8>) -> error::Result<()>;
+
+    /// Read with `options`, masters that don't support them just read
+    async fn read_with(
+        &self,
+        address: u8,
+        num_bytes: usize,
+        _options: RequestOptions,
+    ) -> error::Result<Vec<u8>> {
+        self.read(address, num_bytes).await
+    }
+
+    /// Write with `options`, masters that don't support them just write
+    async fn write_with(
+        &self,
+        address: u8,
+        bytes: Vec<u8>,
+        _options: RequestOptions,
+    ) -> error::Result<()> {
+        self.write(address, bytes).await
+    }
+
+    /// Carry out `ops` in order and return their results (bytes read, empty for writes).
+    /// The transaction is aborted on first failed operation.
+    async fn transaction(&self, ops: Vec<Op>) -> error::Result<Vec<Vec<u8>>> {
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops.into_iter() {
+            results.push(match op {
+                Op::Read { address, num_bytes } => self.read(address, num_bytes).await?,
+                Op::Write { address, bytes } => {
+                    self.write(address, bytes).await?;
+                    Vec::new()
+                }
+            });
+        }
+        Ok(results)
+    }
+}
+
+/// Async I2C device. I2cDevice is closed when last sender channel is dropped or explicitly
+/// by `close()`.
 pub struct AsyncI2cDev {
     request_tx: mpsc::UnboundedSender<Request>,
+    /// How long to wait for each request to complete
+    timeout: Duration,
+    /// Tells serving thread to fail the requests it hasn't started yet
+    closing: Arc<AtomicBool>,
+    /// Final result of the serving thread, `None` once it has been waited for
+    server: Mutex<Option<oneshot::Receiver<error::Result<()>>>>,
 }
 
-/// TODO: Make this into a trait, then implement different backends.
-/// TODO: Write tests for this and for power controller (fake async I2C with power controller,
-/// check power initialization goes as expected etc., maybe reuse I2C bus from sensors?).
 /// TODO: Reuse traits from `i2c/i2c.rs`
 impl AsyncI2cDev {
     /// Open I2C device
-    /// Although this function is not async, it has to be called from within Tokio context
-    /// because it spawns task in a separate thread that serves the (blocking) I2C requests.
+    /// Requests are served by a separate thread, so the device can be opened (and used with
+    /// blocking requests) before the runtime is up.
     pub fn open<P: AsRef<Path>>(path: P) -> error::Result<Self> {
         let i2c_device = I2cdev::new(path).with_context(|e| ErrorKind::I2c(e.to_string()))?;
+        Ok(Self::from_blocking(i2c_device, DEFAULT_REQUEST_TIMEOUT))
+    }
+
+    /// Serve requests with arbitrary blocking I2C implementation
+    /// Each request fails with timeout error when it's not carried out in `timeout`.
+    pub fn from_blocking<T, E>(i2c_device: T, timeout: Duration) -> Self
+    where
+        T: Read<Error = E> + Write<Error = E> + Send + 'static,
+        E: fmt::Display,
+    {
         let (request_tx, request_rx) = mpsc::unbounded();
+        let closing = Arc::new(AtomicBool::new(false));
 
-        // Spawn the future in a separate blocking pool (for blocking operations)
-        // so that this doesn't block the regular threadpool.
-        task::spawn_blocking(move || {
-            if let Err(e) = serve_requests(i2c_device, request_rx) {
+        // Blocking I2C requests are served by a plain thread, it doesn't need the runtime and
+        // doesn't hold up its threads
+        let server_closing = closing.clone();
+        let (server_tx, server) = oneshot::channel();
+        thread::spawn(move || {
+            let result = serve_requests(i2c_device, request_rx, &server_closing);
+            if let Err(e) = &result {
                 error!("{}", e);
             }
+            // Nobody listens unless the device is being closed
+            let _ = server_tx.send(result);
+        });
+
+        Self {
+            request_tx,
+            timeout,
+            closing,
+            server: Mutex::new(Some(server)),
+        }
+    }
+
+    /// Close the device: request in progress is finished, requests still queued (and any later
+    /// ones) fail with an error. Returns once the serving thread has exited, with its final
+    /// error, if any. Closing device again does nothing.
+    pub async fn close(&self) -> error::Result<()> {
+        self.closing.store(true, Ordering::SeqCst);
+        self.request_tx.close_channel();
+        let server = self
+            .server
+            .lock()
+            .expect("BUG: I2C server lock poisoned")
+            .take();
+        match server {
+            Some(server) => server
+                .await
+                .map_err(|_| ErrorKind::I2c("I2C server panicked".into()))?,
+            None => Ok(()),
+        }
+    }
+
+    /// Send request to the serving thread and wait (at most `timeout`) for reply
+    async fn submit<R>(
+        &self,
+        request: Request,
+        reply_rx: oneshot::Receiver<error::Result<R>>,
+    ) -> error::Result<R> {
+        self.request_tx
+            .unbounded_send(request)
+            .map_err(|_| ErrorKind::I2c("I2C server has terminated".into()))?;
+        match reply_rx.timeout(self.timeout).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(ErrorKind::I2c("I2C server dropped request".into()))?,
+            Err(_) => Err(ErrorKind::I2c("timeout".into()))?,
+        }
+    }
+
+    /// Blocking variant of `submit`. Tokio timer isn't available outside of the runtime, so
+    /// `timeout` is kept by a helper thread that gives up waiting for the reply.
+    fn submit_blocking<R>(
+        &self,
+        request: Request,
+        reply_rx: oneshot::Receiver<error::Result<R>>,
+    ) -> error::Result<R> {
+        self.request_tx
+            .unbounded_send(request)
+            .map_err(|_| ErrorKind::I2c("I2C server has terminated".into()))?;
+        let (timeout_tx, timeout_rx) = oneshot::channel();
+        // Dropped when the reply comes, which lets the helper thread go right away
+        let (replied_tx, replied_rx) = sync::mpsc::channel::<()>();
+        let timeout = self.timeout;
+        thread::spawn(move || {
+            if let Err(sync::mpsc::RecvTimeoutError::Timeout) = replied_rx.recv_timeout(timeout) {
+                let _ = timeout_tx.send(());
+            }
         });
+        let reply = block_on(future::select(reply_rx, timeout_rx));
+        drop(replied_tx);
+        match reply {
+            Either::Left((Ok(result), _)) => result,
+            Either::Left((Err(_), _)) => Err(ErrorKind::I2c("I2C server dropped request".into()))?,
+            Either::Right(_) => Err(ErrorKind::I2c("timeout".into()))?,
+        }
+    }
 
-        Ok(Self { request_tx })
+    /// Blocking read for code that runs outside of the runtime (ie. early initialization).
+    /// It blocks the calling thread, so never call it from async code (runtime threads).
+    pub fn read_blocking(&self, address: u8, num_bytes: usize) -> error::Result<Vec<u8>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let request = Request::Read {
+            address,
+            num_bytes,
+            options: Default::default(),
+            reply: reply_tx,
+        };
+        self.submit_blocking(request, reply_rx)
     }
 
-    pub async fn read(&self, address: u8, num_bytes: usize) -> error::Result<Vec<u8>> {
+    /// Blocking write for code that runs outside of the runtime (ie. early initialization).
+    /// It blocks the calling thread, so never call it from async code (runtime threads).
+    pub fn write_blocking(&self, address: u8, bytes: Vec<u8>) -> error::Result<()> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let request = Request::Write {
+            address,
+            bytes,
+            options: Default::default(),
+            reply: reply_tx,
+        };
+        self.submit_blocking(request, reply_rx)
+    }
+}
+
+#[async_trait]
+impl AsyncI2c for AsyncI2cDev {
+    async fn read(&self, address: u8, num_bytes: usize) -> error::Result<Vec<u8>> {
+        self.read_with(address, num_bytes, Default::default()).await
+    }
+
+    async fn write(&self, address: u8, bytes: Vec<u8>) -> error::Result<()> {
+        self.write_with(address, bytes, Default::default()).await
+    }
+
+    async fn read_with(
+        &self,
+        address: u8,
+        num_bytes: usize,
+        options: RequestOptions,
+    ) -> error::Result<Vec<u8>> {
         let (reply_tx, reply_rx) = oneshot::channel();
         let request = Request::Read {
             address,
             num_bytes,
+            options,
             reply: reply_tx,
         };
-        self.request_tx
-            .unbounded_send(request)
-            .expect("I2C request failed");
-        reply_rx.await.expect("failed to receive I2C reply")
+        self.submit(request, reply_rx).await
     }
 
-    pub async fn write(&self, address: u8, bytes: Vec<u8>) -> error::Result<()> {
+    async fn write_with(
+        &self,
+        address: u8,
+        bytes: Vec<u8>,
+        options: RequestOptions,
+    ) -> error::Result<()> {
         let (reply_tx, reply_rx) = oneshot::channel();
         let request = Request::Write {
             address,
             bytes,
+            options,
             reply: reply_tx,
         };
-        self.request_tx
-            .unbounded_send(request)
-            .expect("I2C request failed");
-        reply_rx.await.expect("failed to receive I2C reply")
+        self.submit(request, reply_rx).await
+    }
+
+    /// Whole transaction is carried out by the serving thread without interleaving other
+    /// requests, so it costs just one round-trip.
+    async fn transaction(&self, ops: Vec<Op>) -> error::Result<Vec<Vec<u8>>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let request = Request::Transaction {
+            ops,
+            options: Default::default(),
+            reply: reply_tx,
+        };
+        self.submit(request, reply_rx).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_compat::tokio;
+    use test_utils::FakeBlockingI2c;
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let i2c_device = FakeBlockingI2c::new(Duration::from_millis(200));
+        let device = AsyncI2cDev::from_blocking(i2c_device.clone(), Duration::from_millis(50));
+
+        // bus is stuck for longer than we are willing to wait
+        match device.read(0x10, 1).await {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::I2c("timeout".into())),
+            Ok(_) => panic!("stuck read didn't time out"),
+        }
+
+        // bus recovers, device is still usable
+        i2c_device.set_delay(Duration::from_millis(0));
+        // let the stuck transaction finish
+        tokio::time::delay_for(Duration::from_millis(250)).await;
+        device
+            .write(0x10, vec![0xaa])
+            .await
+            .expect("write after timeout failed");
+        assert_eq!(device.read(0x10, 2).await.unwrap(), vec![0xaa, 0xaa]);
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let device = AsyncI2cDev::from_blocking(
+            FakeBlockingI2c::new(Duration::from_millis(200)),
+            DEFAULT_REQUEST_TIMEOUT,
+        );
+
+        // first write occupies the bus, second one waits in queue when the device is closed
+        let (in_progress, queued, closed) = futures::join!(
+            device.write(0x10, vec![0xaa]),
+            device.write(0x10, vec![0x55]),
+            async {
+                tokio::time::delay_for(Duration::from_millis(50)).await;
+                device.close().await
+            }
+        );
+        in_progress.expect("request in progress not finished");
+        match queued {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::I2c("I2C device closed".into())),
+            Ok(_) => panic!("queued request carried out after close"),
+        }
+        closed.expect("close failed");
+
+        match device.read(0x10, 1).await {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::I2c("I2C server has terminated".into())),
+            Ok(_) => panic!("closed device still serves requests"),
+        }
+        device.close().await.expect("second close failed");
+    }
+
+    #[tokio::test]
+    async fn test_priority() {
+        let device = AsyncI2cDev::from_blocking(
+            FakeBlockingI2c::new(Duration::from_millis(100)),
+            DEFAULT_REQUEST_TIMEOUT,
+        );
+
+        // Bus is busy with the first read when the rest is queued. Reads return the last written
+        // byte, so they tell whether the write went before them.
+        let (first, second, third, write) = futures::join!(
+            device.read(0x10, 1),
+            device.read(0x10, 1),
+            device.read(0x10, 1),
+            device.write_with(0x20, vec![0x77], RequestOptions::high_priority()),
+        );
+        write.expect("write failed");
+        assert_eq!(first.unwrap(), vec![0x00]);
+        assert_eq!(second.unwrap(), vec![0x77]);
+        assert_eq!(third.unwrap(), vec![0x77]);
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let i2c_device = FakeBlockingI2c::new(Duration::from_millis(0));
+        let device = AsyncI2cDev::from_blocking(i2c_device.clone(), DEFAULT_REQUEST_TIMEOUT);
+        device.write(0x4c, vec![0x42]).await.expect("write failed");
+
+        // not retried by default
+        i2c_device.fail_next(1);
+        assert!(device.read(0x4c, 1).await.is_err());
+
+        let retriable = RequestOptions::default().with_retries(1, Duration::from_millis(10));
+        i2c_device.fail_next(1);
+        assert_eq!(device.read_with(0x4c, 1, retriable).await.unwrap(), vec![0x42]);
+
+        // retries run out
+        i2c_device.fail_next(2);
+        assert!(device.read_with(0x4c, 1, retriable).await.is_err());
+        assert_eq!(device.read(0x4c, 1).await.unwrap(), vec![0x42]);
+    }
+
+    #[test]
+    fn test_retry_abandoned() {
+        let options = RequestOptions::default().with_retries(5, Duration::from_secs(10));
+        assert_eq!(options.retry_delay, MAX_RETRY_DELAY);
+
+        // Originator gives up after the first retry
+        let attempts = std::cell::Cell::new(0);
+        let result: error::Result<()> = options.retry(
+            || attempts.get() == 2,
+            || {
+                attempts.set(attempts.get() + 1);
+                Err(ErrorKind::I2c("nack".into()).into())
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[test]
+    fn test_blocking() {
+        let i2c_device = FakeBlockingI2c::new(Duration::from_millis(0));
+        // No runtime is running
+        let device = AsyncI2cDev::from_blocking(i2c_device.clone(), Duration::from_millis(100));
+
+        device.write_blocking(0x10, vec![0x5a]).expect("write failed");
+        assert_eq!(device.read_blocking(0x10, 2).unwrap(), vec![0x5a, 0x5a]);
+        i2c_device.fail_next(1);
+        assert!(device.read_blocking(0x10, 1).is_err());
+
+        // stuck bus
+        i2c_device.set_delay(Duration::from_millis(300));
+        match device.read_blocking(0x10, 1) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::I2c("timeout".into())),
+            Ok(_) => panic!("stuck read didn't time out"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let device = AsyncI2cDev::from_blocking(
+            FakeBlockingI2c::new(Duration::from_millis(0)),
+            DEFAULT_REQUEST_TIMEOUT,
+        );
+
+        let ops = vec![
+            Op::Write {
+                address: 0x10,
+                bytes: vec![0x01],
+            },
+            Op::Read {
+                address: 0x10,
+                num_bytes: 1,
+            },
+            Op::Write {
+                address: 0x10,
+                bytes: vec![0x02],
+            },
+            Op::Read {
+                address: 0x10,
+                num_bytes: 2,
+            },
+        ];
+        let results = device.transaction(ops).await.expect("transaction failed");
+        assert_eq!(results, vec![vec![], vec![0x01], vec![], vec![0x02, 0x02]]);
+    }
+
+    #[tokio::test]
+    async fn test_default_transaction() {
+        let i2c = test_utils::FakeAsyncI2c::new();
+        i2c.push_reply(0x4c, &[0x11, 0x22, 0x33]);
+
+        let ops = vec![
+            Op::Read {
+                address: 0x4c,
+                num_bytes: 1,
+            },
+            Op::Read {
+                address: 0x4c,
+                num_bytes: 2,
+            },
+        ];
+        let results = i2c.transaction(ops).await.expect("transaction failed");
+        assert_eq!(results, vec![vec![0x11], vec![0x22, 0x33]]);
     }
 }
@@ -0,0 +1,152 @@
+//! In-memory `AsyncI2c` backend for tests
+
+use crate::async_i2c::AsyncI2c;
+use crate::error::{self, ErrorKind};
+
+use async_trait::async_trait;
+use embedded_hal::blocking::i2c::{Read, Write};
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Default)]
+struct Inner {
+    /// Bytes that are returned by reads, per address
+    replies: HashMap<u8, VecDeque<u8>>,
+    /// Log of all writes as `(address, bytes)`
+    writes: Vec<(u8, Vec<u8>)>,
+}
+
+/// Fake I2C master: reads are served from per-address queue of bytes prepared by the test,
+/// writes are recorded. Clones share the same state, so the test can keep one clone to inspect
+/// what the code under test did.
+#[derive(Clone, Default)]
+pub struct FakeAsyncI2c {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl FakeAsyncI2c {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Queue `bytes` to be returned by subsequent reads from `address`
+    pub fn push_reply(&self, address: u8, bytes: &[u8]) {
+        self.inner
+            .lock()
+            .expect("BUG: fake I2C lock poisoned")
+            .replies
+            .entry(address)
+            .or_default()
+            .extend(bytes.iter());
+    }
+
+    /// Take all writes done so far
+    pub fn take_writes(&self) -> Vec<(u8, Vec<u8>)> {
+        self.inner
+            .lock()
+            .expect("BUG: fake I2C lock poisoned")
+            .writes
+            .drain(..)
+            .collect()
+    }
+}
+
+#[async_trait]
+impl AsyncI2c for FakeAsyncI2c {
+    async fn read(&self, address: u8, num_bytes: usize) -> error::Result<Vec<u8>> {
+        let mut inner = self.inner.lock().expect("BUG: fake I2C lock poisoned");
+        let queue = inner.replies.entry(address).or_default();
+        if queue.len() < num_bytes {
+            Err(ErrorKind::I2c(format!(
+                "no reply prepared on address {:#04x}",
+                address
+            )))?
+        }
+        Ok(queue.drain(..num_bytes).collect())
+    }
+
+    async fn write(&self, address: u8, bytes: Vec<u8>) -> error::Result<()> {
+        self.inner
+            .lock()
+            .expect("BUG: fake I2C lock poisoned")
+            .writes
+            .push((address, bytes));
+        Ok(())
+    }
+}
+
+/// Fake blocking I2C device to be served by `AsyncI2cDev`. Every transaction takes `delay`
+/// (to simulate stuck bus) and reads return the last written byte.
+#[derive(Clone)]
+pub struct FakeBlockingI2c {
+    delay: Arc<Mutex<Duration>>,
+    last_written: Arc<Mutex<u8>>,
+    /// How many following transactions fail
+    failures: Arc<Mutex<usize>>,
+}
+
+impl FakeBlockingI2c {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay: Arc::new(Mutex::new(delay)),
+            last_written: Arc::new(Mutex::new(0)),
+            failures: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Make next `count` transactions fail (to simulate flaky device)
+    pub fn fail_next(&self, count: usize) {
+        *self.failures.lock().expect("BUG: fake I2C lock poisoned") = count;
+    }
+
+    /// Whether this transaction fails, counts it down if so
+    fn failed(&self) -> bool {
+        let mut failures = self.failures.lock().expect("BUG: fake I2C lock poisoned");
+        if *failures == 0 {
+            return false;
+        }
+        *failures -= 1;
+        true
+    }
+
+    pub fn set_delay(&self, delay: Duration) {
+        *self.delay.lock().expect("BUG: fake I2C lock poisoned") = delay;
+    }
+
+    fn stall(&self) {
+        let delay = *self.delay.lock().expect("BUG: fake I2C lock poisoned");
+        thread::sleep(delay);
+    }
+}
+
+impl Read for FakeBlockingI2c {
+    type Error = String;
+
+    fn read(&mut self, _address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.stall();
+        if self.failed() {
+            return Err("NACK".into());
+        }
+        let byte = *self.last_written.lock().expect("BUG: fake I2C lock poisoned");
+        buffer.iter_mut().for_each(|b| *b = byte);
+        Ok(())
+    }
+}
+
+impl Write for FakeBlockingI2c {
+    type Error = String;
+
+    fn write(&mut self, _address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.stall();
+        if self.failed() {
+            return Err("NACK".into());
+        }
+        if let Some(byte) = bytes.last() {
+            *self.last_written.lock().expect("BUG: fake I2C lock poisoned") = *byte;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+//! Worker benchmark (`--bench-secs`)
+
+use std::time::Duration;
+
+use kasop::PluginManager;
+use log::info;
+
+use crate::cli::Opt;
+use crate::miner::MinerManager;
+use crate::Error;
+
+/// Run workers on a synthetic job for `--bench-secs` and log their hashrate
+pub async fn bench(opt: &Opt, plugin_manager: &PluginManager) -> Result<(), Error> {
+    // Nothing meets the bench job target, so nothing is ever submitted
+    let (client_channel, _submitted) = tokio::sync::mpsc::channel(1);
+    let mut miner_manager = MinerManager::new(
+        client_channel,
+        opt.num_threads,
+        opt.cpu_threads,
+        plugin_manager,
+        Duration::from_secs(opt.hashrate_interval),
+        None,
+    );
+    info!("Benchmarking workers for {} s", opt.bench_secs);
+    let rates = miner_manager.bench(opt.bench_duration()).await?;
+    for (name, rate) in &rates {
+        info!("Bench {}: {}", name, MinerManager::format_hashrate(*rate));
+    }
+    let total: f64 = rates.iter().map(|(_, rate)| rate).sum();
+    info!("Bench total: {}", MinerManager::format_hashrate(total));
+    Ok(())
+}
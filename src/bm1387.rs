@@ -1,7 +1,7 @@
-pub mod i2c;
-
 use crate::error::{self, ErrorKind};
 
+use logging::macros::*;
+
 use packed_struct::prelude::*;
 use packed_struct_codegen::PackedStruct;
 use packed_struct_codegen::{PrimitiveEnum_u16, PrimitiveEnum_u8};
@@ -14,8 +14,8 @@ use std::convert::TryInto;
 use std::default::Default;
 use std::fmt::Debug;
 use std::mem::size_of;
+use std::ops::RangeInclusive;
 
-#[allow(dead_code)]
 pub const HASH_COUNTING_REG: u8 = 0x14;
 
 /// Maximum supported baud rate clock divisor
@@ -27,6 +27,9 @@ pub const CHIP_OSC_CLK_BASE_BAUD_DIV: usize = 8;
 /// How many cores are on the chip
 pub const NUM_CORES_ON_CHIP: usize = 114;
 
+/// Oscillator speed for all chips on S9 hash boards
+pub const CHIP_OSC_CLK_HZ: usize = 25_000_000;
+
 /// `MidstateCount` represents the number of midstates S9 FPGA sends to chips.
 /// This information needs to be accessible to everyone that processes `work_id`.
 ///
@@ -37,7 +40,7 @@ pub const NUM_CORES_ON_CHIP: usize = 114;
 ///
 /// `MidstateCount` is always valid - creation of `MidstateCount` object that isn't
 /// supported by hardware shouldn't be possible.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MidstateCount {
     /// internal representation is base-2 logarithm of number of midstates
     log2: usize,
@@ -46,11 +49,21 @@ pub struct MidstateCount {
 impl MidstateCount {
     /// Construct Self, panic if number of midstates is not valid for this hw
     pub fn new(count: usize) -> Self {
+        Self::try_new(count).expect("BUG: invalid midstate count")
+    }
+
+    /// Construct Self, fail if number of midstates is not valid for this hw (for counts that
+    /// come from configuration)
+    pub fn try_new(count: usize) -> error::Result<Self> {
         match count {
-            1 => Self { log2: 0 },
-            2 => Self { log2: 1 },
-            4 => Self { log2: 2 },
-            _ => panic!("Unsupported S9 midstate count {}", count),
+            1 => Ok(Self { log2: 0 }),
+            2 => Ok(Self { log2: 1 }),
+            4 => Ok(Self { log2: 2 }),
+            _ => Err(ErrorKind::General(format!(
+                "Unsupported S9 midstate count {}, expected 1, 2 or 4",
+                count
+            ))
+            .into()),
         }
     }
 
@@ -103,7 +116,7 @@ impl ChipAddress {
     }
 
     /// Return hardware chip address or 0 if it's a broadcast
-    fn to_hw_addr(&self) -> u8 {
+    pub fn to_hw_addr(&self) -> u8 {
         match self {
             ChipAddress::All => 0,
             ChipAddress::One(x) => ((*x) * 4)
@@ -133,6 +146,24 @@ impl CoreAddress {
             core: (nonce >> 24) & 0x7f,
         }
     }
+
+    /// Inverse of `new`: nonce bits that identify this chip and core, all other bits are zero.
+    /// Every nonce found by this core has these bits set the same way.
+    pub fn to_nonce_base(&self) -> u32 {
+        assert!(self.chip <= 0x3f, "chip {} out of nonce range", self.chip);
+        assert!(self.core <= 0x7f, "core {} out of nonce range", self.core);
+        ((self.chip as u32) << 2) | ((self.core as u32) << 24)
+    }
+}
+
+/// Decode solution reported by chip into index of the midstate it was found for and address of
+/// the core that found it.
+///
+/// Midstate index is in the low bits of `work_id` (see `MidstateCount::to_mask`), while the core
+/// address is carried by the `nonce` itself (see `CoreAddress`), so both are needed.
+pub fn decode_solution(nonce: u32, work_id: u32, midstates: MidstateCount) -> (usize, CoreAddress) {
+    let midstate_idx = work_id as usize & midstates.to_mask();
+    (midstate_idx, CoreAddress::new(nonce))
 }
 
 /// Control or work command layout
@@ -208,6 +239,13 @@ pub struct CmdResponse {
     _zero_in_bm1387_but_its_register_number_in_bm1391: u8,
 }
 
+impl CmdResponse {
+    /// Number of register this is a response to (always zero in bm1387)
+    pub fn register_number(&self) -> u8 {
+        self._zero_in_bm1387_but_its_register_number_in_bm1391
+    }
+}
+
 /// Sets configuration register
 #[derive(PackedStruct, Debug)]
 #[packed_struct(endian = "msb")]
@@ -319,6 +357,20 @@ impl Register for HashrateReg {
     const REG_NUM: u8 = 0x08;
 }
 
+/// Number of hashes chip has done, counted by the chip itself (wraps around). It's independent of
+/// nonces the chip sends, but 32 bits at the hashrate of one chip wrap many times a second, so
+/// nonce counters aren't cross-checked with it.
+#[allow(dead_code)]
+#[derive(PackedStruct, Debug, Clone, PartialEq)]
+#[packed_struct(endian = "msb", size_bytes = "4")]
+pub struct HashCountingReg {
+    pub hash_count: u32,
+}
+
+impl Register for HashCountingReg {
+    const REG_NUM: u8 = HASH_COUNTING_REG;
+}
+
 #[derive(PackedStruct, Debug, Clone, PartialEq)]
 #[packed_struct(size_bytes = "1", bit_numbering = "lsb0")]
 pub struct I2cControlFlags {
@@ -410,6 +462,29 @@ impl TicketMaskReg {
         let ticket_mask = (difficulty - 1).reverse_bits().swap_bytes();
         Ok(Self { ticket_mask })
     }
+
+    /// Builds ticket mask register for any (ie. pool) difficulty. Difficulty that isn't
+    /// power of 2 is rounded down, so chips send a few more nonces rather than miss some.
+    pub fn new_rounded(difficulty: u32) -> error::Result<Self> {
+        let rounded = round_asic_difficulty(difficulty)?;
+        if rounded != difficulty {
+            info!(
+                "ASIC difficulty {} rounded down to power of 2: {}",
+                difficulty, rounded
+            );
+        }
+        Self::new(rounded)
+    }
+}
+
+/// Round `difficulty` down to the nearest power of 2 (the only difficulties chips support)
+pub fn round_asic_difficulty(difficulty: u32) -> error::Result<u32> {
+    if difficulty == 0 {
+        Err(ErrorKind::General(format!(
+            "ASIC difficulty must be at least 1!",
+        )))?
+    }
+    Ok(1 << (31 - difficulty.leading_zeros()))
 }
 
 impl Register for TicketMaskReg {
@@ -526,6 +601,12 @@ impl MiscCtrlReg {
         })
     }
 
+    /// Alter the value of MiscCtrl register to process `midstate_count` midstates per work.
+    /// Multi-midstate processing is enabled only for more than one midstate.
+    pub fn set_midstate_count(&mut self, midstate_count: MidstateCount) {
+        self.mmen = midstate_count.to_count() > 1;
+    }
+
     /// Alter the value of MiscCtrl register to enable I2C
     ///
     /// When we enable/disable I2C on chip, we want to leave the rest of the settings
@@ -576,6 +657,87 @@ pub struct PllReg {
 }
 
 impl PllReg {
+    /// Supported divider ranges (`postdiv2` is further limited by `postdiv1`)
+    pub const FBDIV_RANGE: RangeInclusive<u8> = 32..=127;
+    pub const REFDIV_RANGE: RangeInclusive<u8> = 1..=63;
+    pub const POSTDIV_RANGE: RangeInclusive<u8> = 1..=7;
+
+    /// Builds PLL register from dividers and verifies they are within supported ranges
+    pub fn new(fbdiv: u8, refdiv: u8, postdiv1: u8, postdiv2: u8) -> error::Result<Self> {
+        let reg = Self {
+            fbdiv,
+            refdiv,
+            postdiv1,
+            postdiv2,
+        };
+        reg.validate()?;
+        Ok(reg)
+    }
+
+    /// Check that dividers are within supported ranges
+    pub fn validate(&self) -> error::Result<()> {
+        if !Self::FBDIV_RANGE.contains(&self.fbdiv) {
+            Err(ErrorKind::PLL(format!(
+                "fbdiv {} out of range {:?}",
+                self.fbdiv,
+                Self::FBDIV_RANGE
+            )))?
+        }
+        if !Self::REFDIV_RANGE.contains(&self.refdiv) {
+            Err(ErrorKind::PLL(format!(
+                "refdiv {} out of range {:?}",
+                self.refdiv,
+                Self::REFDIV_RANGE
+            )))?
+        }
+        if !Self::POSTDIV_RANGE.contains(&self.postdiv1) {
+            Err(ErrorKind::PLL(format!(
+                "postdiv1 {} out of range {:?}",
+                self.postdiv1,
+                Self::POSTDIV_RANGE
+            )))?
+        }
+        if !Self::POSTDIV_RANGE.contains(&self.postdiv2) || self.postdiv2 > self.postdiv1 {
+            Err(ErrorKind::PLL(format!(
+                "postdiv2 {} out of range {:?} or above postdiv1 {}",
+                self.postdiv2,
+                Self::POSTDIV_RANGE,
+                self.postdiv1
+            )))?
+        }
+        Ok(())
+    }
+
+    /// Builds PLL register from raw register value (ie. `0x680221` from vendor configuration)
+    /// and verifies the dividers are within supported ranges
+    pub fn from_raw(raw: u32) -> error::Result<Self> {
+        let reg = Self::from_reg(raw);
+        if reg.to_reg() != raw {
+            Err(ErrorKind::PLL(format!(
+                "register value {:#08x} has bits set outside of dividers",
+                raw
+            )))?
+        }
+        reg.validate()?;
+        Ok(reg)
+    }
+
+    pub fn fbdiv(&self) -> u8 {
+        self.fbdiv
+    }
+
+    pub fn refdiv(&self) -> u8 {
+        self.refdiv
+    }
+
+    pub fn postdiv1(&self) -> u8 {
+        self.postdiv1
+    }
+
+    pub fn postdiv2(&self) -> u8 {
+        self.postdiv2
+    }
+
     /// Simulate divider/PLL and calculate target frequency
     pub fn calc(&self, xtal_freq: usize) -> usize {
         // we have to do the arithmetic in u64 (at least) to be sure
@@ -599,7 +761,7 @@ impl Register for PllReg {
 
 // TODO: how to initialize with custom XTAL frequency?
 pub static PRECOMPUTED_PLL: Lazy<Vec<PllFrequency>> =
-    Lazy::new(|| PllFrequency::precompute_pll_table(crate::CHIP_OSC_CLK_HZ));
+    Lazy::new(|| PllFrequency::precompute_pll_table(CHIP_OSC_CLK_HZ));
 
 // compute distance between two usizes
 fn distance(x: usize, y: usize) -> usize {
@@ -612,7 +774,7 @@ fn distance(x: usize, y: usize) -> usize {
 
 /// Represents PLL divider and associated frequency computed at some crystal speed (which is
 /// assumed common to all hashboards and constant over the duration of program)
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PllFrequency {
     pub frequency: usize,
     pub reg: PllReg,
@@ -628,6 +790,10 @@ impl PllFrequency {
     fn precompute_pll_table(xtal_freq: usize) -> Vec<Self> {
         let min_mhz = Self::MIN_FREQ_HZ / Self::BIN_SIZE_HZ;
         let max_mhz = Self::MAX_FREQ_HZ / Self::BIN_SIZE_HZ;
+        // Frequencies that round into bins of supported range
+        let min_freq = (min_mhz * Self::BIN_SIZE_HZ).saturating_sub(Self::BIN_SIZE_HZ / 2) as u64;
+        let max_freq = (max_mhz * Self::BIN_SIZE_HZ + Self::BIN_SIZE_HZ / 2 - 1) as u64;
+        let xtal_freq_u64 = xtal_freq as u64;
         // One bin for each MHz in the range [0; MAX_MHZ].
         // Each bin contains either nothing or the best approximation found so far.
         let mut freq_bins: Vec<Option<Self>> = vec![None; max_mhz + 1];
@@ -636,7 +802,18 @@ impl PllFrequency {
         for postdiv1 in 1..=7 {
             for refdiv in 1..=63 {
                 for postdiv2 in 1..=postdiv1 {
-                    for fbdiv in 32..128 {
+                    // Nested integer divisions in `PllReg::calc` equal division by the product,
+                    // so frequency is `xtal_freq * fbdiv / divisor` (rounded down), which grows
+                    // with `fbdiv`. Only `fbdiv` range that lands in supported bins is visited, in
+                    // the same order as the full scan, so the table stays the same.
+                    let divisor = refdiv as u64 * postdiv1 as u64 * postdiv2 as u64;
+                    let fbdiv_min = (min_freq * divisor + xtal_freq_u64 - 1) / xtal_freq_u64;
+                    let fbdiv_max = ((max_freq + 1) * divisor - 1) / xtal_freq_u64;
+                    let fbdiv_range = &PllReg::FBDIV_RANGE;
+                    let fbdiv_min = fbdiv_min.max(*fbdiv_range.start() as u64);
+                    let fbdiv_max = fbdiv_max.min(*fbdiv_range.end() as u64);
+                    for fbdiv in fbdiv_min..=fbdiv_max {
+                        let fbdiv = fbdiv as u8;
                         // Contruct PLL register
                         let reg = PllReg {
                             fbdiv,
@@ -681,10 +858,18 @@ impl PllFrequency {
         pll_table
     }
 
+    /// All frequencies achievable by chip PLL (one per MHz bin), sorted by frequency
+    pub fn table() -> &'static [PllFrequency] {
+        &PRECOMPUTED_PLL
+    }
+
     /// Lookup best divider for a given frequency from a table of dividers
     /// This table is built on-demand (via `once_cell::Lazy`)
     pub fn lookup_freq(target_freq: usize) -> error::Result<PllFrequency> {
-        let plls = &PRECOMPUTED_PLL;
+        Self::lookup_in(&PRECOMPUTED_PLL, target_freq)
+    }
+
+    fn lookup_in(plls: &[PllFrequency], target_freq: usize) -> error::Result<PllFrequency> {
         // The table is sorted
         let result = plls.binary_search_by_key(&target_freq, |p| p.frequency);
         match result {
@@ -931,6 +1116,24 @@ mod test {
         assert!(TicketMaskReg::new(2048).is_ok());
     }
 
+    #[test]
+    fn test_round_asic_difficulty() {
+        assert_eq!(round_asic_difficulty(1).unwrap(), 1);
+        assert_eq!(round_asic_difficulty(2).unwrap(), 2);
+        assert_eq!(round_asic_difficulty(3).unwrap(), 2);
+        assert_eq!(round_asic_difficulty(100).unwrap(), 64);
+        assert_eq!(round_asic_difficulty(2047).unwrap(), 1024);
+        assert_eq!(round_asic_difficulty(2048).unwrap(), 2048);
+        assert_eq!(round_asic_difficulty(u32::MAX).unwrap(), 1 << 31);
+        assert!(round_asic_difficulty(0).is_err());
+
+        assert_eq!(
+            TicketMaskReg::new_rounded(100).expect("Cannot build difficulty register"),
+            TicketMaskReg::new(64).expect("Cannot build difficulty register")
+        );
+        assert!(TicketMaskReg::new_rounded(0).is_err());
+    }
+
     #[test]
     fn test_ticket_mask_reg_to_u32() {
         assert_eq!(
@@ -956,6 +1159,15 @@ mod test {
         assert_eq!(reg.hashrate(), 0x23000000);
     }
 
+    #[test]
+    fn test_hash_counting_reg() {
+        let reg = HashCountingReg::from_reg(0x12345678);
+        assert_eq!(reg.hash_count, 0x12345678);
+        assert_eq!(reg.pack(), [0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(reg.to_reg(), 0x12345678);
+        assert_eq!(HashCountingReg::REG_NUM, 0x14);
+    }
+
     /// Test serialization and evaluation of PLL divider
     fn try_one_divider(freq: usize, reg: u32, fbdiv: u8, refdiv: u8, postdiv1: u8, postdiv2: u8) {
         let pll = PllReg {
@@ -984,6 +1196,47 @@ mod test {
         try_one_divider(1175_000_000, 0x5e0211, 0x5e, 2, 1, 1);
     }
 
+    #[test]
+    fn test_pll_reg_new() {
+        let pll = PllReg::new(0x68, 2, 2, 1).expect("valid dividers");
+        assert_eq!(pll.to_reg(), 0x680221);
+        assert_eq!(pll.calc(DEFAULT_XTAL_FREQ), 650_000_000);
+        assert_eq!(
+            (pll.fbdiv(), pll.refdiv(), pll.postdiv1(), pll.postdiv2()),
+            (0x68, 2, 2, 1)
+        );
+        // range limits
+        assert!(PllReg::new(32, 1, 1, 1).is_ok());
+        assert!(PllReg::new(127, 63, 7, 7).is_ok());
+
+        assert!(PllReg::new(31, 2, 2, 1).is_err());
+        assert!(PllReg::new(128, 2, 2, 1).is_err());
+        assert!(PllReg::new(0x68, 0, 2, 1).is_err());
+        assert!(PllReg::new(0x68, 64, 2, 1).is_err());
+        assert!(PllReg::new(0x68, 2, 0, 1).is_err());
+        assert!(PllReg::new(0x68, 2, 8, 1).is_err());
+        assert!(PllReg::new(0x68, 2, 2, 0).is_err());
+        assert!(PllReg::new(0x68, 2, 2, 3).is_err());
+    }
+
+    #[test]
+    fn test_pll_reg_from_raw() {
+        let pll = PllReg::from_raw(0x680221).expect("valid register");
+        assert_eq!(pll, PllReg::new(0x68, 2, 2, 1).unwrap());
+        assert_eq!(pll.calc(DEFAULT_XTAL_FREQ), 650_000_000);
+        assert_eq!(
+            PllReg::from_raw(0x500211).unwrap().calc(DEFAULT_XTAL_FREQ),
+            1000_000_000
+        );
+        // zero dividers
+        assert!(PllReg::from_raw(0).is_err());
+        // postdiv2 above postdiv1
+        assert!(PllReg::from_raw(0x680212).is_err());
+        // bits outside of dividers
+        assert!(PllReg::from_raw(0x01680221).is_err());
+        assert!(PllReg::from_raw(0x681221).is_err());
+    }
+
     fn lookup_one(freq: usize) -> Option<usize> {
         if let Ok(PllFrequency { frequency, reg }) = PllFrequency::lookup_freq(freq) {
             // found frequency and PLL register have to match
@@ -1017,6 +1270,64 @@ mod test {
         assert_eq!(lookup_one(1_033_333_333), Some(1_033_333_333));
     }
 
+    #[test]
+    fn test_pll_table() {
+        let table = PllFrequency::table();
+        assert!(!table.is_empty());
+        assert!(table.windows(2).all(|pair| pair[0].frequency < pair[1].frequency));
+        assert_eq!(table.first().unwrap().frequency, 100_000_000);
+        assert_eq!(table.last().unwrap().frequency, 1_200_000_000);
+    }
+
+    /// Original exhaustive scan of all dividers
+    fn reference_pll_table(xtal_freq: usize) -> Vec<PllFrequency> {
+        let min_mhz = PllFrequency::MIN_FREQ_HZ / PllFrequency::BIN_SIZE_HZ;
+        let max_mhz = PllFrequency::MAX_FREQ_HZ / PllFrequency::BIN_SIZE_HZ;
+        let mut freq_bins: Vec<Option<PllFrequency>> = vec![None; max_mhz + 1];
+        for postdiv1 in 1..=7 {
+            for refdiv in 1..=63 {
+                for postdiv2 in 1..=postdiv1 {
+                    for fbdiv in 32..128 {
+                        let reg = PllReg {
+                            fbdiv,
+                            refdiv,
+                            postdiv1,
+                            postdiv2,
+                        };
+                        let frequency = reg.calc(xtal_freq);
+                        let bin_no = (frequency + 500_000) / PllFrequency::BIN_SIZE_HZ;
+                        if bin_no < min_mhz || bin_no > max_mhz {
+                            continue;
+                        }
+                        let bin_freq = bin_no * PllFrequency::BIN_SIZE_HZ;
+                        let bin = &mut freq_bins[bin_no];
+                        if let Some(old) = bin.as_ref() {
+                            if distance(bin_freq, old.frequency) <= distance(bin_freq, frequency) {
+                                continue;
+                            }
+                        }
+                        *bin = Some(PllFrequency { frequency, reg });
+                    }
+                }
+            }
+        }
+        freq_bins.into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn test_pll_table_matches_reference() {
+        for xtal_freq in [DEFAULT_XTAL_FREQ, 24_000_000, 33_333_333] {
+            let reference = reference_pll_table(xtal_freq);
+            let table = PllFrequency::precompute_pll_table(xtal_freq);
+            assert_eq!(table, reference);
+
+            for target in (0..1_300_000_000).step_by(1_234_567) {
+                let expected = PllFrequency::lookup_in(&reference, target).ok();
+                assert_eq!(PllFrequency::lookup_in(&table, target).ok(), expected);
+            }
+        }
+    }
+
     #[test]
     fn test_core_address() {
         assert_eq!(
@@ -1052,6 +1363,56 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_core_address_nonce_base() {
+        assert_eq!(CoreAddress { chip: 0, core: 0 }.to_nonce_base(), 0);
+        assert_eq!(CoreAddress { chip: 0x3f, core: 0x7f }.to_nonce_base(), 0x7f_00_00_fc);
+        assert_eq!(CoreAddress { chip: 23, core: 42 }.to_nonce_base(), 0x2a_00_00_5c);
+        for nonce in [0xffffffff, 0x2a105d5d, 0xd25738d3, 0x47268d19, 0xa5e09223, 0x40e55650].iter() {
+            let address = CoreAddress::new(*nonce);
+            // Base keeps only chip and core bits of the nonce
+            assert_eq!(address.to_nonce_base(), nonce & 0x7f_00_00_fc);
+            assert_eq!(CoreAddress::new(address.to_nonce_base()), address);
+        }
+        for chip in 0..64 {
+            for core in 0..128 {
+                let address = CoreAddress { chip, core };
+                assert_eq!(CoreAddress::new(address.to_nonce_base()), address);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_core_address_nonce_base_out_of_range() {
+        CoreAddress { chip: 64, core: 0 }.to_nonce_base();
+    }
+
+    #[test]
+    fn test_decode_solution() {
+        let core = CoreAddress { chip: 23, core: 42 };
+        // (midstate count, work_id, expected midstate index)
+        let cases = [
+            (1, 0x2b, 0),
+            (1, 0x7f, 0),
+            (2, 0x2b, 1),
+            (2, 0x2e, 0),
+            (4, 0x2b, 3),
+            (4, 0x2e, 2),
+            (4, 0x7d, 1),
+            (4, 0x00, 0),
+        ];
+        for (count, work_id, midstate_idx) in cases.iter() {
+            assert_eq!(
+                decode_solution(0x2a105d5d, *work_id, MidstateCount::new(*count)),
+                (*midstate_idx, core),
+                "work_id {:#x} with {} midstates",
+                work_id,
+                count
+            );
+        }
+    }
+
     #[test]
     fn test_midstate_count_instance() {
         MidstateCount::new(1);
@@ -1065,6 +1426,21 @@ mod test {
         MidstateCount::new(3);
     }
 
+    #[test]
+    fn test_midstate_count_try_new() {
+        for count in [1, 2, 4].iter() {
+            assert_eq!(
+                MidstateCount::try_new(*count)
+                    .expect("valid midstate count rejected")
+                    .to_count(),
+                *count
+            );
+        }
+        for count in [0, 3, 8].iter() {
+            assert!(MidstateCount::try_new(*count).is_err());
+        }
+    }
+
     #[test]
     fn test_midstate_count_conversion() {
         use fpga_io_am1_s9::common::ctrl_reg::MIDSTATE_CNT_A;
@@ -1073,4 +1449,37 @@ mod test {
         assert_eq!(MidstateCount::new(2).to_count(), 2);
         assert_eq!(MidstateCount::new(4).to_reg(), MIDSTATE_CNT_A::FOUR);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_misc_ctrl_midstate_count() {
+        let mut reg = MiscCtrlReg::new(true, true, 1, false, false).expect("building register failed");
+        reg.set_midstate_count(MidstateCount::new(4));
+        assert!(reg.mmen);
+        reg.set_midstate_count(MidstateCount::new(1));
+        assert!(!reg.mmen);
+    }
+}
+
+#[cfg(all(test, feature = "bench"))]
+mod benches {
+    extern crate test;
+
+    use self::test::{black_box, Bencher};
+    use super::PllFrequency;
+
+    #[bench]
+    pub fn bench_precompute_pll_table(bh: &mut Bencher) {
+        bh.iter(|| black_box(PllFrequency::precompute_pll_table(black_box(super::CHIP_OSC_CLK_HZ))));
+    }
+
+    #[bench]
+    pub fn bench_lookup_freq(bh: &mut Bencher) {
+        // Make sure the table is built outside of measured code
+        PllFrequency::lookup_freq(650_000_000).unwrap();
+        bh.iter(|| {
+            for frequency in (100_000_000..1_200_000_000).step_by(10_000_000) {
+                black_box(PllFrequency::lookup_freq(black_box(frequency)).unwrap());
+            }
+        });
+    }
+}
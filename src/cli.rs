@@ -1,39 +1,313 @@
 use clap::Parser;
 use log::LevelFilter;
-use std::{net::IpAddr, str::FromStr};
+use std::{net::{IpAddr, SocketAddr}, path::PathBuf, str::FromStr, time::Duration};
 
+use crate::address::{self, Network};
+use crate::bm1387::{I2cBusSelect, MidstateCount};
+use crate::counters::ChipReinitConfig;
+use crate::i2c;
+use crate::logformat::LogFormat;
+use crate::monitor::{self, SensorAggregation};
+use crate::power::limit::PowerLimit;
+use crate::target::Uint256;
+use crate::webhook::WebhookUrl;
 use crate::Error;
+use crate::hashchain::{HashboardConfig, SensorConfig};
+
+/// Number of hashboard slots (see `--hashboard`)
+const MAX_HASHBOARDS: usize = 8;
 
 #[derive(Parser, Debug)]
-#[clap(name = "kasop", about = "Kaspa $KAS ASIC Miner")]
+#[clap(name = "kasop", about = "Kaspa $KAS ASIC Miner", args_override_self = true)]
 pub struct Opt {
     #[clap(short, long, help = "Enable debug logging level")]
     pub debug: bool,
+    #[clap(
+        long = "quiet",
+        help = "Don't log individual shares (found, accepted, resubmitted), warnings and periodic summaries are still logged"
+    )]
+    pub quiet: bool,
     #[clap(short = 'a', long = "mining-address", help = "The Kaspa address for the miner reward")]
     pub mining_address: String,
-    #[clap(short = 's', long = "kaspad-address", default_value = "127.0.0.1", help = "The IP of the kaspad instance")]
+    #[clap(short = 's', long = "kaspad-address", default_value = "127.0.0.1", help = "The IP of the kaspad instance, or grpc://host:port, stratum+tcp://host:port or grpc+unix:///path/to/socket")]
     pub kaspad_address: String,
 
     #[clap(long = "devfund-percent", help = "The percentage of blocks to send to the devfund (minimum 2%)", default_value = "2", parse(try_from_str = parse_devfund_percent))]
     pub devfund_percent: u16,
 
+    #[clap(long = "no-devfund", help = "Disable devfund (no blocks are sent to the devfund)")]
+    pub no_devfund: bool,
+
+    #[clap(
+        long = "devfund-window",
+        default_value = "10000",
+        help = "Number of block templates over which the devfund percentage is realized, smaller window switches address more often"
+    )]
+    pub devfund_window: u16,
+
     #[clap(short, long, help = "Kaspad port [default: Mainnet = 16111, Testnet = 16211]")]
     port: Option<u16>,
 
+    #[clap(
+        long = "worker-name",
+        help = "Name of this rig on the stratum pool, sent as <mining-address>.<worker-name> [default: hostname]"
+    )]
+    pub worker_name: Option<String>,
+
     #[clap(long, help = "Use testnet instead of mainnet [default: false]")]
-    testnet: bool,
+    pub testnet: bool,
     #[clap(short = 't', long = "threads", help = "Amount of CPU miner threads to launch [default: 0]")]
     pub num_threads: Option<u16>,
+    #[clap(
+        long = "cpu-threads",
+        default_value = "0",
+        help = "Amount of built-in CPU workers, they go through the same worker interface as GPU plugins (for smoke testing)"
+    )]
+    pub cpu_threads: u16,
     #[clap(
         long = "mine-when-not-synced",
         help = "Mine even when kaspad says it is not synced, only useful when passing `--allow-submit-block-when-not-synced` to kaspad  [default: false]"
     )]
     pub mine_when_not_synced: bool,
 
+    #[clap(
+        long = "hashrate-interval",
+        default_value = "10",
+        help = "Seconds between hashrate reports (total and per worker)"
+    )]
+    pub hashrate_interval: u64,
+
+    #[clap(
+        long = "submit-retries",
+        default_value = "3",
+        help = "How many times a share or block is resubmitted when it gets lost (no response in time, connection reset), 0 disables retries"
+    )]
+    pub submit_retries: u32,
+
+    #[clap(
+        long = "submit-coalesce-ms",
+        default_value = "0",
+        help = "Send solutions found within this many milliseconds together, blocks are never delayed (0 disables). Not supported with stratum, pools don't tell network difficulty, so shares that solve a block can't be told apart"
+    )]
+    pub submit_coalesce_ms: u64,
+
+    #[clap(
+        long = "min-share-difficulty",
+        help = "Don't submit shares below this difficulty even if the pool would accept them (pool mining only, blocks found solo are never dropped, keep it well below network difficulty)"
+    )]
+    pub min_share_difficulty: Option<f64>,
+
+    #[clap(long = "log-format", default_value = "text", help = "Log line format: text or json")]
+    pub log_format: LogFormat,
+
+    #[clap(long = "log-file", help = "Write logs also to this file (rotated by size)")]
+    pub log_file: Option<String>,
+    #[clap(long = "log-file-size", default_value = "10", help = "Size of log file in MiB before it's rotated")]
+    pub log_file_size: u64,
+    #[clap(long = "log-file-keep", default_value = "5", help = "How many rotated log files to keep")]
+    pub log_file_keep: usize,
+
+    #[clap(
+        long = "power-limit-watts",
+        help = "Lower chip frequency to keep estimated power of each hashboard (see --hashboard) under this limit"
+    )]
+    pub power_limit_watts: Option<f64>,
+
+    #[clap(
+        long = "halt-timeout-secs",
+        default_value = "30",
+        help = "Seconds each hashchain task gets to finish on shutdown before it's considered stuck"
+    )]
+    pub halt_timeout_secs: u64,
+
+    #[clap(
+        long = "sensor-i2c-bus",
+        default_value = "bottom",
+        parse(try_from_str = parse_i2c_bus),
+        help = "I2C bus of the hashing chip the temperature sensor is attached to: bottom or middle"
+    )]
+    pub sensor_i2c_bus: I2cBusSelect,
+
+    #[clap(
+        long = "sensor-i2c-address",
+        parse(try_from_str = parse_i2c_address),
+        help = "8-bit I2C address of the temperature sensor (ie. 0x98) [default: probe all known addresses]"
+    )]
+    pub sensor_i2c_address: Option<i2c::Address>,
+
+    #[clap(
+        long = "temp-filter-window",
+        help = "Number of temperature readings fans follow the median of, so that a glitch doesn't spin them up [default: 5]"
+    )]
+    pub temp_filter_window: Option<usize>,
+
+    #[clap(
+        long = "sensor-aggregation",
+        default_value = "max",
+        parse(try_from_str = parse_sensor_aggregation),
+        help = "How temperatures of all sensors of a hashboard make one for fan control: max (the hottest) or average"
+    )]
+    pub sensor_aggregation: SensorAggregation,
+
+    #[clap(
+        long = "expected-fans",
+        help = "Number of fans the miner is fitted with, warn as soon as fewer of them spin (see --hashboard) [default: don't check]"
+    )]
+    pub expected_fans: Option<usize>,
+
+    #[clap(
+        long = "fan-zones",
+        use_value_delimiter = true,
+        help = "Run fan PID per fan: comma separated positions (from 0) among --hashboard options of the hashboard nearest to each fan, ie. 0,0,1,1. All fans share one PWM output, so the hottest zone drives them all [default: one PID for all fans]"
+    )]
+    pub fan_zones: Option<Vec<usize>>,
+
+    #[clap(
+        long = "fan-target-temp",
+        help = "Temperature in °C fans keep hashboards at (see --hashboard), it has to be below the hot temperature [default: 75]"
+    )]
+    pub fan_target_temp: Option<f32>,
+
+    #[clap(
+        long = "midstates",
+        parse(try_from_str = parse_midstates),
+        help = "Number of midstates sent to hashboard chips (see --hashboard) in one work: 1, 2 or 4 [default: 1]"
+    )]
+    pub midstates: Option<MidstateCount>,
+
+    #[clap(
+        long = "hashboard",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        help = "Initialize S9 hashboard in this slot (1-8) and run its monitoring (temperature, fans, hashrate, voltage), can be repeated. Hashboards aren't sent any work yet, they only come up and get monitored"
+    )]
+    pub hashboards: Vec<usize>,
+
+    #[clap(
+        long = "hashrate-divergence-percent",
+        help = "Warn when hashrate reported by hashboard chips and hashrate counted from their nonces differ by more than this many percent [default: 10]"
+    )]
+    pub hashrate_divergence_percent: Option<f64>,
+
+    #[clap(
+        long = "core-health-dir",
+        help = "Keep per-core health of hashboards in this directory, so that it survives restarts [default: don't keep it]"
+    )]
+    pub core_health_dir: Option<PathBuf>,
+
+    #[clap(
+        long = "pic-program",
+        help = "Flash hashboard voltage controllers running unexpected firmware with PIC program from this file (Bitmain .txt format) [default: the embedded one, or /lib/antminer/hash_s8_app.txt]"
+    )]
+    pub pic_program: Option<PathBuf>,
+
+    #[clap(
+        long = "chip-reinit-error-percent",
+        help = "Re-initialize hashboard chip whose nonces are errors more than this many percent of the time [default: 20]"
+    )]
+    pub chip_reinit_error_percent: Option<f64>,
+
+    #[clap(
+        long = "chip-reinit-window-secs",
+        help = "Seconds over which error rate of hashboard chips is evaluated (see --chip-reinit-error-percent) [default: 300]"
+    )]
+    pub chip_reinit_window_secs: Option<u64>,
+
+    #[clap(
+        long = "reset-counters-on-job",
+        help = "Start hashboard nonce and error counters over with every new job, so that stats reflect just the current job [default: count over the whole run]"
+    )]
+    pub reset_counters_on_job: bool,
+
+    #[clap(
+        long = "dry-run",
+        help = "Check options, plugins and connection to the pool/node, then exit without mining"
+    )]
+    pub dry_run: bool,
+
+    #[clap(
+        long = "bench",
+        help = "Hash a synthetic job without connecting to the pool/node, report hashrate per worker and exit"
+    )]
+    pub bench: bool,
+
+    #[clap(long = "bench-secs", default_value = "10", help = "How many seconds --bench hashes for")]
+    pub bench_secs: u64,
+
+    #[clap(
+        long = "dashboard",
+        help = "Repaint hashrate, hashboard temperatures, fans and shares on stdout every second (logs stay on stderr)"
+    )]
+    pub dashboard: bool,
+
+    #[clap(
+        long = "health-listen",
+        help = "Serve health check at http://<address>/healthz (ie. 127.0.0.1:8080), 503 means the miner is wedged, and status at /status.json"
+    )]
+    pub health_listen: Option<SocketAddr>,
+
+    #[clap(
+        long = "health-max-job-age-secs",
+        default_value = "120",
+        help = "Health check fails when the last job is older than this many seconds"
+    )]
+    pub health_max_job_age_secs: u64,
+
+    #[clap(
+        long = "no-job-timeout-secs",
+        help = "Reconnect when the pool/node stays connected but sends no new job for this many seconds (off by default)"
+    )]
+    pub no_job_timeout_secs: Option<u64>,
+
+    #[clap(
+        long = "webhook-url",
+        help = "POST critical events (pool lost and with --hashboard also hashboard failed, fans stalled, overheating, throttling) and found blocks as JSON to this http:// URL"
+    )]
+    pub webhook_url: Option<WebhookUrl>,
+
+    #[clap(
+        long = "webhook-interval-secs",
+        default_value = "300",
+        help = "Post the same kind of event (for the same hashboard) to the webhook at most once per this many seconds"
+    )]
+    pub webhook_interval_secs: u64,
+
+    #[clap(
+        long = "plugin-allow",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        parse(try_from_str = parse_plugin_name),
+        help = "Also load worker plugin library with this name (ie. myworker for libmyworker.so), can be repeated"
+    )]
+    pub plugin_allow: Vec<String>,
+
+    #[clap(
+        long = "plugin-dir",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        help = "Also look for worker plugins in this directory (besides the directory of the executable), can be repeated"
+    )]
+    pub plugin_dir: Vec<String>,
+
+    #[clap(long = "config", help = "TOML file with options, command line options take precedence")]
+    pub config: Option<String>,
+
     #[clap(skip)]
     pub devfund_address: String,
 }
 
+/// Devfund setting as decided from options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Devfund {
+    /// `percent` (in hundredths of a percent) of templates are mined to `address`
+    Enabled { percent: u16, address: String },
+    /// Explicitly disabled by `--no-devfund`
+    Disabled,
+    /// Not disabled explicitly, but nothing is mined to `address` (ie. it's on another network
+    /// than mining address)
+    ZeroPercent { address: String },
+}
+
 fn parse_devfund_percent(s: &str) -> Result<u16, &'static str> {
     let err = "devfund-percent should be --devfund-percent=XX.YY up to 2 numbers after the dot";
     let mut splited = s.split('.');
@@ -62,6 +336,47 @@ fn parse_devfund_percent(s: &str) -> Result<u16, &'static str> {
     Ok(prefix * 100 + postfix)
 }
 
+fn parse_midstates(s: &str) -> Result<MidstateCount, String> {
+    let count = s.parse::<usize>().map_err(|_| format!("midstate count '{}' is not a number", s))?;
+    MidstateCount::try_new(count).map_err(|e| e.to_string())
+}
+
+/// Plugin library name without `lib` prefix and extension, it mustn't be a path
+pub fn parse_plugin_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(format!(
+            "invalid plugin name '{}', expected library name without extension (letters, digits, _ and -)",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+fn parse_i2c_bus(s: &str) -> Result<I2cBusSelect, String> {
+    match s {
+        "bottom" => Ok(I2cBusSelect::Bottom),
+        "middle" => Ok(I2cBusSelect::Middle),
+        _ => Err(format!("unknown I2C bus '{}', expected bottom or middle", s)),
+    }
+}
+
+fn parse_sensor_aggregation(s: &str) -> Result<SensorAggregation, String> {
+    match s {
+        "max" => Ok(SensorAggregation::Max),
+        "average" => Ok(SensorAggregation::Average),
+        _ => Err(format!("unknown sensor aggregation '{}', expected max or average", s)),
+    }
+}
+
+fn parse_i2c_address(s: &str) -> Result<i2c::Address, String> {
+    let address = u8::from_str_radix(s.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("I2C address '{}' is not a hex byte", s))?;
+    if address & 1 != 0 {
+        return Err(format!("I2C address {:#04x} is odd, expected 8-bit address with read/write bit clear", address));
+    }
+    Ok(i2c::Address::new(address))
+}
+
 impl Opt {
     pub fn process(&mut self) -> Result<(), Error> {
         //self.gpus = None;
@@ -76,12 +391,99 @@ impl Opt {
         }
         log::info!("kaspad address: {}", self.kaspad_address);
 
-        if self.num_threads.is_none() {
-            self.num_threads = Some(0);
+        if self.devfund_window < 100 {
+            return Err(format!("devfund window has to be at least 100 templates, got {}", self.devfund_window).into());
+        }
+
+        if let Some(worker_name) = &self.worker_name {
+            if worker_name.is_empty() || worker_name.contains(char::is_whitespace) {
+                return Err(format!("worker name '{}' has to be non-empty without whitespace", worker_name).into());
+            }
+        }
+
+        if self.hashrate_interval == 0 {
+            return Err("hashrate interval has to be at least 1 second".into());
+        }
+
+        if let Some(difficulty) = self.min_share_difficulty {
+            Uint256::from_difficulty(difficulty).map_err(|e| format!("invalid minimum share difficulty: {}", e))?;
+        }
+
+        if self.health_max_job_age_secs == 0 {
+            return Err("health check job age limit has to be at least 1 second".into());
+        }
+
+        if self.bench_secs == 0 {
+            return Err("bench duration has to be at least 1 second".into());
+        }
+
+        if self.no_job_timeout_secs == Some(0) {
+            return Err("no job timeout has to be at least 1 second".into());
+        }
+
+        if self.halt_timeout_secs == 0 {
+            return Err("halt timeout has to be at least 1 second".into());
+        }
+
+        if let Some(watts) = self.power_limit_watts {
+            if watts.is_nan() || watts <= 0.0 {
+                return Err(format!("power limit has to be positive, got {} W", watts).into());
+            }
+        }
+
+        if let Some(&hashboard_idx) = self.hashboards.iter().find(|&&idx| !(1..=MAX_HASHBOARDS).contains(&idx)) {
+            return Err(format!("hashboard slot {} out of range 1..{}", hashboard_idx, MAX_HASHBOARDS).into());
+        }
+
+        if let Some(percent) = self.hashrate_divergence_percent {
+            if !percent.is_finite() || percent <= 0.0 {
+                return Err(format!("hashrate divergence has to be positive, got {}%", percent).into());
+            }
+        }
+
+        if let Some(percent) = self.chip_reinit_error_percent {
+            if !(percent > 0.0 && percent <= 100.0) {
+                return Err(format!("chip re-init error rate has to be in 0..100%, got {}%", percent).into());
+            }
+        }
+
+        if let Some(zone) = self.fan_zones.iter().flatten().find(|&&zone| zone >= self.hashboards.len()) {
+            let count = self.hashboards.len();
+            return Err(format!("fan zone {} out of range, there are {} hashboards", zone, count).into());
+        }
+
+        self.hashboard_config().monitor_config().validate()?;
+
+        if self.temp_filter_window == Some(0) {
+            return Err("temperature filter window has to be at least 1 reading".into());
+        }
+
+        if self.chip_reinit_window_secs == Some(0) {
+            return Err("chip re-init window has to be at least 1 second".into());
+        }
+
+        if self.submit_coalesce().is_some() && self.kaspad_address.starts_with("stratum+tcp://") {
+            // Shares that happen to solve a block would be held back too
+            return Err("--submit-coalesce-ms is not supported with stratum pools".into());
+        }
+
+        let network = address::validate(&self.mining_address).map_err(|e| format!("invalid mining address: {}", e))?;
+        let (expected, mode) =
+            if self.testnet { (Network::Testnet, "--testnet") } else { (Network::Mainnet, "mainnet") };
+        if network != expected {
+            return Err(format!(
+                "mining address {} is for {}, but {} expects {} address",
+                self.mining_address,
+                network,
+                mode,
+                expected
+            )
+            .into());
         }
 
         let miner_network = self.mining_address.split(':').next();
         self.devfund_address = String::from("kaspa:pzhh76qc82wzduvsrd9xh4zde9qhp0xc8rl7qu2mvl2e42uvdqt75zrcgpm00");
+        address::validate(&self.devfund_address).map_err(|e| format!("invalid devfund address: {}", e))?;
         let devfund_network = self.devfund_address.split(':').next();
         if miner_network.is_some() && devfund_network.is_some() && miner_network != devfund_network {
             self.devfund_percent = 0;
@@ -91,13 +493,114 @@ impl Opt {
                 devfund_network.unwrap()
             )
         }
+        if self.no_devfund {
+            self.devfund_percent = 0;
+        }
         Ok(())
     }
 
+    /// Whether devfund is in effect after `process`
+    pub fn devfund(&self) -> Devfund {
+        if self.no_devfund {
+            Devfund::Disabled
+        } else if self.devfund_percent > 0 {
+            Devfund::Enabled { percent: self.devfund_percent, address: self.devfund_address.clone() }
+        } else if !self.devfund_address.is_empty() {
+            Devfund::ZeroPercent { address: self.devfund_address.clone() }
+        } else {
+            Devfund::Disabled
+        }
+    }
+
+    /// Worker name for the pool: `--worker-name` or hostname (without domain), program name when
+    /// even that isn't available
+    pub fn worker_name(&self) -> String {
+        if let Some(worker_name) = &self.worker_name {
+            return worker_name.clone();
+        }
+        let mut buf = [0u8; 256];
+        let hostname = match nix::unistd::gethostname(&mut buf) {
+            Ok(hostname) => hostname.to_string_lossy().split('.').next().unwrap_or_default().to_string(),
+            Err(_) => String::new(),
+        };
+        if hostname.is_empty() {
+            env!("CARGO_PKG_NAME").to_string()
+        } else {
+            hostname
+        }
+    }
+
     fn port(&mut self) -> u16 {
         *self.port.get_or_insert(if self.testnet { 16211 } else { 16110 })
     }
 
+    /// Share coalescing window, `None` when disabled
+    pub fn submit_coalesce(&self) -> Option<Duration> {
+        Some(Duration::from_millis(self.submit_coalesce_ms)).filter(|window| !window.is_zero())
+    }
+
+    /// Target of the local share difficulty floor, `None` when not set
+    pub fn min_share_target(&self) -> Option<Uint256> {
+        self.min_share_difficulty.map(|difficulty| {
+            Uint256::from_difficulty(difficulty).expect("BUG: minimum share difficulty wasn't validated")
+        })
+    }
+
+    pub fn health_max_job_age(&self) -> Duration {
+        Duration::from_secs(self.health_max_job_age_secs)
+    }
+
+    pub fn bench_duration(&self) -> Duration {
+        Duration::from_secs(self.bench_secs)
+    }
+
+    pub fn no_job_timeout(&self) -> Option<Duration> {
+        self.no_job_timeout_secs.map(Duration::from_secs)
+    }
+
+    pub fn halt_timeout(&self) -> Duration {
+        Duration::from_secs(self.halt_timeout_secs)
+    }
+
+    pub fn webhook_interval(&self) -> Duration {
+        Duration::from_secs(self.webhook_interval_secs)
+    }
+
+    pub fn sensor_config(&self) -> SensorConfig {
+        SensorConfig { i2c_bus: self.sensor_i2c_bus, i2c_address: self.sensor_i2c_address }
+    }
+
+    /// When to re-initialize hashboard chips, unset options keep their defaults
+    pub fn chip_reinit_config(&self) -> ChipReinitConfig {
+        let default = ChipReinitConfig::default();
+        ChipReinitConfig {
+            max_error_rate: self.chip_reinit_error_percent.map_or(default.max_error_rate, |percent| percent / 100.0),
+            window: self.chip_reinit_window_secs.map_or(default.window, Duration::from_secs),
+            ..default
+        }
+    }
+
+    /// Settings of hashboards started with `--hashboard`
+    pub fn hashboard_config(&self) -> HashboardConfig {
+        HashboardConfig {
+            hashrate_divergence_percent: self
+                .hashrate_divergence_percent
+                .unwrap_or(crate::hashchain::DEFAULT_HASHRATE_DIVERGENCE_PERCENT),
+            power_limit: self.power_limit_watts.map(PowerLimit::new),
+            core_health_dir: self.core_health_dir.clone(),
+            pic_program: self.pic_program.clone(),
+            chip_reinit: self.chip_reinit_config(),
+            halt_timeout: self.halt_timeout(),
+            sensor: self.sensor_config(),
+            midstate_count: self.midstates,
+            temp_filter_window: self.temp_filter_window.unwrap_or(monitor::DEFAULT_TEMP_FILTER_WINDOW),
+            sensor_aggregation: self.sensor_aggregation,
+            expected_fans: self.expected_fans,
+            fan_zones: self.fan_zones.clone(),
+            fan_target_temp: self.fan_target_temp,
+        }
+    }
+
     pub fn log_level(&self) -> LevelFilter {
         if self.debug {
             LevelFilter::Debug
@@ -106,3 +609,236 @@ impl Opt {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hashchain::{DEFAULT_HASHRATE_DIVERGENCE_PERCENT, HALT_TIMEOUT};
+    use crate::{bm1387, counters, power};
+
+    #[test]
+    fn test_mining_address_validated() {
+        let process = |args: &[&str]| {
+            let mut opt = Opt::try_parse_from([&["kasop"][..], args].concat()).expect("parsing failed");
+            opt.process().map(|_| opt)
+        };
+        let mainnet = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let testnet = "kaspatest:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7an5vlrr8";
+
+        assert_eq!(process(&["-a", mainnet]).expect("valid address rejected").devfund_percent, 200);
+        // Testnet address is fine, there's just no devfund
+        let opt = process(&["-a", testnet, "--testnet"]).expect("valid address rejected");
+        assert_eq!(opt.devfund_percent, 0);
+
+        let err = process(&["-a", mainnet, "--testnet"]).unwrap_err();
+        assert!(err.to_string().contains("--testnet expects kaspatest address"));
+        let err = process(&["-a", testnet]).unwrap_err();
+        assert!(err.to_string().contains("mainnet expects kaspa address"));
+        let err = process(&["-a", &mainnet.replace("qqqqz", "qqqpz")]).unwrap_err();
+        assert!(err.to_string().starts_with("invalid mining address"));
+        assert!(process(&["-a", "kaspa:x"]).is_err());
+    }
+
+    #[test]
+    fn test_submit_coalesce() {
+        let address = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let opt = Opt::try_parse_from(["kasop", "-a", address]).expect("parsing failed");
+        assert_eq!(opt.submit_coalesce(), None);
+        let opt = Opt::try_parse_from(["kasop", "-a", address, "--submit-coalesce-ms", "5"]).expect("parsing failed");
+        assert_eq!(opt.submit_coalesce(), Some(Duration::from_millis(5)));
+
+        let mut opt =
+            Opt::try_parse_from(["kasop", "-a", address, "-s", "stratum+tcp://pool:5555", "--submit-coalesce-ms", "5"])
+                .expect("parsing failed");
+        assert!(opt.process().unwrap_err().to_string().contains("not supported with stratum"));
+    }
+
+    #[test]
+    fn test_min_share_difficulty() {
+        let address = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let mut opt = Opt::try_parse_from(["kasop", "-a", address]).expect("parsing failed");
+        opt.process().expect("processing failed");
+        assert_eq!(opt.min_share_target(), None);
+
+        let mut opt =
+            Opt::try_parse_from(["kasop", "-a", address, "--min-share-difficulty", "4"]).expect("parsing failed");
+        opt.process().expect("processing failed");
+        assert_eq!(opt.min_share_target(), Some(Uint256::from_difficulty(4.0).unwrap()));
+
+        for invalid in ["0", "nan", "inf"] {
+            let mut opt = Opt::try_parse_from(["kasop", "-a", address, "--min-share-difficulty", invalid])
+                .expect("parsing failed");
+            assert!(opt.process().is_err());
+        }
+    }
+
+    #[test]
+    fn test_halt_timeout() {
+        let address = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let mut opt = Opt::try_parse_from(["kasop", "-a", address]).expect("parsing failed");
+        opt.process().expect("processing failed");
+        assert_eq!(opt.halt_timeout(), HALT_TIMEOUT);
+        assert_eq!(opt.hashboard_config().make_halt_pair().0.halt_timeout(), HALT_TIMEOUT);
+
+        let mut opt =
+            Opt::try_parse_from(["kasop", "-a", address, "--halt-timeout-secs", "5"]).expect("parsing failed");
+        opt.process().expect("processing failed");
+        // Halt channels of hashboards (see `run_hashboards`) are made by the config
+        let (sender, _receiver) = opt.hashboard_config().make_halt_pair();
+        assert_eq!(sender.halt_timeout(), Duration::from_secs(5));
+
+        let mut opt =
+            Opt::try_parse_from(["kasop", "-a", address, "--halt-timeout-secs", "0"]).expect("parsing failed");
+        assert!(opt.process().is_err());
+    }
+
+    #[test]
+    fn test_worker_name() {
+        let address = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let process = |args: &[&str]| {
+            let mut opt = Opt::try_parse_from([&["kasop", "-a", address][..], args].concat()).expect("parsing failed");
+            opt.process().map(|_| opt)
+        };
+        assert_eq!(process(&["--worker-name", "rig-1"]).expect("valid name rejected").worker_name(), "rig-1");
+        // Hostname without domain
+        let worker_name = process(&[]).expect("processing failed").worker_name();
+        assert!(!worker_name.is_empty());
+        assert!(!worker_name.contains('.'));
+
+        assert!(process(&["--worker-name", ""]).is_err());
+        assert!(process(&["--worker-name", "rig 1"]).is_err());
+    }
+
+    #[test]
+    fn test_midstates() {
+        let address = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let opt = Opt::try_parse_from(["kasop", "-a", address]).expect("parsing failed");
+        assert!(opt.midstates.is_none());
+        let opt = Opt::try_parse_from(["kasop", "-a", address, "--midstates", "2"]).expect("parsing failed");
+        assert_eq!(opt.midstates.map(|midstates| midstates.to_count()), Some(2));
+        assert_eq!(opt.hashboard_config().midstate_count, Some(MidstateCount::new(2)));
+        // unsupported count is a parse error, not a crash
+        let err = Opt::try_parse_from(["kasop", "-a", address, "--midstates", "3"]).unwrap_err();
+        assert!(err.to_string().contains("Unsupported S9 midstate count 3"));
+        assert!(Opt::try_parse_from(["kasop", "-a", address, "--midstates", "four"]).is_err());
+    }
+
+    #[test]
+    fn test_hashboard_options() {
+        let address = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let process = |args: &[&str]| {
+            let mut opt = Opt::try_parse_from([&["kasop", "-a", address][..], args].concat()).expect("parsing failed");
+            opt.process().map(|_| opt)
+        };
+        let opt = process(&[]).expect("processing failed");
+        assert!(opt.hashboards.is_empty());
+        assert_eq!(opt.hashboard_config(), HashboardConfig::default());
+        assert_eq!(opt.hashboard_config().hashrate_divergence_percent, DEFAULT_HASHRATE_DIVERGENCE_PERCENT);
+        assert_eq!(opt.hashboard_config().power_limit, None);
+        assert_eq!(opt.hashboard_config().core_health_dir, None);
+        assert_eq!(opt.hashboard_config().chip_reinit, counters::ChipReinitConfig::default());
+        assert!(!opt.reset_counters_on_job);
+
+        let opt = process(&["--hashboard", "6", "--hashboard", "8", "--hashrate-divergence-percent", "2.5"])
+            .expect("processing failed");
+        assert_eq!(opt.hashboards, vec![6, 8]);
+        assert_eq!(opt.hashboard_config().hashrate_divergence_percent, 2.5);
+
+        let opt = process(&["--hashboard", "1", "--reset-counters-on-job"]).expect("processing failed");
+        assert!(opt.reset_counters_on_job);
+
+        let opt = process(&["--hashboard", "1", "--power-limit-watts", "1200"]).expect("processing failed");
+        assert_eq!(opt.hashboard_config().power_limit, Some(power::limit::PowerLimit::new(1200.0)));
+
+        let opt = process(&["--hashboard", "1", "--core-health-dir", "/var/lib/kasop"]).expect("processing failed");
+        assert_eq!(opt.hashboard_config().core_health_dir, Some(std::path::PathBuf::from("/var/lib/kasop")));
+
+        let opt = process(&["--hashboard", "1", "--pic-program", "/tmp/hash_s8_app.txt"]).expect("processing failed");
+        assert_eq!(opt.hashboard_config().pic_program, Some(std::path::PathBuf::from("/tmp/hash_s8_app.txt")));
+
+        let opt = process(&["--hashboard", "1", "--temp-filter-window", "9"]).expect("processing failed");
+        assert_eq!(opt.hashboard_config().temp_filter_window, 9);
+        assert!(process(&["--temp-filter-window", "0"]).is_err());
+
+        let opt = process(&["--hashboard", "1", "--sensor-aggregation", "average"]).expect("processing failed");
+        assert_eq!(opt.hashboard_config().sensor_aggregation, SensorAggregation::Average);
+        assert!(Opt::try_parse_from(["kasop", "-a", address, "--sensor-aggregation", "min"]).is_err());
+
+        let opt = process(&["--hashboard", "1", "--expected-fans", "4"]).expect("processing failed");
+        let monitor_config = opt.hashboard_config().monitor_config();
+        assert_eq!(monitor_config.fan_config.and_then(|fan_config| fan_config.expected_fans), Some(4));
+
+        let opt =
+            process(&["--hashboard", "6", "--hashboard", "7", "--fan-zones", "0,0,1"]).expect("processing failed");
+        let monitor_config = opt.hashboard_config().monitor_config();
+        assert_eq!(monitor_config.fan_config.and_then(|fan_config| fan_config.fan_zones), Some(vec![0, 0, 1]));
+        assert!(process(&["--hashboard", "6", "--fan-zones", "0,1"]).is_err());
+
+        let opt = process(&["--hashboard", "1", "--fan-target-temp", "68"]).expect("processing failed");
+        let mode = opt.hashboard_config().monitor_config().fan_config.map(|fan_config| fan_config.mode);
+        assert!(matches!(mode, Some(monitor::FanControlMode::TargetTemperature(t)) if t == 68.0));
+        // at or over hot temperature
+        assert!(process(&["--hashboard", "1", "--fan-target-temp", "90"]).is_err());
+
+        assert!(process(&["--hashboard", "0"]).is_err());
+        assert!(process(&["--hashboard", "9"]).is_err());
+        assert!(process(&["--hashrate-divergence-percent", "0"]).is_err());
+        assert!(process(&["--hashrate-divergence-percent", "nan"]).is_err());
+
+        let opt =
+            process(&["--chip-reinit-error-percent", "5", "--chip-reinit-window-secs", "60"]).expect("processing failed");
+        let chip_reinit = opt.hashboard_config().chip_reinit;
+        assert_eq!(chip_reinit.max_error_rate, 0.05);
+        assert_eq!(chip_reinit.window, Duration::from_secs(60));
+        assert_eq!(chip_reinit.min_nonces, counters::ChipReinitConfig::default().min_nonces);
+        assert!(process(&["--chip-reinit-error-percent", "0"]).is_err());
+        assert!(process(&["--chip-reinit-error-percent", "101"]).is_err());
+        assert!(process(&["--chip-reinit-window-secs", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_sensor_config() {
+        let parse = |args: &[&str]| Opt::try_parse_from([&["kasop", "-a", "kaspa:x"][..], args].concat());
+
+        let opt = parse(&[]).expect("parsing failed");
+        assert_eq!(opt.sensor_config(), SensorConfig::default());
+
+        let opt = parse(&["--sensor-i2c-bus", "middle", "--sensor-i2c-address", "0x9a"]).expect("parsing failed");
+        let config = opt.sensor_config();
+        assert_eq!(config.i2c_bus, bm1387::I2cBusSelect::Middle);
+        assert_eq!(config.i2c_address, Some(i2c::Address::new(0x9a)));
+        assert_eq!(config.to_string(), "Middle I2C bus, address 0x9a");
+        assert_eq!(opt.hashboard_config().sensor, config);
+
+        assert!(parse(&["--sensor-i2c-bus", "top"]).is_err());
+        // Odd address would be a 7-bit one or one with read/write bit set
+        assert!(parse(&["--sensor-i2c-address", "0x4d"]).is_err());
+        assert!(parse(&["--sensor-i2c-address", "0x198"]).is_err());
+    }
+
+    #[test]
+    fn test_devfund_decision() {
+        let process = |args: &[&str]| {
+            let mut opt = Opt::try_parse_from([&["kasop"][..], args].concat()).expect("parsing failed");
+            opt.process().expect("processing failed");
+            opt
+        };
+        let mainnet = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let testnet = "kaspatest:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7an5vlrr8";
+        let devfund = "kaspa:pzhh76qc82wzduvsrd9xh4zde9qhp0xc8rl7qu2mvl2e42uvdqt75zrcgpm00".to_string();
+
+        assert_eq!(process(&["-a", mainnet]).devfund(), Devfund::Enabled { percent: 200, address: devfund.clone() });
+        let opt = process(&["-a", mainnet, "--devfund-percent", "5.25"]);
+        assert_eq!(opt.devfund(), Devfund::Enabled { percent: 525, address: devfund.clone() });
+
+        let opt = process(&["-a", mainnet, "--no-devfund"]);
+        assert_eq!(opt.devfund(), Devfund::Disabled);
+        assert_eq!(opt.devfund_percent, 0);
+        let opt = process(&["-a", mainnet, "--devfund-percent", "5", "--no-devfund"]);
+        assert_eq!(opt.devfund(), Devfund::Disabled);
+        assert_eq!(process(&["-a", testnet, "--testnet", "--no-devfund"]).devfund(), Devfund::Disabled);
+
+        // Percent dropped to zero without asking for it
+        assert_eq!(process(&["-a", testnet, "--testnet"]).devfund(), Devfund::ZeroPercent { address: devfund });
+    }
+}
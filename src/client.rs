@@ -1,16 +1,451 @@
 use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::error::Error as StdError;
+use std::fmt;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
 
 pub mod grpc;
 pub mod stratum;
 
 use crate::pow::BlockSeed;
+use crate::proto::RpcBlock;
 use crate::{Error, MinerManager};
 
 #[async_trait(?Send)]
 pub trait Client {
-    fn add_devfund(&mut self, address: String, percent: u16);
+    fn add_devfund(&mut self, address: String, schedule: DevfundSchedule);
+    fn set_submit_retry(&mut self, retry: SubmitRetry);
     async fn register(&mut self) -> Result<(), Error>;
     async fn listen(&mut self, miner: &mut MinerManager) -> Result<(), Error>;
     fn get_block_channel(&self) -> Sender<BlockSeed>;
+    /// Take submissions still waiting for response, so they can be resubmitted after reconnect
+    fn take_unanswered(&mut self) -> Vec<Unanswered>;
+    /// Submit again what previous connection left without response (called after `register`)
+    async fn resubmit(&mut self, unanswered: Vec<Unanswered>) -> Result<(), Error>;
+    /// How long it took to connect and to receive the first job
+    fn connect_latency(&self) -> ConnectLatency;
+}
+
+/// Stage of client life in which an error occurred
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Connect,
+    Register,
+    Listen,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Connect => write!(f, "connect to"),
+            Phase::Register => write!(f, "register with"),
+            Phase::Listen => write!(f, "listen to"),
+        }
+    }
+}
+
+/// Client error annotated with endpoint and phase. The message includes the whole chain of
+/// underlying causes, so it can be printed as is. For the same reason `source()` doesn't return
+/// the cause (chained reporters would print it twice), it's available as `source` field.
+#[derive(Debug)]
+pub struct ClientError {
+    pub address: String,
+    pub phase: Phase,
+    pub source: Error,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to {} {}: {}", self.phase, self.address, self.source)?;
+        let mut cause = self.source.source();
+        while let Some(e) = cause {
+            write!(f, ": {}", e)?;
+            cause = e.source();
+        }
+        Ok(())
+    }
+}
+
+impl ClientError {
+    /// Wrap `source` with client `address` and `phase`
+    pub fn new(address: &str, phase: Phase, source: Error) -> Self {
+        Self { address: address.to_string(), phase, source }
+    }
+}
+
+impl StdError for ClientError {}
+
+/// Default number of templates in which the devfund share is realized
+pub const DEFAULT_DEVFUND_WINDOW: u16 = 10_000;
+
+/// Decides which templates are mined to devfund: first `percent` (in hundredths of a percent) of
+/// every `window` templates. Smaller window switches more often, but the long-run fraction
+/// stays the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DevfundSchedule {
+    percent: u16,
+    window: u16,
+}
+
+impl DevfundSchedule {
+    pub fn new(percent: u16, window: u16) -> Self {
+        assert!(window > 0, "BUG: empty devfund window");
+        Self { percent, window }
+    }
+
+    /// Number of templates of each window mined to devfund, rounded up so that the share is never
+    /// below `percent`
+    fn devfund_templates(&self) -> u32 {
+        (self.window as u32 * self.percent as u32 + 9_999) / 10_000
+    }
+
+    /// Whether template with counter value `ctr` is mined to devfund
+    pub fn is_devfund(&self, ctr: u16) -> bool {
+        ((ctr % self.window) as u32) < self.devfund_templates()
+    }
+
+    /// Counter value for next template
+    pub fn next(&self, ctr: u16) -> u16 {
+        ((ctr as u32 + 1) % self.window as u32) as u16
+    }
+}
+
+impl Default for DevfundSchedule {
+    /// Devfund disabled
+    fn default() -> Self {
+        Self::new(0, DEFAULT_DEVFUND_WINDOW)
+    }
+}
+
+/// Whether a failed submission may succeed when sent again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitFailure {
+    /// Submission or its response got lost on the way (no response in time, connection reset)
+    Transient,
+    /// Pool or node rejected the submission (low difficulty, stale, invalid block)
+    Permanent,
+}
+
+/// Default number of retries of a transiently failed submission
+pub const DEFAULT_SUBMIT_RETRIES: u32 = 3;
+
+/// Bounded retry of transiently failed submissions with exponential backoff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubmitRetry {
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled with every further one
+    pub backoff: Duration,
+    /// How long to wait for response before the submission is considered lost
+    pub timeout: Duration,
+}
+
+impl SubmitRetry {
+    /// Delay before resending submission that failed with `failure` after it was already retried
+    /// `retries` times, `None` if it should be given up
+    pub fn retry_delay(&self, failure: SubmitFailure, retries: u32) -> Option<Duration> {
+        match failure {
+            SubmitFailure::Permanent => None,
+            SubmitFailure::Transient if retries >= self.max_retries => None,
+            SubmitFailure::Transient => Some(self.backoff * 2u32.saturating_pow(retries)),
+        }
+    }
+}
+
+impl Default for SubmitRetry {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_SUBMIT_RETRIES,
+            backoff: Duration::from_millis(250),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Submission left without response when its connection was closed
+#[derive(Debug, Clone)]
+pub enum Unanswered {
+    /// Stratum share, only reported since job ids don't carry over to a new connection
+    Share { job_id: String },
+    /// Block submitted to kaspad
+    Block { block: RpcBlock, retries: u32 },
+}
+
+/// Time it took to connect to the pool/node and to receive the first job (both measured from
+/// the start of connecting)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectLatency {
+    pub connect: Option<Duration>,
+    pub first_job: Option<Duration>,
+}
+
+impl fmt::Display for ConnectLatency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |latency: Option<Duration>| latency.map_or("-".to_string(), |l| format!("{} ms", l.as_millis()));
+        write!(f, "connect {}, first job {}", ms(self.connect), ms(self.first_job))
+    }
+}
+
+/// Measures `ConnectLatency` of a single connection
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectTimer {
+    started: Instant,
+    latency: ConnectLatency,
+}
+
+impl ConnectTimer {
+    pub fn start(now: Instant) -> Self {
+        Self { started: now, latency: Default::default() }
+    }
+
+    /// Record that connection was established at `now`
+    pub fn connected(&mut self, now: Instant) -> Duration {
+        let latency = now.duration_since(self.started);
+        self.latency.connect = Some(latency);
+        latency
+    }
+
+    /// Record job received at `now`, returns latency only for the first job of the connection
+    pub fn job(&mut self, now: Instant) -> Option<Duration> {
+        if self.latency.first_job.is_some() {
+            return None;
+        }
+        let latency = now.duration_since(self.started);
+        self.latency.first_job = Some(latency);
+        Some(latency)
+    }
+
+    pub fn latency(&self) -> ConnectLatency {
+        self.latency
+    }
+}
+
+/// How often it's repeated that the miner is idle because the node isn't synced
+const NOT_SYNCED_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What to log about the miner being idle because the node isn't synced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncLog {
+    /// Node stopped being synced, so did mining
+    Idle,
+    /// Node is still not synced, mining has been idle for the duration
+    StillIdle(Duration),
+    /// Node is synced again after mining was idle for the duration
+    Resynced(Duration),
+}
+
+/// Tracks whether mining is idle because the node isn't synced, throttles logging about it
+#[derive(Debug, Default)]
+pub struct NotSyncedIdle {
+    /// When mining went idle and when it was last logged
+    idle: Option<(Instant, Instant)>,
+}
+
+impl NotSyncedIdle {
+    /// Record template received at `now`, `mining` is false when it isn't mined on because the node
+    /// isn't synced. Returns what to log, if anything.
+    pub fn update(&mut self, mining: bool, now: Instant) -> Option<SyncLog> {
+        match (mining, self.idle) {
+            (true, None) => None,
+            (true, Some((since, _))) => {
+                self.idle = None;
+                Some(SyncLog::Resynced(now.saturating_duration_since(since)))
+            }
+            (false, None) => {
+                self.idle = Some((now, now));
+                Some(SyncLog::Idle)
+            }
+            (false, Some((since, logged))) if now.saturating_duration_since(logged) >= NOT_SYNCED_LOG_INTERVAL => {
+                self.idle = Some((since, now));
+                Some(SyncLog::StillIdle(now.saturating_duration_since(since)))
+            }
+            (false, Some(_)) => None,
+        }
+    }
+}
+
+/// How many latest submissions are kept for latency summary
+const LATENCY_WINDOW: usize = 1000;
+
+/// Time between sending a share (or block) and receiving the response, over the latest
+/// `LATENCY_WINDOW` submissions
+#[derive(Debug, Default)]
+pub struct SubmitLatency {
+    samples: VecDeque<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub min: Duration,
+    pub avg: Duration,
+    pub p95: Duration,
+}
+
+impl SubmitLatency {
+    pub fn record(&mut self, latency: Duration) {
+        if self.samples.len() == LATENCY_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    /// Returns `None` when nothing was submitted yet
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let mut sorted: Vec<_> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let count = sorted.len();
+        let min = *sorted.first()?;
+        let avg = sorted.iter().sum::<Duration>() / count as u32;
+        // Nearest-rank percentile
+        let p95 = sorted[(count * 95 + 99) / 100 - 1];
+        Some(LatencySummary { count, min, avg, p95 })
+    }
+}
+
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Latency min/avg/p95: {}/{}/{} ms ({} submissions)",
+            self.min.as_millis(),
+            self.avg.as_millis(),
+            self.p95.as_millis(),
+            self.count
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_client_error() {
+        let res: Result<(), Error> = Err(io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused").into());
+        let err: Error = res.map_err(|e| ClientError::new("stratum+tcp://pool:5555", Phase::Connect, e)).unwrap_err();
+        assert_eq!(err.to_string(), "failed to connect to stratum+tcp://pool:5555: connection refused");
+        // the cause is printed once
+        assert!(err.source().is_none());
+        let err = err.downcast::<ClientError>().expect("not a client error");
+        assert_eq!(err.source.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(err.phase, Phase::Connect);
+    }
+
+    #[test]
+    fn test_not_synced_idle() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut idle = NotSyncedIdle::default();
+        assert_eq!(idle.update(true, at(0)), None);
+
+        assert_eq!(idle.update(false, at(1)), Some(SyncLog::Idle));
+        // Templates keep coming with every block, reminders are throttled
+        assert_eq!(idle.update(false, at(2)), None);
+        assert_eq!(idle.update(false, at(60)), None);
+        assert_eq!(idle.update(false, at(61)), Some(SyncLog::StillIdle(Duration::from_secs(60))));
+        assert_eq!(idle.update(false, at(100)), None);
+        assert_eq!(idle.update(false, at(121)), Some(SyncLog::StillIdle(Duration::from_secs(120))));
+
+        assert_eq!(idle.update(true, at(130)), Some(SyncLog::Resynced(Duration::from_secs(129))));
+        assert_eq!(idle.update(true, at(131)), None);
+        // Going out of sync again is logged right away
+        assert_eq!(idle.update(false, at(132)), Some(SyncLog::Idle));
+    }
+
+    #[test]
+    fn test_connect_timer() {
+        let start = Instant::now();
+        let mut timer = ConnectTimer::start(start);
+        assert_eq!(timer.latency(), ConnectLatency::default());
+        assert_eq!(timer.latency().to_string(), "connect -, first job -");
+
+        assert_eq!(timer.connected(start + Duration::from_millis(40)), Duration::from_millis(40));
+        assert_eq!(timer.job(start + Duration::from_millis(125)), Some(Duration::from_millis(125)));
+        // Only the first job counts
+        assert_eq!(timer.job(start + Duration::from_millis(900)), None);
+        assert_eq!(
+            timer.latency(),
+            ConnectLatency { connect: Some(Duration::from_millis(40)), first_job: Some(Duration::from_millis(125)) }
+        );
+        assert_eq!(timer.latency().to_string(), "connect 40 ms, first job 125 ms");
+    }
+
+    #[test]
+    fn test_devfund_fraction() {
+        for (percent, window) in [(200, DEFAULT_DEVFUND_WINDOW), (200, 100), (375, 1_000), (9_999, 10_000)] {
+            let schedule = DevfundSchedule::new(percent, window);
+            let mut ctr = 1234 % window;
+            let templates = 200_000;
+            let mut devfund = 0;
+            for _ in 0..templates {
+                if schedule.is_devfund(ctr) {
+                    devfund += 1;
+                }
+                ctr = schedule.next(ctr);
+            }
+            let fraction = devfund as f64 / templates as f64;
+            let expected = percent as f64 / 10_000.0;
+            assert!((fraction - expected).abs() < 0.001, "{}/{}: {} != {}", percent, window, fraction, expected);
+        }
+
+        let disabled = DevfundSchedule::default();
+        assert!((0..DEFAULT_DEVFUND_WINDOW).all(|ctr| !disabled.is_devfund(ctr)));
+    }
+
+    #[test]
+    fn test_devfund_share_not_below_percent() {
+        for percent in [1, 200, 375, 5_000, 9_999] {
+            for window in (1..=1_000).chain([DEFAULT_DEVFUND_WINDOW, u16::MAX]) {
+                let schedule = DevfundSchedule::new(percent, window);
+                let devfund = (0..window).filter(|ctr| schedule.is_devfund(*ctr)).count() as u64;
+                assert!(
+                    devfund * 10_000 >= window as u64 * percent as u64,
+                    "{}/{}: {} devfund templates",
+                    percent,
+                    window,
+                    devfund
+                );
+            }
+        }
+        // 2% of 120 templates isn't a whole number
+        assert_eq!(DevfundSchedule::new(200, 120).devfund_templates(), 3);
+    }
+
+    #[test]
+    fn test_submit_retry() {
+        let retry = SubmitRetry { max_retries: 3, backoff: Duration::from_millis(100), ..Default::default() };
+        let delays: Vec<_> = (0..5).map(|retries| retry.retry_delay(SubmitFailure::Transient, retries)).collect();
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(delays, [ms(100), ms(200), ms(400), None, None]);
+        assert_eq!(retry.retry_delay(SubmitFailure::Permanent, 0), None);
+
+        let disabled = SubmitRetry { max_retries: 0, ..retry };
+        assert_eq!(disabled.retry_delay(SubmitFailure::Transient, 0), None);
+    }
+
+    #[test]
+    fn test_submit_latency_summary() {
+        let mut latency = SubmitLatency::default();
+        assert_eq!(latency.summary(), None);
+
+        for ms in (1..=100).rev() {
+            latency.record(Duration::from_millis(ms));
+        }
+        let summary = latency.summary().unwrap();
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.min, Duration::from_millis(1));
+        assert_eq!(summary.avg, Duration::from_micros(50_500));
+        assert_eq!(summary.p95, Duration::from_millis(95));
+        assert_eq!(summary.to_string(), "Latency min/avg/p95: 1/50/95 ms (100 submissions)");
+
+        // Only latest submissions are kept
+        for _ in 0..LATENCY_WINDOW {
+            latency.record(Duration::from_millis(200));
+        }
+        let summary = latency.summary().unwrap();
+        assert_eq!(summary.count, LATENCY_WINDOW);
+        assert_eq!(summary.min, Duration::from_millis(200));
+    }
 }
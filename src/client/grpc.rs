@@ -1,26 +1,41 @@
-use crate::client::Client;
-use crate::pow::BlockSeed;
+use crate::client::{
+    Client, ConnectLatency, ConnectTimer, DevfundSchedule, NotSyncedIdle, SubmitFailure, SubmitLatency, SubmitRetry,
+    SyncLog, Unanswered,
+};
+use crate::counters::format_duration;
+use crate::pow::{self, BlockSeed};
 use crate::pow::BlockSeed::{FullBlock, PartialBlock};
 use crate::proto::kaspad_message::Payload;
 use crate::proto::rpc_client::RpcClient;
 use crate::proto::{
-    GetBlockTemplateRequestMessage, GetInfoRequestMessage, KaspadMessage, NotifyBlockAddedRequestMessage,
+    GetBlockTemplateRequestMessage, GetInfoRequestMessage, KaspadMessage, NotifyBlockAddedRequestMessage, RpcBlock,
 };
 use crate::{miner::MinerManager, Error};
 use async_trait::async_trait;
 use futures_util::StreamExt;
 use log::{error, info, warn};
 use rand::{thread_rng, RngCore};
+use std::collections::VecDeque;
 use std::sync::atomic::{AtomicU16, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::sync::mpsc::{self, error::SendError, Sender};
 use tokio::task::JoinHandle;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::sync::{PollSendError, PollSender};
-use tonic::{transport::Channel as TonicChannel, Streaming};
+use tonic::transport::{Channel as TonicChannel, Endpoint, Uri};
+use tonic::Streaming;
 
 type BlockHandle = JoinHandle<Result<(), PollSendError<KaspadMessage>>>;
 
+/// Block waiting for kaspad response
+struct PendingBlock {
+    sent: Instant,
+    block: RpcBlock,
+    /// How many times the block was resubmitted
+    retries: u32,
+}
+
 #[allow(dead_code)]
 pub struct KaspadHandler {
     client: RpcClient<TonicChannel>,
@@ -29,8 +44,19 @@ pub struct KaspadHandler {
     miner_address: String,
     mine_when_not_synced: bool,
     devfund_address: Option<String>,
-    devfund_percent: u16,
+    devfund_schedule: DevfundSchedule,
+    mining_dev: Option<bool>,
     block_template_ctr: Arc<AtomicU16>,
+    /// Blocks waiting for response, kaspad answers them in order
+    submitted: Arc<Mutex<VecDeque<PendingBlock>>>,
+    submit_latency: SubmitLatency,
+    /// Kaspad answers every submission over the single message stream, so a submission can only
+    /// be lost together with the connection. Only retries and backoff apply, not the timeout.
+    submit_retry: SubmitRetry,
+    /// Blocks accepted after they were resubmitted
+    retried_accepted: u64,
+    connect_timer: ConnectTimer,
+    not_synced: NotSyncedIdle,
 
     block_channel: Sender<BlockSeed>,
     block_handle: BlockHandle,
@@ -38,9 +64,13 @@ pub struct KaspadHandler {
 
 #[async_trait(?Send)]
 impl Client for KaspadHandler {
-    fn add_devfund(&mut self, address: String, percent: u16) {
+    fn add_devfund(&mut self, address: String, schedule: DevfundSchedule) {
         self.devfund_address = Some(address);
-        self.devfund_percent = percent;
+        self.devfund_schedule = schedule;
+    }
+
+    fn set_submit_retry(&mut self, retry: SubmitRetry) {
+        self.submit_retry = retry;
     }
 
     async fn register(&mut self) -> Result<(), Error> {
@@ -62,6 +92,50 @@ impl Client for KaspadHandler {
     fn get_block_channel(&self) -> Sender<BlockSeed> {
         self.block_channel.clone()
     }
+
+    fn take_unanswered(&mut self) -> Vec<Unanswered> {
+        self.submitted
+            .lock()
+            .expect("BUG: submission lock poisoned")
+            .drain(..)
+            .map(|pending| Unanswered::Block { block: pending.block, retries: pending.retries })
+            .collect()
+    }
+
+    async fn resubmit(&mut self, unanswered: Vec<Unanswered>) -> Result<(), Error> {
+        let mut blocks = Vec::new();
+        let mut delay = None;
+        for submission in unanswered {
+            let (block, retries) = match submission {
+                Unanswered::Block { block, retries } => (block, retries),
+                Unanswered::Share { .. } => continue,
+            };
+            match self.submit_retry.retry_delay(SubmitFailure::Transient, retries) {
+                Some(block_delay) => {
+                    delay = delay.max(Some(block_delay));
+                    blocks.push((block, retries));
+                }
+                None => warn!("Block left without response, giving up after {} retries", retries),
+            }
+        }
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        for (block, retries) in blocks {
+            info!("Resubmitting block left without response");
+            self.submitted.lock().expect("BUG: submission lock poisoned").push_back(PendingBlock {
+                sent: Instant::now(),
+                block: block.clone(),
+                retries: retries + 1,
+            });
+            self.client_send(KaspadMessage::submit_block(block)).await?;
+        }
+        Ok(())
+    }
+
+    fn connect_latency(&self) -> ConnectLatency {
+        self.connect_timer.latency()
+    }
 }
 
 impl KaspadHandler {
@@ -75,12 +149,47 @@ impl KaspadHandler {
         D: std::convert::TryInto<tonic::transport::Endpoint>,
         D::Error: Into<Error>,
     {
-        let mut client = RpcClient::connect(address).await?;
+        let connect_timer = ConnectTimer::start(Instant::now());
+        let client = RpcClient::connect(address).await?;
+        Self::start(client, connect_timer, miner_address, mine_when_not_synced, block_template_ctr).await
+    }
+
+    /// Connect to kaspad listening on Unix domain socket at `path`
+    #[cfg(unix)]
+    pub async fn connect_unix(
+        path: &str,
+        miner_address: String,
+        mine_when_not_synced: bool,
+        block_template_ctr: Option<Arc<AtomicU16>>,
+    ) -> Result<Box<Self>, Error> {
+        if !std::path::Path::new(path).exists() {
+            return Err(format!("socket {} does not exist, is kaspad running?", path).into());
+        }
+        let connect_timer = ConnectTimer::start(Instant::now());
+        let path = path.to_string();
+        // The URI is ignored by the connector, tonic just needs a valid one
+        let channel = Endpoint::from_static("http://[::]:16110")
+            .connect_with_connector(tower::service_fn(move |_: Uri| tokio::net::UnixStream::connect(path.clone())))
+            .await?;
+        let client = RpcClient::new(channel);
+        Self::start(client, connect_timer, miner_address, mine_when_not_synced, block_template_ctr).await
+    }
+
+    async fn start(
+        mut client: RpcClient<TonicChannel>,
+        mut connect_timer: ConnectTimer,
+        miner_address: String,
+        mine_when_not_synced: bool,
+        block_template_ctr: Option<Arc<AtomicU16>>,
+    ) -> Result<Box<Self>, Error> {
+        let latency = connect_timer.connected(Instant::now());
+        info!(connect_ms = latency.as_millis() as u64; "Connected to kaspad in {} ms", latency.as_millis());
         let (send_channel, recv) = mpsc::channel(3);
         send_channel.send(GetInfoRequestMessage {}.into()).await?;
         send_channel.send(GetBlockTemplateRequestMessage { pay_address: miner_address.clone() }.into()).await?;
         let stream = client.message_stream(ReceiverStream::new(recv)).await?.into_inner();
-        let (block_channel, block_handle) = Self::create_block_channel(send_channel.clone());
+        let submitted = Arc::new(Mutex::new(VecDeque::new()));
+        let (block_channel, block_handle) = Self::create_block_channel(send_channel.clone(), submitted.clone());
         Ok(Box::new(Self {
             client,
             stream,
@@ -88,23 +197,40 @@ impl KaspadHandler {
             miner_address,
             mine_when_not_synced,
             devfund_address: None,
-            devfund_percent: 0,
+            devfund_schedule: Default::default(),
+            mining_dev: None,
             block_template_ctr: block_template_ctr
                 .unwrap_or_else(|| Arc::new(AtomicU16::new((thread_rng().next_u64() % 10_000u64) as u16))),
+            submitted,
+            submit_latency: Default::default(),
+            submit_retry: Default::default(),
+            retried_accepted: 0,
+            connect_timer,
+            not_synced: Default::default(),
             block_channel,
             block_handle,
         }))
     }
 
-    fn create_block_channel(send_channel: Sender<KaspadMessage>) -> (Sender<BlockSeed>, BlockHandle) {
+    fn create_block_channel(
+        send_channel: Sender<KaspadMessage>,
+        submitted: Arc<Mutex<VecDeque<PendingBlock>>>,
+    ) -> (Sender<BlockSeed>, BlockHandle) {
         // KaspadMessage::submit_block(block)
         let (send, recv) = mpsc::channel::<BlockSeed>(1);
         (
             send,
             tokio::spawn(async move {
                 ReceiverStream::new(recv)
-                    .map(|block_seed| match block_seed {
-                        FullBlock(block) => KaspadMessage::submit_block(block),
+                    .map(move |block_seed| match block_seed {
+                        FullBlock(block) => {
+                            submitted.lock().expect("BUG: submission lock poisoned").push_back(PendingBlock {
+                                sent: Instant::now(),
+                                block: block.clone(),
+                                retries: 0,
+                            });
+                            KaspadMessage::submit_block(block)
+                        }
                         PartialBlock { .. } => unreachable!("All blocks sent here should have arrived from here"),
                     })
                     .map(Ok)
@@ -119,30 +245,95 @@ impl KaspadHandler {
     }
 
     async fn client_get_block_template(&mut self) -> Result<(), SendError<KaspadMessage>> {
-        let pay_address = match &self.devfund_address {
-            Some(devfund_address) if self.block_template_ctr.load(Ordering::SeqCst) <= self.devfund_percent => {
-                devfund_address.clone()
-            }
-            _ => self.miner_address.clone(),
+        let devfund_turn = self.devfund_schedule.is_devfund(self.block_template_ctr.load(Ordering::SeqCst));
+        let (mining_dev, pay_address) = match &self.devfund_address {
+            Some(devfund_address) if devfund_turn => (true, devfund_address.clone()),
+            _ => (false, self.miner_address.clone()),
         };
-        self.block_template_ctr.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| Some((v + 1) % 10_000)).unwrap();
+        if self.mining_dev != Some(mining_dev) {
+            info!("Mining to {} address {}", if mining_dev { "devfund" } else { "user" }, pay_address);
+            self.mining_dev = Some(mining_dev);
+        }
+        let schedule = self.devfund_schedule;
+        self.block_template_ctr.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| Some(schedule.next(v))).unwrap();
         self.client_send(GetBlockTemplateRequestMessage { pay_address }).await
     }
 
+    /// Explain why hashrate dropped to zero when the node isn't synced, `mining` is false when
+    /// templates aren't mined on because of that
+    fn log_sync_state(&mut self, mining: bool, daa_score: Option<u64>) {
+        let progress = daa_score.map(|daa_score| format!(" (virtual DAA score {})", daa_score)).unwrap_or_default();
+        match self.not_synced.update(mining, Instant::now()) {
+            Some(SyncLog::Idle) => warn!(
+                synced = false;
+                "Miner is idle: kaspad reports it is not synced{}, waiting for it to sync \
+                 (see --mine-when-not-synced)",
+                progress
+            ),
+            Some(SyncLog::StillIdle(idle)) => warn!(
+                synced = false;
+                "Miner still idle for {}: kaspad is not synced yet{}", format_duration(idle), progress
+            ),
+            Some(SyncLog::Resynced(idle)) => {
+                info!(synced = true; "kaspad is synced, mining resumed after {} idle", format_duration(idle))
+            }
+            None => {}
+        }
+    }
+
     async fn handle_message(&mut self, msg: Payload, miner: &mut MinerManager) -> Result<(), Error> {
         match msg {
             Payload::BlockAddedNotification(_) => self.client_get_block_template().await?,
-            Payload::GetBlockTemplateResponse(template) => match (template.block, template.is_synced, template.error) {
-                (Some(b), true, None) => miner.process_block(Some(FullBlock(b))).await?,
-                (Some(b), false, None) if self.mine_when_not_synced => miner.process_block(Some(FullBlock(b))).await?,
-                (_, false, None) => miner.process_block(None).await?,
-                (_, _, Some(e)) => warn!("GetTemplate returned with an error: {:?}", e),
-                (None, true, None) => error!("No block and No Error!"),
-            },
-            Payload::SubmitBlockResponse(res) => match res.error {
-                None => info!("block submitted successfully!"),
-                Some(e) => warn!("Failed submitting block: {:?}", e),
-            },
+            Payload::GetBlockTemplateResponse(template) => {
+                if template.block.is_some() {
+                    if let Some(latency) = self.connect_timer.job(Instant::now()) {
+                        info!(
+                            first_job_ms = latency.as_millis() as u64;
+                            "First block template received {} ms after connecting", latency.as_millis()
+                        );
+                        miner.set_connect_latency(self.connect_timer.latency());
+                    }
+                }
+                if template.error.is_none() {
+                    let daa_score = template.block.as_ref().and_then(|b| b.header.as_ref()).map(|h| h.daa_score);
+                    self.log_sync_state(template.is_synced || self.mine_when_not_synced, daa_score);
+                }
+                match (template.block, template.is_synced, template.error) {
+                    (Some(b), true, None) => miner.process_block(Some(FullBlock(b))).await?,
+                    (Some(b), false, None) if self.mine_when_not_synced => {
+                        miner.process_block(Some(FullBlock(b))).await?
+                    }
+                    (_, false, None) => miner.process_block(None).await?,
+                    (_, _, Some(e)) => warn!("GetTemplate returned with an error: {:?}", e),
+                    (None, true, None) => error!("No block and No Error!"),
+                }
+            }
+            Payload::SubmitBlockResponse(res) => {
+                let pending = self.submitted.lock().expect("BUG: submission lock poisoned").pop_front();
+                if let Some(pending) = &pending {
+                    self.submit_latency.record(pending.sent.elapsed());
+                    if res.error.is_none() {
+                        // Found for sure only now, the solution could have been stale
+                        pow::report_accepted_block(&pending.block);
+                    }
+                }
+                let retries = pending.map_or(0, |pending| pending.retries);
+                match res.error {
+                    None if retries > 0 => {
+                        self.retried_accepted += 1;
+                        info!(
+                            "block submitted successfully after {} retries! ({} blocks accepted after retry)",
+                            retries, self.retried_accepted
+                        );
+                    }
+                    None => info!("block submitted successfully!"),
+                    // Rejections are permanent, such blocks are never resubmitted
+                    Some(e) => warn!("Failed submitting block: {:?}", e),
+                }
+                if let Some(summary) = self.submit_latency.summary() {
+                    info!("{}", summary);
+                }
+            }
             Payload::GetBlockResponse(msg) => {
                 if let Some(e) = msg.error {
                     return Err(e.message.into());
@@ -1,10 +1,10 @@
 use futures::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{Display, Formatter};
 use std::pin::Pin;
 use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio_util::codec::Framed;
 
@@ -12,27 +12,31 @@ mod statum_codec;
 
 use crate::client::stratum::statum_codec::StratumCommand;
 use crate::client::stratum::statum_codec::{ErrorCode, MiningNotify, MiningSubmit, NewLineJsonCodecError, StratumLine};
-use crate::client::Client;
+use crate::client::{
+    Client, ConnectLatency, ConnectTimer, DevfundSchedule, SubmitFailure, SubmitLatency, SubmitRetry, Unanswered,
+};
+use crate::events::{self, Event};
 use crate::pow::BlockSeed;
 use crate::pow::BlockSeed::PartialBlock;
 use crate::{miner::MinerManager, Error, Uint256};
 use async_trait::async_trait;
 use futures_util::TryStreamExt;
-use log::{error, info, warn};
-use num::Float;
+use log::{debug, error, info, warn};
+use once_cell::sync::OnceCell;
 use rand::{thread_rng, RngCore};
 use statum_codec::NewLineJsonCodec;
 use tokio::sync::mpsc::{self, Sender};
-use tokio::sync::Mutex;
 use tokio::task;
 use tokio::task::JoinHandle;
 use tokio::time::MissedTickBehavior;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::sync::{PollSendError, PollSender};
 
-//const DIFFICULTY_1_TARGET: Uint256 = Uint256([0x00000000ffff0000, 0x0000000000000000, 0x0000000000000000, 0x0000000000000000]);
-const DIFFICULTY_1_TARGET: (u64, i16) = (0xffffu64, 208); // 0xffff 2^208
 const LOG_RATE: Duration = Duration::from_secs(30);
+/// How often shares waiting for response are checked for timeout
+const RETRY_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// How many jobs without clean jobs flag are still accepted for submission
+const MAX_ACTIVE_JOBS: usize = 16;
 
 type BlockHandle = JoinHandle<Result<(), PollSendError<StratumLine>>>;
 
@@ -42,20 +46,63 @@ pub struct ShareStats {
     pub stale: AtomicU64,
     pub low_diff: AtomicU64,
     pub duplicate: AtomicU64,
-    pub shares_pending: Mutex<HashMap<u32, String>>,
+    /// Shares accepted after they were resubmitted
+    pub retried: AtomicU64,
+    /// Shares dropped locally for not meeting `--min-share-difficulty`, never sent to the pool
+    pub below_floor: AtomicU64,
+    /// Shares waiting for pool response, by request id
+    pub shares_pending: std::sync::Mutex<HashMap<u32, PendingShare>>,
+    pub latency: std::sync::Mutex<SubmitLatency>,
 }
 
-static mut SHARE_STATS: Option<Arc<ShareStats>> = None;
+#[derive(Debug, Clone)]
+pub struct PendingShare {
+    job_id: String,
+    nonce: u64,
+    sent: Instant,
+    /// How many times the share was resubmitted
+    retries: u32,
+}
+
+/// Shared by all stratum connections, so that counts survive reconnects
+static SHARE_STATS: OnceCell<Arc<ShareStats>> = OnceCell::new();
+
+/// Jobs that shares may still be submitted for. Job with clean jobs flag makes all previous
+/// jobs stale.
+#[derive(Default)]
+struct JobTracker {
+    active: VecDeque<String>,
+}
+
+impl JobTracker {
+    fn on_notify(&mut self, id: &str, clean_jobs: bool) {
+        if clean_jobs {
+            self.active.clear();
+        }
+        if self.active.len() == MAX_ACTIVE_JOBS {
+            self.active.pop_front();
+        }
+        self.active.push_back(id.to_string());
+    }
+
+    fn is_active(&self, id: &str) -> bool {
+        self.active.iter().any(|active| active == id)
+    }
+}
 
 impl Display for ShareStats {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Shares: {}{}{}{}Pending: {}",
+            "Shares: {}{}{}{}{}{}Pending: {}",
             match self.accepted.load(Ordering::SeqCst) {
                 0 => "".to_string(),
                 v => format!("Accepted: {} ", v),
             },
+            match self.retried.load(Ordering::SeqCst) {
+                0 => "".to_string(),
+                v => format!("Accepted after retry: {} ", v),
+            },
             match self.stale.load(Ordering::SeqCst) {
                 0 => "".to_string(),
                 v => format!("Stale: {} ", v),
@@ -68,11 +115,75 @@ impl Display for ShareStats {
                 0 => "".to_string(),
                 v => format!("Duplicate: {} ", v),
             },
-            self.shares_pending.try_lock().unwrap().len()
-        )
+            match self.below_floor.load(Ordering::SeqCst) {
+                0 => "".to_string(),
+                v => format!("Below local floor: {} ", v),
+            },
+            self.pending().len()
+        )?;
+        if let Some(summary) = self.latency.lock().expect("BUG: latency lock poisoned").summary() {
+            write!(f, " {}", summary)?;
+        }
+        Ok(())
     }
 }
 
+impl ShareStats {
+    fn pending(&self) -> std::sync::MutexGuard<'_, HashMap<u32, PendingShare>> {
+        self.shares_pending.lock().expect("BUG: pending shares lock poisoned")
+    }
+
+    /// Take share with request `id` from pending ones and record how long the pool took to respond
+    fn take_pending(&self, id: u32) -> Option<PendingShare> {
+        let share = self.pending().remove(&id)?;
+        self.latency.lock().expect("BUG: latency lock poisoned").record(share.sent.elapsed());
+        Some(share)
+    }
+}
+
+/// Accepted and rejected (stale, low difficulty, duplicate) shares since start, `None` before
+/// the first stratum connection
+pub fn share_counts() -> Option<(u64, u64)> {
+    let stats = SHARE_STATS.get()?;
+    let accepted = stats.accepted.load(Ordering::SeqCst);
+    let rejected = stats.stale.load(Ordering::SeqCst)
+        + stats.low_diff.load(Ordering::SeqCst)
+        + stats.duplicate.load(Ordering::SeqCst);
+    Some((accepted, rejected))
+}
+
+/// Take shares that got no response within `timeout` from `pending`
+fn take_expired(pending: &mut HashMap<u32, PendingShare>, timeout: Duration) -> Vec<PendingShare> {
+    let expired: Vec<u32> =
+        pending.iter().filter(|(_, share)| share.sent.elapsed() >= timeout).map(|(id, _)| *id).collect();
+    expired.into_iter().filter_map(|id| pending.remove(&id)).collect()
+}
+
+/// Drop shares of jobs that are no longer active from `pending`, returns how many were dropped
+fn drop_flushed(pending: &mut HashMap<u32, PendingShare>, jobs: &JobTracker) -> usize {
+    let count = pending.len();
+    pending.retain(|_, share| jobs.is_active(&share.job_id));
+    count - pending.len()
+}
+
+fn submit_line(id: u32, miner_address: &str, job_id: &str, nonce: u64) -> StratumLine {
+    StratumLine::StratumCommand(StratumCommand::MiningSubmit(MiningSubmit::MiningSubmitShort {
+        id,
+        params: (miner_address.to_string(), job_id.to_string(), format!("{:#08x}", nonce)),
+        error: None,
+    }))
+}
+
+/// `mining.authorize` of `pay_address` as worker `worker_name` (pools show rigs by the part after
+/// the dot)
+fn authorize_line(id: u32, pay_address: &str, worker_name: &str) -> StratumLine {
+    StratumLine::StratumCommand(StratumCommand::Authorize {
+        id,
+        params: (format!("{}.{}", pay_address, worker_name), "x".into()),
+        error: None,
+    })
+}
+
 #[allow(dead_code)]
 pub struct StratumHandler {
     log_handler: JoinHandle<()>,
@@ -81,18 +192,27 @@ pub struct StratumHandler {
     send_channel: Sender<StratumLine>,
     stream: Pin<Box<dyn Stream<Item = Result<StratumLine, NewLineJsonCodecError>>>>,
     miner_address: String,
+    /// Name of this rig on the pool
+    worker_name: String,
     mine_when_not_synced: bool,
     devfund_address: Option<String>,
-    devfund_percent: u16,
+    devfund_schedule: DevfundSchedule,
     mining_dev: Option<bool>,
     block_template_ctr: Arc<AtomicU16>,
+    submit_retry: SubmitRetry,
 
     target_pool: Uint256,
     target_real: Uint256,
     nonce_mask: u64,
     nonce_fixed: u64,
     extranonce: Option<String>,
+    /// Id of `mining.extranonce.subscribe` request (its result isn't a share result)
+    extranonce_subscribe_id: Option<u32>,
+    /// Last job received from pool, it's handed to workers again when extranonce changes
+    last_job: Option<(String, [u64; 4], u64)>,
+    jobs: Arc<std::sync::Mutex<JobTracker>>,
     last_stratum_id: Arc<AtomicU32>,
+    connect_timer: ConnectTimer,
 
     shares_stats: Arc<ShareStats>,
     block_channel: Sender<BlockSeed>,
@@ -101,9 +221,13 @@ pub struct StratumHandler {
 
 #[async_trait(?Send)]
 impl Client for StratumHandler {
-    fn add_devfund(&mut self, address: String, percent: u16) {
+    fn add_devfund(&mut self, address: String, schedule: DevfundSchedule) {
         self.devfund_address = Some(address);
-        self.devfund_percent = percent;
+        self.devfund_schedule = schedule;
+    }
+
+    fn set_submit_retry(&mut self, retry: SubmitRetry) {
+        self.submit_retry = retry;
     }
 
     async fn register(&mut self) -> Result<(), Error> {
@@ -119,11 +243,17 @@ impl Client for StratumHandler {
             }))
             .await?;
         id = self.last_stratum_id.fetch_add(1, Ordering::SeqCst);
+        // Ask pool to notify us about extranonce changes mid-session
+        self.extranonce_subscribe_id = Some(id);
+        self.send_channel
+            .send(StratumLine::StratumCommand(StratumCommand::ExtranonceSubscribe { id, params: vec![], error: None }))
+            .await?;
+        id = self.last_stratum_id.fetch_add(1, Ordering::SeqCst);
 
+        let devfund_turn = self.devfund_schedule.is_devfund(self.block_template_ctr.load(Ordering::SeqCst));
         let pay_address = match &self.devfund_address {
-            Some(devfund_address) if self.block_template_ctr.load(Ordering::SeqCst) <= self.devfund_percent => {
+            Some(devfund_address) if devfund_turn => {
                 self.mining_dev = Some(true);
-                info!("Mining to devfund");
                 devfund_address.clone()
             }
             _ => {
@@ -131,29 +261,32 @@ impl Client for StratumHandler {
                 self.miner_address.clone()
             }
         };
-        self.send_channel
-            .send(StratumLine::StratumCommand(StratumCommand::Authorize {
-                id,
-                params: (pay_address.clone(), "x".into()),
-                error: None,
-            }))
-            .await?;
+        info!(
+            "Mining to {} address {} as worker {}",
+            if self.mining_dev == Some(true) { "devfund" } else { "user" },
+            pay_address,
+            self.worker_name
+        );
+        self.send_channel.send(authorize_line(id, &pay_address, &self.worker_name)).await?;
         Ok(())
     }
 
     async fn listen(&mut self, miner: &mut MinerManager) -> Result<(), Error> {
         info!("Waiting for stuff");
         loop {
-            {
-                if (!self.mining_dev.unwrap_or(true)
-                    && self.block_template_ctr.load(Ordering::SeqCst) <= self.devfund_percent)
-                    || (self.mining_dev.unwrap_or(false)
-                        && self.block_template_ctr.load(Ordering::SeqCst) > self.devfund_percent)
-                {
-                    return Ok(());
-                }
+            // Pool authorizes single address per connection, so switching address means reconnecting
+            let devfund_turn = self.devfund_schedule.is_devfund(self.block_template_ctr.load(Ordering::SeqCst));
+            if self.mining_dev.map_or(false, |mining_dev| mining_dev != devfund_turn) {
+                info!("Switching mining address to {}", if devfund_turn { "devfund" } else { "user" });
+                return Ok(());
             }
-            match self.stream.try_next().await? {
+            self.resend_expired();
+            // Wake up now and then to check pending shares even if the pool is silent
+            let msg = match tokio::time::timeout(RETRY_CHECK_INTERVAL, self.stream.try_next()).await {
+                Ok(msg) => msg?,
+                Err(_) => continue,
+            };
+            match msg {
                 Some(msg) => self.handle_message(msg, miner).await?,
                 None => return Err("stratum message payload is empty".into()),
             }
@@ -163,44 +296,75 @@ impl Client for StratumHandler {
     fn get_block_channel(&self) -> Sender<BlockSeed> {
         self.block_channel.clone()
     }
+
+    fn take_unanswered(&mut self) -> Vec<Unanswered> {
+        self.shares_stats
+            .pending()
+            .drain()
+            .map(|(_, share)| Unanswered::Share { job_id: share.job_id })
+            .collect()
+    }
+
+    /// Shares are not resubmitted: job ids and extranonce belong to the previous connection, the
+    /// pool would reject them
+    async fn resubmit(&mut self, unanswered: Vec<Unanswered>) -> Result<(), Error> {
+        for submission in unanswered {
+            if let Unanswered::Share { job_id, .. } = submission {
+                warn!(
+                    share = "lost", job_id = job_id.as_str();
+                    "Share left without response by previous connection, dropping it (Job id: {:?})", job_id
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn connect_latency(&self) -> ConnectLatency {
+        self.connect_timer.latency()
+    }
 }
 
 impl StratumHandler {
     pub async fn connect(
         address: String,
         miner_address: String,
+        worker_name: String,
         mine_when_not_synced: bool,
+        min_share_target: Option<Uint256>,
         block_template_ctr: Option<Arc<AtomicU16>>,
     ) -> Result<Box<Self>, Error> {
         info!("Connecting to {}", address);
-        let socket = TcpStream::connect(address).await?;
+        let mut connect_timer = ConnectTimer::start(Instant::now());
+        let socket = TcpStream::connect(address.as_str()).await?;
+        let latency = connect_timer.connected(Instant::now());
+        info!(connect_ms = latency.as_millis() as u64; "Connected to {} in {} ms", address, latency.as_millis());
 
         let client = Framed::new(socket, NewLineJsonCodec::new());
         let (send_channel, recv) = mpsc::channel::<StratumLine>(3);
         let (sink, stream) = client.split();
         tokio::spawn(async move { ReceiverStream::new(recv).map(Ok).forward(sink).await });
 
-        let share_state = unsafe {
-            if SHARE_STATS.is_none() {
-                SHARE_STATS = Some(Arc::new(ShareStats::default()));
-            }
-            SHARE_STATS.clone().unwrap()
-        };
+        let share_state = SHARE_STATS.get_or_init(|| Arc::new(ShareStats::default())).clone();
         let last_stratum_id = Arc::new(AtomicU32::new(0));
+        let jobs = Arc::new(std::sync::Mutex::new(JobTracker::default()));
         let (block_channel, block_handle) = Self::create_block_channel(
             send_channel.clone(),
             miner_address.clone(),
             last_stratum_id.clone(),
             share_state.clone(),
+            jobs.clone(),
+            min_share_target,
         );
         Ok(Box::new(Self {
             log_handler: task::spawn(Self::log_shares(share_state.clone())),
             stream: Box::pin(stream),
             send_channel,
             miner_address,
+            worker_name,
             mine_when_not_synced,
             devfund_address: None,
-            devfund_percent: 0,
+            devfund_schedule: Default::default(),
+            submit_retry: Default::default(),
             block_template_ctr: block_template_ctr
                 .unwrap_or_else(|| Arc::new(AtomicU16::new((thread_rng().next_u64() % 10_000u64) as u16))),
             target_pool: Default::default(),
@@ -208,6 +372,10 @@ impl StratumHandler {
             nonce_mask: 0,
             nonce_fixed: 0,
             extranonce: None,
+            extranonce_subscribe_id: None,
+            last_job: None,
+            connect_timer,
+            jobs,
             last_stratum_id,
             shares_stats: share_state,
             mining_dev: None,
@@ -221,29 +389,35 @@ impl StratumHandler {
         miner_address: String,
         last_stratum_id: Arc<AtomicU32>,
         share_stats: Arc<ShareStats>,
+        jobs: Arc<std::sync::Mutex<JobTracker>>,
+        min_share_target: Option<Uint256>,
     ) -> (Sender<BlockSeed>, BlockHandle) {
         let (send, recv) = mpsc::channel::<BlockSeed>(1);
 
         let handle = tokio::spawn(async move {
             ReceiverStream::new(recv)
-                .map(move |block_seed| {
+                .filter_map(move |block_seed| {
+                    if !block_seed.meets_floor(min_share_target.as_ref()) {
+                        share_stats.below_floor.fetch_add(1, Ordering::SeqCst);
+                        debug!(share = "below_floor"; "Dropping share below local difficulty floor");
+                        return future::ready(None);
+                    }
                     let (nonce, id) = match block_seed {
                         BlockSeed::PartialBlock { ref nonce, ref id, .. } => (nonce, id),
                         BlockSeed::FullBlock(_) => unreachable!(),
                     };
-                    let msg_id = last_stratum_id.fetch_add(1, Ordering::SeqCst);
-                    {
-                        share_stats.shares_pending.try_lock().unwrap().insert(
-                            msg_id,
-                            //SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
-                            id.clone(), //block_seed.clone()
-                        );
+                    if !jobs.lock().expect("BUG: job tracker lock poisoned").is_active(id) {
+                        // Job was flushed by pool, the share would be rejected anyway
+                        share_stats.stale.fetch_add(1, Ordering::SeqCst);
+                        warn!(share = "stale", job_id = id.as_str(); "Dropping share of flushed job {}", id);
+                        return future::ready(None);
                     }
-                    StratumLine::StratumCommand(StratumCommand::MiningSubmit(MiningSubmit::MiningSubmitShort {
-                        id: msg_id,
-                        params: (miner_address.clone(), id.into(), format!("{:#08x}", nonce)),
-                        error: None,
-                    }))
+                    let msg_id = last_stratum_id.fetch_add(1, Ordering::SeqCst);
+                    share_stats.pending().insert(
+                        msg_id,
+                        PendingShare { job_id: id.clone(), nonce: *nonce, sent: Instant::now(), retries: 0 },
+                    );
+                    future::ready(Some(submit_line(msg_id, &miner_address, id, *nonce)))
                 })
                 .map(Ok)
                 .forward(PollSender::new(send_channel))
@@ -254,10 +428,27 @@ impl StratumHandler {
 
     async fn handle_message(&mut self, msg: StratumLine, miner: &mut MinerManager) -> Result<(), Error> {
         match msg.clone() {
+            StratumLine::StratumResult { id, ref error, .. } if Some(id) == self.extranonce_subscribe_id => {
+                match error {
+                    None => info!("Subscribed to extranonce changes"),
+                    // Not all pools support it, extranonce just stays the same for whole session then
+                    Some((code, error, _)) => debug!("Pool refused extranonce subscription: {} {}", code, error),
+                }
+                Ok(())
+            }
             StratumLine::StratumResult { id, error: None, .. } => {
-                if let Some(_jobid) = self.shares_stats.shares_pending.try_lock().unwrap().remove(&id) {
+                if let Some(share) = self.shares_stats.take_pending(id) {
                     self.shares_stats.accepted.fetch_add(1, Ordering::SeqCst);
-                    info!("Share accepted");
+                    if share.retries > 0 {
+                        self.shares_stats.retried.fetch_add(1, Ordering::SeqCst);
+                        info!(
+                            share = "accepted", job_id = share.job_id.as_str(), retries = share.retries;
+                            "Share accepted after {} retries", share.retries
+                        );
+                    } else {
+                        info!(share = "accepted", job_id = share.job_id.as_str(); "Share accepted");
+                    }
+                    events::emit(Event::ShareAccepted { job_id: share.job_id });
                 } else {
                     info!("{:?} (Last: {})", msg.clone(), self.last_stratum_id.load(Ordering::SeqCst));
                     warn!("Ignoring result for now");
@@ -265,34 +456,48 @@ impl StratumHandler {
                 Ok(())
             }
             StratumLine::StratumResult { id, error: Some((code, error, _)), .. } => {
-                let jobid = { self.shares_stats.shares_pending.try_lock().unwrap().remove(&id) }.unwrap();
-                match code {
-                    ErrorCode::Unknown => {
+                // Rejections are permanent, such shares are never resubmitted
+                match (code, self.shares_stats.take_pending(id)) {
+                    (code @ (ErrorCode::Unknown | ErrorCode::Unauthorized | ErrorCode::NotSubscribed), _) => {
                         error!("Got error code {}: {}", code, error);
                         Err(error.into())
                     }
-                    ErrorCode::JobNotFound => {
+                    (ErrorCode::JobNotFound, Some(share)) => {
+                        let jobid = share.job_id;
                         self.shares_stats.stale.fetch_add(1, Ordering::SeqCst);
-                        warn!("Stale share (Job id: {:?})", jobid);
+                        warn!(share = "stale", job_id = jobid.as_str(); "Stale share (Job id: {:?})", jobid);
+                        events::emit(Event::ShareRejected { job_id: jobid, reason: "stale".into() });
+                        Ok(())
+                    }
+                    (ErrorCode::DuplicateShare, Some(share)) if share.retries > 0 => {
+                        // The share timed out, but the pool got it the first time
+                        info!(
+                            share = "duplicate_retry", job_id = share.job_id.as_str();
+                            "Resubmitted share was already received by pool (Job id: {:?})", share.job_id
+                        );
                         Ok(())
                     }
-                    ErrorCode::DuplicateShare => {
+                    (ErrorCode::DuplicateShare, Some(share)) => {
+                        let jobid = share.job_id;
                         self.shares_stats.duplicate.fetch_add(1, Ordering::SeqCst);
-                        warn!("Duplicate share (Job id: {:?})", jobid);
+                        warn!(share = "duplicate", job_id = jobid.as_str(); "Duplicate share (Job id: {:?})", jobid);
+                        events::emit(Event::ShareRejected { job_id: jobid, reason: "duplicate".into() });
                         Ok(())
                     }
-                    ErrorCode::LowDifficultyShare => {
+                    (ErrorCode::LowDifficultyShare, Some(share)) => {
+                        let jobid = share.job_id;
                         self.shares_stats.low_diff.fetch_add(1, Ordering::SeqCst);
-                        warn!("Low difficulty share (Job id: {:?})", jobid);
+                        warn!(
+                            share = "low_difficulty", job_id = jobid.as_str();
+                            "Low difficulty share (Job id: {:?})", jobid
+                        );
+                        events::emit(Event::ShareRejected { job_id: jobid, reason: "low difficulty".into() });
                         Ok(())
                     }
-                    ErrorCode::Unauthorized => {
-                        error!("Got error code {}: {}", code, error);
-                        Err(error.into())
-                    }
-                    ErrorCode::NotSubscribed => {
-                        error!("Got error code {}: {}", code, error);
-                        Err(error.into())
+                    // Share timed out before and was resubmitted, given up on or dropped meanwhile
+                    (code, None) => {
+                        debug!("Ignoring rejection of request {} that is no longer pending: {} {}", id, code, error);
+                        Ok(())
                     }
                 }
             }
@@ -300,7 +505,12 @@ impl StratumHandler {
                 params: (ref extranonce, ref nonce_size),
                 ref error,
                 ..
-            }) if error.is_none() => self.set_extranonce(extranonce.as_str(), nonce_size),
+            })
+            | StratumLine::StratumCommand(StratumCommand::MiningSetExtranonce {
+                params: (ref extranonce, ref nonce_size),
+                ref error,
+                ..
+            }) if error.is_none() => self.set_extranonce(extranonce.as_str(), nonce_size, miner).await,
             StratumLine::StratumCommand(StratumCommand::MiningSetDifficulty {
                 params: (ref difficulty,),
                 ref error,
@@ -310,25 +520,14 @@ impl StratumHandler {
                 params: (id, header_hash, timestamp),
                 ref error,
                 ..
-            })) if error.is_none() => {
-                self.block_template_ctr
-                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| Some((v + 1) % 10_000))
-                    .unwrap();
-                miner
-                    .process_block(Some(PartialBlock {
-                        id,
-                        header_hash,
-                        timestamp,
-                        nonce: 0,
-                        target: self.target_pool,
-                        nonce_mask: self.nonce_mask,
-                        nonce_fixed: self.nonce_fixed,
-                        hash: None,
-                    }))
-                    .await
-            }
+            })) if error.is_none() => self.handle_notify(miner, id, header_hash, timestamp, false).await,
+            StratumLine::StratumCommand(StratumCommand::MiningNotify(MiningNotify::MiningNotifyClean {
+                params: (id, header_hash, timestamp, clean_jobs),
+                ref error,
+                ..
+            })) if error.is_none() => self.handle_notify(miner, id, header_hash, timestamp, clean_jobs).await,
             StratumLine::SubscribeResult { result: (ref _subscriptions, ref extranonce, ref nonce_size), .. } => {
-                self.set_extranonce(extranonce.as_str(), nonce_size)
+                self.set_extranonce(extranonce.as_str(), nonce_size, miner).await
                 /*for (name, value) in _subscriptions {
                     match name.as_str() {
                         "mining.set_difficulty" => {self.set_difficulty(&f32::from_str(value.as_str())?)?;},
@@ -337,37 +536,145 @@ impl StratumHandler {
                 }
                 Ok(())*/
             }
+            StratumLine::UnknownMethod { ref method, ref params } => {
+                debug!("Ignoring unknown stratum method {} (params: {})", method, params);
+                Ok(())
+            }
             _ => Err(format!("Unhandled stratum response: {:?}", msg).into()),
         }
     }
 
     fn set_difficulty(&mut self, difficulty: &f32) -> Result<(), Error> {
-        let mut buf = [0u64, 0u64, 0u64, 0u64];
-        let (mantissa, exponent, _) = difficulty.recip().integer_decode();
-        let new_mantissa = mantissa * DIFFICULTY_1_TARGET.0;
-        let new_exponent = (DIFFICULTY_1_TARGET.1 + exponent) as u64;
-        let start = (new_exponent / 64) as usize;
-        let remainder = new_exponent % 64;
-
-        buf[start] = new_mantissa << remainder; // bottom
-        if start < 3 {
-            buf[start + 1] = new_mantissa >> (64 - remainder); // top
-        } else if new_mantissa.leading_zeros() < remainder as u32 {
-            return Err("Target is too big".into());
-        }
-
-        self.target_pool = Uint256::new(buf);
+        self.target_pool = Uint256::from_difficulty(*difficulty as f64)?;
         info!("Difficulty: {:?}, Target: 0x{:x}", difficulty, self.target_pool);
         Ok(())
     }
 
-    fn set_extranonce(&mut self, extranonce: &str, nonce_size: &u32) -> Result<(), Error> {
+    /// New job replaces the current work of workers right away. With `clean_jobs`, late shares
+    /// of previous jobs are dropped as stale instead of being submitted.
+    async fn handle_notify(
+        &mut self,
+        miner: &mut MinerManager,
+        id: String,
+        header_hash: [u64; 4],
+        timestamp: u64,
+        clean_jobs: bool,
+    ) -> Result<(), Error> {
+        let schedule = self.devfund_schedule;
+        self.block_template_ctr.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| Some(schedule.next(v))).unwrap();
+        if clean_jobs {
+            debug!("Job {} flushes previous jobs", id);
+        }
+        let dropped = {
+            let mut jobs = self.jobs.lock().expect("BUG: job tracker lock poisoned");
+            jobs.on_notify(&id, clean_jobs);
+            // Responses to shares of flushed jobs don't matter and they mustn't be resubmitted
+            if clean_jobs {
+                drop_flushed(&mut self.shares_stats.pending(), &jobs)
+            } else {
+                0
+            }
+        };
+        if dropped > 0 {
+            debug!("Dropped {} pending shares of flushed jobs", dropped);
+        }
+        if let Some(latency) = self.connect_timer.job(Instant::now()) {
+            info!(
+                first_job_ms = latency.as_millis() as u64;
+                "First job received {} ms after connecting", latency.as_millis()
+            );
+            miner.set_connect_latency(self.connect_timer.latency());
+        }
+        self.last_job = Some((id, header_hash, timestamp));
+        self.dispatch_last_job(miner).await
+    }
+
+    /// Hand the last job to workers (with current target and extranonce)
+    async fn dispatch_last_job(&mut self, miner: &mut MinerManager) -> Result<(), Error> {
+        let (id, header_hash, timestamp) = match &self.last_job {
+            Some(job) => job.clone(),
+            None => return Ok(()),
+        };
+        miner
+            .process_block(Some(PartialBlock {
+                id,
+                header_hash,
+                timestamp,
+                nonce: 0,
+                target: self.target_pool,
+                nonce_mask: self.nonce_mask,
+                nonce_fixed: self.nonce_fixed,
+                hash: None,
+            }))
+            .await
+    }
+
+    async fn set_extranonce(
+        &mut self,
+        extranonce: &str,
+        nonce_size: &u32,
+        miner: &mut MinerManager,
+    ) -> Result<(), Error> {
+        let (nonce_mask, nonce_fixed) = extranonce_nonce_bits(extranonce, *nonce_size)?;
+        let changed = self.extranonce.is_some() && (nonce_mask, nonce_fixed) != (self.nonce_mask, self.nonce_fixed);
         self.extranonce = Some(extranonce.to_string());
-        self.nonce_fixed = u64::from_str_radix(extranonce, 16)? << (nonce_size * 8);
-        self.nonce_mask = (1 << (nonce_size * 8)) - 1;
+        self.nonce_mask = nonce_mask;
+        self.nonce_fixed = nonce_fixed;
+        info!("Extranonce: {:?}, extranonce2 size: {} bytes", extranonce, nonce_size);
+        if changed {
+            // Work in progress uses the old extranonce, its shares would be rejected
+            self.dispatch_last_job(miner).await?;
+        }
         Ok(())
     }
 
+    /// Resubmit shares that got no response in time (unless retries are exhausted or their job
+    /// was flushed meanwhile)
+    fn resend_expired(&self) {
+        let expired = take_expired(&mut self.shares_stats.pending(), self.submit_retry.timeout);
+        for share in expired {
+            let job_id = share.job_id.clone();
+            let delay = self.submit_retry.retry_delay(SubmitFailure::Transient, share.retries);
+            let active = self.jobs.lock().expect("BUG: job tracker lock poisoned").is_active(&job_id);
+            match delay {
+                Some(delay) if active => {
+                    warn!(
+                        share = "retry", job_id = job_id.as_str();
+                        "No response to share in {:?}, resubmitting (Job id: {:?})", self.submit_retry.timeout, job_id
+                    );
+                    self.resend(share, delay);
+                }
+                Some(_) => {
+                    self.shares_stats.stale.fetch_add(1, Ordering::SeqCst);
+                    warn!(share = "stale", job_id = job_id.as_str(); "No response to share of flushed job {}", job_id);
+                }
+                None => warn!(
+                    share = "lost", job_id = job_id.as_str();
+                    "No response to share, giving up after {} retries (Job id: {:?})", share.retries, job_id
+                ),
+            }
+        }
+    }
+
+    /// Submit `share` again with new request id after `delay`
+    fn resend(&self, share: PendingShare, delay: Duration) {
+        let send_channel = self.send_channel.clone();
+        let last_stratum_id = self.last_stratum_id.clone();
+        let share_stats = self.shares_stats.clone();
+        let miner_address = self.miner_address.clone();
+        task::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let id = last_stratum_id.fetch_add(1, Ordering::SeqCst);
+            let line = submit_line(id, &miner_address, &share.job_id, share.nonce);
+            let share = PendingShare { sent: Instant::now(), retries: share.retries + 1, ..share };
+            share_stats.pending().insert(id, share);
+            if send_channel.send(line).await.is_err() {
+                // Stays pending until reconnect, which drops it
+                warn!("Pool is disconnected, cannot resubmit share");
+            }
+        });
+    }
+
     async fn log_shares(shares_info: Arc<ShareStats>) {
         let mut ticker = tokio::time::interval(LOG_RATE);
         ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
@@ -379,9 +686,149 @@ impl StratumHandler {
     }
 }
 
+/// Split 64-bit nonce into part fixed by pool-assigned `extranonce` (hex, in upper bytes) and
+/// `extranonce2_size` bytes the miner is free to roll. Returns `(nonce_mask, nonce_fixed)`.
+fn extranonce_nonce_bits(extranonce: &str, extranonce2_size: u32) -> Result<(u64, u64), Error> {
+    let extranonce_size = (extranonce.len() + 1) / 2;
+    if extranonce_size + extranonce2_size as usize > 8 || extranonce2_size == 0 {
+        return Err(format!(
+            "extranonce {:?} with {} bytes of extranonce2 doesn't fit into nonce",
+            extranonce, extranonce2_size
+        )
+        .into());
+    }
+    let extranonce = if extranonce.is_empty() { 0 } else { u64::from_str_radix(extranonce, 16)? };
+    let free_bits = extranonce2_size * 8;
+    if free_bits == 64 {
+        return Ok((u64::MAX, 0));
+    }
+    Ok(((1 << free_bits) - 1, extranonce << free_bits))
+}
+
 impl Drop for StratumHandler {
     fn drop(&mut self) {
         self.log_handler.abort();
         self.block_handle.abort()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+
+    #[test]
+    fn test_subscribe_result_extranonce() {
+        let mut codec = NewLineJsonCodec::new();
+        let mut buf = BytesMut::from(&b"{\"id\":1,\"result\":[[],\"1a2b\",6],\"error\":null}\n"[..]);
+        let (extranonce, size) = match codec.decode(&mut buf) {
+            Ok(Some(StratumLine::SubscribeResult { result: (_, extranonce, size), .. })) => (extranonce, size),
+            other => panic!("unexpected result {:?}", other),
+        };
+        let (mask, fixed) = extranonce_nonce_bits(&extranonce, size).unwrap();
+        assert_eq!(mask, 0x0000_ffff_ffff_ffff);
+        assert_eq!(fixed, 0x1a2b_0000_0000_0000);
+
+        assert_eq!(extranonce_nonce_bits("", 8).unwrap(), (u64::MAX, 0));
+        assert!(extranonce_nonce_bits("1a2b", 7).is_err());
+        assert!(extranonce_nonce_bits("xyz", 4).is_err());
+    }
+
+    #[test]
+    fn test_authorize_worker_name() {
+        let address = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let line = serde_json::to_value(authorize_line(7, address, "rig-1")).unwrap();
+        assert_eq!(line["method"], "mining.authorize");
+        assert_eq!(line["id"], 7);
+        assert_eq!(line["params"][0], format!("{}.rig-1", address));
+        assert_eq!(line["params"][1], "x");
+    }
+
+    #[test]
+    fn test_transient_failure_retried() {
+        let retry = SubmitRetry::default();
+        let share = |job_id: &str, age: Duration| PendingShare {
+            job_id: job_id.into(),
+            nonce: 0x1234,
+            sent: Instant::now().checked_sub(age).unwrap(),
+            retries: 0,
+        };
+        let mut pending = HashMap::new();
+        pending.insert(1, share("fresh", Duration::ZERO));
+        pending.insert(2, share("timed-out", retry.timeout + Duration::from_secs(1)));
+        pending.insert(3, share("rejected", retry.timeout + Duration::from_secs(1)));
+
+        // Pool rejected the share: it's taken from pending ones, so it's never resubmitted
+        let stats = ShareStats { shares_pending: std::sync::Mutex::new(pending), ..Default::default() };
+        assert_eq!(stats.take_pending(3).unwrap().job_id, "rejected");
+        // Late rejection of a share that isn't pending anymore
+        assert!(stats.take_pending(3).is_none());
+
+        let mut pending = stats.pending();
+        let expired = take_expired(&mut pending, retry.timeout);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].job_id, "timed-out");
+        assert!(retry.retry_delay(SubmitFailure::Transient, expired[0].retries).is_some());
+        assert_eq!(pending.len(), 1);
+        assert!(pending.contains_key(&1));
+
+        match submit_line(7, "kaspa:addr", &expired[0].job_id, expired[0].nonce) {
+            StratumLine::StratumCommand(StratumCommand::MiningSubmit(MiningSubmit::MiningSubmitShort {
+                id,
+                params: (_, job_id, nonce),
+                ..
+            })) => {
+                assert_eq!(id, 7);
+                assert_eq!(job_id, "timed-out");
+                assert_eq!(nonce, "0x001234");
+            }
+            other => panic!("unexpected line {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_clean_jobs_flush() {
+        let mut codec = NewLineJsonCodec::new();
+        let mut jobs = JobTracker::default();
+        let lines = concat!(
+            r#"{"id":null,"method":"mining.notify","params":["1",[1,2,3,4],1000]}"#,
+            "\n",
+            r#"{"id":null,"method":"mining.notify","params":["2",[1,2,3,4],1001,false]}"#,
+            "\n",
+            r#"{"id":null,"method":"mining.notify","params":["3",[5,6,7,8],1002,true]}"#,
+            "\n",
+        );
+        let mut buf = BytesMut::from(lines.as_bytes());
+        while let Some(line) = codec.decode(&mut buf).unwrap() {
+            match line {
+                StratumLine::StratumCommand(StratumCommand::MiningNotify(MiningNotify::MiningNotifyShort {
+                    params: (id, ..),
+                    ..
+                })) => jobs.on_notify(&id, false),
+                StratumLine::StratumCommand(StratumCommand::MiningNotify(MiningNotify::MiningNotifyClean {
+                    params: (id, _, _, clean_jobs),
+                    ..
+                })) => {
+                    jobs.on_notify(&id, clean_jobs);
+                    if id == "2" {
+                        assert!(jobs.is_active("1") && jobs.is_active("2"));
+                    }
+                }
+                other => panic!("unexpected line {:?}", other),
+            }
+        }
+        assert!(!jobs.is_active("1"));
+        assert!(!jobs.is_active("2"));
+        assert!(jobs.is_active("3"));
+
+        // Pending shares of flushed jobs are dropped
+        let share = |job_id: &str| PendingShare { job_id: job_id.into(), nonce: 0, sent: Instant::now(), retries: 0 };
+        let mut pending = HashMap::new();
+        pending.insert(1, share("1"));
+        pending.insert(2, share("2"));
+        pending.insert(3, share("3"));
+        assert_eq!(drop_flushed(&mut pending, &jobs), 2);
+        assert_eq!(pending.keys().collect::<Vec<_>>(), vec![&3]);
+    }
+}
@@ -41,6 +41,12 @@ pub(crate) enum MiningNotify {
         params: (String, [u64; 4], u64),
         error: StratumError,
     },
+    /// Same as `MiningNotifyShort`, with clean jobs flag
+    MiningNotifyClean {
+        id: Option<u32>,
+        params: (String, [u64; 4], u64, bool),
+        error: StratumError,
+    },
     MiningNotifyLong {
         id: Option<u32>,
         params: (String, String, String, String, Vec<String>, String, String, String, bool),
@@ -60,6 +66,10 @@ pub(crate) enum MiningSubmit {
 pub(crate) enum StratumCommand {
     #[serde(rename = "set_extranonce")]
     SetExtranonce { id: u32, params: (String, u32), error: StratumError },
+    #[serde(rename = "mining.set_extranonce")]
+    MiningSetExtranonce { id: Option<u32>, params: (String, u32), error: StratumError },
+    #[serde(rename = "mining.extranonce.subscribe")]
+    ExtranonceSubscribe { id: u32, params: Vec<String>, error: StratumError },
     #[serde(rename = "mining.set_difficulty")]
     MiningSetDifficulty { id: Option<u32>, params: (f32,), error: StratumError },
     #[serde(rename = "mining.notify")]
@@ -78,6 +88,39 @@ pub(crate) enum StratumLine {
     StratumCommand(StratumCommand),
     StratumResult { id: u32, result: Option<bool>, error: StratumError },
     SubscribeResult { id: u32, result: (Vec<(String, String)>, String, u32), error: StratumError },
+    /// Request or notification with a method we don't know (ie. proprietary pool extension)
+    #[serde(skip)]
+    UnknownMethod { method: String, params: Value },
+}
+
+/// Whether `method` is understood by `StratumCommand`, anything else is decoded as
+/// `StratumLine::UnknownMethod`. Serde checks the tag before any other field, so the method alone
+/// is enough to tell an unknown method from a malformed known one.
+fn is_known_method(method: &str) -> bool {
+    match serde_json::from_value::<StratumCommand>(serde_json::json!({ "method": method })) {
+        Ok(_) => true,
+        Err(e) => !e.to_string().starts_with("unknown variant"),
+    }
+}
+
+fn parse_line(line: String) -> Result<StratumLine, NewLineJsonCodecError> {
+    match serde_json::from_str::<StratumLine>(line.as_str()) {
+        Ok(msg) => Ok(msg),
+        Err(e) => {
+            // Malformed known messages are still errors, unknown methods are passed on to be ignored
+            let mut value = match serde_json::from_str::<Value>(line.as_str()) {
+                Ok(value) => value,
+                Err(_) => return Err((e.to_string(), line).into()),
+            };
+            match value.get("method").and_then(Value::as_str) {
+                Some(method) if !is_known_method(method) => Ok(StratumLine::UnknownMethod {
+                    method: method.to_string(),
+                    params: value.get_mut("params").map(Value::take).unwrap_or(Value::Null),
+                }),
+                _ => Err((e.to_string(), line).into()),
+            }
+        }
+    }
 }
 
 /// An error occurred while encoding or decoding a line.
@@ -125,9 +168,7 @@ impl Decoder for NewLineJsonCodec {
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         debug!("decoding {:?}", src);
         match self.lines_codec.decode(src) {
-            Ok(Some(s)) => {
-                serde_json::from_str::<StratumLine>(s.as_str()).map_err(|e| (e.to_string(), s).into()).map(Some)
-            }
+            Ok(Some(s)) => parse_line(s).map(Some),
             Err(_) => Err(NewLineJsonCodecError::LineSplitError),
             _ => Ok(None),
         }
@@ -136,7 +177,7 @@ impl Decoder for NewLineJsonCodec {
     fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         debug!("Finalizing decoding");
         match self.lines_codec.decode_eof(buf) {
-            Ok(Some(s)) => serde_json::from_str(s.as_str()).map_err(|e| (e.to_string(), s).into()),
+            Ok(Some(s)) => parse_line(s).map(Some),
             Err(_) => Err(NewLineJsonCodecError::LineSplitError),
             _ => Ok(None),
         }
@@ -159,3 +200,72 @@ impl Default for NewLineJsonCodec {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unknown_method() {
+        let mut codec = NewLineJsonCodec::new();
+        let mut buf = BytesMut::from(
+            concat!(
+                r#"{"id":null,"method":"mining.proprietary_ping","params":[1,"x"]}"#,
+                "\n",
+                r#"{"id":null,"method":"mining.set_difficulty","params":[2.0]}"#,
+                "\n",
+            )
+            .as_bytes(),
+        );
+
+        match codec.decode(&mut buf) {
+            Ok(Some(StratumLine::UnknownMethod { method, .. })) => assert_eq!(method, "mining.proprietary_ping"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        // The stream goes on with next message
+        match codec.decode(&mut buf) {
+            Ok(Some(StratumLine::StratumCommand(StratumCommand::MiningSetDifficulty { params, .. }))) => {
+                assert_eq!(params, (2.0,))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(matches!(codec.decode(&mut buf), Ok(None)));
+    }
+
+    #[test]
+    fn test_is_known_method() {
+        for method in ["set_extranonce", "mining.notify", "mining.submit", "mining.extranonce.subscribe"] {
+            assert!(is_known_method(method), "{}", method);
+        }
+        assert!(!is_known_method("mining.proprietary_ping"));
+        assert!(!is_known_method("client.reconnect"));
+    }
+
+    #[test]
+    fn test_set_extranonce() {
+        let mut codec = NewLineJsonCodec::new();
+        let mut buf = BytesMut::from(&br#"{"id":null,"method":"mining.set_extranonce","params":["1a2b",6]}"#[..]);
+        buf.extend_from_slice(b"\n");
+        match codec.decode(&mut buf) {
+            Ok(Some(StratumLine::StratumCommand(StratumCommand::MiningSetExtranonce { params, .. }))) => {
+                assert_eq!(params, ("1a2b".to_string(), 6))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut buf = BytesMut::new();
+        let subscribe = StratumCommand::ExtranonceSubscribe { id: 3, params: vec![], error: None };
+        codec.encode(StratumLine::StratumCommand(subscribe), &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            concat!(r#"{"method":"mining.extranonce.subscribe","id":3,"params":[],"error":null}"#, "\n")
+        );
+    }
+
+    #[test]
+    fn test_malformed_known_method() {
+        let mut codec = NewLineJsonCodec::new();
+        let mut buf = BytesMut::from(&b"{\"id\":null,\"method\":\"mining.set_difficulty\",\"params\":[\"x\"]}\n"[..]);
+        assert!(matches!(codec.decode(&mut buf), Err(NewLineJsonCodecError::JsonParseError(_))));
+    }
+}
@@ -44,6 +44,11 @@ pub trait Interface: Send + Sync {
         value: &'a T,
     ) -> error::Result<()>;
 
+    /// Send raw command (ie. one that isn't a register access, like chip addressing)
+    ///
+    /// * if `wait` is true, wait for the command to be sent out
+    async fn send_raw_command(&self, cmd: Vec<u8>, wait: bool);
+
     /// Read exactly one register and return reply
     ///
     /// * `chip_address` can be only unicast
@@ -83,6 +88,108 @@ pub trait Interface: Send + Sync {
     }
 }
 
+/// Values of several registers read in one pass (see `Context::read_registers`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterBatch {
+    /// Register number and its raw values (one per responding chip, in chain order)
+    registers: Vec<(u8, Vec<u32>)>,
+}
+
+impl RegisterBatch {
+    fn new(reg_nums: &[u8]) -> Self {
+        Self {
+            registers: reg_nums.iter().map(|reg_num| (*reg_num, Vec::new())).collect(),
+        }
+    }
+
+    /// Values of register `T` or `None` if `T` wasn't part of the batch
+    pub fn get<T: bm1387::Register>(&self) -> Option<Vec<T>> {
+        self.registers
+            .iter()
+            .find(|(reg_num, _)| *reg_num == T::REG_NUM)
+            .map(|(_, values)| values.iter().map(|value| T::from_reg(*value)).collect())
+    }
+}
+
+/// Transport of commands to chips and of their responses (`io::CommandRxTx` on hardware)
+#[async_trait]
+trait CommandIo: Send {
+    async fn send_command(&mut self, cmd: Vec<u8>, wait: bool);
+
+    async fn recv_response(&mut self, timeout: Duration) -> error::Result<Option<Vec<u8>>>;
+}
+
+#[async_trait]
+impl CommandIo for io::CommandRxTx {
+    async fn send_command(&mut self, cmd: Vec<u8>, wait: bool) {
+        io::CommandRxTx::send_command(self, cmd, wait).await
+    }
+
+    async fn recv_response(&mut self, timeout: Duration) -> error::Result<Option<Vec<u8>>> {
+        io::CommandRxTx::recv_response(self, timeout).await
+    }
+}
+
+/// Read registers `reg_nums` of chips at `chip_address` in one pass, expecting one response per
+/// chip in `chip_count` on broadcast.
+///
+/// bm1387 responses carry neither chip address nor register number, and near chips' responses to
+/// a command can overtake far chips' responses to the previous one. So each `GetStatusCmd` goes out
+/// only after all responses to the previous one were collected. The pass is faster than reading
+/// registers one by one because it doesn't wait for `COMMAND_READ_TIMEOUT` after each register
+/// once all expected responses are in.
+async fn read_registers_from(
+    command_io: &mut impl CommandIo,
+    chip_address: ChipAddress,
+    reg_nums: &[u8],
+    chip_count: Option<usize>,
+) -> error::Result<RegisterBatch> {
+    let expected = if chip_address == ChipAddress::All {
+        chip_count
+    } else {
+        Some(1)
+    };
+    let mut batch = RegisterBatch::new(reg_nums);
+    for (reg_num, values) in batch.registers.iter_mut() {
+        let cmd = bm1387::GetStatusCmd::new(chip_address, *reg_num);
+        command_io.send_command(cmd.pack().to_vec(), false).await;
+
+        while expected != Some(values.len()) {
+            let response = match command_io
+                .recv_response(InnerContext::COMMAND_READ_TIMEOUT)
+                .await?
+            {
+                Some(response) => response,
+                None => break,
+            };
+            let response = bm1387::CmdResponse::unpack_from_slice(&response)
+                .context(format!("response unpacking failed"))?;
+            // Only bm1391 tags responses with register number
+            let response_reg_num = response.register_number();
+            if response_reg_num != 0 && response_reg_num != *reg_num {
+                Err(ErrorKind::Hashchip(format!(
+                    "unsolicited response for register {:#x} to GetStatusCmd(reg={:#x})",
+                    response_reg_num, reg_num
+                )))?;
+            }
+            values.push(response.value);
+        }
+
+        if let Some(expected) = expected {
+            if values.len() != expected {
+                Err(ErrorKind::Hashchip(format!(
+                    "Number of responses {} of GetStatusCmd(reg={:#x}) to {:?} doesn't match expected {}",
+                    values.len(),
+                    reg_num,
+                    chip_address,
+                    expected
+                )))?;
+            }
+        }
+    }
+    Ok(batch)
+}
+
 /// `InnerContext` holds FPGA registers with command FIFO and implements on top
 /// of them functions to issue commands to chip registers (via `send_raw_command`)
 /// or to read/write chip registers (via `Interface` interface).
@@ -171,6 +278,17 @@ impl InnerContext {
             .collect::<Vec<T>>())
     }
 
+    /// Read registers `reg_nums` in one pass (see `read_registers_from`)
+    ///
+    /// Throw an error if unexpected number of replies have been received for any register.
+    async fn read_registers(
+        &mut self,
+        chip_address: ChipAddress,
+        reg_nums: &[u8],
+    ) -> error::Result<RegisterBatch> {
+        read_registers_from(&mut self.command_io, chip_address, reg_nums, self.chip_count).await
+    }
+
     async fn flush_command_rx(&mut self) -> error::Result<()> {
         while let Some(response) = self
             .command_io
@@ -246,22 +364,172 @@ impl Interface for Context {
         let mut inner = self.inner.lock().await;
         inner.write_register(chip_address, value).await
     }
-}
 
-impl Context {
-    pub async fn send_raw_command(&self, cmd: Vec<u8>, wait: bool) {
+    async fn send_raw_command(&self, cmd: Vec<u8>, wait: bool) {
         let mut inner = self.inner.lock().await;
         inner.send_raw_command(cmd, wait).await
     }
+}
 
+impl Context {
     pub async fn set_chip_count(&self, chip_count: usize) {
         let mut inner = self.inner.lock().await;
         inner.set_chip_count(chip_count);
     }
 
+    /// Read several registers of chips at `chip_address` in one pass, without waiting for
+    /// stragglers after each register. Use `RegisterBatch::get` to pick values of each register.
+    pub async fn read_registers(
+        &self,
+        chip_address: ChipAddress,
+        reg_nums: &[u8],
+    ) -> error::Result<RegisterBatch> {
+        let mut inner = self.inner.lock().await;
+        inner.read_registers(chip_address, reg_nums).await
+    }
+
     pub fn new(command_io: io::CommandRxTx) -> Self {
         Self {
             inner: Arc::new(Mutex::new(InnerContext::new(command_io))),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bm1387::Register;
+    // Hashboard drivers run on tokio 0.2
+    use async_compat::tokio;
+    use std::collections::VecDeque;
+
+    /// Command backend of a chain of chips, `chips[chip]` holds (register number, value) pairs of
+    /// one chip. Responses to a command are queued when the command is sent, the nearest chip's
+    /// response overtakes responses to previous commands that weren't collected yet.
+    #[derive(Default)]
+    struct MockCommandIo {
+        chips: Vec<Vec<(u8, u32)>>,
+        /// Chips put register number in responses (bm1391)
+        tagged: bool,
+        responses: VecDeque<Vec<u8>>,
+        /// Register numbers of sent commands along with number of responses not collected when
+        /// each of them was sent
+        sent: Vec<(u8, usize)>,
+        /// How many times reading timed out
+        timeouts: usize,
+    }
+
+    impl MockCommandIo {
+        fn response(&self, value: u32, reg_num: u8) -> Vec<u8> {
+            let mut response = value.to_be_bytes().to_vec();
+            response.extend_from_slice(&[0, if self.tagged { reg_num } else { 0 }]);
+            response
+        }
+    }
+
+    #[async_trait]
+    impl CommandIo for MockCommandIo {
+        async fn send_command(&mut self, cmd: Vec<u8>, _wait: bool) {
+            let (broadcast, hw_addr, reg_num) = (cmd[0] & 0x10 != 0, cmd[2] as usize, cmd[3]);
+            self.sent.push((reg_num, self.responses.len()));
+            let responses: Vec<_> = self
+                .chips
+                .iter()
+                .enumerate()
+                .filter(|(chip, _)| broadcast || *chip == hw_addr / 4)
+                .filter_map(|(_, registers)| registers.iter().find(|(num, _)| *num == reg_num))
+                .map(|(_, value)| self.response(*value, reg_num))
+                .collect();
+            let mut responses = responses.into_iter();
+            if let Some(nearest) = responses.next() {
+                self.responses.push_front(nearest);
+            }
+            self.responses.extend(responses);
+        }
+
+        async fn recv_response(&mut self, _timeout: Duration) -> error::Result<Option<Vec<u8>>> {
+            let response = self.responses.pop_front();
+            if response.is_none() {
+                self.timeouts += 1;
+            }
+            Ok(response)
+        }
+    }
+
+    fn chain(chip_count: u32) -> Vec<Vec<(u8, u32)>> {
+        (0..chip_count)
+            .map(|chip| {
+                vec![
+                    (bm1387::PllReg::REG_NUM, 0x0068_0221 + chip),
+                    (bm1387::MiscCtrlReg::REG_NUM, 0x4020_1a00 + chip),
+                ]
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_read_registers() {
+        let reg_nums = [bm1387::PllReg::REG_NUM, bm1387::MiscCtrlReg::REG_NUM];
+        let mut command_io = MockCommandIo {
+            chips: chain(3),
+            ..Default::default()
+        };
+        let batch = read_registers_from(&mut command_io, ChipAddress::All, &reg_nums, Some(3))
+            .await
+            .expect("batch read failed");
+        let pll: Vec<_> = batch.get::<bm1387::PllReg>().expect("no PLL values").iter().map(|r| r.to_reg()).collect();
+        assert_eq!(pll, vec![0x0068_0221, 0x0068_0222, 0x0068_0223]);
+        let misc: Vec<_> =
+            batch.get::<bm1387::MiscCtrlReg>().expect("no misc values").iter().map(|r| r.to_reg()).collect();
+        assert_eq!(misc, vec![0x4020_1a00, 0x4020_1a01, 0x4020_1a02]);
+        assert!(batch.get::<bm1387::HashCountingReg>().is_none());
+        // Each command waits for all responses to the previous one, but not for a timeout
+        assert_eq!(command_io.sent, vec![(bm1387::PllReg::REG_NUM, 0), (bm1387::MiscCtrlReg::REG_NUM, 0)]);
+        assert_eq!(command_io.timeouts, 0);
+
+        // Without chip count responses are collected until timeout
+        let mut command_io = MockCommandIo {
+            chips: chain(3),
+            ..Default::default()
+        };
+        let batch = read_registers_from(&mut command_io, ChipAddress::All, &reg_nums, None)
+            .await
+            .expect("batch read failed");
+        assert_eq!(batch.get::<bm1387::PllReg>().expect("no PLL values").len(), 3);
+        assert_eq!(command_io.timeouts, 2);
+
+        // Unicast
+        let mut command_io = MockCommandIo {
+            chips: chain(3),
+            ..Default::default()
+        };
+        let batch = read_registers_from(&mut command_io, ChipAddress::One(2), &reg_nums, Some(3))
+            .await
+            .expect("batch read failed");
+        assert_eq!(batch.get::<bm1387::MiscCtrlReg>().expect("no misc values")[0].to_reg(), 0x4020_1a02);
+        assert_eq!(command_io.timeouts, 0);
+    }
+
+    #[tokio::test]
+    async fn test_read_registers_bad_responses() {
+        let reg_nums = [bm1387::PllReg::REG_NUM, bm1387::MiscCtrlReg::REG_NUM];
+        // A chip doesn't answer
+        let mut chips = chain(3);
+        chips[2].pop();
+        let mut command_io = MockCommandIo {
+            chips,
+            ..Default::default()
+        };
+        assert!(read_registers_from(&mut command_io, ChipAddress::All, &reg_nums, Some(3)).await.is_err());
+
+        // Tagged response for register that wasn't asked for
+        let mut command_io = MockCommandIo {
+            chips: chain(3),
+            tagged: true,
+            ..Default::default()
+        };
+        let stray = command_io.response(0, bm1387::HashCountingReg::REG_NUM);
+        command_io.responses.push_back(stray);
+        assert!(read_registers_from(&mut command_io, ChipAddress::All, &reg_nums, Some(3)).await.is_err());
+    }
+}
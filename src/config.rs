@@ -0,0 +1,264 @@
+//! Configuration file support
+//!
+//! The file is TOML with keys named after long command line options (either `kebab-case` or
+//! `snake_case`). Options of plugins go to `[plugins.<name>]` tables named after the plugin library
+//! (without `lib`/`kaspa` prefix), only loaded plugins and their own options are accepted there, ie:
+//!
+//! ```toml
+//! mining-address = "kaspa:..."
+//! threads = 4
+//!
+//! [plugins.opencl]
+//! opencl-workload = [256.0, 512.0]
+//! ```
+//!
+//! File values are turned into command line arguments that are placed in front of the real
+//! ones. Because all options override themselves, anything given on the command line wins.
+
+use clap::App;
+use kasop::PluginOptions;
+use std::fs;
+
+use crate::Error;
+
+const CONFIG_OPTION: &str = "--config";
+
+/// Find all values of `option` (ie. `--config`) in raw command line `args`
+fn find_option_values(args: &[String], option: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == option {
+            values.extend(args.next().cloned());
+        } else if let Some(value) = arg.strip_prefix(option).and_then(|rest| rest.strip_prefix('=')) {
+            values.push(value.to_string());
+        }
+    }
+    values
+}
+
+/// Find path of the configuration file in raw command line `args`
+fn find_config_path(args: &[String]) -> Option<String> {
+    find_option_values(args, CONFIG_OPTION).into_iter().next()
+}
+
+/// Values of option `long` needed before the command line can be parsed (ie. which plugins to
+/// load, plugins add their own options): from the top level of configuration file followed by
+/// those from raw command line `args`
+pub fn early_option_values(args: &[String], long: &str) -> Result<Vec<String>, Error> {
+    let mut values = Vec::new();
+    if let Some(path) = find_config_path(args) {
+        let config = fs::read_to_string(&path).map_err(|e| format!("cannot read config file {}: {}", path, e))?;
+        values = config_option_values(&config, long).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    values.extend(find_option_values(args, &format!("--{}", long)));
+    Ok(values)
+}
+
+/// Values of top level option `long` in configuration file
+fn config_option_values(config: &str, long: &str) -> Result<Vec<String>, Error> {
+    let config: toml::value::Table = toml::from_str(config)?;
+    let value = match config.get(long).or_else(|| config.get(&long.replace('-', "_"))) {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+    match value {
+        toml::Value::Array(values) => values.iter().map(|value| scalar_to_arg(long, value)).collect(),
+        value => Ok(vec![scalar_to_arg(long, value)?]),
+    }
+}
+
+/// Format scalar TOML value as command line argument value
+fn scalar_to_arg(key: &str, value: &toml::Value) -> Result<String, Error> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(format!("unsupported value of '{}' in config file", key).into()),
+    }
+}
+
+/// Append arguments for one `key = value` pair
+fn push_option(app: &App, args: &mut Vec<String>, key: &str, value: &toml::Value) -> Result<(), Error> {
+    let long = key.replace('_', "-");
+    let arg = app
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long.as_str()))
+        .ok_or_else(|| format!("unknown option '{}' in config file", key))?;
+    let option = format!("--{}", long);
+
+    if !arg.is_takes_value_set() {
+        return match value {
+            toml::Value::Boolean(true) => {
+                args.push(option);
+                Ok(())
+            }
+            toml::Value::Boolean(false) => Ok(()),
+            _ => Err(format!("option '{}' in config file is a flag, expected true or false", key).into()),
+        };
+    }
+
+    args.push(option);
+    match value {
+        toml::Value::Array(values) => {
+            for value in values {
+                args.push(scalar_to_arg(key, value)?);
+            }
+        }
+        value => args.push(scalar_to_arg(key, value)?),
+    }
+    Ok(())
+}
+
+/// Check that `[plugins.<plugin>]` table belongs to one of loaded `plugins` and has only its
+/// options
+fn check_plugin_table(plugins: &[PluginOptions], plugin: &str, options: &toml::value::Table) -> Result<(), Error> {
+    let plugin_options = match plugins.iter().find(|loaded| loaded.name == plugin) {
+        Some(loaded) => &loaded.options,
+        None => {
+            let loaded: Vec<_> = plugins.iter().map(|loaded| loaded.name.as_str()).collect();
+            let loaded = if loaded.is_empty() { "none".to_string() } else { loaded.join(", ") };
+            return Err(format!("[plugins.{}] in config file but no such plugin is loaded (loaded: {})", plugin, loaded)
+                .into());
+        }
+    };
+    for key in options.keys() {
+        if !plugin_options.contains(&key.replace('_', "-")) {
+            return Err(format!("'{}' in [plugins.{}] is not an option of plugin {}", key, plugin, plugin).into());
+        }
+    }
+    Ok(())
+}
+
+/// Translate configuration file contents to command line arguments accepted by `app`, plugin
+/// tables are checked against loaded `plugins`
+pub fn config_to_args(app: &App, plugins: &[PluginOptions], config: &str) -> Result<Vec<String>, Error> {
+    let config: toml::value::Table = toml::from_str(config)?;
+    let mut args = Vec::new();
+    for (key, value) in config.iter() {
+        match (key.as_str(), value) {
+            ("config", _) => return Err("config file cannot include another config file".into()),
+            ("plugins", toml::Value::Table(tables)) => {
+                for (plugin, options) in tables.iter() {
+                    let options =
+                        options.as_table().ok_or_else(|| format!("[plugins.{}] has to be a table", plugin))?;
+                    check_plugin_table(plugins, plugin, options)?;
+                    for (key, value) in options.iter() {
+                        push_option(app, &mut args, key, value)?;
+                    }
+                }
+            }
+            (key, value) => push_option(app, &mut args, key, value)?,
+        }
+    }
+    Ok(args)
+}
+
+/// Prepend options from configuration file (if any was given with `--config`) to command line
+/// `args`
+pub fn merge_args(app: &App, plugins: &[PluginOptions], args: Vec<String>) -> Result<Vec<String>, Error> {
+    let path = match find_config_path(&args) {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+    let config = fs::read_to_string(&path).map_err(|e| format!("cannot read config file {}: {}", path, e))?;
+    let file_args = config_to_args(app, plugins, &config).map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut args = args.into_iter();
+    Ok(args.next().into_iter().chain(file_args.into_iter()).chain(args).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cli::Opt;
+    use clap::{Arg, ArgMatches, FromArgMatches, IntoApp};
+
+    /// `--opencl-workload` as added by the OpenCL plugin
+    fn opencl_plugin() -> PluginOptions {
+        PluginOptions { name: "opencl".into(), options: vec!["opencl-workload".into()] }
+    }
+
+    fn parse_matches(config: &str, cli: &[&str]) -> Result<ArgMatches, Error> {
+        let app = Opt::into_app().arg(
+            Arg::new("opencl-workload").long("opencl-workload").takes_value(true).multiple_values(true),
+        );
+        let file_args = config_to_args(&app, &[opencl_plugin()], config)?;
+        let args = std::iter::once("kasop".to_string())
+            .chain(file_args.into_iter())
+            .chain(cli.iter().map(|arg| arg.to_string()));
+        Ok(app.try_get_matches_from(args)?)
+    }
+
+    fn parse(config: &str, cli: &[&str]) -> Result<Opt, Error> {
+        Ok(Opt::from_arg_matches(&parse_matches(config, cli)?)?)
+    }
+
+    #[test]
+    fn test_cli_takes_precedence() {
+        let config = r#"
+            mining-address = "kaspa:fromfile"
+            kaspad_address = "10.0.0.1"
+            threads = 4
+            debug = true
+        "#;
+        let opt = parse(config, &["-t", "2"]).expect("parsing failed");
+        assert_eq!(opt.mining_address, "kaspa:fromfile");
+        assert_eq!(opt.kaspad_address, "10.0.0.1");
+        assert_eq!(opt.num_threads, Some(2));
+        assert!(opt.debug);
+
+        let opt = parse(config, &["--mining-address", "kaspa:fromcli"]).expect("parsing failed");
+        assert_eq!(opt.mining_address, "kaspa:fromcli");
+        assert_eq!(opt.num_threads, Some(4));
+    }
+
+    #[test]
+    fn test_plugin_table() {
+        let config = r#"
+            mining-address = "kaspa:fromfile"
+
+            [plugins.opencl]
+            opencl_workload = [256.0, 512.5]
+        "#;
+        let matches = parse_matches(config, &[]).expect("parsing failed");
+        assert_eq!(matches.values_of("opencl-workload").unwrap().collect::<Vec<_>>(), vec!["256", "512.5"]);
+        assert_eq!(Opt::from_arg_matches(&matches).unwrap().mining_address, "kaspa:fromfile");
+
+        // plugin that isn't loaded
+        assert!(parse("[plugins.cuda]\ncuda-workload = [1.0]", &["-a", "kaspa:x"]).is_err());
+        // core options belong to the top level
+        assert!(parse("[plugins.opencl]\nthreads = 3", &["-a", "kaspa:x"]).is_err());
+        assert!(config_to_args(&Opt::into_app(), &[], "[plugins.opencl]\n").is_err());
+    }
+
+    #[test]
+    fn test_bad_config() {
+        assert!(parse("no-such-option = 1", &["-a", "kaspa:x"]).is_err());
+        assert!(parse("[plugins.opencl]\nno-such-option = 1", &["-a", "kaspa:x"]).is_err());
+        assert!(parse("debug = 1", &["-a", "kaspa:x"]).is_err());
+        assert!(parse("mining-address = ", &[]).is_err());
+    }
+
+    #[test]
+    fn test_early_option_values() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let cli = args(&["kasop", "--plugin-allow", "a", "-t", "1", "--plugin-allow=b", "--plugin-allowed", "c"]);
+        assert_eq!(early_option_values(&cli, "plugin-allow").unwrap(), vec!["a", "b"]);
+        assert!(early_option_values(&args(&["kasop"]), "plugin-allow").unwrap().is_empty());
+
+        assert_eq!(config_option_values("plugin-allow = [\"c\", \"d\"]", "plugin-allow").unwrap(), vec!["c", "d"]);
+        assert_eq!(config_option_values("plugin_allow = \"e\"", "plugin-allow").unwrap(), vec!["e"]);
+        assert!(config_option_values("threads = 2", "plugin-allow").unwrap().is_empty());
+        assert!(config_option_values("plugin-allow = [[1]]", "plugin-allow").is_err());
+    }
+
+    #[test]
+    fn test_find_config_path() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(find_config_path(&args(&["kasop", "--config", "a.toml"])), Some("a.toml".into()));
+        assert_eq!(find_config_path(&args(&["kasop", "-t", "1", "--config=b.toml"])), Some("b.toml".into()));
+        assert_eq!(find_config_path(&args(&["kasop", "--configuration"])), None);
+    }
+}
@@ -4,8 +4,30 @@
 
 use crate::bm1387;
 
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
+/// Number of hashes represented by one share of difficulty 1
+const HASHES_PER_SHARE: f64 = 4_294_967_296.0;
+
+/// Blocks found since start (accepted by kaspad), apart from shares accepted by pools
+static BLOCKS_FOUND: AtomicU64 = AtomicU64::new(0);
+
+/// Count a found block, returns how many were found since start
+pub fn count_block_found() -> u64 {
+    BLOCKS_FOUND.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+pub fn blocks_found() -> u64 {
+    BLOCKS_FOUND.load(Ordering::Relaxed)
+}
+
 /// Per-core counters for valid nonces/errors
 #[derive(Clone, Copy)]
 pub struct Core {
@@ -29,7 +51,7 @@ impl Core {
 
 #[derive(Clone, Copy)]
 pub struct Chip {
-    pub core: [Core; super::CORE_ADR_SPACE_SIZE],
+    pub core: [Core; crate::hashchain::CORE_ADR_SPACE_SIZE],
     pub valid: usize,
     pub errors: usize,
 }
@@ -39,7 +61,7 @@ impl Chip {
         Self {
             valid: 0,
             errors: 0,
-            core: [Core::new(); super::CORE_ADR_SPACE_SIZE],
+            core: [Core::new(); crate::hashchain::CORE_ADR_SPACE_SIZE],
         }
     }
 
@@ -54,12 +76,16 @@ impl Chip {
 
 #[derive(Clone)]
 pub struct HashChain {
+    /// Counters of chips indexed by chip address (see `bm1387::CoreAddress`)
     pub chip: Vec<Chip>,
     pub valid: usize,
     pub errors: usize,
     pub started: Instant,
     pub stopped: Option<Instant>,
     pub asic_difficulty: usize,
+    /// Per-core counters not included in the ones above: loaded from previous runs and folded
+    /// in on `reset`
+    pub history: CoreHealth,
 }
 
 impl HashChain {
@@ -71,10 +97,13 @@ impl HashChain {
             stopped: None,
             chip: vec![Chip::new(); chip_count],
             asic_difficulty,
+            history: CoreHealth::default(),
         }
     }
 
     pub fn reset(&mut self) {
+        // Keep long-term health, only the session counters start over
+        self.history = self.cumulative_health();
         self.valid = 0;
         self.errors = 0;
         for chip in self.chip.iter_mut() {
@@ -127,4 +156,486 @@ impl HashChain {
     pub fn chip_count(&self) -> usize {
         self.chip.len()
     }
+
+    /// Valid nonces (in shares) and errors of each chip, including the ones folded into history
+    /// by `reset`, so that they never go down
+    pub fn cumulative_chip_counts(&self) -> Vec<(usize, usize)> {
+        let mut counts: Vec<_> = self.chip.iter().map(|chip| (chip.valid, chip.errors)).collect();
+        for totals in self.history.cores.iter() {
+            if let Some(count) = counts.get_mut(totals.chip) {
+                count.0 += totals.valid as usize;
+                count.1 += totals.errors as usize;
+            }
+        }
+        counts
+    }
+
+    /// Per-core counters of this session merged with history
+    pub fn cumulative_health(&self) -> CoreHealth {
+        self.history.merge(&CoreHealth::from_chain(self))
+    }
+
+    pub fn health_summary(&self) -> HealthSummary {
+        HealthSummary {
+            session: HealthTotals {
+                valid: self.valid as u64,
+                errors: self.errors as u64,
+            },
+            cumulative: self.cumulative_health().totals(),
+        }
+    }
+
+    /// Hashrate (in hashes per second) estimated from valid nonces
+    pub fn hashrate(&self) -> f64 {
+        self.hashrate_over(self.duration())
+    }
+
+    fn hashrate_over(&self, duration: Duration) -> f64 {
+        let secs = duration.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.valid as f64 * HASHES_PER_SHARE / secs
+    }
+
+    /// Status at `now`, `now_utc` is the same moment in wall clock time (`Instant`s have none)
+    pub fn status(&self, now: Instant, now_utc: DateTime<Utc>) -> ChainStatus {
+        let wall_clock = |at: Instant| {
+            let ago = chrono::Duration::from_std(now.saturating_duration_since(at))
+                .unwrap_or_else(|_| chrono::Duration::zero());
+            (now_utc - ago).to_rfc3339()
+        };
+        let uptime = self.stopped.unwrap_or(now).saturating_duration_since(self.started);
+        ChainStatus {
+            started: wall_clock(self.started),
+            stopped: self.stopped.map(wall_clock),
+            uptime_secs: uptime.as_secs(),
+            uptime: format_duration(uptime),
+            valid: self.valid,
+            errors: self.errors,
+            hashrate: self.hashrate_over(uptime),
+        }
+    }
+}
+
+/// Chain counters as shown in status output
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChainStatus {
+    /// When counting started (RFC 3339)
+    pub started: String,
+    /// When counting stopped (RFC 3339), `None` while it's running
+    pub stopped: Option<String>,
+    pub uptime_secs: u64,
+    /// Uptime for humans, see `format_duration`
+    pub uptime: String,
+    pub valid: usize,
+    pub errors: usize,
+    /// Hashes per second
+    pub hashrate: f64,
+}
+
+/// Format `duration` for humans with up to three most significant units, ie. `3d 4h 12m`,
+/// `4h 12m 5s` or `5s`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let units = [
+        (secs / 86_400, "d"),
+        (secs / 3_600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let first = units
+        .iter()
+        .position(|(value, _)| *value > 0)
+        .unwrap_or(units.len() - 1);
+    units[first..]
+        .iter()
+        .take(3)
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Valid/error counters of a single core
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoreTotals {
+    /// Chip address (`ChipAddress::One(chip)`), it's the same across restarts as long as the
+    /// chain stays the same
+    pub chip: usize,
+    pub core: usize,
+    pub valid: u64,
+    pub errors: u64,
+}
+
+/// Per-core health that is persisted across restarts, so that a core that has been flaky for
+/// days doesn't look fine after reboot. Only cores with nonzero counters are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoreHealth {
+    pub cores: Vec<CoreTotals>,
+}
+
+impl CoreHealth {
+    /// Current counters of `chain` (without its history)
+    pub fn from_chain(chain: &HashChain) -> Self {
+        let mut cores = Vec::new();
+        for (chip_idx, chip) in chain.chip.iter().enumerate() {
+            for (core_idx, core) in chip.core.iter().enumerate() {
+                if core.valid > 0 || core.errors > 0 {
+                    cores.push(CoreTotals {
+                        chip: chip_idx,
+                        core: core_idx,
+                        valid: core.valid as u64,
+                        errors: core.errors as u64,
+                    });
+                }
+            }
+        }
+        Self { cores }
+    }
+
+    /// Sum counters of both, core by core
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut merged = BTreeMap::new();
+        for totals in self.cores.iter().chain(other.cores.iter()) {
+            let entry = merged.entry((totals.chip, totals.core)).or_insert((0, 0));
+            entry.0 += totals.valid;
+            entry.1 += totals.errors;
+        }
+        Self {
+            cores: merged
+                .into_iter()
+                .map(|((chip, core), (valid, errors))| CoreTotals {
+                    chip,
+                    core,
+                    valid,
+                    errors,
+                })
+                .collect(),
+        }
+    }
+
+    /// Counters of all cores together
+    pub fn totals(&self) -> HealthTotals {
+        HealthTotals {
+            valid: self.cores.iter().map(|totals| totals.valid).sum(),
+            errors: self.cores.iter().map(|totals| totals.errors).sum(),
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Write to temporary file first, so that crash in the middle doesn't destroy the history
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents =
+            serde_json::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut tmp_path = path.to_path_buf().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Health file of hashboard `hashboard_idx` in `dir`
+    pub fn path(dir: &Path, hashboard_idx: usize) -> PathBuf {
+        dir.join(format!("core-health-{}.json", hashboard_idx))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HealthTotals {
+    pub valid: u64,
+    pub errors: u64,
+}
+
+/// Chain counters of this session (since start or last reset) and including all history
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HealthSummary {
+    pub session: HealthTotals,
+    pub cumulative: HealthTotals,
+}
+
+/// When is a chip considered glitched (ie. after baud/PLL glitch) and worth re-initializing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChipReinitConfig {
+    /// Fraction of nonces (0.0-1.0) that are errors above which chip is re-initialized
+    pub max_error_rate: f64,
+    /// Period over which error rate is evaluated
+    pub window: Duration,
+    /// Chips with fewer nonces in the window are not judged (the rate would be just noise)
+    pub min_nonces: usize,
+}
+
+impl Default for ChipReinitConfig {
+    fn default() -> Self {
+        Self {
+            max_error_rate: 0.2,
+            window: Duration::from_secs(300),
+            min_nonces: 50,
+        }
+    }
+}
+
+/// Watches per-chip error rate over sliding window and picks chips to re-initialize
+pub struct ChipErrorMonitor {
+    config: ChipReinitConfig,
+    /// Per-chip nonce and error counts at the time of check, oldest first
+    history: VecDeque<(Instant, Vec<(usize, usize)>)>,
+}
+
+impl ChipErrorMonitor {
+    pub fn new(config: ChipReinitConfig) -> Self {
+        Self {
+            config,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> ChipReinitConfig {
+        self.config
+    }
+
+    /// Record current counters of `chain` and return addresses of chips whose error rate over
+    /// the last window exceeds the threshold. Nothing is returned until the history covers one
+    /// full window, and after chips are picked, they get another full window to recover.
+    /// Cumulative counters are used, so resetting `chain` doesn't hide errors.
+    pub fn check(&mut self, now: Instant, chain: &HashChain) -> Vec<usize> {
+        // Valid counters are in shares, errors in instances
+        let difficulty = chain.asic_difficulty.max(1);
+        let current: Vec<_> = chain
+            .cumulative_chip_counts()
+            .into_iter()
+            .map(|(valid, errors)| (valid / difficulty + errors, errors))
+            .collect();
+
+        // Keep the newest snapshot that is at least a window old as baseline
+        while self.history.len() >= 2
+            && now.saturating_duration_since(self.history[1].0) >= self.config.window
+        {
+            self.history.pop_front();
+        }
+        let mut chips = Vec::new();
+        if let Some((time, baseline)) = self.history.front() {
+            if now.saturating_duration_since(*time) >= self.config.window {
+                for (idx, (current, baseline)) in current.iter().zip(baseline.iter()).enumerate() {
+                    // Chips could have been re-enumerated meanwhile
+                    let nonces = current.0.saturating_sub(baseline.0);
+                    let errors = current.1.saturating_sub(baseline.1);
+                    if nonces >= self.config.min_nonces
+                        && errors as f64 / nonces as f64 > self.config.max_error_rate
+                    {
+                        chips.push(idx);
+                    }
+                }
+            }
+        }
+        if !chips.is_empty() {
+            self.history.clear();
+        }
+        self.history.push_back((now, current));
+        chips
+    }
+}
+
+/// Hashrate measured by chips themselves (`HashrateReg`) side by side with hashrate derived
+/// from counted nonces (both in hashes per second). Significant difference usually means that
+/// solutions are being lost somewhere between chips and us.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashrateComparison {
+    pub chip_reported: f64,
+    pub counted: f64,
+}
+
+impl HashrateComparison {
+    /// Difference of counted hashrate from chip-reported hashrate, in percent of the latter
+    pub fn divergence_percent(&self) -> f64 {
+        if self.chip_reported == 0.0 {
+            return if self.counted == 0.0 { 0.0 } else { f64::INFINITY };
+        }
+        (self.counted - self.chip_reported).abs() / self.chip_reported * 100.0
+    }
+
+    /// Return true if hashrates differ by more than `max_percent`
+    pub fn diverges(&self, max_percent: f64) -> bool {
+        self.divergence_percent() > max_percent
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(300)), "0s");
+        assert_eq!(format_duration(Duration::from_secs(5)), "5s");
+        assert_eq!(format_duration(Duration::from_secs(12 * 60 + 5)), "12m 5s");
+        assert_eq!(format_duration(Duration::from_secs(3600)), "1h 0m 0s");
+        assert_eq!(format_duration(Duration::from_secs(4 * 3600 + 12 * 60 + 5)), "4h 12m 5s");
+        let days = Duration::from_secs(3 * 86_400 + 4 * 3600 + 12 * 60 + 59);
+        assert_eq!(format_duration(days), "3d 4h 12m");
+        assert_eq!(format_duration(Duration::from_secs(400 * 86_400)), "400d 0h 0m");
+    }
+
+    #[test]
+    fn test_chain_status() {
+        let mut chain = HashChain::new(1, 1);
+        chain.valid = 10;
+        let now = chain.started + Duration::from_secs(90_061);
+        let now_utc = DateTime::parse_from_rfc3339("2021-03-05T06:07:08+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let status = chain.status(now, now_utc);
+        assert_eq!(status.started, "2021-03-04T05:06:07+00:00");
+        assert_eq!(status.stopped, None);
+        assert_eq!(status.uptime_secs, 90_061);
+        assert_eq!(status.uptime, "1d 1h 1m");
+        assert_eq!(status.hashrate, 10.0 * HASHES_PER_SHARE / 90_061.0);
+
+        chain.stopped = Some(chain.started + Duration::from_secs(61));
+        let status = chain.status(now, now_utc);
+        assert_eq!(status.stopped.as_deref(), Some("2021-03-04T05:07:08+00:00"));
+        assert_eq!(status.uptime, "1m 1s");
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["started"], "2021-03-04T05:06:07+00:00");
+        assert_eq!(json["uptime_secs"], 61);
+    }
+
+    #[test]
+    fn test_hashrate_divergence() {
+        let comparison = HashrateComparison {
+            chip_reported: 14e12,
+            counted: 13.5e12,
+        };
+        assert!(!comparison.diverges(5.0));
+        assert!(comparison.diverges(3.0));
+
+        let lost = HashrateComparison {
+            chip_reported: 14e12,
+            counted: 7e12,
+        };
+        assert_eq!(lost.divergence_percent(), 50.0);
+
+        let idle = HashrateComparison {
+            chip_reported: 0.0,
+            counted: 0.0,
+        };
+        assert!(!idle.diverges(0.0));
+        let bogus = HashrateComparison {
+            chip_reported: 0.0,
+            counted: 1.0,
+        };
+        assert!(bogus.diverges(1000.0));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut chain = HashChain::new(2, 256);
+        chain.add_valid(bm1387::CoreAddress { chip: 0, core: 5 });
+        chain.add_valid(bm1387::CoreAddress { chip: 1, core: 6 });
+        chain.add_error(bm1387::CoreAddress { chip: 1, core: 6 });
+        let started = chain.started;
+
+        chain.reset();
+        assert_eq!(chain.valid, 0);
+        assert_eq!(chain.errors, 0);
+        for chip in chain.chip.iter() {
+            assert_eq!((chip.valid, chip.errors), (0, 0));
+            assert!(chip.core.iter().all(|core| core.valid == 0 && core.errors == 0));
+        }
+        assert_eq!(chain.chip.len(), 2);
+        assert_eq!(chain.asic_difficulty, 256);
+        assert!(chain.started >= started);
+        // Cumulative counters survive
+        assert_eq!(chain.cumulative_chip_counts(), vec![(256, 0), (256, 1)]);
+        chain.add_error(bm1387::CoreAddress { chip: 0, core: 5 });
+        assert_eq!(chain.cumulative_chip_counts(), vec![(256, 1), (256, 1)]);
+    }
+
+    #[test]
+    fn test_chip_error_monitor() {
+        let mut monitor = ChipErrorMonitor::new(ChipReinitConfig {
+            max_error_rate: 0.2,
+            window: Duration::from_secs(60),
+            min_nonces: 10,
+        });
+        let mut chain = HashChain::new(3, 4);
+        let start = Instant::now();
+        // Every 10 s: chip 0 has a few errors, chip 1 starts glitching at 60 s and chip 2 has
+        // only errors, but too few nonces to be judged
+        let mut check = |secs: u64| {
+            for _ in 0..10 {
+                chain.add_valid(bm1387::CoreAddress { chip: 0, core: 1 });
+                chain.add_valid(bm1387::CoreAddress { chip: 1, core: 2 });
+            }
+            chain.add_error(bm1387::CoreAddress { chip: 0, core: 1 });
+            if secs >= 60 {
+                for _ in 0..5 {
+                    chain.add_error(bm1387::CoreAddress { chip: 1, core: 2 });
+                }
+            }
+            chain.add_error(bm1387::CoreAddress { chip: 2, core: 3 });
+            let picked = monitor.check(start + Duration::from_secs(secs), &chain);
+            // Counters reset on new jobs don't change anything
+            chain.reset();
+            picked
+        };
+
+        // Nothing is decided before a full window is covered
+        for secs in (0..60).step_by(10) {
+            assert!(check(secs).is_empty());
+        }
+        // Chip 1 error rate over the window: 5/65, 10/70, 15/75 (exactly the threshold), 20/80
+        assert!(check(60).is_empty());
+        assert!(check(70).is_empty());
+        assert!(check(80).is_empty());
+        assert_eq!(check(90), vec![1]);
+        // Re-initialized chip gets a full window before it's judged again
+        for secs in (100..150).step_by(10) {
+            assert!(check(secs).is_empty());
+        }
+        assert_eq!(check(150), vec![1]);
+    }
+
+    #[test]
+    fn test_core_health_round_trip() {
+        let mut chain = HashChain::new(4, 1);
+        chain.add_valid(bm1387::CoreAddress { chip: 1, core: 7 });
+        chain.add_valid(bm1387::CoreAddress { chip: 1, core: 7 });
+        chain.add_error(bm1387::CoreAddress { chip: 3, core: 100 });
+        let health = chain.cumulative_health();
+        assert_eq!(health.cores.len(), 2);
+
+        let dir = std::env::temp_dir().join(format!("kasop-health-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = CoreHealth::path(&dir, 0);
+        health.save(&path).unwrap();
+        let loaded = CoreHealth::load(&path).unwrap();
+        assert_eq!(loaded, health);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Next run starts with fresh session counters on top of loaded history
+        let mut chain = HashChain::new(4, 1);
+        chain.history = loaded;
+        chain.add_error(bm1387::CoreAddress { chip: 1, core: 7 });
+        let summary = chain.health_summary();
+        assert_eq!(summary.session, HealthTotals { valid: 0, errors: 1 });
+        assert_eq!(summary.cumulative, HealthTotals { valid: 2, errors: 2 });
+        assert_eq!(
+            chain.cumulative_health().cores[0],
+            CoreTotals {
+                chip: 1,
+                core: 7,
+                valid: 2,
+                errors: 1
+            }
+        );
+
+        // Reset starts a new session, but history is kept
+        chain.reset();
+        let summary = chain.health_summary();
+        assert_eq!(summary.session, HealthTotals::default());
+        assert_eq!(summary.cumulative, HealthTotals { valid: 2, errors: 2 });
+    }
 }
\ No newline at end of file
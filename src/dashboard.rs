@@ -0,0 +1,167 @@
+//! Console dashboard (`--dashboard`)
+//!
+//! Repaints a summary of the miner on stdout every second. Logs keep going to stderr (and the log
+//! file), so redirect them when the dashboard should have the terminal for itself.
+
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::client::stratum;
+use crate::miner::{MinerManager, WorkerRates};
+use crate::monitor::{self, ChainTemperature};
+
+/// How often the dashboard is repainted
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// ANSI sequence that clears the screen and moves cursor to the top left corner
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// One hashboard as shown on the dashboard
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoardSnapshot {
+    pub hashboard_idx: usize,
+    pub temperature: ChainTemperature,
+    /// Why the board was taken out of mining
+    pub failed: Option<String>,
+}
+
+/// Everything the dashboard shows, collected at one point in time
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    /// Name and hashrate (hash/s) of every worker
+    pub workers: Vec<(String, f64)>,
+    pub boards: Vec<BoardSnapshot>,
+    pub fan_rpm: Vec<usize>,
+    /// How many of expected fans aren't spinning
+    pub fans_missing: usize,
+    /// Accepted and rejected shares, `None` if the client doesn't count them
+    pub shares: Option<(u64, u64)>,
+}
+
+impl Snapshot {
+    /// Take hashboards and fans from monitor status
+    pub fn with_monitor_status(mut self, status: &monitor::Status) -> Self {
+        self.boards = status
+            .chains
+            .iter()
+            .map(|chain| BoardSnapshot {
+                hashboard_idx: chain.hashboard_idx,
+                temperature: chain.temperature,
+                failed: chain.failed.clone(),
+            })
+            .collect();
+        self.fan_rpm = status.fan_feedback.rpm.clone();
+        self.fans_missing = status.fans_missing;
+        self
+    }
+}
+
+/// Render `snapshot` as text (without the screen clearing sequence)
+pub fn render(snapshot: &Snapshot) -> String {
+    let mut out = String::new();
+    let total: f64 = snapshot.workers.iter().map(|(_, rate)| rate).sum();
+    // Writing to `String` can't fail
+    let _ = writeln!(out, "Hashrate: {}", MinerManager::format_hashrate(total));
+    let name_width = snapshot.workers.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, rate) in &snapshot.workers {
+        let _ = writeln!(out, "  {:<width$}  {}", name, MinerManager::format_hashrate(*rate), width = name_width);
+    }
+    if !snapshot.boards.is_empty() {
+        let _ = writeln!(out, "Hashboards:");
+        for board in &snapshot.boards {
+            let state = match (&board.failed, board.temperature) {
+                (Some(reason), _) => format!("failed: {}", reason),
+                (None, ChainTemperature::Ok(t)) => format!("{:.1} C", t),
+                (None, ChainTemperature::Failed) => "temperature sensor failed".to_string(),
+                (None, ChainTemperature::Unknown) => "temperature unknown".to_string(),
+            };
+            let _ = writeln!(out, "  {}  {}", board.hashboard_idx, state);
+        }
+    }
+    if !snapshot.fan_rpm.is_empty() {
+        let rpm: Vec<_> = snapshot.fan_rpm.iter().map(|rpm| format!("{} RPM", rpm)).collect();
+        let _ = write!(out, "Fans: {}", rpm.join(", "));
+        if snapshot.fans_missing > 0 {
+            let _ = write!(out, " ({} not spinning)", snapshot.fans_missing);
+        }
+        let _ = writeln!(out);
+    }
+    match snapshot.shares {
+        Some((accepted, rejected)) => {
+            let _ = writeln!(out, "Shares: {} accepted, {} rejected", accepted, rejected);
+        }
+        None => {
+            let _ = writeln!(out, "Shares: n/a");
+        }
+    }
+    out
+}
+
+/// Task that repaints the dashboard every `REFRESH_INTERVAL` with worker hashrates from `rates`
+/// and hashboards/fans from `monitor_status` (if there's a monitor). It runs until aborted.
+pub async fn dashboard_task(
+    rates: Arc<Mutex<WorkerRates>>,
+    monitor_status: Option<async_compat::tokio::sync::watch::Receiver<Option<monitor::Status>>>,
+) {
+    let mut ticker = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let mut snapshot = Snapshot {
+            workers: rates.lock().expect("BUG: rates lock poisoned").clone(),
+            shares: stratum::share_counts(),
+            ..Default::default()
+        };
+        if let Some(status) = monitor_status.as_ref().and_then(|status| status.borrow().clone()) {
+            snapshot = snapshot.with_monitor_status(&status);
+        }
+        let mut stdout = std::io::stdout();
+        let _ = write!(stdout, "{}{}", CLEAR_SCREEN, render(&snapshot));
+        let _ = stdout.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let snapshot = Snapshot {
+            workers: vec![("GPU 0".into(), 1.5e9), ("CPU worker 0".into(), 2_500.0)],
+            boards: vec![
+                BoardSnapshot { hashboard_idx: 0, temperature: ChainTemperature::Ok(72.4), failed: None },
+                BoardSnapshot {
+                    hashboard_idx: 1,
+                    temperature: ChainTemperature::Unknown,
+                    failed: Some("no nonces after 3 re-init attempts".into()),
+                },
+                BoardSnapshot { hashboard_idx: 2, temperature: ChainTemperature::Failed, failed: None },
+            ],
+            fan_rpm: vec![3600, 3550],
+            fans_missing: 0,
+            shares: Some((10, 1)),
+        };
+        let expected = "\
+Hashrate: 1.50 Ghash/s
+  GPU 0         1.50 Ghash/s
+  CPU worker 0  2.50 Khash/s
+Hashboards:
+  0  72.4 C
+  1  failed: no nonces after 3 re-init attempts
+  2  temperature sensor failed
+Fans: 3600 RPM, 3550 RPM
+Shares: 10 accepted, 1 rejected
+";
+        assert_eq!(render(&snapshot), expected);
+        // Same snapshot, same screen
+        assert_eq!(render(&snapshot.clone()), expected);
+
+        let one_fan_down = Snapshot { fan_rpm: vec![3600, 0], fans_missing: 1, ..Default::default() };
+        assert!(render(&one_fan_down).contains("Fans: 3600 RPM, 0 RPM (1 not spinning)\n"));
+
+        let empty = Snapshot::default();
+        assert_eq!(render(&empty), "Hashrate: 0.00 hash/s\nShares: n/a\n");
+    }
+}
@@ -0,0 +1,185 @@
+//! Miner lifecycle events
+//!
+//! Subsystems (client, stratum shares, hashboards, monitor, halt) emit `Event`s to one broadcast
+//! bus, anything interested (dashboard, webhooks) subscribes to it. Emitting never blocks and costs
+//! next to nothing without subscribers; a subscriber that falls behind loses the oldest events.
+//! Hashboard and fan events come only from hashboards started with `--hashboard`.
+
+use std::fmt;
+
+use log::debug;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// How many events a slow subscriber may fall behind before it starts losing them
+const BUS_CAPACITY: usize = 256;
+
+static BUS: Lazy<EventBus> = Lazy::new(|| EventBus::new(BUS_CAPACITY));
+
+/// Serialized as an object with the kind in `event` field and the rest as other fields
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Connected and registered with pool/node at `address`
+    Connected { address: String },
+    /// Connection to `address` was lost (`error` is `None` when it was closed gracefully)
+    Disconnected { address: String, error: Option<String> },
+    ShareAccepted { job_id: String },
+    /// Pool rejected share (stale, duplicate, low difficulty)
+    ShareRejected { job_id: String, reason: String },
+    BlockFound { block_hash: String },
+    /// Hashboard lowered chip frequencies to keep under power limit
+    Throttled { hashboard_idx: usize, watts: f64, limit_watts: f64 },
+    /// Hashboard is back at full frequency
+    ThrottleLifted { hashboard_idx: usize },
+    /// Hashboard was given up and halted
+    BoardFailed { hashboard_idx: usize, reason: String },
+    /// Fan feedback shows fewer fans spinning than expected
+    FansStalled { fans_missing: usize },
+    /// Hashboard temperature went above HOT, fans are on full speed
+    Overheated { temperature: f32 },
+    /// Miner halted on termination (`error` names tasks that didn't stop in time)
+    Halted { error: Option<String> },
+}
+
+impl Event {
+    /// Short machine-readable name of the event kind
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Connected { .. } => "connected",
+            Event::Disconnected { .. } => "disconnected",
+            Event::ShareAccepted { .. } => "share_accepted",
+            Event::ShareRejected { .. } => "share_rejected",
+            Event::BlockFound { .. } => "block_found",
+            Event::Throttled { .. } => "throttled",
+            Event::ThrottleLifted { .. } => "throttle_lifted",
+            Event::BoardFailed { .. } => "board_failed",
+            Event::FansStalled { .. } => "fans_stalled",
+            Event::Overheated { .. } => "overheated",
+            Event::Halted { .. } => "halted",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Connected { address } => write!(f, "connected to {}", address),
+            Event::Disconnected { address, error: None } => write!(f, "disconnected from {}", address),
+            Event::Disconnected { address, error: Some(error) } => {
+                write!(f, "disconnected from {}: {}", address, error)
+            }
+            Event::ShareAccepted { job_id } => write!(f, "share accepted (job {})", job_id),
+            Event::ShareRejected { job_id, reason } => write!(f, "share rejected (job {}): {}", job_id, reason),
+            Event::BlockFound { block_hash } => write!(f, "block found: {}", block_hash),
+            Event::Throttled { hashboard_idx, watts, limit_watts } => write!(
+                f,
+                "hashboard {} throttled, estimated {:.0} W over limit {:.0} W",
+                hashboard_idx, watts, limit_watts
+            ),
+            Event::ThrottleLifted { hashboard_idx } => write!(f, "hashboard {} no longer throttled", hashboard_idx),
+            Event::BoardFailed { hashboard_idx, reason } => write!(f, "hashboard {} failed: {}", hashboard_idx, reason),
+            Event::FansStalled { fans_missing } => write!(f, "{} fan(s) not spinning", fans_missing),
+            Event::Overheated { temperature } => write!(f, "temperature {:.1} C above HOT", temperature),
+            Event::Halted { error: None } => write!(f, "halted"),
+            Event::Halted { error: Some(error) } => write!(f, "halted: {}", error),
+        }
+    }
+}
+
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    pub fn emit(&self, event: Event) {
+        // Failing means there are no subscribers, nobody to tell
+        let _ = self.sender.send(event);
+    }
+
+    /// Receive events emitted from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+/// Emit `event` to the miner-wide bus
+pub fn emit(event: Event) {
+    BUS.emit(event)
+}
+
+/// Subscribe to the miner-wide bus
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    BUS.subscribe()
+}
+
+/// Task that passes events from `receiver` to `handler` until the bus goes away. Events lost by
+/// falling behind are skipped.
+pub async fn for_each(mut receiver: broadcast::Receiver<Event>, mut handler: impl FnMut(Event)) {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => handler(event),
+            Err(broadcast::error::RecvError::Lagged(count)) => debug!("Event subscriber lost {} events", count),
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Subscribe debug log to the miner-wide bus, it runs until aborted
+pub async fn log_task() {
+    for_each(subscribe(), |event| debug!(event = event.kind(); "Event: {}", event)).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_share_accepted_reaches_subscriber() {
+        let mut receiver = subscribe();
+        emit(Event::ShareAccepted { job_id: "1a".into() });
+        // Other tests may emit to the same bus
+        loop {
+            let event = receiver.recv().await.expect("event lost");
+            if let Event::ShareAccepted { ref job_id } = event {
+                if job_id == "1a" {
+                    assert_eq!(event.kind(), "share_accepted");
+                    assert_eq!(event.to_string(), "share accepted (job 1a)");
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bus() {
+        let bus = EventBus::new(2);
+        // nobody listens
+        bus.emit(Event::ThrottleLifted { hashboard_idx: 0 });
+
+        let mut first = bus.subscribe();
+        let mut second = bus.subscribe();
+        bus.emit(Event::BoardFailed { hashboard_idx: 1, reason: "no nonces".into() });
+        let expected = Event::BoardFailed { hashboard_idx: 1, reason: "no nonces".into() };
+        assert_eq!(first.recv().await.unwrap(), expected);
+        assert_eq!(second.recv().await.unwrap(), expected);
+
+        // slow subscriber skips what it missed
+        for hashboard_idx in 0..3 {
+            bus.emit(Event::ThrottleLifted { hashboard_idx });
+        }
+        drop(bus);
+        let mut received = Vec::new();
+        for_each(first, |event| received.push(event)).await;
+        assert_eq!(
+            received,
+            vec![Event::ThrottleLifted { hashboard_idx: 1 }, Event::ThrottleLifted { hashboard_idx: 2 }]
+        );
+    }
+}
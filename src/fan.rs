@@ -36,11 +36,66 @@ impl Feedback {
     pub fn num_fans_running(&self) -> usize {
         self.rpm.iter().filter(|rpm| **rpm > 0).count()
     }
+
+    /// How many fans short of `expected` are spinning (0 when there are enough of them)
+    pub fn num_fans_missing(&self, expected: usize) -> usize {
+        expected.saturating_sub(self.num_fans_running())
+    }
+}
+
+/// PWM requested for each fan
+///
+/// FPGA fan controller has one `fan_pwm` register shared by all fans, so the requests are
+/// combined: fans are driven with the highest requested speed, no fan spins slower than it was
+/// asked to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanSpeeds {
+    speeds: Vec<Speed>,
+}
+
+impl FanSpeeds {
+    pub fn new(num_fans: usize) -> Self {
+        Self {
+            speeds: vec![Speed::STOPPED; num_fans],
+        }
+    }
+
+    pub fn num_fans(&self) -> usize {
+        self.speeds.len()
+    }
+
+    pub fn get(&self, fan_idx: usize) -> Option<Speed> {
+        self.speeds.get(fan_idx).cloned()
+    }
+
+    /// Request `speed` for fan `fan_idx`
+    pub fn set(&mut self, fan_idx: usize, speed: Speed) -> error::Result<()> {
+        let num_fans = self.num_fans();
+        let fan_speed = self.speeds.get_mut(fan_idx).ok_or_else(|| {
+            ErrorKind::General(format!("fan {} out of range, there are {} fans", fan_idx, num_fans))
+        })?;
+        *fan_speed = speed;
+        Ok(())
+    }
+
+    /// Request `speed` for all fans
+    pub fn set_all(&mut self, speed: Speed) {
+        self.speeds.iter_mut().for_each(|fan_speed| *fan_speed = speed);
+    }
+
+    /// Value of the shared `fan_pwm` register
+    pub fn pwm_register(&self) -> u8 {
+        // Only lower 8 bits of FAN_PWM register are considered, so writing 256 would stop fans,
+        // `Speed` never gets over 100 though.
+        self.speeds.iter().map(Speed::to_pwm).max().unwrap_or(0) as u8
+    }
 }
 
 /// Memory-mapped fan controller
 pub struct Control {
     regs: uio_async::UioTypedMapping<fpga_io_am1_s9::fan_ctrl::RegisterBlock>,
+    /// Speeds requested for individual fans
+    speeds: FanSpeeds,
 }
 
 impl Control {
@@ -53,9 +108,10 @@ impl Control {
             ErrorKind::UioDevice(name.clone(), "cannot map uio device".to_string())
         })?;
 
-        Ok(Self {
-            regs: map.into_typed(),
-        })
+        let regs: uio_async::UioTypedMapping<fpga_io_am1_s9::fan_ctrl::RegisterBlock> =
+            map.into_typed();
+        let speeds = FanSpeeds::new(regs.fan_rps.len());
+        Ok(Self { regs, speeds })
     }
 
     /// Read feedback registers and convert them to RPM
@@ -70,14 +126,30 @@ impl Control {
         }
     }
 
-    /// Set PWM for fans in percent (0 means fans stopped, 100 means fans on full)
-    pub fn set_speed(&self, speed: Speed) {
+    /// Set PWM for all fans in percent (0 means fans stopped, 100 means fans on full)
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speeds.set_all(speed);
+        self.write_pwm();
+    }
+
+    /// Set PWM of fan `fan_idx` in percent. Fans share one PWM output, so it's actually the
+    /// highest speed requested for any of the fans (see `FanSpeeds`).
+    pub fn set_speed_for(&mut self, fan_idx: usize, speed: Speed) -> error::Result<()> {
+        self.speeds.set(fan_idx, speed)?;
+        self.write_pwm();
+        Ok(())
+    }
+
+    pub fn num_fans(&self) -> usize {
+        self.speeds.num_fans()
+    }
+
+    fn write_pwm(&self) {
+        let pwm = self.speeds.pwm_register();
         // Only lower 8 bits of FAN_PWM register are considered, so writing 256 would stop fans,
         // hence the assert.
-        assert!(speed.0 <= 100);
-        self.regs
-            .fan_pwm
-            .write(|w| unsafe { w.bits(speed.0 as u8) })
+        assert!(pwm <= 100);
+        self.regs.fan_pwm.write(|w| unsafe { w.bits(pwm) })
     }
 }
 
@@ -116,4 +188,31 @@ mod test {
         );
         assert_eq!(Feedback { rpm: Vec::new() }.num_fans_running(), 0);
     }
+
+    #[test]
+    fn test_fan_speeds() {
+        let mut speeds = FanSpeeds::new(4);
+        assert_eq!(speeds.pwm_register(), 0);
+
+        speeds.set(1, Speed::new(40)).unwrap();
+        assert_eq!(speeds.pwm_register(), 40);
+        speeds.set(3, Speed::new(75)).unwrap();
+        assert_eq!(speeds.pwm_register(), 75);
+        // the shared register follows the fan that needs the most airflow
+        speeds.set(3, Speed::new(20)).unwrap();
+        assert_eq!(speeds.pwm_register(), 40);
+        assert_eq!(speeds.get(1), Some(Speed::new(40)));
+        assert_eq!(speeds.get(3), Some(Speed::new(20)));
+
+        assert!(speeds.set(4, Speed::FULL_SPEED).is_err());
+        assert_eq!(speeds.get(4), None);
+        assert_eq!(speeds.pwm_register(), 40);
+
+        speeds.set_all(Speed::STOPPED);
+        assert_eq!(speeds.pwm_register(), 0);
+        speeds.set_all(Speed::FULL_SPEED);
+        assert_eq!(speeds.pwm_register(), 100);
+
+        assert_eq!(FanSpeeds::new(0).pwm_register(), 0);
+    }
 }
\ No newline at end of file
@@ -7,6 +7,12 @@ use offset_pid::OffsetPIDController;
 use pid_control::Controller;
 use std::time::Instant;
 
+/// PWM the fans run at when temperature is right at target, PID adds corrections around it.
+/// It's not the target temperature, that's set with `TempControl::set_target`.
+const PWM_OFFSET: f64 = 70.0;
+/// Temperature the PID keeps until told otherwise
+pub const DEFAULT_TARGET_TEMP: f64 = 75.0;
+
 pub struct TempControl {
     pid: OffsetPIDController,
     last_update: Instant,
@@ -14,14 +20,20 @@ pub struct TempControl {
 
 impl TempControl {
     pub fn new() -> Self {
+        Self::with_target(DEFAULT_TARGET_TEMP)
+    }
+
+    /// Control keeping temperature at `target`
+    pub fn with_target(target: f64) -> Self {
         // kp/ki/kd constants are negative because the PID works in reverse direction
         // (the lower the PWM, the higher the temperature)
-        let pid = OffsetPIDController::new(-5.0, -0.03, -0.15, 70.0);
+        let pid = OffsetPIDController::new(-5.0, -0.03, -0.15, PWM_OFFSET);
 
         let mut temp_control = Self {
             pid,
             last_update: Instant::now(),
         };
+        temp_control.set_target(target);
         temp_control.set_warm_up_limits();
         return temp_control;
     }
@@ -40,6 +52,10 @@ impl TempControl {
         self.pid.set_target(target);
     }
 
+    pub fn target(&self) -> f64 {
+        self.pid.target()
+    }
+
     pub fn update(&mut self, temperature: f64) -> Speed {
         let pwm = self
             .pid
@@ -48,3 +64,99 @@ impl TempControl {
         Speed::new(pwm as usize)
     }
 }
+
+/// Temperature control with independent PID for every fan. Each fan follows temperature of its
+/// own thermal zone (the sensor nearest to it). S9 control board has one PWM register for all
+/// fans, so there the speed of the hottest zone applies to every fan.
+pub struct ZoneTempControl {
+    /// Index of sensor nearest to each fan
+    zone_sensors: Vec<usize>,
+    pids: Vec<TempControl>,
+}
+
+impl ZoneTempControl {
+    /// `zone_sensors[i]` is index of the sensor nearest to fan `i`
+    pub fn new(zone_sensors: Vec<usize>) -> Self {
+        let pids = zone_sensors.iter().map(|_| TempControl::new()).collect();
+        Self { zone_sensors, pids }
+    }
+
+    pub fn zone_sensors(&self) -> &[usize] {
+        &self.zone_sensors
+    }
+
+    pub fn set_warm_up_limits(&mut self) {
+        self.pids.iter_mut().for_each(TempControl::set_warm_up_limits);
+    }
+
+    pub fn set_normal_limits(&mut self) {
+        self.pids.iter_mut().for_each(TempControl::set_normal_limits);
+    }
+
+    pub fn set_target(&mut self, target: f64) {
+        self.pids.iter_mut().for_each(|pid| pid.set_target(target));
+    }
+
+    /// Compute speed of every fan from `sensor_temperatures`. Fans whose sensor has no
+    /// temperature (or doesn't exist) follow `fallback` temperature instead.
+    pub fn update(&mut self, sensor_temperatures: &[Option<f64>], fallback: f64) -> Vec<Speed> {
+        self.zone_sensors
+            .iter()
+            .zip(self.pids.iter_mut())
+            .map(|(sensor, pid)| {
+                let temperature = sensor_temperatures
+                    .get(*sensor)
+                    .cloned()
+                    .flatten()
+                    .unwrap_or(fallback);
+                pid.update(temperature)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_target_temperature() {
+        assert_eq!(TempControl::new().target(), DEFAULT_TARGET_TEMP);
+
+        // running the board hotter means slower fans at the same temperature
+        let speed = |target| {
+            let mut control = TempControl::with_target(target);
+            control.set_normal_limits();
+            control.update(75.0).to_pwm()
+        };
+        assert_eq!(speed(75.0), PWM_OFFSET as usize);
+        assert!(speed(80.0) < speed(75.0));
+        assert!(speed(70.0) > speed(75.0));
+
+        let mut control = TempControl::with_target(70.0);
+        control.set_target(80.0);
+        assert_eq!(control.target(), 80.0);
+    }
+
+    #[test]
+    fn test_zone_temp_control() {
+        // fans 0 and 1 are near sensor 0, fan 2 near sensor 1
+        let mut control = ZoneTempControl::new(vec![0, 0, 1]);
+        control.set_normal_limits();
+        control.set_target(75.0);
+
+        let speeds = control.update(&[Some(80.0), Some(70.0)], 75.0);
+        assert_eq!(speeds.len(), 3);
+        assert_eq!(speeds[0], speeds[1]);
+        // the hot zone is cooled harder than the cool one
+        assert!(speeds[0].to_pwm() > speeds[2].to_pwm());
+
+        // zone without temperature follows the fallback
+        let mut control = ZoneTempControl::new(vec![0, 1, 5]);
+        control.set_normal_limits();
+        control.set_target(75.0);
+        let speeds = control.update(&[Some(75.0), None], 80.0);
+        assert!(speeds[1].to_pwm() > speeds[0].to_pwm());
+        assert_eq!(speeds[1], speeds[2]);
+    }
+}
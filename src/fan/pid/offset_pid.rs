@@ -1,23 +1,42 @@
 //! Extension of PID controller that adds configurable offset so that control variable of "0" could
 //! have a different interpretation.
+//!
+//! The integral term is kept here instead of in `PIDController`: it only clamps the error sum to
+//! output limits, so during long saturation (ie. warming up with fans on minimum) the sum winds up
+//! to the limit and the fans then lag behind when the temperature turns. Here the error isn't
+//! integrated past the point where the output saturates (conditional integration).
 
 use pid_control::{Controller, PIDController};
 
 pub struct OffsetPIDController {
+    /// Proportional and derivative part
     pid: PIDController,
+    i_gain: f64,
+    /// Integral term (already multiplied by `i_gain`)
+    integral: f64,
+    /// Output limits without offset
+    min: f64,
+    max: f64,
     offset: f64,
 }
 
 impl OffsetPIDController {
     pub fn new(p_gain: f64, i_gain: f64, d_gain: f64, offset: f64) -> Self {
         Self {
-            pid: PIDController::new(p_gain, i_gain, d_gain),
+            pid: PIDController::new(p_gain, 0.0, d_gain),
+            i_gain,
+            integral: 0.0,
+            min: f64::NEG_INFINITY,
+            max: f64::INFINITY,
             offset,
         }
     }
 
     pub fn set_limits(&mut self, min: f64, max: f64) {
-        self.pid.set_limits(min - self.offset, max - self.offset);
+        assert!(min <= max, "BUG: PID limits {} > {}", min, max);
+        self.min = min - self.offset;
+        self.max = max - self.offset;
+        self.integral = self.integral.max(self.min).min(self.max);
     }
 }
 
@@ -31,11 +50,21 @@ impl Controller for OffsetPIDController {
     }
 
     fn update(&mut self, value: f64, delta_t: f64) -> f64 {
-        self.pid.update(value, delta_t) + self.offset
+        let proportional_derivative = self.pid.update(value, delta_t);
+        let step = self.i_gain * (self.pid.target() - value) * delta_t;
+        // Integrate only up to the point where output saturates, never past it
+        let integral = if step > 0.0 {
+            (self.integral + step).min(self.integral.max(self.max - proportional_derivative))
+        } else {
+            (self.integral + step).max(self.integral.min(self.min - proportional_derivative))
+        };
+        self.integral = integral.max(self.min).min(self.max);
+        (proportional_derivative + self.integral).max(self.min).min(self.max) + self.offset
     }
 
     fn reset(&mut self) {
-        self.pid.reset()
+        self.pid.reset();
+        self.integral = 0.0;
     }
 }
 
@@ -52,4 +81,55 @@ mod test {
         pid.set_limits(60.0, 60.0);
         assert_relative_eq!(pid.update(0.0, 1.0), 60.0);
     }
+
+    /// Same constants as fan control uses
+    fn fan_pid() -> OffsetPIDController {
+        let mut pid = OffsetPIDController::new(-5.0, -0.03, -0.15, 70.0);
+        pid.set_limits(1.0, 100.0);
+        pid.set_target(75.0);
+        pid
+    }
+
+    /// Long saturation must not leave the controller sluggish once the error reverses
+    #[test]
+    fn test_pid_anti_windup() {
+        // Cold miner warming up for over an hour keeps the fans on minimum
+        let mut saturated = fan_pid();
+        for _ in 0..1000 {
+            assert_relative_eq!(saturated.update(40.0, 5.0), 1.0);
+        }
+        let mut fresh = fan_pid();
+        assert_relative_eq!(fresh.update(40.0, 5.0), 1.0);
+        // Now it gets hot, both controllers react the same
+        let hot = saturated.update(80.0, 5.0);
+        assert_relative_eq!(hot, fresh.update(80.0, 5.0), epsilon = 0.001);
+        assert!(hot > 90.0, "fans lag after saturation: {}", hot);
+
+        // And the other way round, fans on maximum for a long time
+        let mut saturated = fan_pid();
+        for _ in 0..1000 {
+            assert_relative_eq!(saturated.update(110.0, 5.0), 100.0);
+        }
+        let mut fresh = fan_pid();
+        assert_relative_eq!(fresh.update(110.0, 5.0), 100.0);
+        let cool = saturated.update(72.0, 5.0);
+        assert_relative_eq!(cool, fresh.update(72.0, 5.0), epsilon = 0.001);
+        assert!(cool < 90.0, "fans lag after saturation: {}", cool);
+    }
+
+    /// Integral still removes steady state error when output isn't saturated
+    #[test]
+    fn test_pid_integral() {
+        let mut pid = OffsetPIDController::new(0.0, -0.1, 0.0, 50.0);
+        pid.set_limits(1.0, 100.0);
+        pid.set_target(70.0);
+        assert_relative_eq!(pid.update(75.0, 1.0), 50.5);
+        assert_relative_eq!(pid.update(75.0, 1.0), 51.0);
+        // Integral is clamped to limits
+        for _ in 0..10000 {
+            pid.update(75.0, 1.0);
+        }
+        assert_relative_eq!(pid.update(75.0, 1.0), 100.0);
+        assert_relative_eq!(pid.update(70.0, 1.0), 100.0);
+    }
 }
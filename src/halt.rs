@@ -12,7 +12,10 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 
+use logging::macros::*;
+
 use crate::error;
+use crate::events::{self, Event};
 use error::ErrorKind;
 
 use futures::channel::mpsc;
@@ -20,8 +23,12 @@ use futures::future::{select, Either};
 use futures::lock::Mutex;
 
 use async_compat::prelude::*;
+#[cfg(unix)]
 use crate::halt::tokio::signal::unix::SignalKind;
+#[cfg(unix)]
 use crate::halt::tokio::signal::unix::signal;
+#[cfg(windows)]
+use crate::halt::tokio::signal::windows::ctrl_break;
 
 /// Token sent by halted task to confirm that halting is done
 struct Done;
@@ -104,6 +111,8 @@ impl NotifyReceiver {
 struct NotifySender {
     notify_tx: mpsc::UnboundedSender<DoneSender>,
     name: String,
+    /// Overrides the sender-wide `halt_timeout` for this client
+    halt_timeout: Option<Duration>,
 }
 
 impl NotifySender {
@@ -123,11 +132,18 @@ impl NotifySender {
     }
 }
 
-fn make_notify_pair(name: String) -> (NotifySender, NotifyReceiver) {
+fn make_notify_pair(
+    name: String,
+    halt_timeout: Option<Duration>,
+) -> (NotifySender, NotifyReceiver) {
     let (notify_tx, notify_rx) = mpsc::unbounded();
 
     (
-        NotifySender { notify_tx, name },
+        NotifySender {
+            notify_tx,
+            name,
+            halt_timeout,
+        },
         NotifyReceiver { notify_rx },
     )
 }
@@ -143,7 +159,20 @@ pub struct Receiver {
 
 impl Receiver {
     pub async fn register_client(&self, name: String) -> NotifyReceiver {
-        self.sender.clone().register_client(name).await
+        self.sender.clone().register_client(name, None).await
+    }
+
+    /// Register client that is given `halt_timeout` to finish instead of the default timeout of
+    /// the halt sender (ie. for clients doing lengthy cleanup such as flash writes)
+    pub async fn register_client_with_timeout(
+        &self,
+        name: String,
+        halt_timeout: Duration,
+    ) -> NotifyReceiver {
+        self.sender
+            .clone()
+            .register_client(name, Some(halt_timeout))
+            .await
     }
 }
 
@@ -165,9 +194,18 @@ impl Sender {
         })
     }
 
+    /// How long clients (without their own timeout) are given to finish
+    pub fn halt_timeout(&self) -> Duration {
+        self.halt_timeout
+    }
+
     /// Register one client. Available only through `Receiver` API
-    async fn register_client(self: Arc<Self>, name: String) -> NotifyReceiver {
-        let (notify_sender, notify_receiver) = make_notify_pair(name);
+    async fn register_client(
+        self: Arc<Self>,
+        name: String,
+        halt_timeout: Option<Duration>,
+    ) -> NotifyReceiver {
+        let (notify_sender, notify_receiver) = make_notify_pair(name, halt_timeout);
         self.clients.lock().await.push(notify_sender);
         notify_receiver
     }
@@ -185,9 +223,12 @@ impl Sender {
     /// tasks was halted (we send them channel to reply back) and one of them would be dropped
     /// before it had a chance to run (ie. as a result of another task that is being terminated
     /// dropping it in termination handler) it wouldn't respond with "termination successful".
+    /// Client that fails to halt doesn't stop the others from being halted, all failures are
+    /// reported together.
     async fn send_halt_internal(self: Arc<Self>) -> error::Result<()> {
         // take the list of clients
         let mut clients: Vec<_> = self.clients.lock().await.drain(..).collect();
+        let mut failures = Vec::new();
 
         // notify clients one-by-one
         for client in clients.drain(..) {
@@ -198,32 +239,48 @@ impl Sender {
                 // extract handle, wait on it later
                 Some(handle) => handle,
             };
-            
-            match done_wait.done_rx.next().timeout(self.halt_timeout).await {
+
+            let halt_timeout = client.halt_timeout.unwrap_or(self.halt_timeout);
+            match done_wait.done_rx.next().timeout(halt_timeout).await {
                 Ok(confirm) => match confirm {
                     Some(_) => (),
-                    None => Err(ErrorKind::Halt(format!(
-                        "failed to halt client {}: dropped handle",
-                        client.name
-                    )))?,
+                    None => failures.push(format!("{} (dropped handle)", client.name)),
                 },
-                Err(_) => Err(ErrorKind::Halt(format!(
-                    "failed to halt client {}: timeout",
-                    client.name
-                )))?,
+                Err(_) => failures.push(format!("{} (timeout)", client.name)),
             }
         }
 
-        // run exit hooks (in order they came in)
-        for hook in self.exit_hooks.lock().await.drain(..) {
-            hook.await;
+        // run exit hooks (in order they came in), hanging hook mustn't block the shutdown
+        for (idx, hook) in self.exit_hooks.lock().await.drain(..).enumerate() {
+            if hook.timeout(self.halt_timeout).await.is_err() {
+                warn!(
+                    "Halt: exit hook {} didn't finish in {:?}, skipping it",
+                    idx, self.halt_timeout
+                );
+            }
+        }
+        if !failures.is_empty() {
+            Err(ErrorKind::Halt(format!(
+                "failed to halt clients: {}",
+                failures.join(", ")
+            )))?
         }
         Ok(())
     }
 
+    /// Halt all client tasks and run exit hooks, but only of this sender. It's meant for senders
+    /// that guard a part of the miner (ie. single hashboard) that is to be torn down while the
+    /// rest goes on.
+    pub async fn halt(self: Arc<Self>) -> error::Result<()> {
+        self.send_halt_internal().await
+    }
+
     /// This is a hack around `halt_sender` having to be run from tokio context, because it spawns
     /// additional threads.
-    pub fn hook_termination_signals(self: Arc<Self>) {
+    /// Once the halt issued by a signal is done, `halted` is notified, so that the caller can
+    /// exit on its own terms (ie. after letting halt events out).
+    #[cfg(unix)]
+    pub fn hook_termination_signals(self: Arc<Self>, halted: mpsc::UnboundedSender<()>) {
         // Hook `SIGINT`, `SIGHUP` and `SIGTERM`
         for signal_type in vec![
             SignalKind::interrupt(),
@@ -231,6 +288,7 @@ impl Sender {
             SignalKind::terminate(),
         ] {
             let halt_sender = self.clone();
+            let halted = halted.clone();
             tokio::spawn(async move {
                 if let Some(_) = signal(signal_type)
                     .expect("BUG: failed hooking signal")
@@ -238,25 +296,53 @@ impl Sender {
                     .await
                 {
                     // Exit after receiving signal
-                    // halt_sender.send_halt().await;
+                    halt_sender.send_halt().await;
+                    let _ = halted.unbounded_send(());
                 }
             });
         }
     }
 
-    // pub async fn send_halt(self: Arc<Self>) {
-    //     let (finish_tx, mut finish_rx) = mpsc::unbounded();
-    //     let handle: task::JoinHandle<error::Result<()>> = tokio::spawn(async move {
-    //         self.send_halt_internal().await?;
-    //         let _result = finish_tx.unbounded_send(());
-    //         Ok(())
-    //     });
-    //     finish_rx.next().await;
-    //     handle
-    //         .await
-    //         .expect("halt task has panicked")
-    //         .expect("halt failed");
-    // }
+    /// Windows counterpart of the unix signal hooks: `Ctrl-C` and `Ctrl-Break` (the latter is
+    /// also what a console close ends up delivering to processes started from a console)
+    #[cfg(windows)]
+    pub fn hook_termination_signals(self: Arc<Self>, halted: mpsc::UnboundedSender<()>) {
+        let halt_sender = self.clone();
+        let ctrl_c_halted = halted.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                // Exit after receiving signal
+                halt_sender.send_halt().await;
+                let _ = ctrl_c_halted.unbounded_send(());
+            }
+        });
+
+        let halt_sender = self.clone();
+        tokio::spawn(async move {
+            if let Some(_) = ctrl_break()
+                .expect("BUG: failed hooking ctrl-break")
+                .next()
+                .await
+            {
+                // Exit after receiving signal
+                halt_sender.send_halt().await;
+                let _ = halted.unbounded_send(());
+            }
+        });
+    }
+
+    /// Halt all client tasks and run exit hooks when the whole miner is terminating. Clients that
+    /// failed to halt are only logged, the miner is going down anyway.
+    pub async fn send_halt(self: Arc<Self>) {
+        let error = match self.send_halt_internal().await {
+            Ok(()) => None,
+            Err(e) => {
+                error!("Halt: {}", e);
+                Some(e.to_string())
+            }
+        };
+        events::emit(Event::Halted { error });
+    }
 }
 
 /// Build a halt sender/receiver pair
@@ -268,3 +354,138 @@ pub fn make_pair(halt_timeout: Duration) -> (Arc<Sender>, Receiver) {
 
     (sender, receiver)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_compat::tokio;
+    use tokio::time::delay_for;
+
+    /// Register halt handler that takes `cleanup` time to finish
+    async fn register_slow_client(
+        receiver: &Receiver,
+        name: &str,
+        halt_timeout: Option<Duration>,
+        cleanup: Duration,
+    ) {
+        let notify_receiver = match halt_timeout {
+            Some(halt_timeout) => {
+                receiver
+                    .register_client_with_timeout(name.into(), halt_timeout)
+                    .await
+            }
+            None => receiver.register_client(name.into()).await,
+        };
+        notify_receiver.spawn_halt_handler(async move {
+            delay_for(cleanup).await;
+        });
+    }
+
+    #[tokio::test]
+    async fn test_per_client_halt_timeout() {
+        let (sender, receiver) = make_pair(Duration::from_millis(50));
+
+        register_slow_client(&receiver, "fast", None, Duration::from_millis(1)).await;
+        register_slow_client(
+            &receiver,
+            "flash write",
+            Some(Duration::from_millis(500)),
+            Duration::from_millis(150),
+        )
+        .await;
+
+        sender
+            .send_halt_internal()
+            .await
+            .expect("client within its own halt timeout failed");
+    }
+
+    #[tokio::test]
+    async fn test_slow_exit_hook_times_out() {
+        let (sender, _receiver) = make_pair(Duration::from_millis(50));
+        let (hook_tx, mut hook_rx) = mpsc::unbounded();
+
+        sender
+            .add_exit_hook(async {
+                delay_for(Duration::from_secs(3600)).await;
+            })
+            .await;
+        // Hooks after the stuck one still run
+        sender
+            .add_exit_hook(async move {
+                hook_tx.unbounded_send(()).expect("hook channel closed");
+            })
+            .await;
+
+        sender
+            .send_halt_internal()
+            .timeout(Duration::from_secs(5))
+            .await
+            .expect("shutdown blocked by exit hook")
+            .expect("halt failed");
+        assert_eq!(hook_rx.try_next().expect("hook didn't run"), Some(()));
+    }
+
+    #[tokio::test]
+    async fn test_all_stuck_clients_reported() {
+        let (sender, receiver) = make_pair(Duration::from_millis(50));
+
+        register_slow_client(&receiver, "board 0", None, Duration::from_millis(150)).await;
+        register_slow_client(&receiver, "board 1", None, Duration::from_millis(1)).await;
+        register_slow_client(&receiver, "board 2", None, Duration::from_millis(150)).await;
+        // Client whose handle is gone without confirming
+        let dropped = receiver.register_client("board 3".into()).await;
+        tokio::spawn(async move {
+            drop(dropped.wait_for_halt().await);
+        });
+
+        let err = sender
+            .send_halt_internal()
+            .await
+            .expect_err("stuck clients not reported")
+            .to_string();
+        assert!(err.contains("board 0 (timeout)"), "{}", err);
+        assert!(err.contains("board 2 (timeout)"), "{}", err);
+        assert!(err.contains("board 3 (dropped handle)"), "{}", err);
+        assert!(!err.contains("board 1"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_send_halt() {
+        let (sender, receiver) = make_pair(Duration::from_millis(50));
+        let (hook_tx, mut hook_rx) = mpsc::unbounded();
+        let mut events = events::subscribe();
+
+        register_slow_client(&receiver, "fast", None, Duration::from_millis(1)).await;
+        // Stuck client doesn't keep the exit hooks from running
+        register_slow_client(&receiver, "stuck", None, Duration::from_millis(150)).await;
+        sender
+            .add_exit_hook(async move {
+                hook_tx.unbounded_send(()).expect("hook channel closed");
+            })
+            .await;
+
+        sender
+            .send_halt()
+            .timeout(Duration::from_secs(5))
+            .await
+            .expect("halt blocked");
+        assert_eq!(hook_rx.try_next().expect("hook didn't run"), Some(()));
+        // Halt is announced with the stuck client (other tests may emit to the same bus)
+        loop {
+            match events.try_recv().expect("halt not announced") {
+                Event::Halted { error: Some(error) } if error.contains("stuck (timeout)") => break,
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_halt_timeout_expires() {
+        let (sender, receiver) = make_pair(Duration::from_millis(50));
+
+        register_slow_client(&receiver, "slow", None, Duration::from_millis(150)).await;
+
+        assert!(sender.send_halt_internal().await.is_err());
+    }
+}
@@ -0,0 +1,1746 @@
+//! Hashboard (hash chain) driver
+//!
+//! Powers up and enumerates the BM1387 chips of every hashboard started with `--hashboard`, sets
+//! their frequency and baud rate and then keeps the board healthy: temperature, voltage, hashrate,
+//! chip error and power limit checks.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use crate::bm1387::{self, ChipAddress, MidstateCount, Register, CHIP_OSC_CLK_HZ};
+use crate::command::{self, Interface};
+use crate::error::{self, ErrorKind};
+use crate::supervisor::{BoardSupervisor, SupervisorAction};
+use crate::target::Uint256;
+use crate::{counters, events, gpio, halt, i2c, io, monitor, power, sensor, watch};
+
+use embedded_hal::digital::v2::InputPin;
+use embedded_hal::digital::v2::OutputPin;
+use packed_struct::prelude::*;
+
+use failure::ResultExt;
+
+use futures::channel::mpsc;
+use futures::lock::Mutex;
+use futures::stream::StreamExt;
+use async_compat::futures;
+
+/// Timing constants
+const INACTIVATE_FROM_CHAIN_DELAY: Duration = Duration::from_millis(100);
+/// How many times `InactivateFromChainCmd` is sent, so that every chip on the chain gets it
+const INACTIVATE_FROM_CHAIN_COUNT: usize = 3;
+/// Base delay quantum during hashboard initialization
+const INIT_DELAY: Duration = Duration::from_secs(1);
+/// Time to wait between successive hashboard initialization attempts
+const ENUM_RETRY_DELAY: Duration = Duration::from_secs(10);
+/// How many times to retry the enumeration
+const ENUM_RETRY_COUNT: usize = 10;
+
+/// Maximum number of chips is limitted by the fact that there is only 8-bit address field and
+/// addresses to the chips need to be assigned with step of 4 (e.g. 0, 4, 8, etc.)
+pub const MAX_CHIPS_ON_CHAIN: usize = 64;
+/// Number of chips to consider OK for initialization
+pub const EXPECTED_CHIPS_ON_CHAIN: usize = 63;
+
+/// Exact value of the initial baud rate after reset of the hashing chips.
+const INIT_CHIP_BAUD_RATE: usize = 115740;
+/// Exact desired target baud rate when hashing at full speed (matches the divisor, too)
+const TARGET_CHIP_BAUD_RATE: usize = 1562500;
+
+/// How far (in percent) actual baud rate may be from the requested one
+const MAX_BAUD_RATE_ERR_PERCENT: usize = 5;
+
+/// Calculate baud rate clock divisor for `baud_rate`. The same scheme is used by hashing chips
+/// and the FPGA IP core: `baud_rate = base_clock_hz / (base_clock_div * (divisor + 1))`.
+///
+/// Returns the divisor and actual baud rate, fails when no divisor gets close enough to the
+/// requested rate.
+pub fn calc_baud_clock_div(
+    baud_rate: usize,
+    base_clock_hz: usize,
+    base_clock_div: usize,
+) -> error::Result<(usize, usize)> {
+    let max_baud_rate = base_clock_hz / base_clock_div;
+    if baud_rate == 0 || baud_rate > max_baud_rate {
+        Err(ErrorKind::BaudRate(format!(
+            "requested {} baud is out of range 1..{}",
+            baud_rate, max_baud_rate
+        )))?
+    }
+    // Round the divisor mathematically in fixed point
+    let baud_div = (10 * base_clock_hz / (base_clock_div * baud_rate) + 5) / 10 - 1;
+    let actual_baud_rate = base_clock_hz / (base_clock_div * (baud_div + 1));
+    let baud_rate_diff = (actual_baud_rate as isize - baud_rate as isize).abs() as usize;
+    if baud_rate_diff > MAX_BAUD_RATE_ERR_PERCENT * baud_rate / 100 {
+        Err(ErrorKind::BaudRate(format!(
+            "requested {} baud, nearest possible is {} baud (more than {}% off)",
+            baud_rate, actual_baud_rate, MAX_BAUD_RATE_ERR_PERCENT
+        )))?
+    }
+    Ok((baud_div, actual_baud_rate))
+}
+
+/// Address of chip with connected temp sensor
+const TEMP_CHIP: ChipAddress = ChipAddress::One(61);
+
+/// Default timeout for completion of haschain halt (`--halt-timeout-secs`)
+pub const HALT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to read temperature, it doubles as heartbeat for monitor (which expects one in less
+/// than 10 seconds)
+const TEMPERATURE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often to compare hashrate reported by chips with hashrate derived from nonces
+const HASHRATE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How often to read back board voltage
+const VOLTAGE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often to evaluate per-chip error rates
+const CHIP_ERROR_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often to check that hashboard still produces nonces
+const SUPERVISOR_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How many times dark hashboard is re-initialized before it's halted
+const MAX_BOARD_REINITS: usize = 3;
+/// How often to write per-core health to disk
+const HEALTH_PERSIST_INTERVAL: Duration = Duration::from_secs(300);
+/// How often to re-evaluate power limit
+const POWER_LIMIT_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+/// Default tolerated difference (in percent) between chip-reported and counted hashrate
+pub const DEFAULT_HASHRATE_DIVERGENCE_PERCENT: f64 = 10.0;
+
+/// Difficulty of nonces sent back by chips
+const ASIC_DIFFICULTY: usize = 64;
+/// Frequency chips are ramped up to after initialization
+const DEFAULT_FREQUENCY: Frequency = 650_000_000;
+/// Index of the Linux I2C interface the voltage controllers of all hashboards are on
+const VOLTAGE_CTRL_I2C_INTERFACE: usize = 0;
+
+/// Core address space size (it should be 114, but the addresses are non-consecutive)
+pub const CORE_ADR_SPACE_SIZE: usize = 128;
+
+/// Power type alias
+/// TODO: Implement it as a proper type (not just alias)
+pub type Power = usize;
+
+/// Type representing plug pin
+#[derive(Clone)]
+pub struct PlugPin {
+    pin: gpio::PinIn,
+}
+
+impl PlugPin {
+    pub fn open(gpio_mgr: &gpio::ControlPinManager, hashboard_idx: usize) -> error::Result<Self> {
+        Ok(Self {
+            pin: gpio_mgr
+                .get_pin_in(gpio::PinInName::Plug(hashboard_idx))
+                .context(ErrorKind::Hashboard(
+                    hashboard_idx,
+                    "failed to initialize plug pin".to_string(),
+                ))?,
+        })
+    }
+
+    pub fn hashboard_present(&self) -> error::Result<bool> {
+        Ok(self.pin.is_high()?)
+    }
+}
+
+/// Type representing reset pin
+#[derive(Clone)]
+pub struct ResetPin {
+    pin: gpio::PinOut,
+}
+
+impl ResetPin {
+    pub fn open(gpio_mgr: &gpio::ControlPinManager, hashboard_idx: usize) -> error::Result<Self> {
+        Ok(Self {
+            pin: gpio_mgr
+                .get_pin_out(gpio::PinOutName::Rst(hashboard_idx))
+                .context(ErrorKind::Hashboard(
+                    hashboard_idx,
+                    "failed to initialize reset pin".to_string(),
+                ))?,
+        })
+    }
+
+    pub fn enter_reset(&mut self) -> error::Result<()> {
+        self.pin.set_low()?;
+        Ok(())
+    }
+
+    pub fn exit_reset(&mut self) -> error::Result<()> {
+        self.pin.set_high()?;
+        Ok(())
+    }
+}
+
+/// Hash Chain Controller provides abstraction of the FPGA interface for operating hashing boards.
+/// It is the user-space driver for the IP Core
+///
+/// Main responsibilities:
+/// - memory mapping of the FPGA control interface
+/// - mining work submission and solution processing
+///
+/// TODO: disable voltage controller via async `Drop` trait (which doesn't exist yet)
+pub struct HashChain {
+    /// Number of chips that have been detected
+    chip_count: usize,
+    /// Eliminates the need to query the IP core about the current number of configured midstates
+    midstate_count: Mutex<MidstateCount>,
+    /// ASIC difficulty
+    asic_difficulty: usize,
+    /// ASIC target (matches difficulty)
+    asic_target: crate::target::Uint256,
+    /// Voltage controller on this hashboard
+    voltage_ctrl: Arc<power::Control>,
+    /// Pin for resetting the hashboard
+    reset_pin: ResetPin,
+    hashboard_idx: usize,
+    pub command_context: command::Context,
+    pub common_io: io::Common,
+    work_rx_io: Mutex<Option<io::WorkRx>>,
+    work_tx_io: Mutex<Option<io::WorkTx>>,
+    monitor_tx: mpsc::UnboundedSender<monitor::Message>,
+    /// Do not send open-core work if this is true (some tests that test chip initialization may
+    /// want to do this).
+    disable_init_work: bool,
+    /// channels through which temperature status is sent
+    temperature_sender: Mutex<Option<watch::Sender<Option<sensor::Readings>>>>,
+    temperature_receiver: watch::Receiver<Option<sensor::Readings>>,
+    /// nonce counter
+    pub counter: Arc<Mutex<counters::HashChain>>,
+    /// halter to stop this hashchain
+    halt_sender: Arc<halt::Sender>,
+    /// termination context of hashboard tasks, we need to keep the halt receiver around,
+    /// otherwise the "stop-notify" channel closes when chain ends
+    halt_receiver: halt::Receiver,
+    /// Current hashchain settings
+    frequency: Mutex<FrequencySettings>,
+    /// Addresses of chips used for mining, in the same order as in `FrequencySettings`
+    active_chips: Vec<ChipAddress>,
+    /// Chips that responded during enumeration but are not used for mining
+    excluded_chips: Vec<ChipAddress>,
+}
+
+/// Outcome of chip enumeration
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChipEnumeration {
+    /// Chips that reported the expected revision and are used for mining
+    pub active: Vec<ChipAddress>,
+    /// Chips that reported an unknown revision, along with what they reported
+    pub excluded: Vec<(ChipAddress, EnumCatchAll<bm1387::ChipRev>)>,
+}
+
+impl ChipEnumeration {
+    /// Sort chips by revision they reported in `GetAddressReg`. Responses are in chain order, the
+    /// same order `addresses` were assigned in (see `assign_chip_addresses`).
+    pub fn from_responses(addresses: &[ChipAddress], responses: &[bm1387::GetAddressReg]) -> Self {
+        let mut enumeration = Self {
+            active: Vec::new(),
+            excluded: Vec::new(),
+        };
+        for (&address, addr_reg) in addresses.iter().zip(responses.iter()) {
+            if addr_reg.chip_rev == bm1387::CHIP_REV_BM1387 {
+                enumeration.active.push(address);
+            } else {
+                enumeration.excluded.push((address, addr_reg.chip_rev));
+            }
+        }
+        enumeration
+    }
+
+    /// Frequencies active chips run at according to their PLL registers (`plls` in chain order,
+    /// the same order as `addresses`), `None` for chips whose dividers make no sense
+    pub fn active_frequencies(&self, addresses: &[ChipAddress], plls: &[bm1387::PllReg]) -> Vec<Option<Frequency>> {
+        addresses
+            .iter()
+            .zip(plls.iter())
+            .filter(|(address, _)| self.active.contains(address))
+            .map(|(_, pll)| pll.validate().ok().map(|()| pll.calc(CHIP_OSC_CLK_HZ)))
+            .collect()
+    }
+}
+
+/// Where the temperature sensor of a hashboard is connected (`--sensor-i2c-bus`,
+/// `--sensor-i2c-address`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorConfig {
+    /// I2C bus of `TEMP_CHIP` the sensor is attached to
+    pub i2c_bus: bm1387::I2cBusSelect,
+    /// Sensor address on that bus, `None` probes all known sensor addresses
+    pub i2c_address: Option<i2c::Address>,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        Self {
+            i2c_bus: bm1387::I2cBusSelect::Bottom,
+            i2c_address: None,
+        }
+    }
+}
+
+impl fmt::Display for SensorConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} I2C bus, ", self.i2c_bus)?;
+        match self.i2c_address {
+            Some(address) => write!(f, "address {:#04x}", address.to_readable_hw_addr()),
+            None => write!(f, "any known address"),
+        }
+    }
+}
+
+/// Settings of hashboards started with `--hashboard` (see `Opt::hashboard_config`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashboardConfig {
+    /// Tolerated difference (in percent) between chip-reported and counted hashrate
+    pub hashrate_divergence_percent: f64,
+    /// Lower chip frequency to keep estimated power under this limit
+    pub power_limit: Option<power::limit::PowerLimit>,
+    /// Directory where per-core health is kept across restarts
+    pub core_health_dir: Option<std::path::PathBuf>,
+    /// PIC program flashed to voltage controllers with unexpected firmware, `None` picks the
+    /// default one (see `power::firmware::PicProgram::load`)
+    pub pic_program: Option<std::path::PathBuf>,
+    /// When to re-initialize chips with too many errors
+    pub chip_reinit: counters::ChipReinitConfig,
+    /// How long each hashboard task gets to finish on halt
+    pub halt_timeout: Duration,
+    /// Where the temperature sensor is connected
+    pub sensor: SensorConfig,
+    /// Number of midstates per work, `None` keeps what the IP core is set to
+    pub midstate_count: Option<MidstateCount>,
+    /// Number of readings the temperature fans follow is the median of
+    pub temp_filter_window: usize,
+    /// How temperatures of multiple sensors of one hashboard make one
+    pub sensor_aggregation: monitor::SensorAggregation,
+    /// Number of fans the miner is fitted with, `None` doesn't check it
+    pub expected_fans: Option<usize>,
+    /// Hashboard (index in order of start) whose temperature each fan follows, `None` runs one
+    /// PID for all fans
+    pub fan_zones: Option<Vec<usize>>,
+    /// Temperature fans keep hashboards at, `None` keeps the default
+    pub fan_target_temp: Option<f32>,
+}
+
+impl Default for HashboardConfig {
+    fn default() -> Self {
+        Self {
+            hashrate_divergence_percent: DEFAULT_HASHRATE_DIVERGENCE_PERCENT,
+            power_limit: None,
+            core_health_dir: None,
+            pic_program: None,
+            chip_reinit: counters::ChipReinitConfig::default(),
+            halt_timeout: HALT_TIMEOUT,
+            sensor: SensorConfig::default(),
+            midstate_count: None,
+            temp_filter_window: monitor::DEFAULT_TEMP_FILTER_WINDOW,
+            sensor_aggregation: monitor::SensorAggregation::default(),
+            expected_fans: None,
+            fan_zones: None,
+            fan_target_temp: None,
+        }
+    }
+}
+
+impl HashboardConfig {
+    /// Halt channel of hashboards (or of the whole hardware part of the miner) with `halt_timeout`
+    pub fn make_halt_pair(&self) -> (Arc<halt::Sender>, halt::Receiver) {
+        halt::make_pair(self.halt_timeout)
+    }
+
+    /// Fan and temperature control of the hashboards
+    pub fn monitor_config(&self) -> monitor::Config {
+        let mut config = monitor::Config::default();
+        if let Some(fan_config) = config.fan_config.as_mut() {
+            fan_config.expected_fans = self.expected_fans;
+            fan_config.fan_zones = self.fan_zones.clone();
+            if let Some(target_temp) = self.fan_target_temp {
+                fan_config.mode = monitor::FanControlMode::TargetTemperature(target_temp);
+            }
+        }
+        config
+    }
+}
+
+/// Enumeration assigns addresses sequentially, so a chain that stops answering after chip
+/// `chip_count - 1` is missing all the chips behind it.
+fn missing_chip_addresses(chip_count: usize) -> Vec<ChipAddress> {
+    (chip_count..EXPECTED_CHIPS_ON_CHAIN).map(ChipAddress::One).collect()
+}
+
+/// Count solution with `nonce` into `counter`. Nonce carries address of the core that found it
+/// (see `bm1387::CoreAddress`), it's valid when that's a core of one of `active` chips and an
+/// error otherwise (excluded chip or garbage from the chain).
+fn count_solution(counter: &mut counters::HashChain, active: &[ChipAddress], nonce: u32) {
+    let core_address = bm1387::CoreAddress::new(nonce);
+    if active.contains(&ChipAddress::One(core_address.chip)) {
+        counter.add_valid(core_address);
+    } else {
+        counter.add_error(core_address);
+    }
+}
+
+impl HashChain {
+    /// Check that hashboard `hashboard_idx` is plugged in and open its IP core. Chips are left
+    /// alone until `init`. Hashboard tasks run until hashboard is halted with `halt_sender`.
+    /// Voltage controller is flashed with `pic_program` if it runs unexpected firmware.
+    pub fn new(
+        gpio_mgr: &gpio::ControlPinManager,
+        voltage_ctrl_backend: Arc<power::I2cBackend>,
+        pic_program: Option<std::path::PathBuf>,
+        hashboard_idx: usize,
+        midstate_count: MidstateCount,
+        monitor_tx: mpsc::UnboundedSender<monitor::Message>,
+        halt_sender: Arc<halt::Sender>,
+        halt_receiver: halt::Receiver,
+    ) -> error::Result<Self> {
+        let plug_pin = PlugPin::open(gpio_mgr, hashboard_idx)?;
+        if !plug_pin.hashboard_present()? {
+            Err(ErrorKind::Hashboard(hashboard_idx, "not present".to_string()))?
+        }
+        let reset_pin = ResetPin::open(gpio_mgr, hashboard_idx)?;
+
+        let core = io::Core::new(hashboard_idx, midstate_count)?;
+        let (common_io, command_io, work_rx_io, work_tx_io) = core.init_and_split()?;
+        let (temperature_sender, temperature_receiver) = watch::channel(None);
+        let mut voltage_ctrl = power::Control::new(voltage_ctrl_backend, hashboard_idx);
+        if let Some(path) = pic_program {
+            voltage_ctrl = voltage_ctrl.with_pic_program_path(path);
+        }
+
+        Ok(Self {
+            chip_count: 0,
+            midstate_count: Mutex::new(midstate_count),
+            asic_difficulty: ASIC_DIFFICULTY,
+            asic_target: Uint256::from_difficulty(ASIC_DIFFICULTY as f64).expect("BUG: invalid ASIC difficulty"),
+            voltage_ctrl: Arc::new(voltage_ctrl),
+            reset_pin,
+            hashboard_idx,
+            command_context: command::Context::new(command_io),
+            common_io,
+            work_rx_io: Mutex::new(Some(work_rx_io)),
+            work_tx_io: Mutex::new(Some(work_tx_io)),
+            monitor_tx,
+            disable_init_work: false,
+            temperature_sender: Mutex::new(Some(temperature_sender)),
+            temperature_receiver,
+            counter: Arc::new(Mutex::new(counters::HashChain::new(MAX_CHIPS_ON_CHAIN, ASIC_DIFFICULTY))),
+            halt_sender,
+            halt_receiver,
+            frequency: Mutex::new(FrequencySettings::from_frequency(FrequencyRamp::default().start)),
+            active_chips: Vec::new(),
+            excluded_chips: Vec::new(),
+        })
+    }
+
+    /// Reset all chips (this is done with a sideband signal)
+    async fn reset_chips(&mut self) -> error::Result<()> {
+        self.reset_pin.enter_reset()?;
+        async_compat::tokio::time::delay_for(INIT_DELAY).await;
+        self.reset_pin.exit_reset()?;
+        async_compat::tokio::time::delay_for(INIT_DELAY).await;
+        Ok(())
+    }
+
+    /// Reset and enumerate chips until all `EXPECTED_CHIPS_ON_CHAIN` show up. When they don't
+    /// after `ENUM_RETRY_COUNT` attempts, hashboard goes on with the chips it has (if any).
+    async fn reset_and_enumerate_chips(&mut self) -> error::Result<()> {
+        for attempt in 1..=ENUM_RETRY_COUNT {
+            self.reset_chips().await?;
+            match self.enumerate_chips().await {
+                Ok(()) if self.enumeration_healthy() => return Ok(()),
+                Ok(()) => warn!(
+                    "Hashboard {}: chips {:?} missing (attempt {}/{})",
+                    self.hashboard_idx,
+                    self.missing_chips(),
+                    attempt,
+                    ENUM_RETRY_COUNT
+                ),
+                Err(e) => warn!(
+                    "Hashboard {}: chip enumeration failed: {} (attempt {}/{})",
+                    self.hashboard_idx, e, attempt, ENUM_RETRY_COUNT
+                ),
+            }
+            if attempt < ENUM_RETRY_COUNT {
+                async_compat::tokio::time::delay_for(ENUM_RETRY_DELAY).await;
+            }
+        }
+        if self.chip_count == 0 {
+            Err(ErrorKind::ChipEnumeration(format!("no chips found in {} attempts", ENUM_RETRY_COUNT)))?
+        }
+        warn!("Hashboard {}: going on with {} chips", self.hashboard_idx, self.chip_count);
+        Ok(())
+    }
+
+    /// Power the hashboard on and bring chips up: enumerate them, switch them to full baud rate
+    /// and ramp them to `DEFAULT_FREQUENCY`. Returns temperature sensors found.
+    pub async fn init(&mut self, config: &HashboardConfig) -> error::Result<sensor::FoundSensors> {
+        info!("Hashboard {}: initializing", self.hashboard_idx);
+        self.voltage_ctrl.clone().init(self.halt_receiver.clone()).await?;
+
+        // Chips come out of reset at the initial baud rate, IP core has to match it
+        let (fpga_baud_div, _) = calc_baud_clock_div(INIT_CHIP_BAUD_RATE, io::F_CLK_SPEED_HZ, io::F_CLK_BASE_BAUD_DIV)?;
+        self.common_io.set_baud_clock_div(fpga_baud_div as u32);
+        self.reset_and_enumerate_chips().await?;
+        if let Some(dir) = &config.core_health_dir {
+            self.load_health(dir).await;
+        }
+
+        // Blocks of all chips stay gated until work arrives
+        let (chip_baud_div, _) =
+            calc_baud_clock_div(INIT_CHIP_BAUD_RATE, CHIP_OSC_CLK_HZ, bm1387::CHIP_OSC_CLK_BASE_BAUD_DIV)?;
+        let mut misc_ctrl = bm1387::MiscCtrlReg::new(true, true, chip_baud_div, true, false)?;
+        misc_ctrl.set_midstate_count(self.midstate_count().await);
+        self.command_context.write_register(ChipAddress::All, &misc_ctrl).await?;
+        let ticket_mask = bm1387::TicketMaskReg::new_rounded(self.asic_difficulty as u32)?;
+        self.command_context.write_register(ChipAddress::All, &ticket_mask).await?;
+
+        let target = FrequencySettings { chip: vec![DEFAULT_FREQUENCY; self.chip_count] };
+        self.ramp_frequency(target, &FrequencyRamp::default()).await?;
+        self.set_baud_rate(TARGET_CHIP_BAUD_RATE).await?;
+        if let Some(midstate_count) = config.midstate_count {
+            self.set_midstate_count(midstate_count).await?;
+        }
+
+        self.probe_temperature_sensors(config.sensor).await
+    }
+
+    /// Register `task` of this hashboard to be stopped when the hashboard is halted and run it
+    async fn spawn_task<F>(&self, name: &str, task: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.halt_receiver
+            .register_client(format!("hashboard {} {}", self.hashboard_idx, name))
+            .await
+            .spawn(task);
+    }
+
+    /// Report hashboard to monitor as started and spawn its tasks (see `spawn_task`)
+    pub async fn start(self: Arc<Self>, sensors: sensor::FoundSensors, config: &HashboardConfig) {
+        if self.monitor_tx.unbounded_send(monitor::Message::On).is_err() {
+            warn!("Hashboard {}: monitor is gone", self.hashboard_idx);
+        }
+        self.spawn_task("solution counter", self.clone().solution_counter_task()).await;
+        self.spawn_task("temperature monitor", self.clone().temperature_monitor_task(sensors)).await;
+        self.spawn_task(
+            "hashrate monitor",
+            self.clone().hashrate_monitor_task(config.hashrate_divergence_percent),
+        )
+        .await;
+        self.spawn_task("voltage monitor", self.clone().voltage_monitor_task()).await;
+        self.spawn_task("chip error monitor", self.clone().chip_error_monitor_task(config.chip_reinit)).await;
+        self.spawn_task("supervisor", self.clone().supervisor_task(MAX_BOARD_REINITS)).await;
+        if let Some(limit) = config.power_limit {
+            // Chips never go over the frequency they were initialized to
+            let max_frequency = self.frequency.lock().await.clone();
+            self.spawn_task("power limit", self.clone().power_limit_task(limit, max_frequency)).await;
+        }
+        if let Some(dir) = &config.core_health_dir {
+            self.spawn_task("health persist", self.clone().health_persist_task(dir.clone())).await;
+        }
+    }
+
+    /// Task that periodically reads temperatures of all `sensors` and sends them to monitor.
+    /// Hashboard without (working) sensor reports invalid readings, so that monitor knows it's
+    /// still alive.
+    async fn temperature_monitor_task(self: Arc<Self>, mut sensors: sensor::FoundSensors) {
+        let count = sensors.len();
+        loop {
+            async_compat::tokio::time::delay_for(TEMPERATURE_CHECK_INTERVAL).await;
+            let mut readings = sensor::Readings::new();
+            for (address, sensor) in sensors.iter_mut() {
+                let temperature = match sensor.read_temperature().await {
+                    Ok(temperature) => temperature,
+                    Err(e) => {
+                        warn!("Hashboard {}: failed reading temperature at {}: {}", self.hashboard_idx, address, e);
+                        sensor::INVALID_TEMPERATURE_READING
+                    }
+                };
+                readings.add(&sensor::sensor_name(*address, count), temperature);
+            }
+            if readings.sensors.is_empty() {
+                readings = sensor::Readings::from(sensor::INVALID_TEMPERATURE_READING);
+            }
+            if let Some(temperature_sender) = self.temperature_sender.lock().await.as_ref() {
+                let _ = temperature_sender.send(Some(readings.clone()));
+            }
+            if self.monitor_tx.unbounded_send(monitor::Message::Running(readings)).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Task that reads solutions from the IP core and counts them (see `count_solution`), so that
+    /// hashrate monitor, chip error monitor and supervisor see what chips produce. Work RX I/O is
+    /// taken from the hashchain for as long as the task runs.
+    async fn solution_counter_task(self: Arc<Self>) {
+        let mut work_rx_io = match self.work_rx_io.lock().await.take() {
+            Some(work_rx_io) => work_rx_io,
+            None => {
+                warn!("Hashboard {}: work RX is in use, solutions are not counted", self.hashboard_idx);
+                return;
+            }
+        };
+        let active_chips = self.active_chips();
+        loop {
+            let (io, solution) = match work_rx_io.recv_solution().await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("Hashboard {}: failed reading solution: {}", self.hashboard_idx, e);
+                    break;
+                }
+            };
+            work_rx_io = io;
+            count_solution(&mut *self.counter.lock().await, &active_chips, solution.nonce);
+        }
+    }
+
+    /// Number of chips detected during enumeration
+    pub fn chip_count(&self) -> usize {
+        self.chip_count
+    }
+
+    /// Return true if all `EXPECTED_CHIPS_ON_CHAIN` chips have been detected
+    pub fn enumeration_healthy(&self) -> bool {
+        self.missing_chips().is_empty()
+    }
+
+    /// Addresses of chips that were expected on the chain but didn't show up during enumeration
+    pub fn missing_chips(&self) -> Vec<ChipAddress> {
+        missing_chip_addresses(self.chip_count)
+    }
+
+    /// Chips excluded from mining during enumeration
+    pub fn excluded_chips(&self) -> &[ChipAddress] {
+        &self.excluded_chips
+    }
+
+    /// Detect chips on the chain and give them addresses (see `assign_chip_addresses`). Chips
+    /// reporting unexpected revision are logged and left out of `chip_count`, counters and
+    /// frequency settings.
+    pub async fn enumerate_chips(&mut self) -> error::Result<()> {
+        let responses = self
+            .command_context
+            .read_register::<bm1387::GetAddressReg>(ChipAddress::All)
+            .await?;
+        if responses.len() > MAX_CHIPS_ON_CHAIN {
+            Err(ErrorKind::ChipEnumeration(format!(
+                "detected {} chips, expected maximum {} chips on one chain",
+                responses.len(),
+                MAX_CHIPS_ON_CHAIN,
+            )))?
+        }
+
+        let addresses = assign_chip_addresses(&self.command_context, responses.len()).await;
+        let enumeration = ChipEnumeration::from_responses(&addresses, &responses);
+        for (address, chip_rev) in enumeration.excluded.iter() {
+            warn!(
+                "Hashboard {}: excluding chip {:?} with unexpected revision {:#x?} (expected {:#x?})",
+                self.hashboard_idx,
+                address,
+                chip_rev,
+                bm1387::CHIP_REV_BM1387
+            );
+        }
+        if !enumeration.excluded.is_empty() {
+            info!(
+                "Hashboard {}: chip revisions: {:?}",
+                self.hashboard_idx,
+                responses.iter().map(|reg| reg.chip_rev).enumerate().collect::<Vec<_>>()
+            );
+        }
+
+        // All chips answer broadcasts, so command context has to expect replies from all of them
+        self.command_context.set_chip_count(responses.len()).await;
+        // Check chips took their addresses and read PLLs they came up with in the same pass
+        let batch = self
+            .command_context
+            .read_registers(ChipAddress::All, &[bm1387::GetAddressReg::REG_NUM, bm1387::PllReg::REG_NUM])
+            .await?;
+        let address_regs = batch.get::<bm1387::GetAddressReg>().expect("BUG: address register not read");
+        check_chip_addresses(&addresses, &address_regs)?;
+        let plls = batch.get::<bm1387::PllReg>().expect("BUG: PLL register not read");
+        let chip_frequencies = enumeration.active_frequencies(&addresses, &plls);
+
+        self.chip_count = enumeration.active.len();
+        self.active_chips = enumeration.active.clone();
+        self.excluded_chips = enumeration.excluded.iter().map(|(address, _)| *address).collect();
+        // Counters are indexed by chip address the nonces carry (see `bm1387::CoreAddress`), so
+        // excluded chips keep their slot
+        self.counter.lock().await.set_chip_count(responses.len());
+        // Chips without known frequency start at the safe one
+        let mut frequency = self.frequency.lock().await;
+        frequency.set_chip_count(self.chip_count, FrequencyRamp::default().start);
+        for (current, read) in frequency.chip.iter_mut().zip(chip_frequencies) {
+            *current = read.unwrap_or(*current);
+        }
+        info!("Hashboard {}: discovered {} chips", self.hashboard_idx, self.chip_count);
+        Ok(())
+    }
+
+    /// Enable I2C on `TEMP_CHIP` and probe for temperature sensors as configured in `config`
+    pub async fn probe_temperature_sensors(&self, config: SensorConfig) -> error::Result<sensor::FoundSensors> {
+        if !self.active_chips.contains(&TEMP_CHIP) {
+            warn!("Hashboard {}: chip {:?} with temperature sensor is not mining", self.hashboard_idx, TEMP_CHIP);
+            return Ok(Vec::new());
+        }
+        let sensors = probe_temperature_sensors(self.command_context.clone(), config).await?;
+        if sensors.is_empty() {
+            warn!("Hashboard {}: no temperature sensor found ({})", self.hashboard_idx, config);
+        }
+        Ok(sensors)
+    }
+
+    /// Addresses of chips used for mining, in the same order as in `FrequencySettings`
+    fn active_chips(&self) -> Vec<ChipAddress> {
+        self.active_chips.clone()
+    }
+
+    /// Chip to read configuration shared by all chips from (it's written with broadcasts)
+    fn reference_chip(&self) -> error::Result<ChipAddress> {
+        Ok(self
+            .active_chips
+            .first()
+            .copied()
+            .ok_or_else(|| ErrorKind::Hashchip("no chips on chain".to_string()))?)
+    }
+
+    /// Program PLLs of all active chips to `frequency`
+    async fn set_pll(&self, frequency: &FrequencySettings) -> error::Result<()> {
+        program_pll(&self.command_context, &self.active_chips(), frequency).await
+    }
+
+    /// Snapshot of current frequency settings (one frequency per active chip)
+    pub async fn get_frequency(&self) -> FrequencySettings {
+        self.frequency.lock().await.clone()
+    }
+
+    /// Program active chips to `frequency` (see `apply_frequency`)
+    pub async fn set_frequency(&self, frequency: FrequencySettings) -> error::Result<()> {
+        apply_frequency(&self.command_context, &self.active_chips(), &self.frequency, frequency).await
+    }
+
+    /// Program PLL of chip at `address` to raw register value (ie. replicating vendor
+    /// configuration) instead of looking up divider for a frequency
+    pub async fn set_pll_raw(&self, address: ChipAddress, raw: u32) -> error::Result<()> {
+        let pll = bm1387::PllReg::from_raw(raw)?;
+        let chip_frequency = pll.calc(CHIP_OSC_CLK_HZ);
+        // Held while programming, so that concurrent updates don't interleave (see `apply_frequency`)
+        let mut frequency = self.frequency.lock().await;
+        self.command_context.write_register(address, &pll).await?;
+        info!(
+            "Hashboard {}: PLL of chip {:?} set to {:#08x} ({})",
+            self.hashboard_idx,
+            address,
+            raw,
+            FrequencySettings::pretty_frequency(chip_frequency)
+        );
+        // Keep frequency settings in line with the chips. Only the frequency is kept, re-initialized
+        // chips get dividers looked up for it, which need not be the raw ones.
+        for (chip_idx, active) in self.active_chips().into_iter().enumerate() {
+            if !address.is_broadcast() && active != address {
+                continue;
+            }
+            if let Some(chip) = frequency.chip.get_mut(chip_idx) {
+                *chip = chip_frequency;
+            }
+        }
+        Ok(())
+    }
+
+    /// Step chips from `ramp.start` up to `target` frequency
+    pub async fn ramp_frequency(&self, target: FrequencySettings, ramp: &FrequencyRamp) -> error::Result<()> {
+        for step in ramp.schedule(&target) {
+            self.set_pll(&step).await?;
+            info!(
+                "Hashboard {}: frequency ramp at {} (target {})",
+                self.hashboard_idx,
+                FrequencySettings::pretty_frequency(step.avg()),
+                FrequencySettings::pretty_frequency(target.avg())
+            );
+            *self.frequency.lock().await = step;
+            async_compat::tokio::time::delay_for(ramp.dwell).await;
+        }
+        Ok(())
+    }
+
+    /// Current number of midstates per work
+    pub async fn midstate_count(&self) -> MidstateCount {
+        *self.midstate_count.lock().await
+    }
+
+    /// Reconfigure chips and IP core to process `midstate_count` midstates per work.
+    ///
+    /// The IP core is stopped while the configuration changes and both work FIFOs are reset, so
+    /// in-flight work is dropped and no solution is decoded with the wrong `work_id` mask. Work
+    /// I/O has to be owned by the hashchain (ie. not taken by running mining tasks).
+    pub async fn set_midstate_count(&self, midstate_count: MidstateCount) -> error::Result<()> {
+        let mut current_count = self.midstate_count.lock().await;
+        let mut work_tx_io = self.work_tx_io.lock().await;
+        let mut work_rx_io = self.work_rx_io.lock().await;
+        let (work_tx_io, work_rx_io) = match (work_tx_io.as_mut(), work_rx_io.as_mut()) {
+            (Some(work_tx_io), Some(work_rx_io)) => (work_tx_io, work_rx_io),
+            _ => Err(ErrorKind::Hashboard(
+                self.hashboard_idx,
+                "cannot change midstate count, work I/O is in use".to_string(),
+            ))?,
+        };
+
+        // Chips are configured the same way, so read-modify-write the first one
+        let mut misc_ctrl = self
+            .command_context
+            .read_one_register::<bm1387::MiscCtrlReg>(self.reference_chip()?)
+            .await?;
+        misc_ctrl.set_midstate_count(midstate_count);
+
+        self.common_io.disable_ip_core();
+        let result = async {
+            self.command_context.write_register(ChipAddress::All, &misc_ctrl).await?;
+            self.common_io.set_midstate_count(midstate_count);
+            work_tx_io.set_midstate_count(midstate_count)?;
+            work_rx_io.set_midstate_count(midstate_count)
+        }
+        .await;
+        self.common_io.enable_ip_core();
+        result?;
+
+        *current_count = midstate_count;
+        info!("Hashboard {}: midstate count set to {}", self.hashboard_idx, midstate_count.to_count());
+        Ok(())
+    }
+
+    /// Switch chips and the IP core UART to `baud_rate`, ie. to run an unstable chain slower.
+    ///
+    /// Chips are switched first (the command goes out at the current rate), then the IP core
+    /// follows. Fails with `ErrorKind::BaudRate` when either side can't do `baud_rate`.
+    pub async fn set_baud_rate(&self, baud_rate: usize) -> error::Result<()> {
+        let (chip_baud_div, actual_baud_rate) =
+            calc_baud_clock_div(baud_rate, CHIP_OSC_CLK_HZ, bm1387::CHIP_OSC_CLK_BASE_BAUD_DIV)?;
+        if chip_baud_div > bm1387::MAX_BAUD_CLOCK_DIV {
+            Err(ErrorKind::BaudRate(format!(
+                "requested {} baud needs chip divisor {}, maximum is {}",
+                baud_rate,
+                chip_baud_div,
+                bm1387::MAX_BAUD_CLOCK_DIV
+            )))?
+        }
+        let (fpga_baud_div, _) =
+            calc_baud_clock_div(actual_baud_rate, io::F_CLK_SPEED_HZ, io::F_CLK_BASE_BAUD_DIV)?;
+
+        // Chips are configured the same way, so read-modify-write the first one
+        let mut misc_ctrl = self
+            .command_context
+            .read_one_register::<bm1387::MiscCtrlReg>(self.reference_chip()?)
+            .await?;
+        misc_ctrl.not_set_baud = false;
+        misc_ctrl.baud_div = (chip_baud_div as u8).into();
+        self.command_context.write_register(ChipAddress::All, &misc_ctrl).await?;
+        // Give the command time to leave at the old baud rate
+        async_compat::tokio::time::delay_for(INACTIVATE_FROM_CHAIN_DELAY).await;
+        self.common_io.set_baud_clock_div(fpga_baud_div as u32);
+
+        info!("Hashboard {}: baud rate set to {}", self.hashboard_idx, actual_baud_rate);
+        Ok(())
+    }
+
+    /// Read hashrate measured by chips and compare it with hashrate derived from nonce counters
+    pub async fn compare_hashrate(&self) -> error::Result<counters::HashrateComparison> {
+        let chip_reported = self
+            .command_context
+            .read_register::<bm1387::HashrateReg>(ChipAddress::All)
+            .await?
+            .iter()
+            .map(|reg| reg.hashrate())
+            .sum::<u64>() as f64;
+        let counted = self.counter.lock().await.snapshot().hashrate();
+        Ok(counters::HashrateComparison { chip_reported, counted })
+    }
+
+    /// Task that periodically sends counters to monitor, compares chip-reported and counted
+    /// hashrate, sends both to monitor and warns when they differ by more than
+    /// `max_divergence_percent`
+    pub async fn hashrate_monitor_task(self: Arc<Self>, max_divergence_percent: f64) {
+        loop {
+            async_compat::tokio::time::delay_for(HASHRATE_CHECK_INTERVAL).await;
+            let status = self.counter.lock().await.status(std::time::Instant::now(), chrono::Utc::now());
+            if self.monitor_tx.unbounded_send(monitor::Message::Counters(status)).is_err() {
+                break;
+            }
+            let comparison = match self.compare_hashrate().await {
+                Ok(comparison) => comparison,
+                Err(e) => {
+                    warn!("Hashboard {}: failed reading chip hashrate: {}", self.hashboard_idx, e);
+                    continue;
+                }
+            };
+            if comparison.diverges(max_divergence_percent) {
+                warn!(
+                    "Hashboard {}: chips report {:.2} GH/s but only {:.2} GH/s was counted ({:.1}% off)",
+                    self.hashboard_idx,
+                    comparison.chip_reported / 1e9,
+                    comparison.counted / 1e9,
+                    comparison.divergence_percent()
+                );
+            }
+            if self.monitor_tx.unbounded_send(monitor::Message::Hashrate(comparison)).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Task that keeps estimated hashboard power under `limit` by lowering chip frequencies.
+    /// Chips never run faster than in `max_frequency`.
+    pub async fn power_limit_task(self: Arc<Self>, limit: power::limit::PowerLimit, max_frequency: FrequencySettings) {
+        let mut throttled = false;
+        loop {
+            async_compat::tokio::time::delay_for(POWER_LIMIT_CHECK_INTERVAL).await;
+            let voltage = match self.voltage_ctrl.read_voltage().await {
+                Ok(voltage) => voltage,
+                Err(e) => {
+                    warn!("Hashboard {}: failed reading voltage set-point: {}", self.hashboard_idx, e);
+                    continue;
+                }
+            };
+            // Held for the whole step, so that it doesn't interleave with other frequency changes
+            let mut current = self.frequency.lock().await;
+            let estimated_watts = power::limit::estimate_watts(voltage, current.total());
+            let avg_frequency = match limit.decide(estimated_watts, current.avg(), max_frequency.avg()) {
+                power::limit::Decision::Keep => continue,
+                power::limit::Decision::Throttle(avg_frequency) => {
+                    if !throttled {
+                        info!(
+                            "Hashboard {}: estimated power {:.0} W (at {} set-point) is over limit {:.0} W, throttling",
+                            self.hashboard_idx, estimated_watts, voltage, limit.watts
+                        );
+                        events::emit(events::Event::Throttled {
+                            hashboard_idx: self.hashboard_idx,
+                            watts: estimated_watts,
+                            limit_watts: limit.watts,
+                        });
+                    }
+                    throttled = true;
+                    avg_frequency
+                }
+                power::limit::Decision::Restore(avg_frequency) => avg_frequency,
+            };
+            let frequency = FrequencySettings {
+                chip: max_frequency.chip.iter().map(|&f| f.min(avg_frequency)).collect(),
+            };
+            if let Err(e) = self.set_pll(&frequency).await {
+                warn!("Hashboard {}: failed setting frequency: {}", self.hashboard_idx, e);
+                continue;
+            }
+            info!(
+                "Hashboard {}: power limiter set frequency to {} (estimated {:.0} W, limit {:.0} W)",
+                self.hashboard_idx,
+                FrequencySettings::pretty_frequency(frequency.avg()),
+                estimated_watts,
+                limit.watts
+            );
+            if frequency.chip == max_frequency.chip {
+                info!("Hashboard {}: power limit no longer throttling", self.hashboard_idx);
+                if throttled {
+                    events::emit(events::Event::ThrottleLifted { hashboard_idx: self.hashboard_idx });
+                }
+                throttled = false;
+            }
+            *current = frequency;
+        }
+    }
+
+    /// Re-initialize chip at `address` (ie. after baud or PLL glitch) without disturbing the rest of
+    /// the chain (see `reinit_chip`)
+    async fn reinit_chip(&self, address: ChipAddress) -> error::Result<()> {
+        let frequency = self.frequency.lock().await.clone();
+        reinit_chip(&self.command_context, &self.active_chips(), &frequency, address).await
+    }
+
+    /// Task that re-initializes chips whose error rate exceeds the limit given by `config`
+    pub async fn chip_error_monitor_task(self: Arc<Self>, config: counters::ChipReinitConfig) {
+        let mut monitor = counters::ChipErrorMonitor::new(config);
+        loop {
+            async_compat::tokio::time::delay_for(CHIP_ERROR_CHECK_INTERVAL).await;
+            reinit_chips_with_errors(
+                &self.command_context,
+                &mut monitor,
+                std::time::Instant::now(),
+                &self.counter,
+                &self.active_chips(),
+                &self.frequency,
+                self.hashboard_idx,
+            )
+            .await;
+        }
+    }
+
+    /// Task that watches the hashboard keeps producing nonces. Dark board is re-initialized up to
+    /// `max_reinits` times, then it's reported to monitor as failed and halted, so that the other
+    /// hashboards keep mining.
+    pub async fn supervisor_task(self: Arc<Self>, max_reinits: usize) {
+        let mut supervisor = BoardSupervisor::new(max_reinits);
+        loop {
+            async_compat::tokio::time::delay_for(SUPERVISOR_CHECK_INTERVAL).await;
+            // Counters may be reset on every job, cumulative ones show progress since last check
+            let valid: usize = self.counter.lock().await.cumulative_chip_counts().iter().map(|(valid, _)| valid).sum();
+            match supervisor.check(valid) {
+                SupervisorAction::Keep => {}
+                SupervisorAction::Reinit => {
+                    warn!(
+                        "Hashboard {}: no nonces in last {:?}, re-initializing chips",
+                        self.hashboard_idx, SUPERVISOR_CHECK_INTERVAL
+                    );
+                    for address in self.active_chips() {
+                        if let Err(e) = self.reinit_chip(address).await {
+                            warn!("Hashboard {}: failed re-initializing chip {:?}: {}", self.hashboard_idx, address, e);
+                        }
+                    }
+                }
+                SupervisorAction::GiveUp => {
+                    let reason = format!("no nonces after {} re-init attempts", max_reinits);
+                    error!("Hashboard {}: {}, halting it", self.hashboard_idx, reason);
+                    events::emit(events::Event::BoardFailed {
+                        hashboard_idx: self.hashboard_idx,
+                        reason: reason.clone(),
+                    });
+                    let _ = self.monitor_tx.unbounded_send(monitor::Message::Failed(reason));
+                    // Halting waits for this task to finish, so it can't be awaited here
+                    let halt_sender = self.halt_sender.clone();
+                    let hashboard_idx = self.hashboard_idx;
+                    async_compat::tokio::spawn(async move {
+                        if let Err(e) = halt_sender.halt().await {
+                            error!("Hashboard {}: failed halting: {}", hashboard_idx, e);
+                        }
+                    });
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Start counting nonces and errors over (ie. when mining conditions change), so that hashrate
+    /// and session totals cover just the time since. Long-term core health is kept, and so is
+    /// what chip error monitor and supervisor judge chips by.
+    pub async fn reset_counters(&self) {
+        self.counter.lock().await.reset();
+    }
+
+    /// Task that resets counters on every new job announced through `new_jobs`, so that hashrate
+    /// and error stats reflect just the current job. It's opt-in: without it, counters cover the
+    /// whole run.
+    pub async fn reset_counters_on_job_task(self: Arc<Self>, mut new_jobs: mpsc::UnboundedReceiver<()>) {
+        while new_jobs.next().await.is_some() {
+            self.reset_counters().await;
+        }
+    }
+
+    /// Merge per-core health persisted by previous runs in `dir` into counters. Missing or
+    /// unreadable file only means starting without history.
+    pub async fn load_health(&self, dir: &std::path::Path) {
+        let path = counters::CoreHealth::path(dir, self.hashboard_idx);
+        match counters::CoreHealth::load(&path) {
+            Ok(health) => {
+                let mut counter = self.counter.lock().await;
+                counter.history = counter.history.merge(&health);
+                info!("Hashboard {}: loaded core health from {}", self.hashboard_idx, path.display());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Hashboard {}: ignoring core health file {}: {}", self.hashboard_idx, path.display(), e),
+        }
+    }
+
+    /// Task that periodically writes per-core health to `dir` (best-effort, failures are only
+    /// logged) and sends session and cumulative totals to monitor
+    pub async fn health_persist_task(self: Arc<Self>, dir: std::path::PathBuf) {
+        let path = counters::CoreHealth::path(&dir, self.hashboard_idx);
+        loop {
+            async_compat::tokio::time::delay_for(HEALTH_PERSIST_INTERVAL).await;
+            let (health, summary, status) = {
+                let counter = self.counter.lock().await;
+                let status = counter.status(std::time::Instant::now(), chrono::Utc::now());
+                (counter.cumulative_health(), counter.health_summary(), status)
+            };
+            info!(
+                "Hashboard {}: up {} since {}, {} valid nonces, {} errors",
+                self.hashboard_idx,
+                status.uptime,
+                status.started,
+                status.valid,
+                status.errors
+            );
+            if let Err(e) = health.save(&path) {
+                warn!("Hashboard {}: failed writing core health to {}: {}", self.hashboard_idx, path.display(), e);
+            }
+            if self.monitor_tx.unbounded_send(monitor::Message::Health(summary)).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Task that periodically reads back voltage set-point and sends it to monitor
+    pub async fn voltage_monitor_task(self: Arc<Self>) {
+        loop {
+            async_compat::tokio::time::delay_for(VOLTAGE_CHECK_INTERVAL).await;
+            let voltage = match self.voltage_ctrl.read_voltage().await {
+                Ok(voltage) => voltage,
+                Err(e) => {
+                    warn!("Hashboard {}: failed reading voltage set-point: {}", self.hashboard_idx, e);
+                    continue;
+                }
+            };
+            let watts = power::limit::estimate_watts(voltage, self.frequency.lock().await.total());
+            if self.monitor_tx.unbounded_send(monitor::Message::Voltage(voltage)).is_err()
+                || self.monitor_tx.unbounded_send(monitor::Message::Power(watts)).is_err()
+            {
+                break;
+            }
+        }
+    }
+}
+
+/// Bring hashboard `hashboard_idx` up and start its tasks, they run until the hashboard is
+/// halted with `chain_halt_sender`
+async fn start_hashboard(
+    hashboard_idx: usize,
+    config: &HashboardConfig,
+    gpio_mgr: &gpio::ControlPinManager,
+    voltage_ctrl_backend: Arc<power::I2cBackend>,
+    monitor_tx: mpsc::UnboundedSender<monitor::Message>,
+    chain_halt_sender: Arc<halt::Sender>,
+    chain_halt_receiver: halt::Receiver,
+) -> error::Result<Arc<HashChain>> {
+    let mut hash_chain = HashChain::new(
+        gpio_mgr,
+        voltage_ctrl_backend,
+        config.pic_program.clone(),
+        hashboard_idx,
+        MidstateCount::new(1),
+        monitor_tx,
+        chain_halt_sender,
+        chain_halt_receiver,
+    )?;
+    let sensors = match hash_chain.init(config).await {
+        Ok(sensors) => sensors,
+        Err(e) => {
+            // Power off whatever was powered on
+            if let Err(halt_error) = hash_chain.halt_sender.clone().halt().await {
+                error!("Hashboard {}: failed halting: {}", hashboard_idx, halt_error);
+            }
+            return Err(e);
+        }
+    };
+    let hash_chain = Arc::new(hash_chain);
+    hash_chain.start(sensors, config).await;
+    info!("Hashboard {}: started with {} chips", hashboard_idx, hash_chain.chip_count());
+    Ok(hash_chain)
+}
+
+/// Start monitor (temperatures, fans) and hashboards `hashboards`, one by one. Hashboard that
+/// fails to come up or breaks later is reported to monitor as failed and halted, the others go
+/// on, all of them are halted with the miner (`halt_sender`). Monitor status is sent to
+/// `status_tx` as soon as monitor runs, or why it didn't start (then no hashboard is started).
+/// Jobs announced through `new_jobs` reset counters of all hashboards.
+pub async fn run_hashboards(
+    hashboards: Vec<usize>,
+    config: HashboardConfig,
+    halt_sender: Arc<halt::Sender>,
+    halt_receiver: halt::Receiver,
+    status_tx: tokio::sync::oneshot::Sender<Result<crate::MonitorStatusReceiver, String>>,
+    new_jobs: Option<mpsc::UnboundedReceiver<()>>,
+) {
+    let monitor =
+        match monitor::Monitor::new_and_start(config.monitor_config(), halt_sender, halt_receiver.clone()).await {
+            Ok(monitor) => monitor,
+            Err(e) => {
+                let _ = status_tx.send(Err(e.to_string()));
+                return;
+            }
+        };
+    monitor.set_temperature_filter_window(config.temp_filter_window).await;
+    monitor.set_sensor_aggregation(config.sensor_aggregation).await;
+    let _ = status_tx.send(Ok(monitor.status_receiver.clone()));
+    let gpio_mgr = gpio::ControlPinManager::new();
+    let voltage_ctrl_backend = Arc::new(power::I2cBackend::new(VOLTAGE_CTRL_I2C_INTERFACE));
+    let mut hash_chains = Vec::new();
+    for hashboard_idx in hashboards {
+        // Hashboard is halted on its own when it breaks, or together with the miner
+        let (chain_halt_sender, chain_halt_receiver) = config.make_halt_pair();
+        let miner_halted = chain_halt_sender.clone();
+        halt_receiver.register_client(format!("hashboard {}", hashboard_idx)).await.spawn_halt_handler(async move {
+            if let Err(e) = miner_halted.halt().await {
+                error!("Hashboard {}: failed halting: {}", hashboard_idx, e);
+            }
+        });
+        let monitor_tx = monitor.register_hashchain_with_halt(hashboard_idx, chain_halt_sender.clone()).await;
+        let result = start_hashboard(
+            hashboard_idx,
+            &config,
+            &gpio_mgr,
+            voltage_ctrl_backend.clone(),
+            monitor_tx.clone(),
+            chain_halt_sender,
+            chain_halt_receiver,
+        )
+        .await;
+        match result {
+            Ok(hash_chain) => hash_chains.push(hash_chain),
+            Err(e) => {
+                error!("Hashboard {}: failed to start: {}", hashboard_idx, e);
+                let reason = format!("failed to start: {}", e);
+                events::emit(events::Event::BoardFailed { hashboard_idx, reason: reason.clone() });
+                let _ = monitor_tx.unbounded_send(monitor::Message::Failed(reason));
+            }
+        }
+    }
+
+    if let Some(mut new_jobs) = new_jobs {
+        let mut job_senders = Vec::new();
+        for hash_chain in hash_chains {
+            let (job_sender, job_receiver) = mpsc::unbounded();
+            hash_chain.spawn_task("counter reset", hash_chain.clone().reset_counters_on_job_task(job_receiver)).await;
+            job_senders.push(job_sender);
+        }
+        // Hashboards that were halted are left out
+        while new_jobs.next().await.is_some() {
+            job_senders.retain(|job_sender| job_sender.unbounded_send(()).is_ok());
+        }
+    }
+}
+
+pub type Frequency = usize;
+
+/// Program PLLs of `chips` to `frequency` (one frequency per chip, in the same order). All
+/// frequencies are looked up first, so nothing is written when one of them is out of range.
+async fn program_pll(
+    command: &impl command::Interface,
+    chips: &[ChipAddress],
+    frequency: &FrequencySettings,
+) -> error::Result<()> {
+    let plls = frequency
+        .chip
+        .iter()
+        .map(|&chip_frequency| bm1387::PllFrequency::lookup_freq(chip_frequency))
+        .collect::<error::Result<Vec<_>>>()?;
+    for (&address, pll) in chips.iter().zip(plls.iter()) {
+        command.write_register(address, &pll.reg).await?;
+    }
+    Ok(())
+}
+
+/// Give `chip_count` chips on the chain their addresses, in chain order. All chips are
+/// inactivated first, then each `SetChipAddressCmd` is taken by the first chip that doesn't have
+/// an address yet. Addresses are spaced by the BM1387 address interval (see `ChipAddress`).
+async fn assign_chip_addresses(command: &impl command::Interface, chip_count: usize) -> Vec<ChipAddress> {
+    for _ in 0..INACTIVATE_FROM_CHAIN_COUNT {
+        command.send_raw_command(bm1387::InactivateFromChainCmd::new().pack().to_vec(), false).await;
+        async_compat::tokio::time::delay_for(INACTIVATE_FROM_CHAIN_DELAY).await;
+    }
+    let addresses = (0..chip_count).map(ChipAddress::One).collect::<Vec<_>>();
+    for &address in addresses.iter() {
+        command.send_raw_command(bm1387::SetChipAddressCmd::new(address).pack().to_vec(), false).await;
+    }
+    addresses
+}
+
+/// Check that chips answering `GetAddressReg` (`responses`, in chain order) took `addresses`
+fn check_chip_addresses(addresses: &[ChipAddress], responses: &[bm1387::GetAddressReg]) -> error::Result<()> {
+    if responses.len() != addresses.len() {
+        Err(ErrorKind::ChipEnumeration(format!(
+            "{} chips answered after addressing, {} were addressed",
+            responses.len(),
+            addresses.len()
+        )))?
+    }
+    for (chip, (address, response)) in addresses.iter().zip(responses.iter()).enumerate() {
+        if response.addr != address.to_hw_addr() {
+            Err(ErrorKind::ChipEnumeration(format!(
+                "chip {} on chain took address {:#04x} instead of {:#04x}",
+                chip,
+                response.addr,
+                address.to_hw_addr()
+            )))?
+        }
+    }
+    Ok(())
+}
+
+/// Route I2C bus of `TEMP_CHIP` given by `config` to chip pins and look for temperature sensors
+/// on it
+async fn probe_temperature_sensors<T>(command: T, config: SensorConfig) -> error::Result<sensor::FoundSensors>
+where
+    T: command::Interface + Clone + 'static,
+{
+    let i2c_bus = i2c::bm1387::Bus::new_and_init_on(command, TEMP_CHIP, config.i2c_bus).await?;
+    sensor::probe_all_i2c_sensors_at(i2c_bus, config.i2c_address).await
+}
+
+/// Re-initialize chip at `address`, one of `chips`, without disturbing the rest of the chain:
+/// configuration is copied from another chip and PLL is set to the chip frequency
+async fn reinit_chip(
+    command: &impl command::Interface,
+    chips: &[ChipAddress],
+    frequency: &FrequencySettings,
+    address: ChipAddress,
+) -> error::Result<()> {
+    let chip_idx = chips
+        .iter()
+        .position(|chip| *chip == address)
+        .ok_or_else(|| ErrorKind::Hashchip(format!("chip {:?} is not mining", address)))?;
+    let reference = chips
+        .iter()
+        .copied()
+        .find(|other| *other != address)
+        .ok_or_else(|| ErrorKind::Hashchip("no chip to copy configuration from".to_string()))?;
+    let misc_ctrl = command.read_one_register::<bm1387::MiscCtrlReg>(reference).await?;
+    command.write_register(address, &misc_ctrl).await?;
+
+    let pll = bm1387::PllFrequency::lookup_freq(frequency.chip[chip_idx])?;
+    command.write_register(address, &pll.reg).await?;
+    Ok(())
+}
+
+/// One pass of `HashChain::chip_error_monitor_task`: record chip counters in `monitor` and
+/// re-initialize those of `chips` whose error rate is over the limit. Returns addresses of those
+/// chips (as counters are indexed).
+async fn reinit_chips_with_errors(
+    command: &impl command::Interface,
+    monitor: &mut counters::ChipErrorMonitor,
+    now: std::time::Instant,
+    counter: &Mutex<counters::HashChain>,
+    chips: &[ChipAddress],
+    frequency: &Mutex<FrequencySettings>,
+    hashboard_idx: usize,
+) -> Vec<usize> {
+    // Excluded chips don't mine, errors are all that comes from them
+    let picked: Vec<_> = monitor
+        .check(now, &*counter.lock().await)
+        .into_iter()
+        .filter(|&chip| chips.contains(&ChipAddress::One(chip)))
+        .collect();
+    if picked.is_empty() {
+        return picked;
+    }
+    let config = monitor.config();
+    let frequency = frequency.lock().await.clone();
+    for &chip in picked.iter() {
+        warn!(
+            "Hashboard {}: chip {} error rate over last {:?} exceeds {:.0}%, re-initializing it",
+            hashboard_idx,
+            chip,
+            config.window,
+            config.max_error_rate * 100.0
+        );
+        if let Err(e) = reinit_chip(command, chips, &frequency, ChipAddress::One(chip)).await {
+            warn!("Hashboard {}: failed re-initializing chip {}: {}", hashboard_idx, chip, e);
+        }
+    }
+    picked
+}
+
+/// Program `chips` to `frequency` and keep it in `current`. Settings have to have a frequency for
+/// each chip, invalid settings are rejected without touching the chips or `current`.
+async fn apply_frequency(
+    command: &impl command::Interface,
+    chips: &[ChipAddress],
+    current: &Mutex<FrequencySettings>,
+    frequency: FrequencySettings,
+) -> error::Result<()> {
+    if frequency.chip.len() != chips.len() {
+        Err(ErrorKind::PLL(format!(
+            "got {} chip frequencies for {} chips",
+            frequency.chip.len(),
+            chips.len()
+        )))?
+    }
+    // Held while programming, so that concurrent updates don't interleave
+    let mut current = current.lock().await;
+    program_pll(command, chips, &frequency).await?;
+    *current = frequency;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrequencySettings {
+    pub chip: Vec<Frequency>,
+}
+
+impl FrequencySettings {
+    /// Build frequency settings with all chips having the same frequency
+    pub fn from_frequency(frequency: usize) -> Self {
+        Self {
+            chip: vec![frequency; EXPECTED_CHIPS_ON_CHAIN],
+        }
+    }
+
+    /// Resize settings to `chip_count` chips. Added chips (ie. ones that failed previous
+    /// enumeration) get average frequency of current chips, or `default` if there are none.
+    pub fn set_chip_count(&mut self, chip_count: usize, default: Frequency) {
+        let frequency = if self.chip.is_empty() { default } else { self.avg() };
+        self.chip.resize(chip_count, frequency);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.chip.iter().fold(0, |total_f, &f| total_f + f as u64)
+    }
+
+    #[allow(dead_code)]
+    pub fn min(&self) -> usize {
+        *self.chip.iter().min().expect("BUG: no chips on chain")
+    }
+
+    #[allow(dead_code)]
+    pub fn max(&self) -> usize {
+        *self.chip.iter().max().expect("BUG: no chips on chain")
+    }
+
+    pub fn avg(&self) -> usize {
+        assert!(self.chip.len() > 0, "BUG: no chips on chain");
+        let sum: u64 = self.chip.iter().map(|frequency| *frequency as u64).sum();
+        (sum / self.chip.len() as u64) as usize
+    }
+
+    fn pretty_frequency(freq: usize) -> String {
+        format!("{:.01} MHz", (freq as f32) / 1_000_000.0)
+    }
+}
+
+/// Gradual increase of chip frequency, so that the board isn't hit by full load at once
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrequencyRamp {
+    /// Frequency to start at (chips with lower target frequency start at their target)
+    pub start: Frequency,
+    /// Maximum frequency increase of one step, zero means jumping straight to target
+    pub step: Frequency,
+    /// How long to stay at each step
+    pub dwell: Duration,
+}
+
+impl Default for FrequencyRamp {
+    fn default() -> Self {
+        Self {
+            start: 250_000_000,
+            step: 50_000_000,
+            dwell: Duration::from_millis(500),
+        }
+    }
+}
+
+impl FrequencyRamp {
+    /// Generate frequency settings for each step of the ramp, the last one is always `target`
+    pub fn schedule(&self, target: &FrequencySettings) -> Vec<FrequencySettings> {
+        if self.step == 0 {
+            return vec![target.clone()];
+        }
+        let mut current = FrequencySettings {
+            chip: target.chip.iter().map(|&f| f.min(self.start)).collect(),
+        };
+        let mut schedule = vec![current.clone()];
+        while current.chip != target.chip {
+            for (f, &target_f) in current.chip.iter_mut().zip(target.chip.iter()) {
+                *f = (*f + self.step).min(target_f);
+            }
+            schedule.push(current.clone());
+        }
+        schedule
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    // Hashboard drivers run on tokio 0.2
+    use async_compat::tokio;
+
+    #[test]
+    fn test_calc_baud_clock_div() {
+        let chip = |baud_rate| calc_baud_clock_div(baud_rate, CHIP_OSC_CLK_HZ, bm1387::CHIP_OSC_CLK_BASE_BAUD_DIV);
+        assert_eq!(chip(INIT_CHIP_BAUD_RATE).unwrap(), (26, INIT_CHIP_BAUD_RATE));
+        assert_eq!(chip(TARGET_CHIP_BAUD_RATE).unwrap(), (1, TARGET_CHIP_BAUD_RATE));
+        assert_eq!(chip(3_125_000).unwrap(), (0, 3_125_000));
+        // close enough
+        assert_eq!(chip(115_200).unwrap(), (26, INIT_CHIP_BAUD_RATE));
+        // divisor doesn't fit into chip register, that's for `set_baud_rate` to reject
+        assert_eq!(chip(9_600).unwrap(), (325, 9_585));
+        // nothing close
+        assert!(chip(2_000_000).is_err());
+        assert!(chip(4_000_000).is_err());
+        assert!(chip(0).is_err());
+
+        let fpga = |baud_rate| calc_baud_clock_div(baud_rate, io::F_CLK_SPEED_HZ, io::F_CLK_BASE_BAUD_DIV);
+        assert_eq!(fpga(TARGET_CHIP_BAUD_RATE).unwrap(), (3, TARGET_CHIP_BAUD_RATE));
+        assert_eq!(fpga(INIT_CHIP_BAUD_RATE).unwrap(), (53, 115_740));
+    }
+
+    #[test]
+    fn test_missing_chip_addresses() {
+        assert!(missing_chip_addresses(EXPECTED_CHIPS_ON_CHAIN).is_empty());
+        assert!(missing_chip_addresses(MAX_CHIPS_ON_CHAIN).is_empty());
+        assert_eq!(missing_chip_addresses(60), vec![ChipAddress::One(60), ChipAddress::One(61), ChipAddress::One(62)]);
+        assert_eq!(missing_chip_addresses(0).len(), EXPECTED_CHIPS_ON_CHAIN);
+    }
+
+    #[test]
+    fn test_count_solution() {
+        let mut counter = counters::HashChain::new(3, 1);
+        let active = [ChipAddress::One(0), ChipAddress::One(2)];
+        let nonce = |chip, core| bm1387::CoreAddress { chip, core }.to_nonce_base() | 0x00ab_0001;
+
+        count_solution(&mut counter, &active, nonce(2, 5));
+        count_solution(&mut counter, &active, nonce(0, 0));
+        // Excluded chip and chip that isn't on the chain at all
+        count_solution(&mut counter, &active, nonce(1, 5));
+        count_solution(&mut counter, &active, nonce(40, 0));
+        assert_eq!((counter.valid, counter.errors), (2, 1));
+        assert_eq!(counter.chip[2].core[5].valid, 1);
+        assert_eq!(counter.chip[1].core[5].errors, 1);
+    }
+
+    #[test]
+    fn test_catch_all_revision_excluded() {
+        let good = bm1387::GetAddressReg::unpack_from_slice(&[0x13, 0x87, 0x90, 0x00]).expect("unpack failed");
+        let broken = bm1387::GetAddressReg::unpack_from_slice(&[0x13, 0x86, 0x90, 0x04]).expect("unpack failed");
+
+        let addresses = [ChipAddress::One(0), ChipAddress::One(1), ChipAddress::One(2)];
+        let enumeration = ChipEnumeration::from_responses(&addresses, &[good.clone(), broken, good]);
+        assert_eq!(enumeration.active, vec![ChipAddress::One(0), ChipAddress::One(2)]);
+        assert_eq!(enumeration.excluded, vec![(ChipAddress::One(1), EnumCatchAll::CatchAll(0x1386))]);
+
+        let plls = [
+            bm1387::PllReg::from_reg(0x0068_0221),
+            bm1387::PllReg::from_reg(0x0050_0211),
+            // Dividers of zero
+            bm1387::PllReg::from_reg(0),
+        ];
+        assert_eq!(enumeration.active_frequencies(&addresses, &plls), vec![Some(650_000_000), None]);
+    }
+
+    #[test]
+    fn test_check_chip_addresses() {
+        let at = |addr| bm1387::GetAddressReg::unpack_from_slice(&[0x13, 0x87, 0x90, addr]).expect("unpack failed");
+        let addresses = [ChipAddress::One(0), ChipAddress::One(1), ChipAddress::One(2)];
+        assert!(check_chip_addresses(&addresses, &[at(0x00), at(0x04), at(0x08)]).is_ok());
+        // Second chip didn't take its address
+        assert!(check_chip_addresses(&addresses, &[at(0x00), at(0x00), at(0x08)]).is_err());
+        // A chip stopped answering
+        assert!(check_chip_addresses(&addresses, &[at(0x00), at(0x04)]).is_err());
+    }
+
+    #[test]
+    fn test_frequency_ramp_schedule() {
+        const MHZ: usize = 1_000_000;
+        let ramp = FrequencyRamp {
+            start: 400 * MHZ,
+            step: 100 * MHZ,
+            dwell: Duration::from_secs(1),
+        };
+        let target = FrequencySettings {
+            chip: vec![650 * MHZ, 500 * MHZ, 300 * MHZ],
+        };
+        let schedule = ramp.schedule(&target).into_iter().map(|step| step.chip).collect::<Vec<_>>();
+        assert_eq!(
+            schedule,
+            vec![
+                vec![400 * MHZ, 400 * MHZ, 300 * MHZ],
+                vec![500 * MHZ, 500 * MHZ, 300 * MHZ],
+                vec![600 * MHZ, 500 * MHZ, 300 * MHZ],
+                vec![650 * MHZ, 500 * MHZ, 300 * MHZ],
+            ]
+        );
+
+        let jump = FrequencyRamp { step: 0, ..ramp };
+        assert_eq!(jump.schedule(&target).len(), 1);
+    }
+
+    #[test]
+    fn test_frequency_settings_shrink() {
+        let mut settings = FrequencySettings::from_frequency(600_000_000);
+        settings.chip[0] = 500_000_000;
+        settings.set_chip_count(10, 250_000_000);
+        assert_eq!(settings.chip.len(), 10);
+        assert_eq!(settings.chip[0], 500_000_000);
+        assert!(settings.chip[1..].iter().all(|&f| f == 600_000_000));
+    }
+
+    #[test]
+    fn test_frequency_settings_grow() {
+        let mut settings = FrequencySettings { chip: vec![500_000_000, 700_000_000] };
+        settings.set_chip_count(4, 250_000_000);
+        assert_eq!(settings.chip, vec![500_000_000, 700_000_000, 600_000_000, 600_000_000]);
+
+        settings.set_chip_count(0, 250_000_000);
+        assert!(settings.chip.is_empty());
+        settings.set_chip_count(EXPECTED_CHIPS_ON_CHAIN + 1, 250_000_000);
+        assert_eq!(settings.chip.len(), EXPECTED_CHIPS_ON_CHAIN + 1);
+        assert!(settings.chip.iter().all(|&f| f == 250_000_000));
+    }
+
+    /// Command backend that records register writes (address, register number and value) and raw
+    /// commands. Reads are answered with the last value of the register (the same for all chips),
+    /// registers that were never set don't answer.
+    #[derive(Default, Clone)]
+    struct RecordingCommand {
+        writes: Arc<std::sync::Mutex<Vec<(ChipAddress, u8, u32)>>>,
+        raw: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+        registers: Arc<std::sync::Mutex<std::collections::HashMap<u8, u32>>>,
+    }
+
+    impl RecordingCommand {
+        fn set_register<T: bm1387::Register>(&self, value: &T) {
+            self.registers.lock().unwrap().insert(T::REG_NUM, value.to_reg());
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl command::Interface for RecordingCommand {
+        async fn read_register<T: bm1387::Register>(&self, _chip_address: ChipAddress) -> error::Result<Vec<T>> {
+            Ok(self.registers.lock().unwrap().get(&T::REG_NUM).map(|&reg| T::from_reg(reg)).into_iter().collect())
+        }
+
+        async fn write_register<'a, T: bm1387::Register>(
+            &'a self,
+            chip_address: ChipAddress,
+            value: &'a T,
+        ) -> error::Result<()> {
+            self.writes.lock().unwrap().push((chip_address, T::REG_NUM, value.to_reg()));
+            self.set_register(value);
+            Ok(())
+        }
+
+        async fn send_raw_command(&self, cmd: Vec<u8>, _wait: bool) {
+            self.raw.lock().unwrap().push(cmd);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_assign_chip_addresses() {
+        let command = RecordingCommand::default();
+        let addresses = assign_chip_addresses(&command, 3).await;
+        assert_eq!(addresses, vec![ChipAddress::One(0), ChipAddress::One(1), ChipAddress::One(2)]);
+        let inactivate = vec![0x55, 0x05, 0x00, 0x00];
+        assert_eq!(
+            *command.raw.lock().unwrap(),
+            vec![
+                inactivate.clone(),
+                inactivate.clone(),
+                inactivate,
+                // Chip addresses go by 4
+                vec![0x41, 0x05, 0x00, 0x00],
+                vec![0x41, 0x05, 0x04, 0x00],
+                vec![0x41, 0x05, 0x08, 0x00],
+            ]
+        );
+        assert!(command.writes.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_apply_frequency() {
+        let command = RecordingCommand::default();
+        let chips = [ChipAddress::One(0), ChipAddress::One(2)];
+        let current = Mutex::new(FrequencySettings { chip: vec![250_000_000; 2] });
+        let settings = FrequencySettings { chip: vec![650_000_000, 600_000_000] };
+        apply_frequency(&command, &chips, &current, settings.clone()).await.unwrap();
+        assert_eq!(*current.lock().await, settings);
+        let pll = |frequency| bm1387::PllFrequency::lookup_freq(frequency).unwrap().reg.to_reg();
+        assert_eq!(
+            *command.writes.lock().unwrap(),
+            vec![
+                (ChipAddress::One(0), bm1387::PllReg::REG_NUM, pll(650_000_000)),
+                (ChipAddress::One(2), bm1387::PllReg::REG_NUM, pll(600_000_000)),
+            ]
+        );
+
+        // Nothing is written when a frequency is out of range or chip count doesn't match
+        let command = RecordingCommand::default();
+        let out_of_range = FrequencySettings { chip: vec![650_000_000, 1] };
+        assert!(apply_frequency(&command, &chips, &current, out_of_range).await.is_err());
+        let too_short = FrequencySettings { chip: vec![650_000_000] };
+        assert!(apply_frequency(&command, &chips, &current, too_short).await.is_err());
+        assert!(command.writes.lock().unwrap().is_empty());
+        assert_eq!(*current.lock().await, settings);
+    }
+
+    #[tokio::test]
+    async fn test_reinit_chips_with_errors() {
+        let command = RecordingCommand::default();
+        let misc_ctrl = bm1387::MiscCtrlReg::new(true, true, 1, true, false).unwrap();
+        command.set_register(&misc_ctrl);
+        let config = counters::ChipReinitConfig {
+            max_error_rate: 0.2,
+            window: Duration::from_secs(60),
+            min_nonces: 10,
+        };
+        let mut monitor = counters::ChipErrorMonitor::new(config);
+        // Counters are indexed by chip address, chip 2 is excluded from mining
+        let counter = Mutex::new(counters::HashChain::new(4, 1));
+        let chips = [ChipAddress::One(0), ChipAddress::One(1), ChipAddress::One(3)];
+        let frequency = Mutex::new(FrequencySettings { chip: vec![650_000_000, 650_000_000, 600_000_000] });
+        let start = std::time::Instant::now();
+        // Every chip sends 20 valid nonces between checks, chip 3 `errors` errors on top of that
+        // and excluded chip 2 just errors
+        let add_nonces = |counter: &mut counters::HashChain, errors: usize| {
+            for &chip in [0, 1, 3].iter() {
+                for _ in 0..20 {
+                    counter.add_valid(bm1387::CoreAddress { chip, core: 0 });
+                }
+            }
+            for _ in 0..errors {
+                counter.add_error(bm1387::CoreAddress { chip: 3, core: 0 });
+                counter.add_error(bm1387::CoreAddress { chip: 2, core: 0 });
+            }
+        };
+
+        add_nonces(&mut *counter.lock().await, 0);
+        let picked = reinit_chips_with_errors(&command, &mut monitor, start, &counter, &chips, &frequency, 1).await;
+        assert!(picked.is_empty());
+        // Chip 3 has 10 errors of 30 nonces over the window, the other active chips have none
+        add_nonces(&mut *counter.lock().await, 10);
+        let now = start + Duration::from_secs(60);
+        let picked = reinit_chips_with_errors(&command, &mut monitor, now, &counter, &chips, &frequency, 1).await;
+        assert_eq!(picked, vec![3]);
+        // Only the glitched chip is re-initialized, with configuration of another chip and its own frequency
+        let pll = bm1387::PllFrequency::lookup_freq(600_000_000).unwrap().reg.to_reg();
+        assert_eq!(
+            *command.writes.lock().unwrap(),
+            vec![
+                (ChipAddress::One(3), bm1387::MiscCtrlReg::REG_NUM, misc_ctrl.to_reg()),
+                (ChipAddress::One(3), bm1387::PllReg::REG_NUM, pll),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_temperature_sensors() {
+        let command = RecordingCommand::default();
+        let misc_ctrl = bm1387::MiscCtrlReg::new(true, true, 1, true, false).unwrap();
+        command.set_register(&misc_ctrl);
+        // I2C controller is idle
+        command.set_register(&bm1387::I2cControlReg::from_reg(0));
+        let config = SensorConfig {
+            i2c_bus: bm1387::I2cBusSelect::Middle,
+            i2c_address: Some(i2c::Address::new(0x9a)),
+        };
+        // Fake I2C controller reads zeros, that's no known sensor
+        let sensors = probe_temperature_sensors(command.clone(), config).await.expect("probing failed");
+        assert!(sensors.is_empty());
+
+        let writes = command.writes.lock().unwrap();
+        // The configured bus is routed to pins of the sensor chip
+        let mut expected = misc_ctrl.clone();
+        expected.set_i2c(Some(bm1387::I2cBusSelect::Middle));
+        assert_eq!(writes[0], (TEMP_CHIP, bm1387::MiscCtrlReg::REG_NUM, expected.to_reg()));
+        // and the sensor is looked for at the configured address only
+        let i2c_commands: Vec<_> = writes[1..]
+            .iter()
+            .map(|&(address, reg_num, value)| {
+                assert_eq!((address, reg_num), (TEMP_CHIP, bm1387::I2cControlReg::REG_NUM));
+                bm1387::I2cControlReg::from_reg(value)
+            })
+            .collect();
+        assert!(!i2c_commands.is_empty());
+        let sensor_address = i2c::Address::new(0x9a).to_readable_hw_addr();
+        assert!(i2c_commands.iter().all(|i2c_command| i2c_command.addr == sensor_address));
+    }
+
+}
@@ -0,0 +1,346 @@
+//! Health check endpoint (`--health-listen`)
+//!
+//! `GET /healthz` answers 200 while the miner keeps getting jobs and hashing them and 503 (with the
+//! reason in the body) otherwise, so that a supervisor (systemd, k8s) can restart a wedged miner.
+//! `GET /status.json` answers worker hashrates, the age of the last job, blocks found, latency of
+//! connecting to the pool/node, hashboard
+//! fans and chain counters (with `--hashboard`), with whatever status plugins report (see
+//! `Plugin::status`).
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use kasop::PluginManager;
+use log::{info, warn};
+use serde_json::Value as JsonValue;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::client::ConnectLatency;
+use crate::miner::WorkerRates;
+use crate::{counters, fan, monitor, Error, MonitorStatusReceiver};
+
+/// Path the health is served at, anything else is 404
+pub const HEALTH_PATH: &str = "/healthz";
+/// Path the status is served at
+pub const STATUS_PATH: &str = "/status.json";
+/// Connection that doesn't send its request and read the response in this time is dropped
+const SERVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Decide whether the miner is healthy given how long ago it received the last job (`None` if it
+/// hasn't received any yet) and how many workers hashed in the last hashrate report
+pub fn check(last_job_age: Option<Duration>, active_workers: usize, max_job_age: Duration) -> Result<(), String> {
+    match last_job_age {
+        None => return Err("no job received yet".to_string()),
+        Some(age) if age > max_job_age => {
+            return Err(format!("last job received {} s ago (limit {} s)", age.as_secs(), max_job_age.as_secs()))
+        }
+        Some(_) => (),
+    }
+    if active_workers == 0 {
+        return Err("no worker is hashing".to_string());
+    }
+    Ok(())
+}
+
+/// Status of core subsystems with `hardware` (see `hardware_status`) and `plugins` status merged in
+pub fn status(
+    last_job_age: Option<Duration>,
+    rates: &WorkerRates,
+    blocks_found: u64,
+    connect_latency: ConnectLatency,
+    hardware: serde_json::Map<String, JsonValue>,
+    plugins: serde_json::Map<String, JsonValue>,
+) -> JsonValue {
+    let workers: Vec<_> =
+        rates.iter().map(|(name, rate)| serde_json::json!({ "name": name, "hashrate": rate })).collect();
+    let mut status = serde_json::json!({
+        "last_job_age_secs": last_job_age.map(|age| age.as_secs()),
+        "workers": workers,
+        "blocks_found": blocks_found,
+        "connect_latency_ms": connect_latency_status(connect_latency),
+        "plugins": plugins,
+    });
+    if let JsonValue::Object(status) = &mut status {
+        status.extend(hardware);
+    }
+    status
+}
+
+/// Milliseconds it took to connect and to receive the first job, `null` until it happens
+fn connect_latency_status(latency: ConnectLatency) -> JsonValue {
+    let ms = |latency: Option<Duration>| latency.map(|latency| latency.as_millis() as u64);
+    serde_json::json!({
+        "connect": ms(latency.connect),
+        "first_job": ms(latency.first_job),
+    })
+}
+
+/// Fans with their speeds in RPM, `missing` is how many of the expected ones aren't spinning
+fn fans_status(feedback: &fan::Feedback, missing: usize) -> JsonValue {
+    serde_json::json!({
+        "rpm": feedback.rpm,
+        "running": feedback.num_fans_running(),
+        "missing": missing,
+    })
+}
+
+/// Hashboards with their counters (`null` until the first report) and why they failed
+fn chains_status(chains: &[monitor::ChainStatus]) -> JsonValue {
+    chains
+        .iter()
+        .map(|chain| {
+            serde_json::json!({
+                "hashboard": chain.hashboard_idx,
+                "counters": chain.counters,
+                "failed": chain.failed,
+            })
+        })
+        .collect()
+}
+
+/// Status of hashboards as seen by their monitor
+fn hardware_status(status: &monitor::Status) -> serde_json::Map<String, JsonValue> {
+    let mut hardware = serde_json::Map::new();
+    hardware.insert("fans".into(), fans_status(&status.fan_feedback, status.fans_missing));
+    hardware.insert("chains".into(), chains_status(&status.chains));
+    hardware
+}
+
+/// HTTP response to request with `request_line` (ie. `GET /healthz HTTP/1.1`)
+fn response(
+    request_line: &str,
+    health: impl FnOnce() -> Result<(), String>,
+    status: impl FnOnce() -> JsonValue,
+) -> String {
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (code, content_type, body) = match path {
+        HEALTH_PATH => match health() {
+            Ok(()) => ("200 OK", "text/plain", "ok".to_string()),
+            Err(reason) => ("503 Service Unavailable", "text/plain", reason),
+        },
+        STATUS_PATH => ("200 OK", "application/json", status().to_string()),
+        _ => ("404 Not Found", "text/plain", "not found".to_string()),
+    };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        code,
+        content_type,
+        body.len() + 1,
+        body
+    )
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    last_job: &Mutex<Option<Instant>>,
+    connect_latency: &Mutex<ConnectLatency>,
+    rates: &Mutex<WorkerRates>,
+    monitor_status: Option<&MonitorStatusReceiver>,
+    plugins: &PluginManager,
+    max_job_age: Duration,
+) -> Result<(), Error> {
+    // Request line is all we need and it fits into the first read
+    let mut buf = [0u8; 1024];
+    let len = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let request_line = request.lines().next().unwrap_or_default();
+    let last_job_age = || last_job.lock().expect("BUG: last job lock poisoned").map(|at| at.elapsed());
+    let worker_rates = || rates.lock().expect("BUG: rates lock poisoned").clone();
+    let connect_latency = || *connect_latency.lock().expect("BUG: connect latency lock poisoned");
+    let hardware = || match monitor_status.and_then(|receiver| receiver.borrow().clone()) {
+        Some(status) => hardware_status(&status),
+        None => serde_json::Map::new(),
+    };
+    let response = response(
+        request_line,
+        || {
+            let active_workers = worker_rates().iter().filter(|(_, rate)| *rate > 0.0).count();
+            check(last_job_age(), active_workers, max_job_age)
+        },
+        || {
+            let blocks_found = counters::blocks_found();
+            status(last_job_age(), &worker_rates(), blocks_found, connect_latency(), hardware(), plugins.status())
+        },
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Task that answers health checks on `listener`, it runs until aborted. Each connection is
+/// served on its own (for at most `SERVE_TIMEOUT`), so that a stuck client doesn't block the
+/// others. Hashboards are reported from `monitor_status` when they run.
+pub async fn health_task(
+    listener: TcpListener,
+    last_job: Arc<Mutex<Option<Instant>>>,
+    connect_latency: Arc<Mutex<ConnectLatency>>,
+    rates: Arc<Mutex<WorkerRates>>,
+    monitor_status: Option<MonitorStatusReceiver>,
+    plugins: Arc<PluginManager>,
+    max_job_age: Duration,
+) {
+    if let Ok(address) = listener.local_addr() {
+        info!("Serving health check at http://{}{}", address, HEALTH_PATH);
+    }
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Health check connection failed: {}", e);
+                continue;
+            }
+        };
+        let (last_job, rates, plugins) = (Arc::clone(&last_job), Arc::clone(&rates), Arc::clone(&plugins));
+        let connect_latency = Arc::clone(&connect_latency);
+        let monitor_status = monitor_status.clone();
+        tokio::spawn(async move {
+            let monitor_status = monitor_status.as_ref();
+            let served = serve(stream, &last_job, &connect_latency, &rates, monitor_status, &plugins, max_job_age);
+            match tokio::time::timeout(SERVE_TIMEOUT, served).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Health check failed: {}", e),
+                Err(_) => warn!("Health check client didn't finish in {} s", SERVE_TIMEOUT.as_secs()),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let max_age = Duration::from_secs(120);
+        assert_eq!(check(Some(Duration::from_secs(5)), 2, max_age), Ok(()));
+        assert_eq!(check(Some(max_age), 1, max_age), Ok(()));
+        assert!(check(Some(Duration::from_secs(121)), 2, max_age).unwrap_err().contains("121 s ago"));
+        assert_eq!(check(None, 2, max_age), Err("no job received yet".to_string()));
+        assert_eq!(check(Some(Duration::from_secs(5)), 0, max_age), Err("no worker is hashing".to_string()));
+    }
+
+    #[test]
+    fn test_response() {
+        let no_status = || panic!("status evaluated for health check");
+        let ok = response("GET /healthz HTTP/1.1", || Ok(()), no_status);
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.ends_with("\r\n\r\nok\n"));
+        assert!(ok.contains("Content-Length: 3\r\n"));
+
+        let stale = response("GET /healthz HTTP/1.1", || Err("no worker is hashing".to_string()), no_status);
+        assert!(stale.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(stale.ends_with("no worker is hashing\n"));
+
+        let other = response("GET / HTTP/1.1", || panic!("health evaluated for unknown path"), no_status);
+        assert!(other.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let status = response("GET /status.json HTTP/1.1", || panic!("health evaluated for status"), || 42.into());
+        assert!(status.starts_with("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n"));
+        assert!(status.ends_with("\r\n\r\n42\n"));
+    }
+
+    #[test]
+    fn test_status() {
+        let mut plugins = serde_json::Map::new();
+        plugins.insert("opencl".into(), serde_json::json!([{ "device": 0, "temperature": 65 }]));
+        let rates = vec![("gpu0".to_string(), 1e9)];
+        let latency = ConnectLatency { connect: Some(Duration::from_millis(42)), first_job: None };
+        assert_eq!(
+            status(Some(Duration::from_secs(3)), &rates, 1, latency, serde_json::Map::new(), plugins),
+            serde_json::json!({
+                "last_job_age_secs": 3,
+                "workers": [{ "name": "gpu0", "hashrate": 1e9 }],
+                "blocks_found": 1,
+                "connect_latency_ms": { "connect": 42, "first_job": null },
+                "plugins": { "opencl": [{ "device": 0, "temperature": 65 }] },
+            })
+        );
+        let no_status = || serde_json::Map::new();
+        let no_latency = ConnectLatency::default();
+        assert_eq!(
+            status(None, &Vec::new(), 0, no_latency, no_status(), no_status())["last_job_age_secs"],
+            JsonValue::Null
+        );
+
+        // Hashboard fans
+        let mut hardware = serde_json::Map::new();
+        hardware.insert("fans".into(), fans_status(&fan::Feedback { rpm: vec![4200, 0, 4150] }, 1));
+        assert_eq!(
+            status(None, &Vec::new(), 0, no_latency, hardware, no_status())["fans"],
+            serde_json::json!({ "rpm": [4200, 0, 4150], "running": 2, "missing": 1 })
+        );
+
+        // Hashboard counters
+        let counters = counters::ChainStatus {
+            started: "2024-05-01T12:00:00+00:00".to_string(),
+            stopped: None,
+            uptime_secs: 3_725,
+            uptime: "1h 2m 5s".to_string(),
+            valid: 1_000,
+            errors: 3,
+            hashrate: 1.2e12,
+        };
+        let chain = |hashboard_idx, counters, failed| monitor::ChainStatus {
+            hashboard_idx,
+            temperature: monitor::ChainTemperature::Unknown,
+            hashrate: None,
+            voltage: None,
+            estimated_power: None,
+            efficiency: None,
+            health: None,
+            counters,
+            failed,
+        };
+        let chains = [chain(6, Some(counters), None), chain(7, None, Some("not responding".to_string()))];
+        let mut hardware = serde_json::Map::new();
+        hardware.insert("chains".into(), chains_status(&chains));
+        assert_eq!(
+            status(None, &Vec::new(), 0, no_latency, hardware, no_status())["chains"],
+            serde_json::json!([
+                {
+                    "hashboard": 6,
+                    "counters": {
+                        "started": "2024-05-01T12:00:00+00:00",
+                        "stopped": null,
+                        "uptime_secs": 3725,
+                        "uptime": "1h 2m 5s",
+                        "valid": 1000,
+                        "errors": 3,
+                        "hashrate": 1.2e12,
+                    },
+                    "failed": null,
+                },
+                { "hashboard": 7, "counters": null, "failed": "not responding" },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stuck_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let last_job = Arc::new(Mutex::new(Some(Instant::now())));
+        let rates = Arc::new(Mutex::new(vec![("cpu0".to_string(), 1e6)]));
+        let task = tokio::spawn(health_task(
+            listener,
+            last_job,
+            rates,
+            None,
+            Arc::new(PluginManager::new()),
+            Duration::from_secs(60),
+        ));
+
+        // Client that connects and never sends its request doesn't hold up the others
+        let _stuck = TcpStream::connect(address).await.unwrap();
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(2), client.read_to_string(&mut response))
+            .await
+            .expect("health check blocked by stuck client")
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        task.abort();
+    }
+}
@@ -1,5 +1,6 @@
 //! Purpose of this module: I2C bus interface defition - AsyncBus and AsyncDevice
 
+pub mod bm1387;
 #[cfg(test)]
 pub mod test_utils;
 
@@ -10,6 +11,7 @@ use std::sync::Arc;
 use futures::lock::Mutex;
 use async_compat::futures;
 
+use crate::async_i2c::{AsyncI2c, Op};
 use crate::error::{self, ErrorKind};
 
 /// Struct representing I2C address
@@ -30,6 +32,10 @@ impl Address {
     pub fn to_readable_hw_addr(&self) -> u8 {
         self.0
     }
+    /// Get 7-bit address (as used by `AsyncI2c` masters)
+    pub fn to_7bit_addr(&self) -> u8 {
+        self.0 >> 1
+    }
 }
 
 impl fmt::Display for Address {
@@ -82,6 +88,50 @@ where
     }
 }
 
+/// `AsyncBus` on top of raw I2C master, registers are accessed by writing register number first
+#[derive(Clone)]
+pub struct AsyncI2cBus {
+    i2c: Arc<dyn AsyncI2c>,
+}
+
+impl AsyncI2cBus {
+    pub fn new(i2c: Arc<dyn AsyncI2c>) -> Self {
+        Self { i2c }
+    }
+}
+
+#[async_trait]
+impl AsyncBus for AsyncI2cBus {
+    async fn read(&mut self, addr: Address, reg: u8) -> error::Result<u8> {
+        let address = addr.to_7bit_addr();
+        // Register pointer and read have to go together, other devices share the bus
+        let results = self
+            .i2c
+            .transaction(vec![
+                Op::Write {
+                    address,
+                    bytes: vec![reg],
+                },
+                Op::Read {
+                    address,
+                    num_bytes: 1,
+                },
+            ])
+            .await?;
+        match results.last().and_then(|bytes| bytes.first()) {
+            Some(val) => Ok(*val),
+            None => Err(ErrorKind::I2c(format!(
+                "nothing read from register {:#04x} of {}",
+                reg, addr
+            )))?,
+        }
+    }
+
+    async fn write(&mut self, addr: Address, reg: u8, val: u8) -> error::Result<()> {
+        self.i2c.write(addr.to_7bit_addr(), vec![reg, val]).await
+    }
+}
+
 /// `AsyncDevice` represents (async) ops on a device on I2C bus
 #[async_trait]
 pub trait AsyncDevice
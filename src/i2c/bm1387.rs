@@ -24,6 +24,8 @@ pub struct Bus<T: CommandInterface> {
     command_context: T,
     /// Chip address that has I2C bus connected
     chip_address: ChipAddress,
+    /// Which of the chip I2C buses to use
+    i2c_bus: bm1387::I2cBusSelect,
 }
 
 /// Implements misc bus commands
@@ -43,10 +45,20 @@ impl<T: CommandInterface> Bus<T> {
     pub async fn new_and_init(
         command_context: T,
         chip_address: ChipAddress,
+    ) -> error::Result<Self> {
+        Self::new_and_init_on(command_context, chip_address, bm1387::I2cBusSelect::Bottom).await
+    }
+
+    /// Make new I2C bus that talks over chip I2C bus `i2c_bus`.
+    pub async fn new_and_init_on(
+        command_context: T,
+        chip_address: ChipAddress,
+        i2c_bus: bm1387::I2cBusSelect,
     ) -> error::Result<Self> {
         let mut bus = Self {
             command_context,
             chip_address,
+            i2c_bus,
         };
         bus.start().await?;
         Ok(bus)
@@ -87,7 +99,7 @@ impl<T: CommandInterface> Bus<T> {
             .command_context
             .read_one_register::<bm1387::MiscCtrlReg>(self.chip_address)
             .await?;
-        misc.set_i2c(Some(bm1387::I2cBusSelect::Bottom));
+        misc.set_i2c(Some(self.i2c_bus));
         self.command_context
             .write_register_readback(self.chip_address, &misc)
             .await?;
@@ -230,6 +242,11 @@ mod test {
                 Some(_) => Ok(()),
             }
         }
+
+        /// Send raw command
+        async fn send_raw_command(&self, _cmd: Vec<u8>, _wait: bool) {
+            panic!("raw commands shouldn't be sent");
+        }
     }
 
     /// Test that i2c bus on chip has been correctly initialized
@@ -300,6 +317,22 @@ mod test {
         shared_regs.inner.lock().await.verify_regs_ok();
     }
 
+    #[tokio::test]
+    async fn test_hashchip_i2c_init_middle_bus() {
+        let sensor_address = ChipAddress::One(0x14);
+        let regs = CheckInit::new(sensor_address);
+        let shared_regs = SharedRegisterInterface::new(regs);
+        Bus::new_and_init_on(
+            shared_regs.clone(),
+            sensor_address,
+            bm1387::I2cBusSelect::Middle,
+        )
+        .await
+        .expect("initialization failed");
+        // Same as with the bottom bus, only the bus select bit (16) is set
+        assert_eq!(shared_regs.inner.lock().await.misc_reg, 0x40_21_41_e0);
+    }
+
     /// More convoluted test: check that:
     ///
     /// 1. read and write return expected values
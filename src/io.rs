@@ -389,6 +389,13 @@ impl WorkRx {
         self.fifo.init()
     }
 
+    /// Switch decoding of solutions to `midstate_count`. The FIFO is reset, so solutions of work
+    /// sent with the previous configuration are dropped.
+    pub fn set_midstate_count(&mut self, midstate_count: MidstateCount) -> error::Result<()> {
+        self.midstate_count = midstate_count;
+        self.init()
+    }
+
     fn new(hashboard_idx: usize, midstate_count: MidstateCount) -> error::Result<Self> {
         Ok(Self {
             fifo: WorkRxFifo::new(hashboard_idx)?,
@@ -449,6 +456,13 @@ impl WorkTx {
         self.fifo.init()
     }
 
+    /// Switch encoding of work to `midstate_count`. The FIFO is reset, so work that hasn't been
+    /// sent yet is dropped.
+    pub fn set_midstate_count(&mut self, midstate_count: MidstateCount) -> error::Result<()> {
+        self.midstate_count = midstate_count;
+        self.init()
+    }
+
     pub fn new(hashboard_idx: usize, midstate_count: MidstateCount) -> error::Result<Self> {
         Ok(Self {
             fifo: WorkTxFifo::new(hashboard_idx)?,
@@ -534,8 +548,6 @@ impl CommandRxTx {
 pub struct Common {
     /// The `common` register block itself
     regs: uio_async::UioTypedMapping<fpga_io_am1_s9::common::RegisterBlock>,
-    /// Current midstate configuration
-    midstate_count: MidstateCount,
     /// With which hashboard is this register block associated?
     /// This is required to print meaningful error messages.
     hashboard_idx: usize,
@@ -629,8 +641,8 @@ impl Common {
         Ok(())
     }
 
-    pub fn set_midstate_count(&self) {
-        self.set_ip_core_midstate_count(self.midstate_count.to_reg());
+    pub fn set_midstate_count(&self, midstate_count: MidstateCount) {
+        self.set_ip_core_midstate_count(midstate_count.to_reg());
     }
 
     fn init(&mut self) -> error::Result<()> {
@@ -642,11 +654,10 @@ impl Common {
         Ok(())
     }
 
-    fn new(hashboard_idx: usize, midstate_count: MidstateCount) -> error::Result<Self> {
+    fn new(hashboard_idx: usize) -> error::Result<Self> {
         let uio = uio::Device::open(hashboard_idx, uio::Type::Common)?;
         Ok(Self {
             regs: uio.map()?,
-            midstate_count,
             hashboard_idx,
         })
     }
@@ -664,7 +675,7 @@ impl Core {
     /// Build a new IP core
     pub fn new(hashboard_idx: usize, midstate_count: MidstateCount) -> error::Result<Self> {
         Ok(Self {
-            common_io: Common::new(hashboard_idx, midstate_count)?,
+            common_io: Common::new(hashboard_idx)?,
             command_io: CommandRxTx::new(hashboard_idx)?,
             work_rx_io: WorkRx::new(hashboard_idx, midstate_count)?,
             work_tx_io: WorkTx::new(hashboard_idx, midstate_count)?,
@@ -768,6 +779,24 @@ mod test {
         }
     }
 
+    /// Work sent with some midstate configuration has to be matched by solutions decoded with
+    /// the same configuration
+    #[test]
+    fn test_midstate_count_round_trip() {
+        for count in [1, 2, 4].iter() {
+            let midstate_count = MidstateCount::new(*count);
+            let work_id_count = ExtWorkId::get_work_id_count(midstate_count);
+            assert_eq!(work_id_count * count, 0x10000);
+
+            let work_id = work_id_count - 1;
+            let midstate_idx = midstate_count.to_mask();
+            let ext_work_id = ExtWorkId::new(work_id, midstate_idx).to_hw(midstate_count);
+            let resp = WorkRxResponse::from_hw(midstate_count, 0, ext_work_id << 8);
+            assert_eq!(resp.work_id, work_id, "midstate count {}", count);
+            assert_eq!(resp.midstate_idx, midstate_idx, "midstate count {}", count);
+        }
+    }
+
     #[test]
     fn test_version_display() {
         let version = Version {
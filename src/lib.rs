@@ -1,3 +1,5 @@
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
+
 use clap::ArgMatches;
 use std::any::Any;
 use std::error::Error as StdError;
@@ -5,6 +7,7 @@ use std::error::Error as StdError;
 pub mod xoshiro256starstar;
 use libloading::{Library, Symbol};
 
+pub mod bm1387;
 pub mod gpio;
 mod error;
 
@@ -46,6 +49,28 @@ impl PlugPin {
 pub struct PluginManager {
     plugins: Vec<Box<dyn Plugin>>,
     loaded_libraries: Vec<Library>,
+    plugin_options: Vec<PluginOptions>,
+}
+
+/// Long command line options added by one loaded plugin, they go to `[plugins.<name>]` table of
+/// configuration file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PluginOptions {
+    /// Library name without `lib`/`kaspa` prefix and extension (ie. `opencl`)
+    pub name: String,
+    pub options: Vec<String>,
+}
+
+/// Name of `[plugins.<name>]` table for plugin library at `path`
+fn plugin_table_name(path: &str) -> String {
+    let stem = std::path::Path::new(path).file_stem().and_then(|stem| stem.to_str()).unwrap_or(path);
+    let name = stem.strip_prefix("lib").unwrap_or(stem);
+    name.strip_prefix("kaspa").filter(|name| !name.is_empty()).unwrap_or(name).to_string()
+}
+
+/// Long options of all arguments of `app`
+fn long_options(app: &clap::App) -> Vec<String> {
+    app.get_arguments().filter_map(|arg| arg.get_long().map(str::to_string)).collect()
 }
 
 /**
@@ -54,7 +79,7 @@ pub struct PluginManager {
 */
 impl PluginManager {
     pub fn new() -> Self {
-        Self { plugins: Vec::new(), loaded_libraries: Vec::new() }
+        Self { plugins: Vec::new(), loaded_libraries: Vec::new(), plugin_options: Vec::new() }
     }
 
     pub(crate) unsafe fn load_single_plugin<'help>(
@@ -109,9 +134,30 @@ impl PluginManager {
         Ok(())
     }
 
+    /// Options added by each loaded plugin
+    pub fn plugin_options(&self) -> &[PluginOptions] {
+        &self.plugin_options
+    }
+
     pub fn has_specs(&self) -> bool {
         !self.plugins.is_empty()
     }
+
+    /// One line per loaded plugin with its name and whether it's enabled
+    pub fn summary(&self) -> Vec<String> {
+        self.plugins
+            .iter()
+            .map(|plugin| {
+                let state = if plugin.enabled() { "enabled" } else { "disabled" };
+                format!("{} ({})", plugin.name(), state)
+            })
+            .collect()
+    }
+
+    /// Status reported by plugins, keyed by plugin name
+    pub fn status(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.plugins.iter().filter_map(|plugin| Some((plugin.name().to_string(), plugin.status()?))).collect()
+    }
 }
 
 pub trait Plugin: Any + Send + Sync {
@@ -119,6 +165,12 @@ pub trait Plugin: Any + Send + Sync {
     fn enabled(&self) -> bool;
     fn get_worker_specs(&self) -> Vec<Box<dyn WorkerSpec>>;
     fn process_option(&mut self, matchs: &ArgMatches) -> Result<(), Error>;
+
+    /// Plugin specific status (ie. per-device clocks and temperatures) shown under the plugin
+    /// name in `/status.json`
+    fn status(&self) -> Option<serde_json::Value> {
+        None
+    }
 }
 
 pub trait WorkerSpec: Any + Send + Sync {
@@ -127,7 +179,59 @@ pub trait WorkerSpec: Any + Send + Sync {
     device_id: u32,
     workload: f32,
     is_absolute: bool*/
-    fn build(&self) -> Box<dyn Worker>;
+    /// Build worker that scans only nonces from `partition`
+    fn build(&self, partition: NoncePartition) -> Box<dyn Worker>;
+}
+
+/// Slice of the nonce space assigned to one of `count` workers so that no two workers scan the
+/// same nonces.
+///
+/// The slice is selected by the top bits of the free (masked) part of the nonce, so it is
+/// carried purely by `nonce_mask`/`nonce_fixed` and composes with however the worker fills the
+/// free bits: sequential counters as well as both `lean` (random start + thread id) and
+/// `xoshiro` (per-thread random stream) GPU nonce generators stay inside the slice.
+/// If `count` is not a power of two, the space is split into the next power of two slices and
+/// the extra ones are left unused. If the free part of the nonce is too small to be split
+/// `count` ways, slices wrap around and overlap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NoncePartition {
+    index: usize,
+    count: usize,
+}
+
+impl NoncePartition {
+    pub fn new(index: usize, count: usize) -> Self {
+        assert!(index < count, "BUG: nonce partition {} out of {}", index, count);
+        Self { index, count }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Restrict job nonce space given by `nonce_mask` (contiguous low bits) and `nonce_fixed`
+    /// to this partition
+    pub fn apply(&self, nonce_mask: u64, nonce_fixed: u64) -> (u64, u64) {
+        let free_bits = u64::BITS - nonce_mask.leading_zeros();
+        let bits = self.count.next_power_of_two().trailing_zeros().min(free_bits);
+        if bits == 0 {
+            return (nonce_mask, nonce_fixed);
+        }
+        let shift = free_bits - bits;
+        let slice = self.index as u64 & ((1 << bits) - 1);
+        (nonce_mask & ((1 << shift) - 1), (nonce_fixed & !nonce_mask) | (slice << shift))
+    }
+}
+
+impl Default for NoncePartition {
+    /// Whole nonce space
+    fn default() -> Self {
+        Self::new(0, 1)
+    }
 }
 
 pub trait Worker {
@@ -142,6 +246,18 @@ pub trait Worker {
     fn copy_output_to(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error>;
 
     fn requires_filter(&self) -> bool;
+
+    /// Stop using the device until `resume` is called. Device and buffers are kept, so the
+    /// worker can continue right away.
+    fn pause(&mut self) {}
+    fn resume(&mut self) {}
+
+    /// Whether the last failure (returned by `sync` or `copy_output_to`) means the device was reset
+    /// (ie. GPU driver restart). The worker is unusable then and has to be built again with
+    /// `WorkerSpec::build`.
+    fn device_lost(&self) -> bool {
+        false
+    }
 }
 
 pub fn load_plugins<'help>(
@@ -151,11 +267,19 @@ pub fn load_plugins<'help>(
     let mut factory = PluginManager::new();
     let mut app = app;
     for path in paths {
+        let core_options = long_options(&app);
         app = unsafe {
-            factory.load_single_plugin(app, path.as_str()).unwrap_or_else(|(app, e)| {
-                eprintln!("Failed loading plugin {}: {}", path, e);
-                app
-            })
+            match factory.load_single_plugin(app, path.as_str()) {
+                Ok(app) => {
+                    let options = long_options(&app).into_iter().filter(|long| !core_options.contains(long)).collect();
+                    factory.plugin_options.push(PluginOptions { name: plugin_table_name(path), options });
+                    app
+                }
+                Err((app, e)) => {
+                    eprintln!("Failed loading plugin {}: {}", path, e);
+                    app
+                }
+            }
         };
     }
     Ok((app, factory))
@@ -190,3 +314,94 @@ macro_rules! declare_plugin {
         }
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Default)]
+    struct FakePlugin {
+        enabled: bool,
+        status: Option<serde_json::Value>,
+    }
+
+    impl Plugin for FakePlugin {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+        fn enabled(&self) -> bool {
+            self.enabled
+        }
+        fn get_worker_specs(&self) -> Vec<Box<dyn WorkerSpec>> {
+            Vec::new()
+        }
+        fn process_option(&mut self, _matchs: &ArgMatches) -> Result<(), Error> {
+            Ok(())
+        }
+        fn status(&self) -> Option<serde_json::Value> {
+            self.status.clone()
+        }
+    }
+
+    #[test]
+    fn test_plugin_summary() {
+        let mut manager = PluginManager::new();
+        assert!(manager.summary().is_empty());
+        manager.plugins.push(Box::new(FakePlugin { enabled: true, ..Default::default() }));
+        manager.plugins.push(Box::new(FakePlugin { enabled: false, ..Default::default() }));
+        assert_eq!(manager.summary(), vec!["fake (enabled)", "fake (disabled)"]);
+    }
+
+    #[test]
+    fn test_plugin_status() {
+        let mut manager = PluginManager::new();
+        manager.plugins.push(Box::new(FakePlugin::default()));
+        assert!(manager.status().is_empty());
+        let gpus = serde_json::json!([{"device": 0, "temperature": 65}]);
+        manager.plugins.push(Box::new(FakePlugin { enabled: true, status: Some(gpus.clone()) }));
+        assert_eq!(manager.status().get("fake"), Some(&gpus));
+    }
+
+    #[test]
+    fn test_plugin_table_name() {
+        assert_eq!(plugin_table_name("/opt/kasop/libkaspaopencl.so"), "opencl");
+        assert_eq!(plugin_table_name("kaspacuda.dll"), "cuda");
+        assert_eq!(plugin_table_name("libmyplugin.so"), "myplugin");
+        assert_eq!(plugin_table_name("libkaspa.so"), "kaspa");
+    }
+
+    /// Enumerate all nonces partition scans in job space given by `mask` and `fixed`
+    fn scanned(partition: NoncePartition, mask: u64, fixed: u64) -> Vec<u64> {
+        let (mask, fixed) = partition.apply(mask, fixed);
+        (0..=mask).map(|nonce| (nonce & mask) | fixed).collect()
+    }
+
+    #[test]
+    fn test_nonce_partition_disjoint() {
+        let mask = 0xff;
+        let fixed = 0xab00;
+        for count in 1..=8 {
+            let mut all = (0..count)
+                .flat_map(|index| scanned(NoncePartition::new(index, count), mask, fixed))
+                .collect::<Vec<u64>>();
+            let total = all.len();
+            all.sort_unstable();
+            all.dedup();
+            // no overlap
+            assert_eq!(all.len(), total, "overlap for {} workers", count);
+            // every nonce stays in job space
+            assert!(all.iter().all(|nonce| nonce & !mask == fixed));
+            // power of two worker counts cover the space exactly
+            if count.is_power_of_two() {
+                assert_eq!(total, 0x100);
+            }
+        }
+    }
+
+    #[test]
+    fn test_nonce_partition_apply() {
+        assert_eq!(NoncePartition::default().apply(u64::MAX, 0), (u64::MAX, 0));
+        assert_eq!(NoncePartition::new(1, 2).apply(u64::MAX, 0), (u64::MAX >> 1, 1 << 63));
+        assert_eq!(NoncePartition::new(2, 3).apply(0xffff, 0x1_0000), (0x3fff, 0x1_8000));
+    }
+}
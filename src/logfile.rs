@@ -0,0 +1,174 @@
+//! Log file sink with size based rotation
+//!
+//! Records are handed over to a background thread, so logging never waits for the disk.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+
+/// How many records may wait for the writer thread before new ones are dropped
+const QUEUE_LENGTH: usize = 1024;
+
+/// File that is rotated when it would grow over `max_size` bytes: `log` is renamed to `log.1`,
+/// `log.1` to `log.2` etc. and at most `keep` rotated files are kept.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open<P: AsRef<Path>>(path: P, max_size: u64, keep: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, max_size, keep, file, size })
+    }
+
+    fn rotated_path(&self, idx: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", idx));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep > 0 {
+            for idx in (1..self.keep).rev() {
+                let from = self.rotated_path(idx);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(idx + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writer that queues data for a background thread. If the thread can't keep up, data is
+/// dropped instead of blocking the caller.
+#[derive(Clone)]
+pub struct NonBlocking {
+    tx: SyncSender<Vec<u8>>,
+}
+
+impl Write for NonBlocking {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.tx.try_send(buf.to_vec()) {
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(buf.len()),
+            Err(TrySendError::Disconnected(_)) => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "log writer has ended"))
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Move `writer` to a background thread. The thread ends when all `NonBlocking` handles are
+/// dropped.
+pub fn spawn<W: Write + Send + 'static>(mut writer: W) -> (NonBlocking, thread::JoinHandle<()>) {
+    let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(QUEUE_LENGTH);
+    let handle = thread::spawn(move || {
+        for buf in rx {
+            if let Err(e) = writer.write_all(&buf).and_then(|_| writer.flush()) {
+                eprintln!("Failed writing log file: {}", e);
+            }
+        }
+    });
+    (NonBlocking { tx }, handle)
+}
+
+/// Writer that duplicates everything to two writers
+pub struct Tee<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: Write, B: Write> Tee<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.first.write_all(buf)?;
+        self.second.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kasop-logfile-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_lines_reach_file() {
+        let dir = test_dir("lines");
+        let path = dir.join("kasop.log");
+        let (writer, handle) = spawn(RotatingFile::open(&path, 1024 * 1024, 2).unwrap());
+
+        let mut tee = Tee::new(Vec::new(), writer);
+        writeln!(tee, "Current hashrate is: 1.00 Ghash/s").unwrap();
+        writeln!(tee, "Found a share!").unwrap();
+        drop(tee);
+        handle.join().unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "Current hashrate is: 1.00 Ghash/s\nFound a share!\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = test_dir("rotation");
+        let path = dir.join("kasop.log");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["line 1\n", "line 2\n", "line 3\n", "line 4\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        drop(file);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 4\n");
+        assert_eq!(fs::read_to_string(dir.join("kasop.log.1")).unwrap(), "line 3\n");
+        assert_eq!(fs::read_to_string(dir.join("kasop.log.2")).unwrap(), "line 2\n");
+        assert!(!dir.join("kasop.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
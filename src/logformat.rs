@@ -0,0 +1,168 @@
+//! Log line formats
+//!
+//! Besides the default human readable `env_logger` format, logs can be emitted as JSON lines for
+//! log aggregation. Key-value pairs attached to records (ie. `info!(job_id = id; "Share accepted")`)
+//! become fields of the JSON object.
+//!
+//! Records about individual shares carry `share` key, which lets `--quiet` drop them without
+//! raising the log level for everything else.
+
+use log::kv::{self, Key, Source, Value, Visitor};
+use log::{Level, Log, Metadata, Record};
+use serde_json::{Map, Value as JsonValue};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', expected 'text' or 'json'", s)),
+        }
+    }
+}
+
+/// Collects key-value pairs of record into JSON fields
+struct FieldVisitor<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs, 'a> Visitor<'kvs> for FieldVisitor<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(b) = value.to_bool() {
+            JsonValue::from(b)
+        } else if let Some(n) = value.to_u64() {
+            JsonValue::from(n)
+        } else if let Some(n) = value.to_i64() {
+            JsonValue::from(n)
+        } else if let Some(n) = value.to_f64() {
+            JsonValue::from(n)
+        } else {
+            JsonValue::from(value.to_string())
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+/// Format `record` as single line JSON object
+pub fn json_line(record: &Record, timestamp: &str) -> String {
+    let mut fields = Map::new();
+    fields.insert("timestamp".into(), timestamp.into());
+    fields.insert("level".into(), record.level().as_str().into());
+    fields.insert("target".into(), record.target().into());
+    fields.insert("message".into(), record.args().to_string().into());
+    // Standard keys can't be overridden by fields, the visitor never fails
+    let mut extra = Map::new();
+    let _ = record.key_values().visit(&mut FieldVisitor(&mut extra));
+    for (key, value) in extra {
+        fields.entry(key).or_insert(value);
+    }
+    JsonValue::Object(fields).to_string()
+}
+
+/// Whether `record` is an informational line about individual share (`--quiet` drops those),
+/// warnings and errors about shares are never considered chatter
+pub fn is_share_chatter(record: &Record) -> bool {
+    record.level() > Level::Warn && record.key_values().get(Key::from("share")).is_some()
+}
+
+/// Logger that passes everything but share chatter to `inner` logger
+pub struct QuietLogger<L> {
+    inner: L,
+}
+
+impl<L: Log> QuietLogger<L> {
+    pub fn new(inner: L) -> Self {
+        Self { inner }
+    }
+}
+
+impl<L: Log> Log for QuietLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !is_share_chatter(record) {
+            self.inner.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Remembers messages of records it's given
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Log for Recorder {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_json_line() {
+        let fields: &[(&str, &str)] = &[("job_id", "1a2b"), ("result", "accepted")];
+        let record = Record::builder()
+            .args(format_args!("Share accepted"))
+            .level(Level::Info)
+            .target("kasop::client::stratum")
+            .key_values(&fields)
+            .build();
+        let line = json_line(&record, "2022-03-01T10:00:00Z");
+        assert!(!line.contains('\n'));
+
+        let parsed: JsonValue = serde_json::from_str(&line).expect("not a valid JSON");
+        assert_eq!(parsed["timestamp"], "2022-03-01T10:00:00Z");
+        assert_eq!(parsed["level"], "INFO");
+        assert_eq!(parsed["target"], "kasop::client::stratum");
+        assert_eq!(parsed["message"], "Share accepted");
+        assert_eq!(parsed["job_id"], "1a2b");
+        assert_eq!(parsed["result"], "accepted");
+    }
+
+    #[test]
+    fn test_quiet_logger() {
+        let logger = QuietLogger::new(Recorder::default());
+        let accepted: &[(&str, &str)] = &[("share", "accepted"), ("job_id", "1a2b")];
+        let stale: &[(&str, &str)] = &[("share", "stale"), ("job_id", "1a2b")];
+        logger.log(
+            &Record::builder().args(format_args!("Share accepted")).level(Level::Info).key_values(&accepted).build(),
+        );
+        logger.log(&Record::builder().args(format_args!("Stale share")).level(Level::Warn).key_values(&stale).build());
+        logger.log(&Record::builder().args(format_args!("Got error code 24")).level(Level::Error).build());
+        logger.log(&Record::builder().args(format_args!("Shares: 12 accepted")).level(Level::Info).build());
+        assert_eq!(
+            *logger.inner.0.lock().unwrap(),
+            vec!["Stale share", "Got error code 24", "Shares: 12 accepted"]
+        );
+    }
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!("text".parse(), Ok(LogFormat::Text));
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,34 +1,42 @@
 #![cfg_attr(all(test, feature = "bench"), feature(test))]
 
-use std::env::consts::DLL_EXTENSION;
 use std::env::current_exe;
 use std::error::Error as StdError;
-use std::ffi::OsStr;
 
 use clap::{App, FromArgMatches, IntoApp};
 use kasop::PluginManager;
-use log::{error, info};
+use log::{debug, error, info, warn};
 use rand::{thread_rng, RngCore};
-use std::fs;
+use std::io::Write;
 use std::sync::atomic::AtomicU16;
 use std::sync::Arc;
 use std::thread::sleep;
 use std::time::Duration;
 
-use crate::cli::Opt;
+use crate::cli::{Devfund, Opt};
 use crate::client::grpc::KaspadHandler;
 use crate::client::stratum::StratumHandler;
-use crate::client::Client;
+use crate::client::{Client, ClientError, DevfundSchedule, Phase, SubmitRetry, Unanswered};
 use crate::miner::MinerManager;
 use crate::target::Uint256;
 
+mod address;
+mod bench;
 mod cli;
 mod client;
+mod config;
+mod dashboard;
+mod events;
+mod health;
+mod logfile;
+mod logformat;
 mod kaspad_messages;
 mod miner;
+mod plugins;
 mod pow;
 mod target;
 mod watch;
+mod webhook;
 
 pub mod async_i2c;
 pub mod counters;
@@ -41,156 +49,15 @@ pub mod gpio;
 pub mod power;
 pub mod sensor;
 pub mod halt;
+pub mod hashchain;
 pub mod monitor;
 pub mod fan;
-
-use bm1387::{ChipAddress, MidstateCount};
-
-use embedded_hal::digital::v2::InputPin;
-use embedded_hal::digital::v2::OutputPin;
-
-use error::ErrorKind;
-use failure::ResultExt;
+pub mod supervisor;
 
 use futures::channel::mpsc;
-use futures::lock::{Mutex, MutexGuard};
 use futures::stream::StreamExt;
 use async_compat::futures;
 
-/// Timing constants
-const INACTIVATE_FROM_CHAIN_DELAY: Duration = Duration::from_millis(100);
-/// Base delay quantum during hashboard initialization
-const INIT_DELAY: Duration = Duration::from_secs(1);
-/// Time to wait between successive hashboard initialization attempts
-const ENUM_RETRY_DELAY: Duration = Duration::from_secs(10);
-/// How many times to retry the enumeration
-const ENUM_RETRY_COUNT: usize = 10;
-
-/// Maximum number of chips is limitted by the fact that there is only 8-bit address field and
-/// addresses to the chips need to be assigned with step of 4 (e.g. 0, 4, 8, etc.)
-pub const MAX_CHIPS_ON_CHAIN: usize = 64;
-/// Number of chips to consider OK for initialization
-pub const EXPECTED_CHIPS_ON_CHAIN: usize = 63;
-
-/// Oscillator speed for all chips on S9 hash boards
-pub const CHIP_OSC_CLK_HZ: usize = 25_000_000;
-
-/// Exact value of the initial baud rate after reset of the hashing chips.
-const INIT_CHIP_BAUD_RATE: usize = 115740;
-/// Exact desired target baud rate when hashing at full speed (matches the divisor, too)
-const TARGET_CHIP_BAUD_RATE: usize = 1562500;
-
-/// Address of chip with connected temp sensor
-const TEMP_CHIP: ChipAddress = ChipAddress::One(61);
-
-/// Timeout for completion of haschain halt
-const HALT_TIMEOUT: Duration = Duration::from_secs(30);
-
-/// Core address space size (it should be 114, but the addresses are non-consecutive)
-const CORE_ADR_SPACE_SIZE: usize = 128;
-
-/// Power type alias
-/// TODO: Implement it as a proper type (not just alias)
-pub type Power = usize;
-
-/// Type representing plug pin
-#[derive(Clone)]
-pub struct PlugPin {
-    pin: gpio::PinIn,
-}
-
-impl PlugPin {
-    pub fn open(gpio_mgr: &gpio::ControlPinManager, hashboard_idx: usize) -> error::Result<Self> {
-        Ok(Self {
-            pin: gpio_mgr
-                .get_pin_in(gpio::PinInName::Plug(hashboard_idx))
-                .context(ErrorKind::Hashboard(
-                    hashboard_idx,
-                    "failed to initialize plug pin".to_string(),
-                ))?,
-        })
-    }
-
-    pub fn hashboard_present(&self) -> error::Result<bool> {
-        Ok(self.pin.is_high()?)
-    }
-}
-
-/// Type representing reset pin
-#[derive(Clone)]
-pub struct ResetPin {
-    pin: gpio::PinOut,
-}
-
-impl ResetPin {
-    pub fn open(gpio_mgr: &gpio::ControlPinManager, hashboard_idx: usize) -> error::Result<Self> {
-        Ok(Self {
-            pin: gpio_mgr
-                .get_pin_out(gpio::PinOutName::Rst(hashboard_idx))
-                .context(ErrorKind::Hashboard(
-                    hashboard_idx,
-                    "failed to initialize reset pin".to_string(),
-                ))?,
-        })
-    }
-
-    pub fn enter_reset(&mut self) -> error::Result<()> {
-        self.pin.set_low()?;
-        Ok(())
-    }
-
-    pub fn exit_reset(&mut self) -> error::Result<()> {
-        self.pin.set_high()?;
-        Ok(())
-    }
-}
-
-/// Hash Chain Controller provides abstraction of the FPGA interface for operating hashing boards.
-/// It is the user-space driver for the IP Core
-///
-/// Main responsibilities:
-/// - memory mapping of the FPGA control interface
-/// - mining work submission and solution processing
-///
-/// TODO: disable voltage controller via async `Drop` trait (which doesn't exist yet)
-pub struct HashChain {
-    /// Number of chips that have been detected
-    chip_count: usize,
-    /// Eliminates the need to query the IP core about the current number of configured midstates
-    midstate_count: MidstateCount,
-    /// ASIC difficulty
-    asic_difficulty: usize,
-    /// ASIC target (matches difficulty)
-    asic_target: crate::target::Uint256,
-    /// Voltage controller on this hashboard
-    voltage_ctrl: Arc<power::Control>,
-    /// Pin for resetting the hashboard
-    reset_pin: ResetPin,
-    hashboard_idx: usize,
-    pub command_context: command::Context,
-    pub common_io: io::Common,
-    work_rx_io: Mutex<Option<io::WorkRx>>,
-    work_tx_io: Mutex<Option<io::WorkTx>>,
-    monitor_tx: mpsc::UnboundedSender<monitor::Message>,
-    /// Do not send open-core work if this is true (some tests that test chip initialization may
-    /// want to do this).
-    disable_init_work: bool,
-    /// channels through which temperature status is sent
-    temperature_sender: Mutex<Option<watch::Sender<Option<sensor::Temperature>>>>,
-    temperature_receiver: watch::Receiver<Option<sensor::Temperature>>,
-    /// nonce counter
-    pub counter: Arc<Mutex<counters::HashChain>>,
-    /// halter to stop this hashchain
-    halt_sender: Arc<halt::Sender>,
-    /// we need to keep the halt receiver around, otherwise the "stop-notify" channel closes when chain ends
-    #[allow(dead_code)]
-    halt_receiver: halt::Receiver,
-    /// Current hashchain settings
-    frequency: Mutex<FrequencySettings>,
-}
-
-const WHITELIST: [&str; 2] = ["libkaspauart", "kaspauart"];
-
 pub mod proto {
     tonic::include_proto!("protowire");
     // include!("protowire.rs"); // FIXME: https://github.com/intellij-rust/intellij-rust/issues/6579
@@ -200,26 +67,15 @@ pub type Error = Box<dyn StdError + Send + Sync + 'static>;
 
 type Hash = Uint256;
 
-fn filter_plugins(dirname: &str) -> Vec<String> {
-    match fs::read_dir(dirname) {
-        Ok(readdir) => readdir
-            .map(|entry| entry.unwrap().path())
-            .filter(|fname| {
-                fname.is_file()
-                    && fname.extension().is_some()
-                    && fname.extension().and_then(OsStr::to_str).unwrap_or_default().starts_with(DLL_EXTENSION)
-            })
-            .filter(|fname| WHITELIST.iter().any(|lib| *lib == fname.file_stem().and_then(OsStr::to_str).unwrap()))
-            .map(|path| path.to_str().unwrap().to_string())
-            .collect::<Vec<String>>(),
-        _ => Vec::<String>::new(),
-    }
-}
+/// Status of hashboard monitor (temperatures, fans, hashboards), see `start_hardware`
+type MonitorStatusReceiver = async_compat::tokio::sync::watch::Receiver<Option<monitor::Status>>;
 
 async fn get_client(
     kaspad_address: String,
     mining_address: String,
+    worker_name: String,
     mine_when_not_synced: bool,
+    min_share_target: Option<Uint256>,
     block_template_ctr: Arc<AtomicU16>,
 ) -> Result<Box<dyn Client + 'static>, Error> {
     if kaspad_address.starts_with("stratum+tcp://") {
@@ -227,7 +83,9 @@ async fn get_client(
         Ok(StratumHandler::connect(
             address.to_string().clone(),
             mining_address.clone(),
+            worker_name,
             mine_when_not_synced,
+            min_share_target,
             Some(block_template_ctr.clone()),
         )
         .await?)
@@ -239,110 +97,370 @@ async fn get_client(
             Some(block_template_ctr.clone()),
         )
         .await?)
+    } else if let Some(path) = kaspad_address.strip_prefix("grpc+unix://") {
+        connect_unix(path, mining_address, mine_when_not_synced, block_template_ctr).await
     } else {
         Err("Did not recognize pool/grpc address schema".into())
     }
 }
 
+#[cfg(unix)]
+async fn connect_unix(
+    path: &str,
+    mining_address: String,
+    mine_when_not_synced: bool,
+    block_template_ctr: Arc<AtomicU16>,
+) -> Result<Box<dyn Client + 'static>, Error> {
+    Ok(KaspadHandler::connect_unix(path, mining_address, mine_when_not_synced, Some(block_template_ctr)).await?)
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(
+    path: &str,
+    _mining_address: String,
+    _mine_when_not_synced: bool,
+    _block_template_ctr: Arc<AtomicU16>,
+) -> Result<Box<dyn Client + 'static>, Error> {
+    Err(format!("cannot connect to {}, Unix domain sockets are not supported on this platform", path).into())
+}
+
 async fn client_main(
     opt: &Opt,
     block_template_ctr: Arc<AtomicU16>,
-    plugin_manager: &PluginManager,
+    plugin_manager: &Arc<PluginManager>,
+    miner_manager: &mut Option<MinerManager>,
+    unanswered: &mut Vec<Unanswered>,
+    health_listener: &mut Option<std::net::TcpListener>,
+    hashboards: &Option<Hashboards>,
 ) -> Result<(), Error> {
     let mut client = get_client(
         opt.kaspad_address.clone(),
         opt.mining_address.clone(),
+        opt.worker_name(),
         opt.mine_when_not_synced,
+        opt.min_share_target(),
         block_template_ctr.clone(),
     )
-    .await?;
+    .await
+    .map_err(|e| ClientError::new(&opt.kaspad_address, Phase::Connect, e))?;
 
     if opt.devfund_percent > 0 {
-        client.add_devfund(opt.devfund_address.clone(), opt.devfund_percent);
+        client.add_devfund(opt.devfund_address.clone(), DevfundSchedule::new(opt.devfund_percent, opt.devfund_window));
     }
-    client.register().await?;
-    let mut miner_manager = MinerManager::new(client.get_block_channel(), opt.num_threads, plugin_manager);
-    client.listen(&mut miner_manager).await?;
-    drop(miner_manager);
+    client.set_submit_retry(SubmitRetry { max_retries: opt.submit_retries, ..Default::default() });
+    client.register().await.map_err(|e| ClientError::new(&opt.kaspad_address, Phase::Register, e))?;
+    events::emit(events::Event::Connected { address: opt.kaspad_address.clone() });
+    // Submissions the previous connection didn't get response to might have never arrived
+    client
+        .resubmit(std::mem::take(unanswered))
+        .await
+        .map_err(|e| ClientError::new(&opt.kaspad_address, Phase::Register, e))?;
+    // Workers survive reconnects (re-initializing GPUs is slow), only the block channel is swapped
+    let miner_manager = match miner_manager {
+        Some(miner_manager) => {
+            miner_manager.set_client_channel(client.get_block_channel());
+            miner_manager.resume();
+            miner_manager
+        }
+        None => {
+            let miner_manager = miner_manager.insert(MinerManager::new(
+                client.get_block_channel(),
+                opt.num_threads,
+                opt.cpu_threads,
+                plugin_manager,
+                Duration::from_secs(opt.hashrate_interval),
+                opt.submit_coalesce(),
+            ));
+            miner_manager.set_job_listener(hashboards.as_ref().and_then(|hashboards| hashboards.new_jobs.clone()));
+            miner_manager
+        }
+    };
+    miner_manager.set_connect_latency(client.connect_latency());
+    if opt.dashboard {
+        miner_manager.spawn_dashboard(hashboards.as_ref().map(|hashboards| hashboards.monitor_status.clone()));
+    }
+    if let Some(listener) = health_listener.take() {
+        let monitor_status = hashboards.as_ref().map(|hashboards| hashboards.monitor_status.clone());
+        miner_manager.spawn_health(listener, monitor_status, Arc::clone(plugin_manager), opt.health_max_job_age())?;
+    }
+    let res = match opt.no_job_timeout() {
+        Some(timeout) => {
+            let watchdog = miner_manager.job_watchdog(timeout);
+            tokio::select! {
+                res = client.listen(miner_manager) => res,
+                starved = watchdog.starved() => Err(starved),
+            }
+        }
+        None => client.listen(miner_manager).await,
+    };
+    unanswered.extend(client.take_unanswered());
+    res.map_err(|e| ClientError::new(&opt.kaspad_address, Phase::Listen, e))?;
     Ok(())
 }
 
+/// Connect and register with the pool/node, without starting workers
+async fn dry_run(opt: &Opt, block_template_ctr: Arc<AtomicU16>) -> Result<(), Error> {
+    let mut client = get_client(
+        opt.kaspad_address.clone(),
+        opt.mining_address.clone(),
+        opt.worker_name(),
+        opt.mine_when_not_synced,
+        opt.min_share_target(),
+        block_template_ctr,
+    )
+    .await
+    .map_err(|e| ClientError::new(&opt.kaspad_address, Phase::Connect, e))?;
+
+    if opt.devfund_percent > 0 {
+        client.add_devfund(opt.devfund_address.clone(), DevfundSchedule::new(opt.devfund_percent, opt.devfund_window));
+    }
+    client.register().await.map_err(|e| ClientError::new(&opt.kaspad_address, Phase::Register, e))?;
+    Ok(())
+}
+
+/// Hashboards started by `start_hardware`, as seen by the miner
+struct Hashboards {
+    /// Status of hashboard monitor (for dashboard)
+    monitor_status: MonitorStatusReceiver,
+    /// New jobs are announced here when hashboards reset counters on them (`--reset-counters-on-job`)
+    new_jobs: Option<mpsc::UnboundedSender<()>>,
+}
+
+/// Hardware drivers (halt, monitor, hashboards) run on tokio 0.2, so they get a runtime (and
+/// thread) of their own. Termination signals are hooked there to halt the miner with
+/// `halt_sender`, `halted` is notified when that's done. Hashboards given by `--hashboard` are
+/// started as well.
+async fn start_hardware(
+    opt: &Opt,
+    halt_sender: Arc<halt::Sender>,
+    halt_receiver: halt::Receiver,
+    halted: mpsc::UnboundedSender<()>,
+) -> Result<Option<Hashboards>, Error> {
+    let hashboards = opt.hashboards.clone();
+    let with_hashboards = !hashboards.is_empty();
+    let config = opt.hashboard_config();
+    let (status_tx, status_rx) = tokio::sync::oneshot::channel();
+    let (new_jobs, new_jobs_rx) = if opt.reset_counters_on_job {
+        let (new_jobs, new_jobs_rx) = mpsc::unbounded();
+        (Some(new_jobs), Some(new_jobs_rx))
+    } else {
+        (None, None)
+    };
+    let mut runtime = async_compat::tokio::runtime::Runtime::new()?;
+    std::thread::Builder::new().name("hardware".to_string()).spawn(move || {
+        runtime.block_on(async move {
+            halt_sender.clone().hook_termination_signals(halted);
+            if with_hashboards {
+                hashchain::run_hashboards(hashboards, config, halt_sender, halt_receiver, status_tx, new_jobs_rx).await;
+            }
+            // Halt and hashboard tasks run on this runtime
+            futures::future::pending::<()>().await
+        })
+    })?;
+    if !with_hashboards {
+        return Ok(None);
+    }
+    let monitor_status = status_rx
+        .await
+        .map_err(|_| "hashboard monitor failed to start")?
+        .map_err(|e| format!("hashboard monitor failed to start: {}", e))?;
+    Ok(Some(Hashboards { monitor_status, new_jobs }))
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let mut path = current_exe().unwrap_or_default();
     path.pop(); // Getting the parent directory
-    let plugins = filter_plugins(path.to_str().unwrap_or("."));
+    let raw_args: Vec<String> = std::env::args().collect();
+    let mut plugin_dirs = vec![path.to_str().unwrap_or(".").to_string()];
+    plugin_dirs.extend(config::early_option_values(&raw_args, "plugin-dir")?);
+    let whitelist = plugins::plugin_whitelist(&config::early_option_values(&raw_args, "plugin-allow")?)?;
+    let plugins = plugins::find_plugins(&plugin_dirs, &whitelist);
     let (app, mut plugin_manager): (App, PluginManager) =
         kasop::load_plugins(Opt::into_app().term_width(120), &plugins)?;
 
-    let matches = app.get_matches();
+    let args = config::merge_args(&app, plugin_manager.plugin_options(), raw_args)?;
+    let matches = app.get_matches_from(args);
 
     plugin_manager.process_options(&matches)?;
     let mut opt: Opt = Opt::from_arg_matches(&matches)?;
     opt.process()?;
-    env_logger::builder().filter_level(opt.log_level()).parse_default_env().init();
-    info!("Found plugins: {:?}", plugins);
-
-    let block_template_ctr = Arc::new(AtomicU16::new((thread_rng().next_u64() % 10_000u64) as u16));
-    if opt.devfund_percent > 0 {
-        info!(
-            "devfund enabled, mining {}.{}% of the time to devfund address: {} ",
-            opt.devfund_percent / 100,
-            opt.devfund_percent % 100,
-            opt.devfund_address
-        );
+    let mut logger = env_logger::builder();
+    logger.filter_level(opt.log_level()).parse_default_env();
+    if opt.log_format == logformat::LogFormat::Json {
+        logger.format(|buf, record| {
+            writeln!(buf, "{}", logformat::json_line(record, &chrono::Utc::now().to_rfc3339()))
+        });
     }
-    loop {
-        match client_main(&opt, block_template_ctr.clone(), &plugin_manager).await {
-            Ok(_) => info!("Client closed gracefully"),
-            Err(e) => error!("Client closed with error {:?}", e),
-        }
-        info!("Client closed, reconnecting");
-        sleep(Duration::from_millis(100));
+    if let Some(log_file) = &opt.log_file {
+        let file = logfile::RotatingFile::open(log_file, opt.log_file_size * 1024 * 1024, opt.log_file_keep)
+            .map_err(|e| format!("cannot open log file {}: {}", log_file, e))?;
+        let (file_writer, _) = logfile::spawn(file);
+        logger.target(env_logger::Target::Pipe(Box::new(logfile::Tee::new(std::io::stderr(), file_writer))));
+    }
+    if opt.quiet {
+        let logger = logger.build();
+        log::set_max_level(logger.filter());
+        log::set_boxed_logger(Box::new(logformat::QuietLogger::new(logger)))?;
+    } else {
+        logger.init();
+    }
+    if let Some(config) = &opt.config {
+        info!("Loaded options from {}", config);
+    }
+    if let Some(watts) = opt.power_limit_watts {
+        info!("Power limit: {:.0} W per hashboard", watts);
+    }
+    info!("Temperature sensor: {}", opt.sensor_config());
+    tokio::spawn(events::log_task());
+    let webhook = opt.webhook_url.as_ref().map(|url| {
+        info!("Posting critical events and found blocks to webhook {}", url);
+        tokio::spawn(webhook::webhook_task(url.clone(), opt.webhook_interval()))
+    });
+    if let Some(midstates) = opt.midstates {
+        info!("Midstates: {}", midstates.to_count());
+    }
+    debug!("Found plugins: {:?}", plugins);
+    for plugin in plugin_manager.summary() {
+        info!("Loaded plugin {}", plugin);
+    }
+    // Shared with the status endpoint
+    let plugin_manager = Arc::new(plugin_manager);
+    let num_threads = miner::resolve_num_threads(opt.num_threads);
+    let cpu_threads = num_threads.saturating_add(opt.cpu_threads);
+    if let Err(e) = plugins::check_workers(&plugin_dirs.join(", "), &whitelist, &plugin_manager, cpu_threads) {
+        error!("{}", e);
+        return Err(e);
     }
-}
-
-type Frequency = usize;
-
-#[derive(Clone)]
-pub struct FrequencySettings {
-    pub chip: Vec<Frequency>,
-}
 
-impl FrequencySettings {
-    /// Build frequency settings with all chips having the same frequency
-    pub fn from_frequency(frequency: usize) -> Self {
-        Self {
-            chip: vec![frequency; EXPECTED_CHIPS_ON_CHAIN],
+    let block_template_ctr = Arc::new(AtomicU16::new((thread_rng().next_u64() % opt.devfund_window as u64) as u16));
+    match opt.devfund() {
+        Devfund::Enabled { percent, address } => info!(
+            "devfund enabled, mining {}.{}% of the time (every {} templates) to devfund address: {} ",
+            percent / 100,
+            percent % 100,
+            opt.devfund_window,
+            address
+        ),
+        Devfund::Disabled => info!("devfund disabled (--no-devfund)"),
+        // Devfund address is a mainnet one, there's nothing to mine to on testnet
+        Devfund::ZeroPercent { .. } if opt.testnet => info!("devfund disabled on testnet"),
+        Devfund::ZeroPercent { address } => {
+            warn!("devfund percent is 0 but devfund address {} is set, nothing will be mined to it", address)
         }
     }
-
-    pub fn set_chip_count(&mut self, chip_count: usize) {
-        assert!(self.chip.len() >= chip_count);
-        self.chip.resize(chip_count, 0);
+    if opt.bench {
+        return bench::bench(&opt, &plugin_manager).await;
     }
-
-    pub fn total(&self) -> u64 {
-        self.chip.iter().fold(0, |total_f, &f| total_f + f as u64)
+    if opt.dry_run {
+        return match dry_run(&opt, block_template_ctr).await {
+            Ok(()) => {
+                info!("Dry run succeeded: options are valid and {} accepted registration", opt.kaspad_address);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Dry run failed: {}", e);
+                Err(e)
+            }
+        };
     }
 
-    #[allow(dead_code)]
-    pub fn min(&self) -> usize {
-        *self.chip.iter().min().expect("BUG: no chips on chain")
+    let (halt_sender, halt_receiver) = halt::make_pair(opt.halt_timeout());
+    let (halted, mut halted_rx) = mpsc::unbounded();
+    let hashboards = start_hardware(&opt, halt_sender, halt_receiver, halted).await?;
+    // Bound right away, so that a taken port is reported before mining starts
+    let mut health_listener = match &opt.health_listen {
+        Some(address) => Some(
+            std::net::TcpListener::bind(address)
+                .map_err(|e| format!("cannot listen for health checks on {}: {}", address, e))?,
+        ),
+        None => None,
+    };
+    let mut miner_manager = None;
+    let mut unanswered = Vec::new();
+    let mine = async {
+        loop {
+            let res = client_main(
+                &opt,
+                block_template_ctr.clone(),
+                &plugin_manager,
+                &mut miner_manager,
+                &mut unanswered,
+                &mut health_listener,
+                &hashboards,
+            )
+            .await;
+            let error = match res {
+                Ok(_) => {
+                    info!("Client closed gracefully");
+                    None
+                }
+                Err(e) => {
+                    error!("Client closed with error: {}", e);
+                    Some(e.to_string())
+                }
+            };
+            events::emit(events::Event::Disconnected { address: opt.kaspad_address.clone(), error });
+            // Don't waste power on a job nobody can take until the client is back
+            if let Some(miner_manager) = &miner_manager {
+                miner_manager.pause();
+            }
+            info!("Client closed, reconnecting");
+            sleep(Duration::from_millis(100));
+        }
+    };
+    tokio::select! {
+        _ = mine => {}
+        _ = halted_rx.next() => info!("Miner halted, exiting"),
     }
-
-    #[allow(dead_code)]
-    pub fn max(&self) -> usize {
-        *self.chip.iter().max().expect("BUG: no chips on chain")
+    if let Some(webhook) = webhook {
+        // Let the webhook post that the miner halted
+        if tokio::time::timeout(webhook::POST_TIMEOUT, webhook).await.is_err() {
+            warn!("Webhook: giving up on posting halt");
+        }
     }
+    Ok(())
+}
 
-    pub fn avg(&self) -> usize {
-        assert!(self.chip.len() > 0, "BUG: no chips on chain");
-        let sum: u64 = self.chip.iter().map(|frequency| *frequency as u64).sum();
-        (sum / self.chip.len() as u64) as usize
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn test_dry_run_unreachable_address() {
+        let address = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let mut opt = Opt::try_parse_from(["kasop", "-a", address, "-s", "ftp://127.0.0.1:5555", "--dry-run"])
+            .expect("parsing failed");
+        opt.process().expect("processing failed");
+        assert!(opt.dry_run);
+        let err = dry_run(&opt, Arc::new(AtomicU16::new(0))).await.unwrap_err();
+        assert!(err.to_string().starts_with("failed to connect to ftp://127.0.0.1:5555"));
     }
 
-    fn pretty_frequency(freq: usize) -> String {
-        format!("{:.01} MHz", (freq as f32) / 1_000_000.0)
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_schema() {
+        let address = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let connect = |kaspad_address: &str| {
+            get_client(
+                kaspad_address.to_string(),
+                address.to_string(),
+                "worker".to_string(),
+                false,
+                None,
+                Arc::new(AtomicU16::new(0)),
+            )
+        };
+        let socket = std::env::temp_dir().join(format!("kasop-missing-{}.sock", std::process::id()));
+        let err = connect(&format!("grpc+unix://{}", socket.display())).await.err().expect("connected to nothing");
+        assert_eq!(err.to_string(), format!("socket {} does not exist, is kaspad running?", socket.display()));
+
+        // Socket exists but nobody speaks gRPC on it
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        drop(listener);
+        let err = connect(&format!("grpc+unix://{}", socket.display())).await.err().expect("connected to nothing");
+        assert!(!err.to_string().contains("schema"), "{}", err);
+        std::fs::remove_file(&socket).unwrap();
     }
-}
\ No newline at end of file
+}
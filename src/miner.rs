@@ -1,20 +1,35 @@
+use std::collections::{HashSet, VecDeque};
 use std::num::Wrapping;
+use std::panic::AssertUnwindSafe;
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::{pow, watch, Error};
+use crate::client::ConnectLatency;
+use crate::{dashboard, health, monitor, pow, watch, Error, Uint256};
 use log::{error, info, warn};
 use rand::{thread_rng, RngCore};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::task::{self, JoinHandle};
 use tokio::time::MissedTickBehavior;
 
+use crate::pow::cpu::{CpuWorkerSpec, DEFAULT_CPU_WORKLOAD};
 use crate::pow::BlockSeed;
-use kasop::{PluginManager, WorkerSpec};
+use kasop::{NoncePartition, PluginManager, Worker, WorkerSpec};
 
 type MinerHandler = std::thread::JoinHandle<Result<(), Error>>;
+/// Name of worker and counter of hashes it tried since last hashrate report
+type WorkerHashes = (String, Arc<AtomicU64>);
+/// Name of worker and its hashrate (hash/s) in last hashrate report
+pub type WorkerRates = Vec<(String, f64)>;
+
+/// How often paused workers check whether they were resumed
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How many times to try building GPU worker again after its device was lost
+const GPU_REBUILD_ATTEMPTS: usize = 5;
+/// Pause between GPU worker rebuild attempts
+const GPU_REBUILD_DELAY: Duration = Duration::from_secs(5);
 
 #[cfg(any(target_os = "linux", target_os = "mac_os"))]
 extern "C" fn signal_panic(_signal: nix::libc::c_int) {
@@ -70,21 +85,186 @@ enum WorkerCommand {
     Close,
 }
 
+/// How often the job watchdog checks for new jobs
+const JOB_WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Catches a pool/node that stays connected but stops sending jobs. While the node says it isn't
+/// synced, no jobs are expected and the watchdog waits.
+pub struct JobWatchdog {
+    jobs: watch::Receiver<Option<WorkerCommand>>,
+    timeout: Duration,
+    last_job: Instant,
+    not_synced: bool,
+}
+
+impl JobWatchdog {
+    fn new(jobs: watch::Receiver<Option<WorkerCommand>>, timeout: Duration, now: Instant) -> Self {
+        Self { jobs, timeout, last_job: now, not_synced: false }
+    }
+
+    /// Look for a new job at `now`, fails when there was none for longer than the timeout
+    pub fn check(&mut self, now: Instant) -> Result<(), Error> {
+        if let Some(command) = self.jobs.get_changed()? {
+            self.last_job = now;
+            self.not_synced = command.is_none();
+        }
+        if !self.not_synced && now.saturating_duration_since(self.last_job) > self.timeout {
+            return Err(format!("no new job received in {} s", self.timeout.as_secs()).into());
+        }
+        Ok(())
+    }
+
+    /// Resolves once the client is starved of jobs
+    pub async fn starved(mut self) -> Error {
+        let mut ticker = tokio::time::interval(JOB_WATCHDOG_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.check(Instant::now()) {
+                return e;
+            }
+        }
+    }
+}
+
+/// Holds back shares found within `window` after the first held one, so that a burst goes out to
+/// the client together. Blocks (see `BlockSeed::is_block`) are never held back.
+#[derive(Debug)]
+pub struct SubmitCoalescer {
+    /// `None` forwards everything right away
+    window: Option<Duration>,
+    pending: Vec<BlockSeed>,
+    /// When pending shares have to go out
+    deadline: Option<Instant>,
+}
+
+impl SubmitCoalescer {
+    pub fn new(window: Option<Duration>) -> Self {
+        Self { window: window.filter(|window| !window.is_zero()), pending: Vec::new(), deadline: None }
+    }
+
+    /// Take `block_seed` found at `now` and return what should be forwarded right away
+    pub fn push(&mut self, block_seed: BlockSeed, now: Instant) -> Vec<BlockSeed> {
+        match self.window {
+            Some(window) if !block_seed.is_block() => {
+                self.deadline.get_or_insert(now + window);
+                self.pending.push(block_seed);
+                Vec::new()
+            }
+            _ => vec![block_seed],
+        }
+    }
+
+    /// When held shares are due, `None` if there are none
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Release held shares if their window is over at `now`
+    pub fn take_due(&mut self, now: Instant) -> Vec<BlockSeed> {
+        match self.deadline {
+            Some(deadline) if deadline <= now => self.take_all(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Release all held shares regardless of their window
+    pub fn take_all(&mut self) -> Vec<BlockSeed> {
+        self.deadline = None;
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// How many recent solutions are remembered to drop duplicates
+const RECENT_SOLUTIONS: usize = 1024;
+
+/// Drops solutions that were already submitted (same job and nonce), workers with overlapping
+/// nonce ranges can find the same one twice. Only the last `capacity` solutions are remembered.
+#[derive(Debug)]
+pub struct SolutionDedup {
+    capacity: usize,
+    recent: VecDeque<(String, u64)>,
+    seen: HashSet<(String, u64)>,
+    duplicates: u64,
+}
+
+impl SolutionDedup {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "BUG: no solutions to remember");
+        Self { capacity, recent: VecDeque::new(), seen: HashSet::new(), duplicates: 0 }
+    }
+
+    /// Remember `block_seed`, returns `false` if it's a duplicate of a recent one
+    pub fn insert(&mut self, block_seed: &BlockSeed) -> bool {
+        let key = match block_seed {
+            BlockSeed::PartialBlock { id, nonce, .. } => (id.clone(), *nonce),
+            // Block hash covers both the template and the nonce
+            BlockSeed::FullBlock(block) => match block.block_hash() {
+                Some(hash) => (format!("{:x}", hash), 0),
+                None => return true,
+            },
+        };
+        if self.seen.contains(&key) {
+            self.duplicates += 1;
+            return false;
+        }
+        if self.recent.len() == self.capacity {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.recent.push_back(key.clone());
+        self.seen.insert(key);
+        true
+    }
+
+    /// Duplicates dropped so far
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+}
+
 #[allow(dead_code)]
 pub struct MinerManager {
     handles: Vec<MinerHandler>,
     block_channel: watch::Sender<Option<WorkerCommand>>,
+    /// Workers submit to this channel, blocks are forwarded to `client_channel`
     send_channel: Sender<BlockSeed>,
+    /// Block channel of the current client, swapped on reconnect
+    client_channel: Arc<Mutex<Sender<BlockSeed>>>,
+    forward_handle: JoinHandle<()>,
     logger_handle: JoinHandle<()>,
+    /// Hashrates of the last report, shared with the dashboard
+    rates: Arc<Mutex<WorkerRates>>,
+    dashboard_handle: Option<JoinHandle<()>>,
+    /// When the last job was handed to workers, shared with the health check
+    last_job: Arc<Mutex<Option<Instant>>>,
+    /// Latency of the current connection, shared with the health check
+    connect_latency: Arc<Mutex<ConnectLatency>>,
+    health_handle: Option<JoinHandle<()>>,
     is_synced: bool,
-    hashes_tried: Arc<AtomicU64>,
+    worker_hashes: Vec<WorkerHashes>,
     current_state_id: AtomicUsize,
+    /// Id of the job workers should be working on, older jobs are stale
+    latest_job_id: Arc<AtomicUsize>,
+    /// Workers idle (keeping their job and devices) while this is set
+    paused: Arc<AtomicBool>,
+    /// Notified about every new job (see `set_job_listener`)
+    job_listener: Option<futures::channel::mpsc::UnboundedSender<()>>,
 }
 
 impl Drop for MinerManager {
     fn drop(&mut self) {
         info!("Closing miner");
         self.logger_handle.abort();
+        if let Some(dashboard_handle) = &self.dashboard_handle {
+            dashboard_handle.abort();
+        }
+        if let Some(health_handle) = &self.health_handle {
+            health_handle.abort();
+        }
+        self.forward_handle.abort();
+        // Paused workers don't look for commands
+        self.paused.store(false, Ordering::Release);
         match self.block_channel.send(Some(WorkerCommand::Close)) {
             Ok(_) => {}
             Err(_) => warn!("All workers are already dead"),
@@ -105,67 +285,290 @@ impl Drop for MinerManager {
     }
 }
 
-pub fn get_num_cpus(n_cpus: Option<u16>) -> u16 {
-    n_cpus.unwrap_or_else(|| {
-        num_cpus::get_physical().try_into().expect("Doesn't make sense to have more than 65,536 CPU cores")
-    })
+/// Number of CPU mining threads (`--threads`), GPU plugins or not. There are none unless asked
+/// for, so that they don't compete for the CPU with GPU plugins or hashboards.
+pub fn resolve_num_threads(requested: Option<u16>) -> u16 {
+    requested.unwrap_or(0)
 }
 
-const LOG_RATE: Duration = Duration::from_secs(10);
-
 impl MinerManager {
-    pub fn new(send_channel: Sender<BlockSeed>, n_cpus: Option<u16>, manager: &PluginManager) -> Self {
+    /// Launch workers submitting to `client_channel`. Workers outlive the client, on reconnect
+    /// only the channel is swapped with `set_client_channel`. Hashrate is logged every
+    /// `hashrate_interval`. Besides plugin workers, `n_cpus` CPU threads (see
+    /// `resolve_num_threads`) and `cpu_workers` built-in CPU `Worker`s are started. Shares found
+    /// within `submit_coalesce` are forwarded together (see `SubmitCoalescer`).
+    pub fn new(
+        client_channel: Sender<BlockSeed>,
+        n_cpus: Option<u16>,
+        cpu_workers: u16,
+        manager: &PluginManager,
+        hashrate_interval: Duration,
+        submit_coalesce: Option<Duration>,
+    ) -> Self {
         register_freeze_handler();
-        let hashes_tried = Arc::new(AtomicU64::new(0));
+        let (send_channel, worker_recv) = mpsc::channel::<BlockSeed>(1);
+        let client_channel = Arc::new(Mutex::new(client_channel));
         let (send, recv) = watch::channel(None);
-        let mut handles =
-            Self::launch_cpu_threads(send_channel.clone(), Arc::clone(&hashes_tried), recv.clone(), n_cpus)
-                .collect::<Vec<MinerHandler>>();
-        if manager.has_specs() {
-            handles.append(&mut Self::launch_gpu_threads(
-                send_channel.clone(),
-                Arc::clone(&hashes_tried),
-                recv,
-                manager,
-            ));
-        }
+        let mut specs = match manager.has_specs() {
+            true => manager.build().unwrap(),
+            false => Vec::new(),
+        };
+        let n_gpus = specs.len();
+        specs.extend((0..cpu_workers as usize).map(|index| {
+            Box::new(CpuWorkerSpec { index, workload: DEFAULT_CPU_WORKLOAD }) as Box<dyn WorkerSpec>
+        }));
+        // Every worker gets its own slice of nonces: CPU threads first, then GPUs and CPU workers
+        let n_cpus = resolve_num_threads(n_cpus) as usize;
+        let n_workers = n_cpus + specs.len();
+        info!("Workers: {} GPU, {} CPU threads, {} built-in CPU workers", n_gpus, n_cpus, cpu_workers);
+        let worker_hashes: Vec<WorkerHashes> = (0..n_cpus)
+            .map(|i| format!("CPU {}", i))
+            .chain((0..n_gpus).map(|i| format!("GPU {}", i)))
+            .chain((0..cpu_workers).map(|i| format!("CPU worker {}", i)))
+            .map(|name| (name, Arc::new(AtomicU64::new(0))))
+            .collect();
+        let counters: Vec<_> = worker_hashes.iter().map(|(_, hashes)| Arc::clone(hashes)).collect();
+        let paused = Arc::new(AtomicBool::new(false));
+        let rates = Arc::new(Mutex::new(WorkerRates::new()));
+        let mut handles = Self::launch_cpu_threads(
+            send_channel.clone(),
+            counters[..n_cpus].to_vec(),
+            recv.clone(),
+            Arc::clone(&paused),
+            n_workers,
+        )
+        .collect::<Vec<MinerHandler>>();
+        handles.append(&mut Self::launch_gpu_threads(
+            send_channel.clone(),
+            counters[n_cpus..].to_vec(),
+            recv,
+            Arc::clone(&paused),
+            specs,
+            n_cpus,
+            n_workers,
+        ));
         Self {
             handles,
             block_channel: send,
             send_channel,
-            logger_handle: task::spawn(Self::log_hashrate(Arc::clone(&hashes_tried))),
+            forward_handle: task::spawn(Self::forward_blocks(
+                worker_recv,
+                Arc::clone(&client_channel),
+                SubmitCoalescer::new(submit_coalesce),
+            )),
+            client_channel,
+            logger_handle: task::spawn(Self::log_hashrate(
+                worker_hashes.clone(),
+                Arc::clone(&rates),
+                hashrate_interval,
+            )),
+            rates,
+            dashboard_handle: None,
+            last_job: Arc::new(Mutex::new(None)),
+            connect_latency: Arc::new(Mutex::new(ConnectLatency::default())),
+            health_handle: None,
             is_synced: true,
-            hashes_tried,
+            worker_hashes,
             current_state_id: AtomicUsize::new(0),
+            latest_job_id: Arc::new(AtomicUsize::new(0)),
+            paused,
+            job_listener: None,
+        }
+    }
+
+    /// Start repainting console dashboard, it's stopped together with the workers
+    pub fn spawn_dashboard(
+        &mut self,
+        monitor_status: Option<async_compat::tokio::sync::watch::Receiver<Option<monitor::Status>>>,
+    ) {
+        if self.dashboard_handle.is_none() {
+            let rates = Arc::clone(&self.rates);
+            self.dashboard_handle = Some(task::spawn(dashboard::dashboard_task(rates, monitor_status)));
+        }
+    }
+
+    /// Start answering health checks and status (with status of hashboards from `monitor_status`
+    /// and of `plugins`) on `listener` (see `health`), it's stopped together with the workers
+    pub fn spawn_health(
+        &mut self,
+        listener: std::net::TcpListener,
+        monitor_status: Option<crate::MonitorStatusReceiver>,
+        plugins: Arc<PluginManager>,
+        max_job_age: Duration,
+    ) -> Result<(), Error> {
+        if self.health_handle.is_none() {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            self.health_handle = Some(task::spawn(health::health_task(
+                listener,
+                Arc::clone(&self.last_job),
+                Arc::clone(&self.connect_latency),
+                Arc::clone(&self.rates),
+                monitor_status,
+                plugins,
+                max_job_age,
+            )));
+        }
+        Ok(())
+    }
+
+    /// Report `latency` of the current connection in status (see `health`)
+    pub fn set_connect_latency(&self, latency: ConnectLatency) {
+        *self.connect_latency.lock().expect("BUG: connect latency lock poisoned") = latency;
+    }
+
+    /// Make all workers idle without tearing them down (GPUs keep their kernels and buffers)
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::AcqRel) {
+            info!("Pausing workers");
+        }
+    }
+
+    /// Let paused workers continue with the latest job
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::AcqRel) {
+            info!("Resuming workers");
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Block the calling worker thread while workers are paused
+    fn wait_while_paused(paused: &AtomicBool) {
+        while paused.load(Ordering::Acquire) {
+            sleep(PAUSE_POLL_INTERVAL);
+        }
+    }
+
+    /// Hash a synthetic job (that never yields a solution) for `duration` and return hash/s each
+    /// worker achieved. Periodic hashrate reports stop, so it's meant to be the last thing the
+    /// manager does.
+    pub async fn bench(&mut self, duration: Duration) -> Result<WorkerRates, Error> {
+        // The logger would reset the counters under our hands
+        self.logger_handle.abort();
+        let job = BlockSeed::PartialBlock {
+            id: "bench".into(),
+            header_hash: [0x0123_4567_89ab_cdef; 4],
+            timestamp: 1_650_000_000_000,
+            nonce: 0,
+            // Only zero hash would meet it
+            target: Uint256::default(),
+            nonce_mask: u64::MAX,
+            nonce_fixed: 0,
+            hash: None,
+        };
+        self.process_block(Some(job)).await?;
+        for (_, hashes) in &self.worker_hashes {
+            hashes.store(0, Ordering::Release);
+        }
+        let start = Instant::now();
+        tokio::time::sleep(duration).await;
+        let elapsed = start.elapsed().as_secs_f64();
+        Ok(self
+            .worker_hashes
+            .iter()
+            .map(|(name, hashes)| (name.clone(), hashes.swap(0, Ordering::AcqRel) as f64 / elapsed))
+            .collect())
+    }
+
+    /// Watchdog for jobs handed to workers from now on, it trips when there's none for `timeout`
+    pub fn job_watchdog(&self, timeout: Duration) -> JobWatchdog {
+        JobWatchdog::new(self.block_channel.subscribe(), timeout, Instant::now())
+    }
+
+    /// Announce every new job handed to workers to `job_listener` (ie. hashboards resetting their
+    /// counters), a listener that is gone is ignored
+    pub fn set_job_listener(&mut self, job_listener: Option<futures::channel::mpsc::UnboundedSender<()>>) {
+        self.job_listener = job_listener;
+    }
+
+    /// Direct blocks found by workers to a new client. Workers keep running (on the last job) while
+    /// the client reconnects.
+    pub fn set_client_channel(&mut self, client_channel: Sender<BlockSeed>) {
+        *self.client_channel.lock().expect("BUG: client channel lock poisoned") = client_channel;
+    }
+
+    async fn forward_blocks(
+        mut recv: Receiver<BlockSeed>,
+        client_channel: Arc<Mutex<Sender<BlockSeed>>>,
+        mut coalescer: SubmitCoalescer,
+    ) {
+        let mut dedup = SolutionDedup::new(RECENT_SOLUTIONS);
+        loop {
+            let received = match coalescer.deadline() {
+                Some(deadline) => match tokio::time::timeout_at(deadline.into(), recv.recv()).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        Self::forward(&client_channel, coalescer.take_due(Instant::now())).await;
+                        continue;
+                    }
+                },
+                None => recv.recv().await,
+            };
+            match received {
+                Some(block_seed) => {
+                    if !dedup.insert(&block_seed) {
+                        warn!(share = "duplicate"; "Dropping duplicate solution ({} so far)", dedup.duplicates());
+                        continue;
+                    }
+                    Self::forward(&client_channel, coalescer.push(block_seed, Instant::now())).await;
+                }
+                None => {
+                    Self::forward(&client_channel, coalescer.take_all()).await;
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn forward(client_channel: &Mutex<Sender<BlockSeed>>, block_seeds: Vec<BlockSeed>) {
+        for block_seed in block_seeds {
+            let send_channel = client_channel.lock().expect("BUG: client channel lock poisoned").clone();
+            if send_channel.send(block_seed).await.is_err() {
+                warn!("Client is disconnected, dropping found block");
+            }
         }
     }
 
     fn launch_cpu_threads(
         send_channel: Sender<BlockSeed>,
-        hashes_tried: Arc<AtomicU64>,
+        hashes_tried: Vec<Arc<AtomicU64>>,
         work_channel: watch::Receiver<Option<WorkerCommand>>,
-        n_cpus: Option<u16>,
+        paused: Arc<AtomicBool>,
+        n_workers: usize,
     ) -> impl Iterator<Item = MinerHandler> {
-        let n_cpus = get_num_cpus(n_cpus);
-        info!("launching: {} cpu miners", n_cpus);
-        (0..n_cpus)
-            .map(move |_| Self::launch_cpu_miner(send_channel.clone(), work_channel.clone(), Arc::clone(&hashes_tried)))
+        info!("launching: {} cpu miners", hashes_tried.len());
+        hashes_tried.into_iter().enumerate().map(move |(i, hashes_tried)| {
+            Self::launch_cpu_miner(
+                send_channel.clone(),
+                work_channel.clone(),
+                hashes_tried,
+                Arc::clone(&paused),
+                NoncePartition::new(i, n_workers),
+            )
+        })
     }
 
     fn launch_gpu_threads(
         send_channel: Sender<BlockSeed>,
-        hashes_tried: Arc<AtomicU64>,
+        hashes_tried: Vec<Arc<AtomicU64>>,
         work_channel: watch::Receiver<Option<WorkerCommand>>,
-        manager: &PluginManager,
+        paused: Arc<AtomicBool>,
+        specs: Vec<Box<dyn WorkerSpec>>,
+        first_index: usize,
+        n_workers: usize,
     ) -> Vec<MinerHandler> {
         let mut vec = Vec::<MinerHandler>::new();
-        let specs = manager.build().unwrap();
-        for spec in specs {
+        for (i, (spec, hashes_tried)) in specs.into_iter().zip(hashes_tried).enumerate() {
             vec.push(Self::launch_gpu_miner(
                 send_channel.clone(),
                 work_channel.clone(),
-                Arc::clone(&hashes_tried),
+                hashes_tried,
+                Arc::clone(&paused),
                 spec,
+                NoncePartition::new(first_index + i, n_workers),
             ));
         }
         vec
@@ -175,15 +578,19 @@ impl MinerManager {
         let state = match block {
             Some(b) => {
                 self.is_synced = true;
+                *self.last_job.lock().expect("BUG: last job lock poisoned") = Some(Instant::now());
                 let id = self.current_state_id.fetch_add(1, Ordering::SeqCst);
-                Some(WorkerCommand::Job(Box::new(pow::State::new(id, b)?)))
+                if let Some(job_listener) = &self.job_listener {
+                    let _ = job_listener.unbounded_send(());
+                }
+                Some(WorkerCommand::Job(Box::new(pow::State::new(id, b)?.replace_older(&self.latest_job_id))))
             }
             None => {
                 if !self.is_synced {
                     return Ok(());
                 }
+                // Client logs the sync state (see `KaspadHandler::log_sync_state`)
                 self.is_synced = false;
-                warn!("Kaspad is not synced, skipping current template");
                 None
             }
         };
@@ -192,20 +599,34 @@ impl MinerManager {
         Ok(())
     }
 
+    /// Log solution found for `job`, solutions for jobs that were replaced meanwhile are stale
+    fn report_solution(block_seed: &BlockSeed, job: &pow::State) {
+        if job.is_stale() {
+            let job_age = job.received.elapsed();
+            info!(
+                share = "stale", job_age_ms = job_age.as_millis() as u64;
+                "Solution is for job {} replaced meanwhile ({} ms old)", job.id, job_age.as_millis()
+            );
+        }
+        block_seed.report_found();
+    }
+
     #[allow(unreachable_code)]
     fn launch_gpu_miner(
         send_channel: Sender<BlockSeed>,
         mut block_channel: watch::Receiver<Option<WorkerCommand>>,
         hashes_tried: Arc<AtomicU64>,
+        paused: Arc<AtomicBool>,
         spec: Box<dyn WorkerSpec>,
+        partition: NoncePartition,
     ) -> MinerHandler {
         std::thread::spawn(move || {
-            let mut box_ = spec.build();
-            let gpu_work = box_.as_mut();
+            let mut box_ = spec.build(partition);
+            let name = box_.id();
             (|| {
-                info!("Spawned Thread for GPU {}", gpu_work.id());
-                let return_size = match gpu_work.requires_filter() {
-                    true => gpu_work.get_workload(),
+                info!("Spawned Thread for GPU {}", name);
+                let return_size = match box_.requires_filter() {
+                    true => box_.get_workload(),
                     false => 1usize
                 };
                 let mut nonces = vec![0u64; return_size];
@@ -213,6 +634,12 @@ impl MinerManager {
                 let mut state = None;
 
                 loop {
+                    let gpu_work = box_.as_mut();
+                    if paused.load(Ordering::Acquire) {
+                        gpu_work.pause();
+                        Self::wait_while_paused(&paused);
+                        gpu_work.resume();
+                    }
                     nonces[0] = 0;
                     if state.is_none() {
                         state = match block_channel.wait_for_change() {
@@ -235,15 +662,17 @@ impl MinerManager {
                         None => continue,
                     };
                     state_ref.pow_gpu(gpu_work);
-                    gpu_work.sync().unwrap();
-
-                    gpu_work.copy_output_to(&mut nonces)?;
+                    if let Err(e) = gpu_work.sync().and_then(|_| gpu_work.copy_output_to(&mut nonces)) {
+                        // Job is loaded to the new worker on the next round
+                        Self::rebuild_lost_worker(&mut box_, spec.as_ref(), partition, e, &name, GPU_REBUILD_DELAY)?;
+                        continue;
+                    }
                     match gpu_work.requires_filter() {
                         false => {
                             if nonces[0] != 0 {
                                 if let Some(block_seed) = state_ref.generate_block_if_pow(nonces[0]) {
                                     match send_channel.blocking_send(block_seed.clone()) {
-                                        Ok(()) => block_seed.report_block(),
+                                        Ok(()) => Self::report_solution(&block_seed, state_ref),
                                         Err(e) => error!("Failed submitting block: ({})", e.to_string()),
                                     };
                                     if let BlockSeed::FullBlock(_) = block_seed {
@@ -264,7 +693,7 @@ impl MinerManager {
                             for nonce in &nonces {
                                 if let Some(block_seed) = state_ref.generate_block_if_pow(*nonce) {
                                     match send_channel.blocking_send(block_seed.clone()) {
-                                        Ok(()) => block_seed.report_block(),
+                                        Ok(()) => Self::report_solution(&block_seed, state_ref),
                                         Err(e) => error!("Failed submitting block: ({})", e.to_string()),
                                     };
                                     if let BlockSeed::FullBlock(_) = block_seed {
@@ -325,17 +754,55 @@ impl MinerManager {
                 Ok(())
             })()
             .map_err(|e: Error| {
-                error!("{}: GPU thread crashed: {}", gpu_work.id(), e.to_string());
+                error!("{}: GPU thread crashed: {}", name, e.to_string());
                 e
             })
         })
     }
 
+    /// Replace `worker` that failed with `error` by a freshly built one if its device was lost
+    /// (GPU reset), any other error is returned. The device may take a while to come back, so
+    /// building is retried `GPU_REBUILD_ATTEMPTS` times `delay` apart.
+    fn rebuild_lost_worker(
+        worker: &mut Box<dyn Worker>,
+        spec: &dyn WorkerSpec,
+        partition: NoncePartition,
+        error: Error,
+        name: &str,
+        delay: Duration,
+    ) -> Result<(), Error> {
+        if !worker.device_lost() {
+            return Err(error);
+        }
+        warn!("{}: device lost ({}), rebuilding worker", name, error);
+        for attempt in 1..=GPU_REBUILD_ATTEMPTS {
+            // Plugins panic when they can't set up the device
+            match std::panic::catch_unwind(AssertUnwindSafe(|| spec.build(partition))) {
+                Ok(rebuilt) => {
+                    let lost = std::mem::replace(worker, rebuilt);
+                    // Releasing resources of a lost device may fail as well
+                    if std::panic::catch_unwind(AssertUnwindSafe(|| drop(lost))).is_err() {
+                        warn!("{}: failed releasing lost worker", name);
+                    }
+                    info!("{}: worker rebuilt", name);
+                    return Ok(());
+                }
+                Err(_) => {
+                    warn!("{}: rebuilding worker failed (attempt {}/{})", name, attempt, GPU_REBUILD_ATTEMPTS);
+                    sleep(delay);
+                }
+            }
+        }
+        Err(format!("{}: device lost and worker couldn't be rebuilt: {}", name, error).into())
+    }
+
     #[allow(unreachable_code)]
     fn launch_cpu_miner(
         send_channel: Sender<BlockSeed>,
         mut block_channel: watch::Receiver<Option<WorkerCommand>>,
         hashes_tried: Arc<AtomicU64>,
+        paused: Arc<AtomicBool>,
+        partition: NoncePartition,
     ) -> MinerHandler {
         let mut nonce = Wrapping(thread_rng().next_u64());
         let mut mask = Wrapping(0);
@@ -360,8 +827,9 @@ impl MinerManager {
                             }
                         };
                         if let Some(s) = &state {
-                            mask = Wrapping(s.nonce_mask);
-                            fixed = Wrapping(s.nonce_fixed);
+                            let (nonce_mask, nonce_fixed) = partition.apply(s.nonce_mask, s.nonce_fixed);
+                            mask = Wrapping(nonce_mask);
+                            fixed = Wrapping(nonce_fixed);
                         }
                     }
                     let state_ref = match state.as_mut() {
@@ -372,7 +840,7 @@ impl MinerManager {
 
                     if let Some(block_seed) = state_ref.generate_block_if_pow(nonce.0) {
                         match send_channel.blocking_send(block_seed.clone()) {
-                            Ok(()) => block_seed.report_block(),
+                            Ok(()) => Self::report_solution(&block_seed, state_ref),
                             Err(e) => error!("Failed submitting block: ({})", e.to_string()),
                         };
                         if let BlockSeed::FullBlock(_) = block_seed {
@@ -384,6 +852,7 @@ impl MinerManager {
                     hashes_tried.fetch_add(1, Ordering::AcqRel);
 
                     if nonce.0 % 128 == 0 {
+                        Self::wait_while_paused(&paused);
                         if let Some(new_cmd) = block_channel.get_changed()? {
                             state = match new_cmd {
                                 Some(WorkerCommand::Job(s)) => Some(s),
@@ -404,24 +873,41 @@ impl MinerManager {
         })
     }
 
-    async fn log_hashrate(hashes_tried: Arc<AtomicU64>) {
-        let mut ticker = tokio::time::interval(LOG_RATE);
+    async fn log_hashrate(worker_hashes: Vec<WorkerHashes>, last_rates: Arc<Mutex<WorkerRates>>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
         ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
         let mut last_instant = ticker.tick().await;
         for i in 0u64.. {
             let now = ticker.tick().await;
-            let hashes = hashes_tried.swap(0, Ordering::AcqRel);
-            let rate = (hashes as f64) / (now - last_instant).as_secs_f64();
-            if hashes == 0 && i % 2 == 0 {
+            let elapsed = (now - last_instant).as_secs_f64();
+            let rates: Vec<(&str, f64)> = worker_hashes
+                .iter()
+                .map(|(name, hashes)| (name.as_str(), hashes.swap(0, Ordering::AcqRel) as f64 / elapsed))
+                .collect();
+            let rate: f64 = rates.iter().map(|(_, rate)| rate).sum();
+            *last_rates.lock().expect("BUG: rates lock poisoned") =
+                rates.iter().map(|(name, rate)| (name.to_string(), *rate)).collect();
+            if rate == 0.0 && i % 2 == 0 {
                 warn!("Workers stalled or crashed. Considered reducing workload and check that your node is synced")
-            } else if hashes != 0 {
-                let (rate, suffix) = Self::hash_suffix(rate);
-                info!("Current hashrate is: {:.2} {}", rate, suffix);
+            } else if rate != 0.0 {
+                info!("Current hashrate is: {}", Self::format_hashrate(rate));
+                if rates.len() > 1 {
+                    let per_worker: Vec<_> = rates
+                        .iter()
+                        .map(|(name, rate)| format!("{}: {}", name, Self::format_hashrate(*rate)))
+                        .collect();
+                    info!("Hashrate per worker: {}", per_worker.join(", "));
+                }
             }
             last_instant = now;
         }
     }
 
+    pub fn format_hashrate(rate: f64) -> String {
+        let (rate, suffix) = Self::hash_suffix(rate);
+        format!("{:.2} {}", rate, suffix)
+    }
+
     #[inline]
     fn hash_suffix(n: f64) -> (f64, &'static str) {
         match n {
@@ -433,4 +919,332 @@ impl MinerManager {
             _ => (n, "hash/s"),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Uint256;
+
+    #[test]
+    fn test_resolve_num_threads() {
+        assert_eq!(resolve_num_threads(Some(3)), 3);
+        assert_eq!(resolve_num_threads(Some(0)), 0);
+        // CPU threads are never started unless asked for
+        assert_eq!(resolve_num_threads(None), 0);
+    }
+
+    fn partial_block(id: &str) -> BlockSeed {
+        BlockSeed::PartialBlock {
+            id: id.into(),
+            header_hash: [0; 4],
+            timestamp: 0,
+            nonce: 0,
+            target: Uint256::default(),
+            nonce_mask: u64::MAX,
+            nonce_fixed: 0,
+            hash: None,
+        }
+    }
+
+    fn block_id(block_seed: Option<BlockSeed>) -> String {
+        match block_seed {
+            Some(BlockSeed::PartialBlock { id, .. }) => id,
+            _ => panic!("expected partial block"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_reuses_workers() {
+        let (first_client, mut first_recv) = mpsc::channel(1);
+        let mut miner =
+            MinerManager::new(first_client, Some(2), 0, &PluginManager::new(), Duration::from_secs(10), None);
+        let workers: Vec<_> = miner.handles.iter().map(|handle| handle.thread().id()).collect();
+        assert_eq!(workers.len(), 2);
+
+        miner.send_channel.send(partial_block("1")).await.unwrap();
+        assert_eq!(block_id(first_recv.recv().await), "1");
+
+        // Client goes away and a new one connects
+        drop(first_recv);
+        let (second_client, mut second_recv) = mpsc::channel(1);
+        miner.set_client_channel(second_client);
+
+        miner.send_channel.send(partial_block("2")).await.unwrap();
+        assert_eq!(block_id(second_recv.recv().await), "2");
+        let reused: Vec<_> = miner.handles.iter().map(|handle| handle.thread().id()).collect();
+        assert_eq!(reused, workers);
+    }
+
+    #[tokio::test]
+    async fn test_bench() {
+        let (client, _recv) = mpsc::channel(1);
+        let mut miner = MinerManager::new(client, Some(0), 1, &PluginManager::new(), Duration::from_secs(3600), None);
+        let start = Instant::now();
+        let rates = miner.bench(Duration::from_millis(500)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert_eq!(rates.len(), 1);
+        assert_eq!(rates[0].0, "CPU worker 0");
+        assert!(rates[0].1 > 0.0, "no hashes: {:?}", rates);
+    }
+
+    #[tokio::test]
+    async fn test_stale_job() {
+        let (client, _recv) = mpsc::channel(1);
+        let mut miner = MinerManager::new(client, Some(0), 0, &PluginManager::new(), Duration::from_secs(3600), None);
+        let mut jobs = miner.block_channel.subscribe();
+        let mut next_job = || match jobs.get_changed() {
+            Ok(Some(Some(WorkerCommand::Job(job)))) => job,
+            _ => panic!("expected job"),
+        };
+        miner.process_block(Some(partial_block("1"))).await.unwrap();
+        let first = next_job();
+        assert!(!first.is_stale());
+        miner.process_block(Some(partial_block("2"))).await.unwrap();
+        let second = next_job();
+        assert!(second.id > first.id);
+        assert!(first.is_stale());
+        assert!(!second.is_stale());
+    }
+
+    #[tokio::test]
+    async fn test_job_listener() {
+        use futures::StreamExt;
+
+        let (client, _recv) = mpsc::channel(1);
+        let mut miner = MinerManager::new(client, Some(0), 0, &PluginManager::new(), Duration::from_secs(3600), None);
+        let (job_listener, mut new_jobs) = futures::channel::mpsc::unbounded();
+        miner.set_job_listener(Some(job_listener));
+        miner.process_block(Some(partial_block("1"))).await.unwrap();
+        miner.process_block(Some(partial_block("2"))).await.unwrap();
+        assert_eq!(new_jobs.next().await, Some(()));
+        assert_eq!(new_jobs.next().await, Some(()));
+        // Node that isn't synced doesn't make a new job
+        miner.process_block(None).await.unwrap();
+        assert!(new_jobs.try_next().is_err());
+
+        // Listener that is gone doesn't disturb mining
+        drop(new_jobs);
+        miner.process_block(Some(partial_block("3"))).await.unwrap();
+    }
+
+    #[test]
+    fn test_job_watchdog() {
+        let (sender, _worker) = watch::channel(None);
+        let start = Instant::now();
+        let mut watchdog = JobWatchdog::new(sender.subscribe(), Duration::from_secs(60), start);
+        let job = || Some(WorkerCommand::Job(Box::new(pow::State::new(0, partial_block("1")).unwrap())));
+        assert!(watchdog.check(start + Duration::from_secs(30)).is_ok());
+        sender.send(job()).unwrap();
+        assert!(watchdog.check(start + Duration::from_secs(50)).is_ok());
+        assert!(watchdog.check(start + Duration::from_secs(110)).is_ok());
+        let err = watchdog.check(start + Duration::from_secs(111)).unwrap_err();
+        assert_eq!(err.to_string(), "no new job received in 60 s");
+
+        // Node that isn't synced isn't expected to send jobs
+        sender.send(None).unwrap();
+        assert!(watchdog.check(start + Duration::from_secs(1000)).is_ok());
+        sender.send(job()).unwrap();
+        assert!(watchdog.check(start + Duration::from_secs(1060)).is_ok());
+        assert!(watchdog.check(start + Duration::from_secs(1061)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_job_watchdog_starved() {
+        let (client, _recv) = mpsc::channel(1);
+        let miner = MinerManager::new(client, Some(1), 0, &PluginManager::new(), Duration::from_secs(3600), None);
+        let watchdog = miner.job_watchdog(Duration::from_millis(10));
+        let starved =
+            tokio::time::timeout(Duration::from_secs(5), watchdog.starved()).await.expect("watchdog didn't trip");
+        assert!(starved.to_string().contains("no new job"));
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume() {
+        let (client, _recv) = mpsc::channel(1);
+        let mut miner =
+            MinerManager::new(client, Some(1), 0, &PluginManager::new(), Duration::from_secs(3600), None);
+        let hashes = Arc::clone(&miner.worker_hashes[0].1);
+        let mut job = partial_block("1");
+        // Nothing ever meets zero target, so the worker just keeps hashing
+        if let BlockSeed::PartialBlock { ref mut target, .. } = job {
+            *target = Uint256::default();
+        }
+        miner.process_block(Some(job)).await.unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(hashes.load(Ordering::Acquire) > 0);
+
+        miner.pause();
+        assert!(miner.is_paused());
+        std::thread::sleep(PAUSE_POLL_INTERVAL * 2);
+        let paused_at = hashes.load(Ordering::Acquire);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(hashes.load(Ordering::Acquire), paused_at);
+
+        miner.resume();
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(hashes.load(Ordering::Acquire) > paused_at);
+    }
+
+    fn full_block() -> BlockSeed {
+        BlockSeed::FullBlock(crate::proto::RpcBlock::default())
+    }
+
+    #[test]
+    fn test_coalesce_shares() {
+        let start = Instant::now();
+        let window = Duration::from_millis(5);
+        let mut coalescer = SubmitCoalescer::new(Some(window));
+        assert!(coalescer.push(partial_block("1"), start).is_empty());
+        assert!(coalescer.push(partial_block("2"), start + Duration::from_millis(3)).is_empty());
+        // Window runs from the first held share
+        assert_eq!(coalescer.deadline(), Some(start + window));
+        assert!(coalescer.take_due(start + Duration::from_millis(4)).is_empty());
+        let due: Vec<_> = coalescer.take_due(start + window).into_iter().map(|seed| block_id(Some(seed))).collect();
+        assert_eq!(due, vec!["1", "2"]);
+        assert_eq!(coalescer.deadline(), None);
+
+        // Off by default
+        for window in [None, Some(Duration::ZERO)] {
+            let mut coalescer = SubmitCoalescer::new(window);
+            assert_eq!(block_id(coalescer.push(partial_block("3"), start).pop()), "3");
+            assert_eq!(coalescer.deadline(), None);
+        }
+    }
+
+    #[test]
+    fn test_coalesce_blocks_bypass() {
+        let start = Instant::now();
+        let mut coalescer = SubmitCoalescer::new(Some(Duration::from_millis(5)));
+        assert!(coalescer.push(partial_block("1"), start).is_empty());
+        let forwarded = coalescer.push(full_block(), start);
+        assert!(matches!(forwarded.as_slice(), [BlockSeed::FullBlock(_)]));
+        // Held share still waits for its window
+        assert_eq!(coalescer.take_all().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_forward_blocks_not_delayed() {
+        let (client, mut client_recv) = mpsc::channel(4);
+        let (send, recv) = mpsc::channel(4);
+        let coalescer = SubmitCoalescer::new(Some(Duration::from_secs(3600)));
+        let forward = task::spawn(MinerManager::forward_blocks(recv, Arc::new(Mutex::new(client)), coalescer));
+        send.send(partial_block("1")).await.unwrap();
+        send.send(full_block()).await.unwrap();
+        let forwarded = tokio::time::timeout(Duration::from_secs(1), client_recv.recv()).await;
+        assert!(matches!(forwarded, Ok(Some(BlockSeed::FullBlock(_)))));
+        // Held share goes out when workers are gone
+        drop(send);
+        assert_eq!(block_id(client_recv.recv().await), "1");
+        forward.await.unwrap();
+    }
+
+    #[test]
+    fn test_solution_dedup() {
+        let share = |id: &str, solution| {
+            let mut share = partial_block(id);
+            if let BlockSeed::PartialBlock { ref mut nonce, .. } = share {
+                *nonce = solution;
+            }
+            share
+        };
+        let mut dedup = SolutionDedup::new(3);
+        assert!(dedup.insert(&share("1", 7)));
+        assert!(!dedup.insert(&share("1", 7)));
+        assert_eq!(dedup.duplicates(), 1);
+        // Same nonce for other job or other nonce for the same job isn't a duplicate
+        assert!(dedup.insert(&share("2", 7)));
+        assert!(dedup.insert(&share("1", 8)));
+        assert_eq!(dedup.duplicates(), 1);
+
+        // Oldest solution is forgotten, the memory stays bounded
+        assert!(dedup.insert(&share("3", 7)));
+        assert_eq!(dedup.recent.len(), 3);
+        assert_eq!(dedup.seen.len(), 3);
+        assert!(dedup.insert(&share("1", 7)));
+        assert!(!dedup.insert(&share("3", 7)));
+
+        // Blocks without a header can't be told apart
+        assert!(dedup.insert(&full_block()));
+        assert!(dedup.insert(&full_block()));
+    }
+
+    /// Worker whose every GPU call fails, as if the device was reset
+    struct LostWorker {
+        lost: bool,
+    }
+
+    impl Worker for LostWorker {
+        fn id(&self) -> String {
+            "fake GPU".into()
+        }
+        fn load_block_constants(&mut self, _: &[u8; 72], _: &[[u16; 64]; 64], _: &[u64; 4]) {}
+        fn calculate_hash(&mut self, _: Option<&Vec<u64>>, _: u64, _: u64) {}
+        fn sync(&self) -> Result<(), Error> {
+            Err("running kernel failed with OpenCL error -34".into())
+        }
+        fn get_workload(&self) -> usize {
+            1
+        }
+        fn copy_output_to(&mut self, _: &mut Vec<u64>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn requires_filter(&self) -> bool {
+            false
+        }
+        fn device_lost(&self) -> bool {
+            self.lost
+        }
+    }
+
+    /// Counts builds, the first `failing` of them panic like a plugin that can't open the device
+    struct CountingSpec {
+        builds: AtomicUsize,
+        failing: usize,
+    }
+
+    impl WorkerSpec for CountingSpec {
+        fn build(&self, _partition: NoncePartition) -> Box<dyn Worker> {
+            if self.builds.fetch_add(1, Ordering::SeqCst) < self.failing {
+                panic!("device not ready");
+            }
+            Box::new(LostWorker { lost: false })
+        }
+    }
+
+    /// Rebuild `worker` after its call failed
+    fn rebuild(worker: &mut Box<dyn Worker>, spec: &CountingSpec) -> Result<(), Error> {
+        let error = worker.sync().unwrap_err();
+        MinerManager::rebuild_lost_worker(worker, spec, NoncePartition::new(0, 1), error, "GPU 0", Duration::ZERO)
+    }
+
+    #[test]
+    fn test_lost_worker_rebuilt() {
+        let spec = CountingSpec { builds: AtomicUsize::new(0), failing: 1 };
+        let mut worker: Box<dyn Worker> = Box::new(LostWorker { lost: true });
+        rebuild(&mut worker, &spec).expect("worker not rebuilt");
+        // First attempt failed, second one succeeded
+        assert_eq!(spec.builds.load(Ordering::SeqCst), 2);
+        assert!(!worker.device_lost());
+
+        // Other errors are not recovered from
+        assert!(rebuild(&mut worker, &spec).is_err());
+        assert_eq!(spec.builds.load(Ordering::SeqCst), 2);
+
+        // Device never comes back
+        let spec = CountingSpec { builds: AtomicUsize::new(0), failing: usize::MAX };
+        let mut worker: Box<dyn Worker> = Box::new(LostWorker { lost: true });
+        assert!(rebuild(&mut worker, &spec).unwrap_err().to_string().contains("couldn't be rebuilt"));
+        assert_eq!(spec.builds.load(Ordering::SeqCst), GPU_REBUILD_ATTEMPTS);
+    }
+
+    #[test]
+    fn test_format_hashrate() {
+        assert_eq!(MinerManager::format_hashrate(0.0), "0.00 hash/s");
+        assert_eq!(MinerManager::format_hashrate(999.0), "999.00 hash/s");
+        assert_eq!(MinerManager::format_hashrate(1_000.0), "1.00 Khash/s");
+        assert_eq!(MinerManager::format_hashrate(12_345_678.0), "12.35 Mhash/s");
+        assert_eq!(MinerManager::format_hashrate(1.5e9), "1.50 Ghash/s");
+        assert_eq!(MinerManager::format_hashrate(2.25e12), "2.25 Thash/s");
+    }
+}
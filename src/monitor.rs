@@ -3,10 +3,15 @@
 
 use logging::macros::*;
 
+use crate::counters;
+use crate::error::{self, ErrorKind};
+use crate::events::{self, Event};
 use crate::fan;
 use crate::halt;
+use crate::power;
 use crate::sensor::{self, Measurement};
 
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -29,6 +34,22 @@ const TICK_LENGTH: Duration = Duration::from_secs(5);
 /// How long does it take until miner warm up? We won't let it tu turn fans off until then...
 const WARM_UP_PERIOD: Duration = Duration::from_secs(90);
 
+/// Temperatures outside of this range are physically impossible and considered to be glitches
+const MIN_VALID_TEMP: f32 = -40.0;
+const MAX_VALID_TEMP: f32 = 150.0;
+/// Fans can't cool hashboards below ambient temperature, lower target only keeps them on full
+const MIN_TARGET_TEMP: f32 = 30.0;
+/// How many temperature readings in a row may be missing before fans go full speed
+const MAX_MISSED_READINGS: usize = 3;
+/// Default number of readings to take median of
+pub const DEFAULT_TEMP_FILTER_WINDOW: usize = 5;
+/// Weight of new sample in moving averages of power and hashrate used for efficiency
+const EFFICIENCY_EMA_ALPHA: f64 = 0.2;
+/// Default temperature above which fans run at full speed
+pub const DEFAULT_HOT_TEMP: f32 = 90.0;
+/// Default temperature at which the miner is shut down
+pub const DEFAULT_DANGEROUS_TEMP: f32 = 100.0;
+
 /// A message from hashchain
 ///
 /// Here are some rules that HashChains registered with monitors have to obey:
@@ -40,8 +61,20 @@ const WARM_UP_PERIOD: Duration = Duration::from_secs(90);
 #[derive(Debug, Clone)]
 pub enum Message {
     On,
-    Running(sensor::Temperature),
+    Running(sensor::Readings),
     Off,
+    /// Periodic hashrate report, it doesn't affect hashchain state
+    Hashrate(counters::HashrateComparison),
+    /// Periodic voltage set-point readback, it doesn't affect hashchain state
+    Voltage(power::Voltage),
+    /// Periodic power estimate in Watts, it doesn't affect hashchain state
+    Power(f64),
+    /// Periodic nonce/error totals, it doesn't affect hashchain state
+    Health(counters::HealthSummary),
+    /// Periodic counters with uptime, it doesn't affect hashchain state
+    Counters(counters::ChainStatus),
+    /// Hashchain was given up and halted, the rest of the miner goes on without it
+    Failed(String),
 }
 
 /// Interpreted hashchain temperature
@@ -78,6 +111,43 @@ impl ChainTemperature {
     }
 }
 
+/// How to make one hashchain temperature out of readings of multiple sensors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensorAggregation {
+    /// Use the hottest sensor
+    Max,
+    /// Use average of all sensors
+    Average,
+}
+
+impl Default for SensorAggregation {
+    fn default() -> Self {
+        SensorAggregation::Max
+    }
+}
+
+impl SensorAggregation {
+    /// Aggregate temperatures of all sensors of one hashchain. Sensors with unknown
+    /// temperature are skipped.
+    fn aggregate(&self, readings: &sensor::Readings) -> ChainTemperature {
+        let temps = readings
+            .sensors
+            .iter()
+            .filter_map(|(_, temp)| match ChainTemperature::from_s9_sensor(temp.clone()) {
+                ChainTemperature::Ok(t) => Some(t),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if temps.is_empty() {
+            return ChainTemperature::Unknown;
+        }
+        match self {
+            SensorAggregation::Max => ChainTemperature::Ok(temps.iter().cloned().fold(f32::MIN, f32::max)),
+            SensorAggregation::Average => ChainTemperature::Ok(temps.iter().sum::<f32>() / temps.len() as f32),
+        }
+    }
+}
+
 /// State of hashchain as seen from Monitor point of view
 /// The `Instant` timestamps are when that event happen (only states that operate with
 /// timeouts use it).
@@ -87,10 +157,12 @@ enum ChainState {
     Running {
         started: Instant,
         last_heartbeat: Instant,
-        temperature: sensor::Temperature,
+        temperature: sensor::Readings,
     },
     Off,
     Broken(&'static str),
+    /// Halted and removed from the miner (no way out)
+    Failed(String),
 }
 
 impl ChainState {
@@ -105,6 +177,10 @@ impl ChainState {
     /// `now` is timestamp of `message` reception (passed explicitly as argument
     /// to facilitate testing).
     fn transition(&mut self, now: Instant, message: Message) {
+        if let ChainState::Failed(_) = self {
+            // Late messages of halted hashchain don't bring it back
+            return;
+        }
         match message {
             Message::On => match *self {
                 ChainState::Off => *self = ChainState::On(now),
@@ -124,6 +200,12 @@ impl ChainState {
                 ChainState::On(_) | ChainState::Running { .. } => *self = ChainState::Off,
                 _ => self.bad_transition(),
             },
+            Message::Failed(reason) => *self = ChainState::Failed(reason),
+            Message::Hashrate(_)
+            | Message::Voltage(_)
+            | Message::Power(_)
+            | Message::Health(_)
+            | Message::Counters(_) => {}
         }
     }
 
@@ -149,13 +231,15 @@ impl ChainState {
     /// Return hashchain temperature as seen from our point of view. For example,
     /// `Broken` miner doesn't have a valid temperature reading even though it sent
     /// some numbers a while ago.
-    fn get_temperature(&self) -> ChainTemperature {
+    fn get_temperature(&self, aggregation: SensorAggregation) -> ChainTemperature {
         match self {
             ChainState::On(_) => ChainTemperature::Unknown,
             ChainState::Off => ChainTemperature::Unknown,
+            // Halted hashchain doesn't heat up
+            ChainState::Failed(_) => ChainTemperature::Unknown,
             ChainState::Broken(_) => ChainTemperature::Failed,
             ChainState::Running { temperature, .. } => {
-                ChainTemperature::from_s9_sensor(temperature.clone())
+                aggregation.aggregate(temperature)
             }
         }
     }
@@ -175,17 +259,81 @@ impl ChainState {
 struct Chain {
     state: ChainState,
     hashboard_idx: usize,
+    /// Last hashrate reported by the chain
+    hashrate: Option<counters::HashrateComparison>,
+    /// Last voltage set-point read back from the voltage controller
+    voltage: Option<power::Voltage>,
+    /// Smoothed power estimate in Watts
+    estimated_power: Ema,
+    /// Smoothed hashrate efficiency is computed from (see `efficiency_hashrate`)
+    smoothed_hashrate: Ema,
+    /// Last nonce/error totals reported by the chain
+    health: Option<counters::HealthSummary>,
+    /// Last counters reported by the chain
+    counters: Option<counters::ChainStatus>,
+    /// Halts just this chain when it breaks, so that the rest of the miner can go on
+    halt_sender: Option<Arc<halt::Sender>>,
 }
 
 impl Chain {
-    fn new(hashboard_idx: usize) -> Self {
+    fn new(hashboard_idx: usize, halt_sender: Option<Arc<halt::Sender>>) -> Self {
         Self {
             state: ChainState::Off,
             hashboard_idx,
+            hashrate: None,
+            voltage: None,
+            estimated_power: Ema::new(EFFICIENCY_EMA_ALPHA),
+            smoothed_hashrate: Ema::new(EFFICIENCY_EMA_ALPHA),
+            health: None,
+            counters: None,
+            halt_sender,
         }
     }
 }
 
+/// Exponential moving average
+#[derive(Debug, Clone, Copy)]
+pub struct Ema {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Ema {
+    pub fn new(alpha: f64) -> Self {
+        Self { alpha, value: None }
+    }
+
+    pub fn update(&mut self, sample: f64) {
+        self.value = Some(match self.value {
+            Some(value) => value + self.alpha * (sample - value),
+            None => sample,
+        });
+    }
+
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+/// Efficiency in Joules per terahash, `None` if there's no hashrate to relate power to
+pub fn joules_per_terahash(watts: f64, hashrate: f64) -> Option<f64> {
+    if hashrate > 0.0 {
+        Some(watts / (hashrate / 1e12))
+    } else {
+        None
+    }
+}
+
+/// Hashrate to relate power to: the counted one, or the one reported by chips while no nonces
+/// are counted (ie. chips hash only the work they generate themselves)
+pub fn efficiency_hashrate(hashrate: &counters::HashrateComparison) -> f64 {
+    if hashrate.counted > 0.0 {
+        hashrate.counted
+    } else {
+        hashrate.chip_reported
+    }
+}
+
 /// What method of controlling fans is configured
 #[derive(Debug, Clone)]
 pub enum FanControlMode {
@@ -200,6 +348,14 @@ pub struct FanControlConfig {
     /// Minimal number of fans - miner will refuse to work until at least
     /// this number of fans is spinning.
     pub min_fans: usize,
+    /// Number of fans the miner is fitted with - a warning is logged as soon as fewer
+    /// of them are spinning, long before it comes to `min_fans`.
+    pub expected_fans: Option<usize>,
+    /// Run PID for every fan separately: `fan_zones[i]` is index of hashchain nearest
+    /// to fan `i` (in order of registration) whose temperature the fan follows.
+    /// All fans share one PWM register (see `fan::FanSpeeds::pwm_register`), so in the end
+    /// the fan of the hottest zone drives all of them.
+    pub fan_zones: Option<Vec<usize>>,
 }
 
 /// Temperature limit configuration
@@ -220,6 +376,60 @@ pub struct Config {
     pub fans_on_while_warming_up: bool,
 }
 
+/// Fans follow the default target temperature, at least one of them has to spin
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            fan_config: Some(FanControlConfig {
+                mode: FanControlMode::TargetTemperature(fan::pid::DEFAULT_TARGET_TEMP as f32),
+                min_fans: 1,
+                expected_fans: None,
+                fan_zones: None,
+            }),
+            temp_config: Some(TempControlConfig {
+                dangerous_temp: DEFAULT_DANGEROUS_TEMP,
+                hot_temp: DEFAULT_HOT_TEMP,
+            }),
+            fans_on_while_warming_up: true,
+        }
+    }
+}
+
+impl Config {
+    /// Check that target temperature (if any) is within limits. Target at or above `hot_temp`
+    /// would leave the fans on full speed all the time.
+    pub fn validate(&self) -> Result<(), String> {
+        let target_temp = match self.fan_config.as_ref().map(|fan_config| &fan_config.mode) {
+            Some(FanControlMode::TargetTemperature(target_temp)) => *target_temp,
+            _ => return Ok(()),
+        };
+        if target_temp < MIN_TARGET_TEMP || target_temp > MAX_VALID_TEMP {
+            return Err(format!(
+                "target temperature {} out of range {}..{}",
+                target_temp, MIN_TARGET_TEMP, MAX_VALID_TEMP
+            ));
+        }
+        if let Some(temp_config) = self.temp_config.as_ref() {
+            if target_temp >= temp_config.hot_temp {
+                return Err(format!(
+                    "target temperature {} has to be below hot temperature {}",
+                    target_temp, temp_config.hot_temp
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Indices of hashchains nearest to each fan, if fans are controlled separately
+    fn fan_zones(&self) -> Option<&[usize]> {
+        self.fan_config.as_ref()?.fan_zones.as_deref()
+    }
+
+    fn zoned_fans(&self) -> bool {
+        self.fan_zones().is_some()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ControlDecisionExplained {
     pub decision: ControlDecision,
@@ -407,15 +617,129 @@ impl TemperatureAccumulator {
     }
 }
 
+/// Telemetry of one hashchain
+#[derive(Debug, Clone)]
+pub struct ChainStatus {
+    pub hashboard_idx: usize,
+    pub temperature: ChainTemperature,
+    /// Chip-reported and counted hashrate
+    pub hashrate: Option<counters::HashrateComparison>,
+    /// Voltage set-point (not a measurement)
+    pub voltage: Option<power::Voltage>,
+    /// Smoothed power estimate in Watts, computed from voltage set-point and chip frequencies
+    /// (nothing on the board measures power)
+    pub estimated_power: Option<f64>,
+    /// Smoothed power estimate over smoothed hashrate (see `efficiency_hashrate`) in J/TH
+    pub efficiency: Option<f64>,
+    /// Nonce/error totals of this session and cumulative over restarts
+    pub health: Option<counters::HealthSummary>,
+    /// Counters with uptime
+    pub counters: Option<counters::ChainStatus>,
+    /// Why the chain was halted and removed from the miner
+    pub failed: Option<String>,
+}
+
+/// Median filter that keeps temperature spikes (ie. caused by I2C glitches) from reaching PID.
+/// Physically impossible readings are dropped altogether.
+///
+/// Missing readings (failed sensor read, impossible value) are bridged with the last filtered
+/// temperature for up to `MAX_MISSED_READINGS` ticks in a row. After that the temperature is
+/// unknown, which turns fans on full speed, so that a dead sensor can't hold fans at low speed.
+#[derive(Debug, Clone)]
+pub struct TemperatureFilter {
+    window: usize,
+    history: VecDeque<f32>,
+    /// Number of missing readings in a row
+    missed: usize,
+}
+
+impl TemperatureFilter {
+    pub fn new(window: usize) -> Self {
+        assert!(window > 0, "BUG: empty temperature filter window");
+        Self {
+            window,
+            history: VecDeque::with_capacity(window),
+            missed: 0,
+        }
+    }
+
+    fn median(&self) -> ChainTemperature {
+        if self.history.is_empty() {
+            return ChainTemperature::Unknown;
+        }
+        let mut temps = self.history.iter().cloned().collect::<Vec<_>>();
+        temps.sort_by(|a, b| a.partial_cmp(b).expect("BUG: temperature is NaN"));
+        let mid = temps.len() / 2;
+        if temps.len() % 2 == 0 {
+            ChainTemperature::Ok((temps[mid - 1] + temps[mid]) / 2.0)
+        } else {
+            ChainTemperature::Ok(temps[mid])
+        }
+    }
+
+    /// Reading is missing, bridge it with the last temperature unless too many are missing
+    fn missed_reading(&mut self) -> ChainTemperature {
+        self.missed += 1;
+        if self.missed > MAX_MISSED_READINGS && !self.history.is_empty() {
+            warn!(
+                "Monitor: no valid temperature for {} readings, giving up on the last one",
+                self.missed
+            );
+            self.history.clear();
+        }
+        self.median()
+    }
+
+    /// Feed raw temperature into filter and return the filtered one
+    pub fn filter(&mut self, temp: ChainTemperature) -> ChainTemperature {
+        match temp {
+            ChainTemperature::Ok(t) if t >= MIN_VALID_TEMP && t <= MAX_VALID_TEMP => {
+                self.missed = 0;
+                if self.history.len() == self.window {
+                    self.history.pop_front();
+                }
+                self.history.push_back(t);
+                self.median()
+            }
+            ChainTemperature::Ok(t) => {
+                warn!("Monitor: discarding impossible temperature {}", t);
+                self.missed_reading()
+            }
+            ChainTemperature::Unknown => self.missed_reading(),
+            // Failure is not to be filtered out, start over next time
+            ChainTemperature::Failed => {
+                self.history.clear();
+                self.missed = 0;
+                temp
+            }
+        }
+    }
+}
+
+/// How many of expected fans aren't spinning. Fans that were stopped on purpose aren't missing.
+fn missing_fans(config: &Config, feedback: &fan::Feedback, fan_speed: Option<fan::Speed>) -> usize {
+    match config.fan_config.as_ref().and_then(|fan_config| fan_config.expected_fans) {
+        Some(expected) if fan_speed != Some(fan::Speed::STOPPED) => feedback.num_fans_missing(expected),
+        _ => 0,
+    }
+}
+
 /// Status of `Monitor` for others to observe
 #[derive(Debug, Clone)]
 pub struct Status {
     pub config: Config,
     pub fan_feedback: fan::Feedback,
     pub fan_speed: Option<fan::Speed>,
+    /// How many of `expected_fans` aren't spinning
+    pub fans_missing: usize,
     pub input_temperature: ChainTemperature,
+    /// Input temperature before filtering
+    pub raw_input_temperature: ChainTemperature,
     pub temperature_accumulator: TemperatureAccumulator,
     pub decision_explained: ControlDecisionExplained,
+    pub chains: Vec<ChainStatus>,
+    /// Efficiency of all chains with known power estimate and hashrate in J/TH
+    pub efficiency: Option<f64>,
 }
 
 /// Monitor - it holds states of all Chains and everything related to fan control
@@ -430,9 +754,19 @@ pub struct MonitorInner {
     current_fan_speed: Option<fan::Speed>,
     /// PID that controls fan with hashchain temperature as input
     pid: fan::pid::TempControl,
+    /// PIDs of individual fans when `fan_zones` are configured
+    zone_pid: Option<fan::pid::ZoneTempControl>,
+    /// Filter of PID input temperature
+    temp_filter: TemperatureFilter,
+    /// How to aggregate multiple sensors of one hashchain
+    sensor_aggregation: SensorAggregation,
     /// Flag whether miner is in failure state - temperature critical, hashboards not responding,
     /// fans gone missing...
     failure_state: bool,
+    /// Whether temperature was above HOT at the last tick, to emit `Overheated` only once
+    overheated: bool,
+    /// Whether some fans were missing at the last tick, to emit `FansStalled` only once
+    fans_stalled: bool,
 }
 
 /// Wrapper around `MonitorInner` with immutable fields
@@ -449,7 +783,8 @@ pub struct Monitor {
 }
 
 impl Monitor {
-    /// Construct a new monitor and start it
+    /// Construct a new monitor and start it, fails on invalid `config` or when fans can't be
+    /// controlled
     ///
     /// * `miner_shutdown` - halt sender to shutdown the whole miner in case of a failure
     /// * `halt_receiver` - termination context in which to start the monitor
@@ -457,15 +792,27 @@ impl Monitor {
         config: Config,
         miner_shutdown: Arc<halt::Sender>,
         halt_receiver: halt::Receiver,
-    ) -> Arc<Self> {
+    ) -> error::Result<Arc<Self>> {
         let (status_sender, status_receiver) = watch::channel(None);
+        if let Err(e) = config.validate() {
+            Err(ErrorKind::General(format!("invalid monitor configuration: {}", e)))?
+        }
+        let target_temp = match config.fan_config.as_ref().map(|fan_config| &fan_config.mode) {
+            Some(FanControlMode::TargetTemperature(target_temp)) => (*target_temp).into(),
+            _ => fan::pid::DEFAULT_TARGET_TEMP,
+        };
 
         let inner = MonitorInner {
             chains: Vec::new(),
             config,
-            fan_control: fan::Control::new().expect("failed initializing fan controller"),
-            pid: fan::pid::TempControl::new(),
+            fan_control: fan::Control::new()?,
+            pid: fan::pid::TempControl::with_target(target_temp),
+            zone_pid: None,
+            temp_filter: TemperatureFilter::new(DEFAULT_TEMP_FILTER_WINDOW),
+            sensor_aggregation: SensorAggregation::default(),
             failure_state: false,
+            overheated: false,
+            fans_stalled: false,
             current_fan_speed: None,
         };
 
@@ -486,7 +833,7 @@ impl Monitor {
             .await
             .spawn(Self::tick_task(monitor.clone()));
 
-        monitor
+        Ok(monitor)
     }
 
     /// Handler that is run when monitor is signalized with shutdown.
@@ -515,6 +862,17 @@ impl Monitor {
         inner.current_fan_speed = Some(fan_speed);
     }
 
+    /// Set speed of each fan separately
+    fn set_fan_speeds(&self, inner: &mut MonitorInner, fan_speeds: &[fan::Speed]) {
+        info!("Monitor: setting fans to {:?}", fan_speeds);
+        for (fan_idx, fan_speed) in fan_speeds.iter().enumerate() {
+            if let Err(e) = inner.fan_control.set_speed_for(fan_idx, *fan_speed) {
+                warn!("Monitor: {}", e);
+            }
+        }
+        inner.current_fan_speed = fan_speeds.iter().cloned().max_by_key(fan::Speed::to_pwm);
+    }
+
     /// One tick of temperature/fan controller
     ///
     /// TODO: Run this tick every time new temperature is submitted to lower temp controller
@@ -524,24 +882,90 @@ impl Monitor {
         let mut inner = self.inner.lock().await;
         let mut temperature_accumulator = TemperatureAccumulator::new();
         let mut miner_warming_up = false;
+        let mut chains = Vec::new();
+        let mut total_power = 0.0;
+        let mut total_hashrate = 0.0;
+        let sensor_aggregation = inner.sensor_aggregation;
+        let mut failed_chains = 0;
         for chain in inner.chains.iter() {
             let mut chain = chain.lock().await;
             chain.state.tick(Instant::now());
 
             if let ChainState::Broken(reason) = chain.state {
-                // TODO: here comes "Shutdown"
                 let reason = format!("Chain {} is broken: {}", chain.hashboard_idx, reason);
-                // drop `chain` here to drop iterator which holds immutable reference
-                // to `monitor`
-                drop(chain);
-                self.shutdown(&mut inner, reason).await;
-                return;
+                match chain.halt_sender.clone() {
+                    // Take the chain out, other chains keep mining
+                    Some(halt_sender) => {
+                        error!("Monitor: {}, halting it", reason);
+                        tokio::spawn(async move {
+                            if let Err(e) = halt_sender.halt().await {
+                                error!("Monitor: failed halting broken chain: {}", e);
+                            }
+                        });
+                        chain.state = ChainState::Failed(reason);
+                    }
+                    None => {
+                        // drop `chain` here to drop iterator which holds immutable reference
+                        // to `monitor`
+                        drop(chain);
+                        self.shutdown(&mut inner, reason).await;
+                        return;
+                    }
+                }
+            }
+            if let ChainState::Failed(reason) = &chain.state {
+                failed_chains += 1;
+                chains.push(ChainStatus {
+                    hashboard_idx: chain.hashboard_idx,
+                    temperature: ChainTemperature::Unknown,
+                    hashrate: None,
+                    voltage: None,
+                    estimated_power: None,
+                    efficiency: None,
+                    health: chain.health,
+                    counters: chain.counters.clone(),
+                    failed: Some(reason.clone()),
+                });
+                continue;
             }
             info!("chain {}: {:?}", chain.hashboard_idx, chain.state);
-            temperature_accumulator.add_chain_temp(chain.state.get_temperature());
+            let temperature = chain.state.get_temperature(sensor_aggregation);
+            temperature_accumulator.add_chain_temp(temperature);
+            let estimated_power = chain.estimated_power.value();
+            let efficiency = match (estimated_power, chain.smoothed_hashrate.value()) {
+                (Some(power), Some(hashrate)) => {
+                    total_power += power;
+                    total_hashrate += hashrate;
+                    joules_per_terahash(power, hashrate)
+                }
+                _ => None,
+            };
+            chains.push(ChainStatus {
+                hashboard_idx: chain.hashboard_idx,
+                temperature,
+                hashrate: chain.hashrate,
+                voltage: chain.voltage,
+                estimated_power,
+                efficiency,
+                health: chain.health,
+                counters: chain.counters.clone(),
+                failed: None,
+            });
             miner_warming_up |= chain.state.is_warming_up(Instant::now());
         }
-        let input_temperature = temperature_accumulator.calc_result();
+        if failed_chains > 0 && failed_chains == inner.chains.len() {
+            self.shutdown(&mut inner, "all chains failed".into()).await;
+            return;
+        }
+        let efficiency = joules_per_terahash(total_power, total_hashrate);
+        if let Some(efficiency) = efficiency {
+            info!(
+                "Monitor: estimated power={:.0} W efficiency={:.1} J/TH",
+                total_power, efficiency
+            );
+        }
+        let raw_input_temperature = temperature_accumulator.calc_result();
+        let input_temperature = inner.temp_filter.filter(raw_input_temperature);
 
         // Read fans
         let fan_feedback = inner.fan_control.read_feedback();
@@ -552,6 +976,16 @@ impl Monitor {
         );
 
         // all right, temperature has been aggregated, decide what to do
+        let hot_temperature = match (inner.config.temp_config.as_ref(), input_temperature) {
+            (Some(temp_config), ChainTemperature::Ok(t)) if t >= temp_config.hot_temp => Some(t),
+            _ => None,
+        };
+        if let Some(temperature) = hot_temperature {
+            if !inner.overheated {
+                events::emit(Event::Overheated { temperature });
+            }
+        }
+        inner.overheated = hot_temperature.is_some();
         let decision_explained =
             ControlDecision::decide(&inner.config, num_fans_running, input_temperature);
         info!("Monitor: {:?}", decision_explained);
@@ -563,6 +997,37 @@ impl Monitor {
             ControlDecision::UseFixedSpeed(fan_speed) => {
                 self.set_fan_speed(&mut inner, fan_speed);
             }
+            ControlDecision::UsePid {
+                target_temp,
+                input_temp,
+            } if inner.config.zoned_fans() => {
+                let zone_sensors = inner.config.fan_zones().expect("BUG: no fan zones").to_vec();
+                let warm_up_limits = inner.config.fans_on_while_warming_up && miner_warming_up;
+                let zone_pid = match inner.zone_pid.take() {
+                    Some(zone_pid) if zone_pid.zone_sensors() == zone_sensors.as_slice() => zone_pid,
+                    _ => fan::pid::ZoneTempControl::new(zone_sensors),
+                };
+                let zone_pid = inner.zone_pid.get_or_insert(zone_pid);
+                if warm_up_limits {
+                    zone_pid.set_warm_up_limits();
+                } else {
+                    zone_pid.set_normal_limits();
+                }
+                zone_pid.set_target(target_temp.into());
+                let zone_temperatures = chains
+                    .iter()
+                    .map(|chain| match chain.temperature {
+                        ChainTemperature::Ok(t) => Some(f64::from(t)),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let speeds = zone_pid.update(&zone_temperatures, input_temp.into());
+                info!(
+                    "Monitor: zones={:?} input={} target={} output={:?}",
+                    zone_temperatures, input_temp, target_temp, speeds
+                );
+                self.set_fan_speeds(&mut inner, &speeds);
+            }
             ControlDecision::UsePid {
                 target_temp,
                 input_temp,
@@ -582,15 +1047,30 @@ impl Monitor {
             }
             ControlDecision::Nothing => {}
         }
+        let fans_missing = missing_fans(&inner.config, &fan_feedback, inner.current_fan_speed);
+        if fans_missing > 0 {
+            warn!(
+                "Monitor: {} fan(s) not spinning, only {} running",
+                fans_missing, num_fans_running
+            );
+            if !inner.fans_stalled {
+                events::emit(Event::FansStalled { fans_missing });
+            }
+        }
+        inner.fans_stalled = fans_missing > 0;
 
         // Broadcast `Status`
         let monitor_status = Status {
             fan_feedback,
             fan_speed: inner.current_fan_speed,
+            fans_missing,
             input_temperature,
+            raw_input_temperature,
             temperature_accumulator,
             decision_explained,
             config: inner.config.clone(),
+            chains,
+            efficiency,
         };
         self.status_sender
             .broadcast(Some(monitor_status))
@@ -610,15 +1090,43 @@ impl Monitor {
     async fn recv_task(chain: Arc<Mutex<Chain>>, mut rx: mpsc::UnboundedReceiver<Message>) {
         while let Some(message) = rx.next().await {
             let mut chain = chain.lock().await;
-            chain.state.transition(Instant::now(), message);
+            match message {
+                Message::Hashrate(hashrate) => {
+                    chain.smoothed_hashrate.update(efficiency_hashrate(&hashrate));
+                    chain.hashrate = Some(hashrate);
+                }
+                Message::Voltage(voltage) => chain.voltage = Some(voltage),
+                Message::Power(watts) => chain.estimated_power.update(watts),
+                Message::Health(health) => chain.health = Some(health),
+                Message::Counters(counters) => chain.counters = Some(counters),
+                message => chain.state.transition(Instant::now(), message),
+            }
         }
     }
 
     /// Registers hashchain within monitor
     /// The `hashboard_idx` parameter is for debugging purposes
     pub async fn register_hashchain(&self, hashboard_idx: usize) -> mpsc::UnboundedSender<Message> {
+        self.register(hashboard_idx, None).await
+    }
+
+    /// Registers hashchain that is halted with `halt_sender` when it breaks, instead of shutting
+    /// down the whole miner (unless it was the last chain running)
+    pub async fn register_hashchain_with_halt(
+        &self,
+        hashboard_idx: usize,
+        halt_sender: Arc<halt::Sender>,
+    ) -> mpsc::UnboundedSender<Message> {
+        self.register(hashboard_idx, Some(halt_sender)).await
+    }
+
+    async fn register(
+        &self,
+        hashboard_idx: usize,
+        halt_sender: Option<Arc<halt::Sender>>,
+    ) -> mpsc::UnboundedSender<Message> {
         let (tx, rx) = mpsc::unbounded();
-        let chain = Arc::new(Mutex::new(Chain::new(hashboard_idx)));
+        let chain = Arc::new(Mutex::new(Chain::new(hashboard_idx, halt_sender)));
         {
             let mut inner = self.inner.lock().await;
             inner.chains.push(chain.clone());
@@ -627,6 +1135,16 @@ impl Monitor {
         tx
     }
 
+    /// Set number of readings the input temperature median is taken from
+    pub async fn set_temperature_filter_window(&self, window: usize) {
+        self.inner.lock().await.temp_filter = TemperatureFilter::new(window);
+    }
+
+    /// Set how temperatures of multiple sensors on one hashchain are aggregated
+    pub async fn set_sensor_aggregation(&self, aggregation: SensorAggregation) {
+        self.inner.lock().await.sensor_aggregation = aggregation;
+    }
+
     pub async fn with_configuration<F, R>(&self, f: F) -> R
     where
         F: FnOnce(&mut Config) -> R,
@@ -641,6 +1159,27 @@ mod test {
     use super::*;
     use approx::assert_relative_eq;
 
+    #[test]
+    fn test_efficiency() {
+        // 1350 W at 14 TH/s
+        assert_relative_eq!(joules_per_terahash(1350.0, 14e12).unwrap(), 96.42857, epsilon = 0.0001);
+        assert_eq!(joules_per_terahash(1350.0, 0.0), None);
+        // Chip-reported hashrate stands in until nonces are counted
+        let comparison = |chip_reported, counted| counters::HashrateComparison { chip_reported, counted };
+        assert_eq!(efficiency_hashrate(&comparison(14e12, 0.0)), 14e12);
+        assert_eq!(efficiency_hashrate(&comparison(14e12, 13e12)), 13e12);
+
+        let mut power = Ema::new(0.5);
+        assert_eq!(power.value(), None);
+        power.update(400.0);
+        assert_relative_eq!(power.value().unwrap(), 400.0);
+        power.update(500.0);
+        assert_relative_eq!(power.value().unwrap(), 450.0);
+        // A spike moves the average only partially
+        power.update(1450.0);
+        assert_relative_eq!(power.value().unwrap(), 950.0);
+    }
+
     macro_rules! assert_variant {
         ($value:expr, $pattern:pat) => {{
             let value = &$value;
@@ -703,13 +1242,13 @@ mod test {
         let running_state = ChainState::Running {
             started: now,
             last_heartbeat: now,
-            temperature: temp.clone(),
+            temperature: temp.clone().into(),
         };
 
         //assert_eq!(send(ChainState::Running(now, temp), later, Message::Off), ChainState::Off);
         assert_variant!(send(ChainState::Off, later, Message::On), ChainState::On(_));
         assert_variant!(
-            send(ChainState::Off, later, Message::Running(temp.clone())),
+            send(ChainState::Off, later, Message::Running(temp.clone().into())),
             ChainState::Broken(_)
         );
         assert_variant!(
@@ -722,7 +1261,7 @@ mod test {
             ChainState::Broken(_)
         );
         assert_variant!(
-            send(ChainState::On(now), later, Message::Running(temp.clone())),
+            send(ChainState::On(now), later, Message::Running(temp.clone().into())),
             ChainState::Running{ .. }
         );
         assert_variant!(
@@ -738,7 +1277,7 @@ mod test {
             send(
                 running_state.clone(),
                 later,
-                Message::Running(temp.clone())
+                Message::Running(temp.clone().into())
             ),
             ChainState::Running { .. }
         );
@@ -748,6 +1287,39 @@ mod test {
         );
     }
 
+    /// Test that chain given up by its supervisor stays failed and doesn't affect temperature
+    #[test]
+    fn test_monitor_chain_failed() {
+        let temp = sensor::Temperature {
+            local: sensor::Measurement::Ok(10.0),
+            remote: sensor::Measurement::Ok(22.0),
+        };
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        let running_state = ChainState::Running {
+            started: now,
+            last_heartbeat: now,
+            temperature: temp.clone().into(),
+        };
+
+        let failed = send(running_state, later, Message::Failed("not responding".into()));
+        assert_eq!(failed, ChainState::Failed("not responding".into()));
+        assert_variant!(
+            send(failed.clone(), later, Message::Running(temp.clone().into())),
+            ChainState::Failed(_)
+        );
+        assert_variant!(send(failed.clone(), later, Message::On), ChainState::Failed(_));
+        assert_variant!(
+            tick(failed.clone(), now + Duration::from_secs(10_000)),
+            ChainState::Failed(_)
+        );
+        assert_eq!(
+            failed.get_temperature(SensorAggregation::Max),
+            ChainTemperature::Unknown
+        );
+        assert_eq!(failed.is_warming_up(later), false);
+    }
+
     /// Test "warm up" period
     #[test]
     fn test_monitor_warm_up() {
@@ -761,7 +1333,7 @@ mod test {
         let running_state = ChainState::Running {
             started: now,
             last_heartbeat: now,
-            temperature: temp.clone(),
+            temperature: temp.clone().into(),
         };
 
         assert_eq!(ChainState::Off.is_warming_up(now), false);
@@ -790,7 +1362,7 @@ mod test {
         let running_state = ChainState::Running {
             started: now,
             last_heartbeat: now,
-            temperature: temp.clone(),
+            temperature: temp.clone().into(),
         };
 
         // test that chains break when no-one updates them for long (unless they are turned off)
@@ -869,6 +1441,66 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_missing_fans() {
+        let config = |expected_fans| Config {
+            fans_on_while_warming_up: true,
+            fan_config: Some(FanControlConfig {
+                mode: FanControlMode::TargetTemperature(75.0),
+                min_fans: 1,
+                expected_fans,
+                fan_zones: None,
+            }),
+            temp_config: None,
+        };
+        let feedback = |rpm: &[usize]| fan::Feedback { rpm: rpm.to_vec() };
+        let running = Some(fan::Speed::new(60));
+
+        // one fan died, still enough to keep mining but it's reported
+        assert_eq!(missing_fans(&config(Some(2)), &feedback(&[3600, 0]), running), 1);
+        assert_eq!(missing_fans(&config(Some(2)), &feedback(&[0, 0]), running), 2);
+        assert_eq!(missing_fans(&config(Some(2)), &feedback(&[3600, 3500]), running), 0);
+        assert_eq!(missing_fans(&config(Some(2)), &feedback(&[3600, 3500, 10]), running), 0);
+        // fans stopped on purpose
+        assert_eq!(missing_fans(&config(Some(2)), &feedback(&[0, 0]), Some(fan::Speed::STOPPED)), 0);
+        // no expectation configured
+        assert_eq!(missing_fans(&config(None), &feedback(&[0, 0]), running), 0);
+    }
+
+    #[test]
+    fn test_validate_target_temperature() {
+        let config = |target_temp| Config {
+            fans_on_while_warming_up: true,
+            fan_config: Some(FanControlConfig {
+                mode: FanControlMode::TargetTemperature(target_temp),
+                min_fans: 1,
+                expected_fans: None,
+                fan_zones: None,
+            }),
+            temp_config: Some(TempControlConfig {
+                dangerous_temp: 100.0,
+                hot_temp: 90.0,
+            }),
+        };
+        assert_eq!(config(75.0).validate(), Ok(()));
+        assert_eq!(config(89.0).validate(), Ok(()));
+        assert_eq!(Config::default().validate(), Ok(()));
+        assert!(config(90.0).validate().unwrap_err().contains("below hot"));
+        assert!(config(10.0).validate().unwrap_err().contains("out of range"));
+
+        let no_temp_control = Config {
+            temp_config: None,
+            ..config(95.0)
+        };
+        assert_eq!(no_temp_control.validate(), Ok(()));
+        assert!(Config {
+            temp_config: None,
+            ..config(200.0)
+        }
+        .validate()
+        .is_err());
+    }
+
     /// Test temperature decision tree (non-exhaustive test)
     #[test]
     fn test_decide() {
@@ -883,6 +1515,8 @@ mod test {
         let fan_config = FanControlConfig {
             mode: FanControlMode::FixedSpeed(fan_speed),
             min_fans: 2,
+            expected_fans: None,
+            fan_zones: None,
         };
         let fans_off = fan::Speed::STOPPED;
         let fans_off_config = Config {
@@ -890,6 +1524,8 @@ mod test {
             fan_config: Some(FanControlConfig {
                 mode: FanControlMode::FixedSpeed(fans_off),
                 min_fans: 2,
+                expected_fans: None,
+                fan_zones: None,
             }),
             temp_config: None,
         };
@@ -918,6 +1554,8 @@ mod test {
             fan_config: Some(FanControlConfig {
                 mode: FanControlMode::TargetTemperature(75.0),
                 min_fans: 2,
+                expected_fans: None,
+                fan_zones: None,
             }),
             temp_config: Some(temp_config.clone()),
         };
@@ -1024,4 +1662,142 @@ mod test {
             }
         );
     }
-}
\ No newline at end of file
+    /// Test that temperature spikes and impossible readings don't make it to PID
+    #[test]
+    fn test_temperature_filter() {
+        let mut filter = TemperatureFilter::new(3);
+        let filtered = [60.0, 61.0, 120.0, 62.0, 200.0, -60.0, 61.0]
+            .iter()
+            .map(|&t| filter.filter(ChainTemperature::Ok(t)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            filtered,
+            vec![
+                ChainTemperature::Ok(60.0),
+                ChainTemperature::Ok(60.5),
+                // spike is ignored
+                ChainTemperature::Ok(61.0),
+                ChainTemperature::Ok(62.0),
+                // impossible readings are discarded
+                ChainTemperature::Ok(62.0),
+                ChainTemperature::Ok(62.0),
+                ChainTemperature::Ok(62.0),
+            ]
+        );
+        assert_eq!(
+            filter.filter(ChainTemperature::Failed),
+            ChainTemperature::Failed
+        );
+        assert_eq!(
+            filter.filter(ChainTemperature::Ok(70.0)),
+            ChainTemperature::Ok(70.0)
+        );
+    }
+
+    #[test]
+    fn test_temperature_filter_missed_readings() {
+        let mut filter = TemperatureFilter::new(3);
+        let unknown = ChainTemperature::Unknown;
+        let filtered = [
+            ChainTemperature::Ok(60.0),
+            // I2C glitches are bridged with the last temperature
+            unknown,
+            unknown,
+            ChainTemperature::Ok(62.0),
+            unknown,
+            ChainTemperature::Ok(300.0),
+            unknown,
+            // too many in a row, the temperature isn't known anymore
+            unknown,
+            unknown,
+            // and the sensor is back
+            ChainTemperature::Ok(64.0),
+        ]
+        .iter()
+        .map(|&t| filter.filter(t))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            filtered,
+            vec![
+                ChainTemperature::Ok(60.0),
+                ChainTemperature::Ok(60.0),
+                ChainTemperature::Ok(60.0),
+                ChainTemperature::Ok(61.0),
+                ChainTemperature::Ok(61.0),
+                ChainTemperature::Ok(61.0),
+                ChainTemperature::Ok(61.0),
+                unknown,
+                unknown,
+                ChainTemperature::Ok(64.0),
+            ]
+        );
+
+        // unknown temperature makes fans go full speed
+        let config = Config {
+            fans_on_while_warming_up: true,
+            fan_config: Some(FanControlConfig {
+                mode: FanControlMode::TargetTemperature(75.0),
+                min_fans: 1,
+                expected_fans: None,
+                fan_zones: None,
+            }),
+            temp_config: Some(TempControlConfig {
+                dangerous_temp: 100.0,
+                hot_temp: 90.0,
+            }),
+        };
+        assert_eq!(
+            ControlDecision::decide(&config, 2, filtered[7]).decision,
+            ControlDecision::UseFixedSpeed(fan::Speed::FULL_SPEED)
+        );
+    }
+
+    /// Test that the hottest sensor of a hashchain is used for control
+    #[test]
+    fn test_sensor_aggregation() {
+        let mut readings = sensor::Readings::new();
+        readings.add(
+            "chip",
+            sensor::Temperature {
+                local: sensor::Measurement::Ok(40.0),
+                remote: sensor::Measurement::Ok(71.0),
+            },
+        );
+        readings.add(
+            "board",
+            sensor::Temperature {
+                local: sensor::Measurement::Ok(40.0),
+                remote: sensor::Measurement::Ok(65.0),
+            },
+        );
+        readings.add(
+            "broken",
+            sensor::Temperature {
+                local: sensor::Measurement::InvalidReading,
+                remote: sensor::Measurement::OpenCircuit,
+            },
+        );
+        assert_eq!(
+            SensorAggregation::Max.aggregate(&readings),
+            ChainTemperature::Ok(71.0)
+        );
+        assert_eq!(
+            SensorAggregation::Average.aggregate(&readings),
+            ChainTemperature::Ok(68.0)
+        );
+
+        // single sensor behaves the same as before
+        let temp = sensor::Temperature {
+            local: sensor::Measurement::Ok(10.0),
+            remote: sensor::Measurement::OpenCircuit,
+        };
+        assert_eq!(
+            SensorAggregation::Max.aggregate(&temp.clone().into()),
+            ChainTemperature::from_s9_sensor(temp)
+        );
+        assert_eq!(
+            SensorAggregation::Max.aggregate(&sensor::Readings::new()),
+            ChainTemperature::Unknown
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Worker plugin discovery: which libraries are loaded and from where
+
+use std::collections::HashSet;
+use std::env::consts::DLL_EXTENSION;
+use std::ffi::OsStr;
+use std::fs;
+
+use kasop::PluginManager;
+
+use crate::{cli, Error};
+
+const WHITELIST: [&str; 2] = ["libkaspauart", "kaspauart"];
+
+/// Names of plugin libraries that are loaded: the default ones and `extra` (with and without
+/// `lib` prefix, as it depends on platform)
+pub(crate) fn plugin_whitelist(extra: &[String]) -> Result<Vec<String>, Error> {
+    let mut whitelist: Vec<String> = WHITELIST.iter().map(|lib| lib.to_string()).collect();
+    for name in extra {
+        let name = cli::parse_plugin_name(name)?;
+        let name = name.strip_prefix("lib").unwrap_or(&name).to_string();
+        for lib in [format!("lib{}", name), name] {
+            if !whitelist.contains(&lib) {
+                whitelist.push(lib);
+            }
+        }
+    }
+    Ok(whitelist)
+}
+
+fn filter_plugins(dirname: &str, whitelist: &[String]) -> Vec<String> {
+    match fs::read_dir(dirname) {
+        Ok(readdir) => readdir
+            .map(|entry| entry.unwrap().path())
+            .filter(|fname| {
+                fname.is_file()
+                    && fname.extension().is_some()
+                    && fname.extension().and_then(OsStr::to_str).unwrap_or_default().starts_with(DLL_EXTENSION)
+            })
+            .filter(|fname| whitelist.iter().any(|lib| Some(lib.as_str()) == fname.file_stem().and_then(OsStr::to_str)))
+            .map(|path| path.to_str().unwrap().to_string())
+            .collect::<Vec<String>>(),
+        _ => Vec::<String>::new(),
+    }
+}
+
+/// Plugins in all `dirnames`, each library only once even if it's reachable through more of them
+pub(crate) fn find_plugins(dirnames: &[String], whitelist: &[String]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    dirnames
+        .iter()
+        .flat_map(|dirname| filter_plugins(dirname, whitelist))
+        .filter(|path| seen.insert(fs::canonicalize(path).unwrap_or_else(|_| path.into())))
+        .collect()
+}
+
+/// Make sure something is going to mine: at least one plugin was loaded or CPU threads are enabled
+pub(crate) fn check_workers(
+    plugin_dir: &str,
+    whitelist: &[String],
+    plugin_manager: &PluginManager,
+    num_threads: u16,
+) -> Result<(), Error> {
+    if plugin_manager.has_specs() || num_threads > 0 {
+        return Ok(());
+    }
+    let libs: Vec<_> = whitelist.iter().map(|lib| format!("{}.{}", lib, DLL_EXTENSION)).collect();
+    Err(format!(
+        "no worker plugins found in {} (looked for {}) and CPU mining is disabled (see --threads and --cpu-threads)",
+        plugin_dir,
+        libs.join(", ")
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cli::Opt;
+    use clap::Parser;
+
+    #[test]
+    fn test_no_plugins_found() {
+        let dir = std::env::temp_dir().join(format!("kasop-no-plugins-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_name = dir.to_str().unwrap();
+        let whitelist = plugin_whitelist(&[]).unwrap();
+        assert!(filter_plugins(dir_name, &whitelist).is_empty());
+
+        let plugin_manager = PluginManager::new();
+        let err = check_workers(dir_name, &whitelist, &plugin_manager, 0).unwrap_err();
+        assert!(err.to_string().starts_with(&format!("no worker plugins found in {}", dir_name)));
+        assert!(err.to_string().contains("kaspauart"));
+        // CPU mining doesn't need plugins
+        assert!(check_workers(dir_name, &whitelist, &plugin_manager, 2).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_plugin_allow() {
+        let dir = std::env::temp_dir().join(format!("kasop-plugin-allow-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for file in ["libmyworker", "libother"] {
+            fs::write(dir.join(format!("{}.{}", file, DLL_EXTENSION)), b"").unwrap();
+        }
+        fs::write(dir.join("libmyworker.txt"), b"").unwrap();
+        let dir_name = dir.to_str().unwrap();
+
+        assert!(filter_plugins(dir_name, &plugin_whitelist(&[]).unwrap()).is_empty());
+        let whitelist = plugin_whitelist(&["myworker".to_string()]).unwrap();
+        assert!(whitelist.contains(&"kaspauart".to_string()));
+        let expected = dir.join(format!("libmyworker.{}", DLL_EXTENSION)).to_str().unwrap().to_string();
+        assert_eq!(filter_plugins(dir_name, &whitelist), vec![expected.clone()]);
+        // with prefix it's the same library
+        assert_eq!(filter_plugins(dir_name, &plugin_whitelist(&["libmyworker".to_string()]).unwrap()), vec![expected]);
+
+        assert!(plugin_whitelist(&["../evil".to_string()]).is_err());
+        assert!(plugin_whitelist(&["".to_string()]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let address = "kaspa:qqqqzqsrqszsvpcgpy9qkrqdpc83qygjzv2p29shrqv35xcur50p7u4jhsajr";
+        let opt = Opt::try_parse_from(["kasop", "-a", address, "--plugin-allow", "a", "--plugin-allow", "b"])
+            .expect("parsing failed");
+        assert_eq!(opt.plugin_allow, vec!["a", "b"]);
+        assert!(Opt::try_parse_from(["kasop", "-a", address, "--plugin-allow", "a/b"]).is_err());
+    }
+
+    #[test]
+    fn test_plugin_dirs() {
+        let dir = std::env::temp_dir().join(format!("kasop-plugin-dirs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (exe_dir, extra_dir) = (dir.join("bin"), dir.join("lib"));
+        fs::create_dir_all(&exe_dir).unwrap();
+        fs::create_dir_all(&extra_dir).unwrap();
+        let plugin = extra_dir.join(format!("libkaspauart.{}", DLL_EXTENSION));
+        fs::write(&plugin, b"").unwrap();
+
+        let whitelist = plugin_whitelist(&[]).unwrap();
+        let exe_dir = exe_dir.to_str().unwrap().to_string();
+        let extra_dir = extra_dir.to_str().unwrap().to_string();
+        assert!(find_plugins(&[exe_dir.clone()], &whitelist).is_empty());
+        let plugins = find_plugins(&[exe_dir.clone(), extra_dir.clone()], &whitelist);
+        assert_eq!(plugins, vec![plugin.to_str().unwrap().to_string()]);
+        // same directory through different path
+        let same_dir = format!("{}/../lib", exe_dir);
+        assert_eq!(find_plugins(&[exe_dir, extra_dir, same_dir], &whitelist), plugins);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,11 @@
 use log::info;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 pub use crate::pow::hasher::HeaderHasher;
 use crate::{
+    counters, events,
     pow::{
         hasher::{Hasher, PowHasher},
         heavy_hash::Matrix,
@@ -13,6 +16,7 @@ use crate::{
 };
 use kasop::Worker;
 
+pub mod cpu;
 mod hasher;
 mod heavy_hash;
 mod keccak;
@@ -34,21 +38,68 @@ pub enum BlockSeed {
 }
 
 impl BlockSeed {
-    pub fn report_block(&self) {
+    /// Whether the solution meets network difficulty. Jobs from the node are mined at network
+    /// target, so all their solutions are blocks. Stratum jobs carry share target only (pools don't
+    /// send network bits), a share that happens to be a block can't be recognized.
+    pub fn is_block(&self) -> bool {
+        matches!(self, BlockSeed::FullBlock(_))
+    }
+
+    /// Log the solution found by a worker. Blocks are only counted once kaspad accepts them (see
+    /// `report_accepted_block`), the solution can still turn out duplicate or stale.
+    pub fn report_found(&self) {
         match self {
             BlockSeed::FullBlock(block) => {
                 let block_hash =
                     block.block_hash().expect("We just got it from the state, we should be able to hash it");
-                info!("Found a block: {:x}", block_hash);
+                info!(
+                    block = "candidate", block_hash = format!("{:x}", block_hash).as_str();
+                    "Found a block candidate: {:x}", block_hash
+                );
+            }
+            BlockSeed::PartialBlock { id, nonce, .. } => {
+                info!(share = "found", job_id = id.as_str(), nonce = *nonce; "Found a share!")
+            }
+        }
+    }
+
+    /// Whether the solution passes local share difficulty floor with target `floor` (the target is
+    /// inclusive like pool target). Blocks always pass, so do shares whose hash isn't known.
+    pub fn meets_floor(&self, floor: Option<&Uint256>) -> bool {
+        match (self, floor) {
+            (BlockSeed::PartialBlock { hash: Some(hash), .. }, Some(floor)) => {
+                match Uint256::from_hex(hash, target::HexOrder::BigEndian) {
+                    Ok(hash) => hash <= *floor,
+                    Err(_) => true,
+                }
             }
-            BlockSeed::PartialBlock { .. } => info!("Found a share!"),
+            _ => true,
         }
     }
 }
 
+/// Count `block` accepted by kaspad as found, log it and emit `BlockFound`
+pub fn report_accepted_block(block: &RpcBlock) {
+    let block_hash = match block.block_hash() {
+        Some(block_hash) => format!("{:x}", block_hash),
+        None => "unknown".to_string(),
+    };
+    let count = counters::count_block_found();
+    info!(
+        block = "found", block_hash = block_hash.as_str(), count = count;
+        "Found a block: {} ({} since start)", block_hash, count
+    );
+    events::emit(events::Event::BlockFound { block_hash });
+}
+
 #[derive(Clone)]
 pub struct State {
+    /// Increasing job number, newer jobs have higher ones
     pub id: usize,
+    /// When the job was received
+    pub received: Instant,
+    /// Id of the latest job, shared by all jobs that replace each other
+    latest_id: Arc<AtomicUsize>,
     matrix: Arc<Matrix>,
     pub target: Uint256,
     pub pow_hash_header: [u8; 72],
@@ -108,6 +159,8 @@ impl State {
         );
         Ok(Self {
             id,
+            received: Instant::now(),
+            latest_id: Arc::new(AtomicUsize::new(id)),
             matrix,
             target: header_target,
             pow_hash_header,
@@ -118,6 +171,18 @@ impl State {
         })
     }
 
+    /// Make this job the latest of jobs sharing `latest_id`, so that older ones become stale
+    pub fn replace_older(mut self, latest_id: &Arc<AtomicUsize>) -> Self {
+        latest_id.store(self.id, Ordering::Release);
+        self.latest_id = Arc::clone(latest_id);
+        self
+    }
+
+    /// Whether a newer job replaced this one, solutions for it are stale
+    pub fn is_stale(&self) -> bool {
+        self.latest_id.load(Ordering::Acquire) != self.id
+    }
+
     #[inline(always)]
     // PRE_POW_HASH || TIME || 32 zero byte padding || NONCE
     pub fn calculate_pow(&self, nonce: u64) -> Uint256 {
@@ -161,6 +226,32 @@ impl State {
     }
 }
 
+/// Length of PRE_POW_HASH || TIME, the part of pow header that's not constant
+const POW_HEADER_PREFIX_LEN: usize = 40;
+
+/// Compute PoW hash of `header_bytes` with `nonce`.
+/// `header_bytes` is PRE_POW_HASH || TIME, optionally followed by the 32 zero byte padding (ie.
+/// `State::pow_hash_header` can be passed as it is).
+pub fn calculate_pow_hash(header_bytes: &[u8], nonce: u64) -> Result<Uint256, Error> {
+    if header_bytes.len() != POW_HEADER_PREFIX_LEN && header_bytes.len() != POW_HEADER_PREFIX_LEN + 32 {
+        return Err(format!("invalid pow header length {}", header_bytes.len()).into());
+    }
+    let pre_pow_hash = Hash::from_le_bytes(header_bytes[..32].try_into().expect("checked length"));
+    let timestamp = u64::from_le_bytes(header_bytes[32..POW_HEADER_PREFIX_LEN].try_into().expect("checked length"));
+
+    let hash = PowHasher::new(pre_pow_hash, timestamp).finalize_with_nonce(nonce);
+    Ok(Matrix::generate(pre_pow_hash).heavy_hash(hash))
+}
+
+/// Check that `nonce` is a solution of `header_bytes` (see `calculate_pow_hash`) for `target`.
+/// Malformed header is never a solution.
+pub fn check_pow(header_bytes: &[u8], nonce: u64, target: &Uint256) -> bool {
+    match calculate_pow_hash(header_bytes, nonce) {
+        Ok(pow) => pow <= *target,
+        Err(_) => false,
+    }
+}
+
 #[cfg(not(any(target_pointer_width = "64", target_pointer_width = "32")))]
 compile_error!("Supporting only 32/64 bits");
 
@@ -249,10 +340,172 @@ fn decode_to_slice<T: AsRef<[u8]>>(data: T, out: &mut [u8]) -> Result<(), FromHe
 #[cfg(test)]
 mod tests {
     use crate::pow::hasher::{Hasher, HeaderHasher};
-    use crate::pow::serialize_header;
-    use crate::proto::{RpcBlockHeader, RpcBlockLevelParents};
+    use crate::events::{self, Event};
+    use crate::counters::blocks_found;
+    use crate::pow::{calculate_pow_hash, check_pow, report_accepted_block, serialize_header, BlockSeed, State};
+    use crate::proto::{RpcBlock, RpcBlockHeader, RpcBlockLevelParents};
+    use crate::target::{self, HexOrder, Uint256};
     use crate::Hash;
 
+    /// Build a stratum-style job and the matching pow header bytes
+    fn partial_block(pre_pow_hash: &str, timestamp: u64) -> (State, Vec<u8>) {
+        let pre_pow_hash = Uint256::from_hex(pre_pow_hash, HexOrder::LittleEndian).unwrap();
+        let seed = BlockSeed::PartialBlock {
+            id: "1".into(),
+            header_hash: pre_pow_hash.0,
+            timestamp,
+            nonce: 0,
+            target: Uint256([u64::MAX; 4]),
+            nonce_mask: u64::MAX,
+            nonce_fixed: 0,
+            hash: None,
+        };
+        let header_bytes = [pre_pow_hash.to_le_bytes().as_slice(), timestamp.to_le_bytes().as_slice()].concat();
+        (State::new(0, seed).unwrap(), header_bytes)
+    }
+
+    /// Kaspa mainnet genesis header
+    fn genesis_header() -> RpcBlockHeader {
+        let zeros = "00".repeat(32);
+        RpcBlockHeader {
+            version: 0,
+            parents: vec![],
+            hash_merkle_root: "8ec898568c6801d13df4ee6e2a1b54b7e6236f671f20954f05306410518eeb32".into(),
+            accepted_id_merkle_root: zeros.clone(),
+            utxo_commitment: "710f27df423e63aa6cdb72b89ea5a06cffa399d66f167704455b5af59def8e20".into(),
+            timestamp: 1637609671037,
+            bits: 486722099,
+            nonce: 0x3392c,
+            daa_score: 1312860,
+            blue_work: "".into(),
+            pruning_point: zeros,
+            blue_score: 0,
+        }
+    }
+
+    #[test]
+    fn test_check_pow() {
+        let header = genesis_header();
+        let block = RpcBlock { header: Some(header.clone()), ..Default::default() };
+        let genesis_hash = "58c2d4199e21f910d1571d114969cecef48f09f934d42ccb6a281a15868f2999";
+        assert_eq!(block.block_hash().unwrap(), Uint256::from_hex(genesis_hash, HexOrder::LittleEndian).unwrap());
+
+        // (pre-pow hash, timestamp, nonce, pow hash): mainnet genesis and the header from kaspad
+        // serialization tests (see `test_serialize_header`). kaspad has no pow hash vectors, expected
+        // hashes come from a standalone Python script written for this test: pure-Python Keccak
+        // (checked against `hashlib` SHA3-256 and SHAKE256), cSHAKE256 and xoshiro256++ matrix
+        // generation following kaspad's `domain/consensus/utils/pow`, and it reproduces the vectors of
+        // `heavy_hash::tests`. It shares no code with this crate.
+        let vectors: [(&str, u64, u64, &str); 2] = [
+            (
+                "3d1d345b2265f488eacc9e3d4fdd26fb9a57a73c11abe9b9fbe34a31c939989f",
+                1637609671037,
+                0x3392c,
+                "b61c15e171ed3eff0a233d7aebc0afc8b9fa2a9414fe1e94ae369e037b639520",
+            ),
+            (
+                "5592d3d98aef2f55983b3a10049581b3ace2aee9a060ca3606e1408e6a006e89",
+                17020149120696937990,
+                8230160685758639177,
+                "1728e0537ae52454cd19eb858d1ccbebb88f398dbd6314d2de883011e8562357",
+            ),
+        ];
+        for (pre_pow_hash, timestamp, nonce, expected) in vectors {
+            let (state, header_bytes) = partial_block(pre_pow_hash, timestamp);
+            let expected = Uint256::from_hex(expected, HexOrder::BigEndian).unwrap();
+            assert_eq!(calculate_pow_hash(&header_bytes, nonce).unwrap(), expected);
+
+            // free function agrees with the mining loop
+            assert_eq!(state.calculate_pow(nonce), expected);
+            assert_eq!(calculate_pow_hash(&state.pow_hash_header, nonce).unwrap(), expected);
+
+            // target is inclusive
+            assert!(check_pow(&header_bytes, nonce, &expected));
+            let below = Uint256([expected.0[0] - 1, expected.0[1], expected.0[2], expected.0[3]]);
+            assert!(!check_pow(&header_bytes, nonce, &below));
+            assert!(!check_pow(&header_bytes, nonce, &Uint256::default()));
+        }
+
+        // pre-pow hash of the genesis header is the first vector
+        let mut hasher = HeaderHasher::new();
+        serialize_header(&mut hasher, &header, true);
+        let pre_pow_hash = hasher.finalize();
+        let header_bytes = [pre_pow_hash.to_le_bytes().as_slice(), header.timestamp.to_le_bytes().as_slice()].concat();
+        assert_eq!(pre_pow_hash, Uint256::from_hex(vectors[0].0, HexOrder::LittleEndian).unwrap());
+        // genesis is not validated for PoW and doesn't meet its own difficulty
+        let genesis_target = target::u256_from_compact_target(header.bits);
+        assert!(!check_pow(&header_bytes, header.nonce, &genesis_target));
+
+        // malformed header
+        assert!(calculate_pow_hash(&[0u8; 39], 0).is_err());
+        assert!(!check_pow(&[0u8; 39], 0, &Uint256([u64::MAX; 4])));
+    }
+
+    #[test]
+    fn test_meets_floor() {
+        let pre_pow_hash = "a98347ec1e71514eb26822162dc7c3992fd41f0b2ccc26e55e7bd8f3fa37215f";
+        let (state, _) = partial_block(pre_pow_hash, 1650000000000);
+        let nonce = 8230160685758639177;
+        let pow = state.calculate_pow(nonce);
+        let share = state.generate_block_if_pow(nonce).unwrap();
+
+        assert!(share.meets_floor(None));
+        // floor target is inclusive
+        assert!(share.meets_floor(Some(&pow)));
+        assert!(share.meets_floor(Some(&Uint256([pow.0[0].wrapping_add(1), pow.0[1], pow.0[2], pow.0[3]]))));
+        assert!(!share.meets_floor(Some(&Uint256([pow.0[0].wrapping_sub(1), pow.0[1], pow.0[2], pow.0[3]]))));
+        assert!(!share.meets_floor(Some(&Uint256::default())));
+
+        // hash is only known once a solution is found
+        let mut job = share.clone();
+        if let BlockSeed::PartialBlock { ref mut hash, .. } = job {
+            *hash = None;
+        }
+        assert!(job.meets_floor(Some(&Uint256::default())));
+
+        // blocks are never dropped
+        let block = BlockSeed::FullBlock(Default::default());
+        assert!(block.meets_floor(Some(&Uint256::default())));
+    }
+
+    #[test]
+    fn test_report_block() {
+        let mut events = events::subscribe();
+        let (state, _) =
+            partial_block("a98347ec1e71514eb26822162dc7c3992fd41f0b2ccc26e55e7bd8f3fa37215f", 1650000000000);
+        let share = state.generate_block_if_pow(42).unwrap();
+        assert!(!share.is_block());
+        let before = blocks_found();
+        share.report_found();
+        assert_eq!(blocks_found(), before);
+
+        // Easiest network target, about every other nonce is a block
+        let zeros = "00".repeat(32);
+        let header = RpcBlockHeader {
+            version: 1,
+            hash_merkle_root: zeros.clone(),
+            accepted_id_merkle_root: zeros.clone(),
+            utxo_commitment: zeros.clone(),
+            bits: 0x207fffff,
+            blue_work: "00".into(),
+            pruning_point: zeros,
+            ..Default::default()
+        };
+        let job = State::new(0, BlockSeed::FullBlock(RpcBlock { header: Some(header), ..Default::default() })).unwrap();
+        let block = (0..).find_map(|nonce| job.generate_block_if_pow(nonce)).unwrap();
+        assert!(block.is_block());
+        // Not before kaspad accepts it
+        block.report_found();
+        assert_eq!(blocks_found(), before);
+        match block {
+            BlockSeed::FullBlock(block) => report_accepted_block(&block),
+            BlockSeed::PartialBlock { .. } => panic!("block expected"),
+        }
+        assert_eq!(blocks_found(), before + 1);
+        // Other tests may emit to the same bus
+        while !matches!(events.try_recv().expect("block found event missing"), Event::BlockFound { .. }) {}
+    }
+
     struct Buf(Vec<u8>);
     impl Hasher for Buf {
         fn update<A: AsRef<[u8]>>(&mut self, data: A) -> &mut Self {
@@ -0,0 +1,143 @@
+//! Built-in CPU `Worker`
+//!
+//! Runs through the same `WorkerSpec`/`Worker` interface as GPU plugins, so it can be used to
+//! smoke test the whole mining pipeline on machines without a GPU or any plugin installed.
+
+use rand::{thread_rng, RngCore};
+
+use crate::pow::{hasher::PowHasher, heavy_hash::Matrix};
+use crate::target::Uint256;
+use crate::{Error, Hash};
+use kasop::{NoncePartition, Worker, WorkerSpec};
+
+/// How many nonces one `calculate_hash` call tries by default
+pub const DEFAULT_CPU_WORKLOAD: usize = 1024;
+
+pub struct CpuWorkerSpec {
+    pub index: usize,
+    pub workload: usize,
+}
+
+impl WorkerSpec for CpuWorkerSpec {
+    fn build(&self, partition: NoncePartition) -> Box<dyn Worker> {
+        Box::new(CpuWorker::new(self.index, self.workload, partition))
+    }
+}
+
+pub struct CpuWorker {
+    index: usize,
+    workload: usize,
+    partition: NoncePartition,
+    hasher: Option<PowHasher>,
+    matrix: Option<Box<Matrix>>,
+    target: Uint256,
+    nonce: u64,
+    /// Last solution found, 0 if none
+    found: u64,
+}
+
+impl CpuWorker {
+    pub fn new(index: usize, workload: usize, partition: NoncePartition) -> Self {
+        Self {
+            index,
+            workload,
+            partition,
+            hasher: None,
+            matrix: None,
+            target: Uint256::default(),
+            nonce: thread_rng().next_u64(),
+            found: 0,
+        }
+    }
+}
+
+impl Worker for CpuWorker {
+    fn id(&self) -> String {
+        format!("CPU worker {}", self.index)
+    }
+
+    fn load_block_constants(&mut self, hash_header: &[u8; 72], matrix: &[[u16; 64]; 64], target: &[u64; 4]) {
+        // PRE_POW_HASH || TIME || 32 zero byte padding
+        let pre_pow_hash = Hash::from_le_bytes(hash_header[..32].try_into().unwrap());
+        let timestamp = u64::from_le_bytes(hash_header[32..40].try_into().unwrap());
+        self.hasher = Some(PowHasher::new(pre_pow_hash, timestamp));
+        self.matrix = Some(Box::new(Matrix(*matrix)));
+        self.target = Uint256(*target);
+    }
+
+    fn calculate_hash(&mut self, _nonces: Option<&Vec<u64>>, nonce_mask: u64, nonce_fixed: u64) {
+        let (hasher, matrix) = match (&self.hasher, &self.matrix) {
+            (Some(hasher), Some(matrix)) => (hasher, matrix),
+            _ => return,
+        };
+        let (nonce_mask, nonce_fixed) = self.partition.apply(nonce_mask, nonce_fixed);
+        for _ in 0..self.workload {
+            self.nonce = self.nonce.wrapping_add(1);
+            let nonce = (self.nonce & nonce_mask) | nonce_fixed;
+            if nonce != 0 && matrix.heavy_hash(hasher.finalize_with_nonce(nonce)) <= self.target {
+                self.found = nonce;
+                return;
+            }
+        }
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn get_workload(&self) -> usize {
+        self.workload
+    }
+
+    fn copy_output_to(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
+        nonces[0] = self.found;
+        self.found = 0;
+        Ok(())
+    }
+
+    fn requires_filter(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pow::{BlockSeed, State};
+
+    #[test]
+    fn test_cpu_worker_finds_solution() {
+        // Roughly one in 16 hashes is a solution
+        let target = Uint256([u64::MAX, u64::MAX, u64::MAX, u64::MAX >> 4]);
+        let seed = BlockSeed::PartialBlock {
+            id: "1".into(),
+            header_hash: [0x0123456789abcdef, 0xfedcba9876543210, 0x1111111111111111, 0x2222222222222222],
+            timestamp: 1646133184000,
+            nonce: 0,
+            target,
+            nonce_mask: u64::MAX,
+            nonce_fixed: 0,
+            hash: None,
+        };
+        let state = State::new(0, seed).unwrap();
+        let partition = NoncePartition::new(1, 4);
+        let mut worker = CpuWorkerSpec { index: 0, workload: DEFAULT_CPU_WORKLOAD }.build(partition);
+
+        state.load_to_gpu(worker.as_mut());
+        state.pow_gpu(worker.as_mut());
+        worker.sync().unwrap();
+        let mut nonces = vec![0u64; 1];
+        worker.copy_output_to(&mut nonces).unwrap();
+
+        let nonce = nonces[0];
+        assert_ne!(nonce, 0, "no solution found in {} nonces", DEFAULT_CPU_WORKLOAD);
+        assert!(state.check_pow(nonce));
+        assert!(state.generate_block_if_pow(nonce).is_some());
+        let (mask, fixed) = partition.apply(u64::MAX, 0);
+        assert_eq!(nonce & !mask, fixed);
+
+        // The solution is reported only once
+        worker.copy_output_to(&mut nonces).unwrap();
+        assert_eq!(nonces[0], 0);
+    }
+}
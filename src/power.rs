@@ -1,12 +1,15 @@
 pub mod firmware;
+pub mod limit;
 
 use logging::macros::*;
 
 use std::convert::TryInto;
+use std::ops::Add;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
-use crate::async_i2c::AsyncI2cDev;
+use crate::async_i2c::{AsyncI2c, AsyncI2cDev, RequestOptions};
 use crate::error::{self, ErrorKind};
 use crate::halt;
 
@@ -66,11 +69,16 @@ const RD_TEMP_OFFSET_VALUE: u8 = 0x23;
 /// The PIC firmware in the voltage controller is expected to provide/return this version
 pub const EXPECTED_VOLTAGE_CTRL_VERSION: u8 = 0x03;
 
+/// How many times PIC program load is tried before giving up
+const PIC_PROGRAM_ATTEMPTS: usize = 3;
+/// Base of delay between PIC program load attempts (grows with every attempt)
+const PIC_PROGRAM_BACKOFF: Duration = Duration::from_secs(1);
+
 /// Path to voltage controller PIC program
 pub const PIC_PROGRAM_PATH: &'static str = "/lib/antminer/hash_s8_app.txt";
 
 /// Bundle voltage value with methods to convert it to/from various representations
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Voltage(u8);
 
 impl Voltage {
@@ -142,7 +150,7 @@ impl std::cmp::PartialOrd for Voltage {
 /// S9 devices have a single I2C master that manages the voltage controllers on all hashboards.
 /// Therefore, this will be a single communication instance.
 pub struct I2cBackend {
-    inner: AsyncI2cDev,
+    inner: Box<dyn AsyncI2c>,
 }
 
 impl I2cBackend {
@@ -159,20 +167,31 @@ impl I2cBackend {
     /// Instantiates a new I2C backend
     /// * `i2c_interface_num` - index of the I2C interface in Linux dev filesystem
     pub fn new(i2c_interface_num: usize) -> Self {
-        Self {
-            inner: AsyncI2cDev::open(format!("/dev/i2c-{}", i2c_interface_num))
+        Self::with_device(Box::new(
+            AsyncI2cDev::open(format!("/dev/i2c-{}", i2c_interface_num))
                 .expect("I2C instantiation failed"),
-        }
+        ))
+    }
+
+    /// Instantiates I2C backend on top of arbitrary I2C master (ie. fake one in tests)
+    pub fn with_device(inner: Box<dyn AsyncI2c>) -> Self {
+        Self { inner }
     }
 
     /// Attempt to write a byte to power controller on I2C.
     /// If write fails then retry (at most `I2C_NUM_RETRIES`).
+    /// Power controller requests have high priority, so that sensor polling on the same bus
+    /// can't hold up voltage control.
     async fn write_retry(&self, hashboard_idx: usize, data: u8) -> error::Result<()> {
         let mut tries_left: usize = Self::I2C_NUM_RETRIES;
         loop {
             let ret = self
                 .inner
-                .write(Self::get_i2c_address(hashboard_idx), vec![data])
+                .write_with(
+                    Self::get_i2c_address(hashboard_idx),
+                    vec![data],
+                    RequestOptions::high_priority(),
+                )
                 .await;
             if ret.is_ok() {
                 return ret;
@@ -213,7 +232,11 @@ impl I2cBackend {
         for _ in 0..length {
             let byte = self
                 .inner
-                .read(Self::get_i2c_address(hashboard_idx), length)
+                .read_with(
+                    Self::get_i2c_address(hashboard_idx),
+                    length,
+                    RequestOptions::high_priority(),
+                )
                 .await?;
             reply.push(byte[0]);
         }
@@ -249,7 +272,7 @@ impl HashboardBackend {
 }
 
 /// Type to represent number of PIC flash words
-/// TODO: implement arithmetic on `PicWords`, `PicAddress`, add constructors, bounds, etc.
+/// Arithmetic panics on overflow, use `checked_*` variants for values that come from outside.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct PicWords(pub u16);
 
@@ -258,10 +281,33 @@ impl PicWords {
         self.0 as usize * 2
     }
 
+    /// Number of words in `num_bytes`, `None` if it's not a whole number of words or too many
+    pub fn checked_from_bytes(num_bytes: usize) -> Option<Self> {
+        if num_bytes % 2 != 0 {
+            return None;
+        }
+        let num_words = num_bytes / 2;
+        if num_words > std::u16::MAX as usize {
+            return None;
+        }
+        Some(Self(num_words as u16))
+    }
+
     pub fn from_bytes(num_bytes: usize) -> Self {
-        assert_eq!(num_bytes % 2, 0);
-        assert!(num_bytes / 2 <= std::u16::MAX as usize);
-        Self((num_bytes / 2) as u16)
+        Self::checked_from_bytes(num_bytes)
+            .unwrap_or_else(|| panic!("BUG: {:#x} bytes is not a valid number of words", num_bytes))
+    }
+
+    pub fn checked_add(&self, other: PicWords) -> Option<PicWords> {
+        self.0.checked_add(other.0).map(PicWords)
+    }
+}
+
+impl Add for PicWords {
+    type Output = PicWords;
+
+    fn add(self, other: PicWords) -> PicWords {
+        self.checked_add(other).expect("BUG: PIC words overflow")
     }
 }
 
@@ -270,13 +316,37 @@ impl PicWords {
 pub struct PicAddress(pub u16);
 
 impl PicAddress {
+    /// Number of words from this address to `end` (both inclusive), so that `a.distance_to(a)`
+    /// is one word. `None` if `end` is before this address or the whole address space is spanned
+    /// (which doesn't fit `PicWords`).
+    pub fn checked_distance_to(&self, end: PicAddress) -> Option<PicWords> {
+        end.0
+            .checked_sub(self.0)
+            .and_then(|distance| distance.checked_add(1))
+            .map(PicWords)
+    }
+
     pub fn distance_to(&self, end: PicAddress) -> PicWords {
-        assert!(self.0 <= end.0);
-        PicWords(end.0 - self.0 + 1)
+        self.checked_distance_to(end).unwrap_or_else(|| {
+            panic!("BUG: invalid PIC address range {:#x}..={:#x}", self.0, end.0)
+        })
+    }
+
+    /// Address `distance` words after this one, `None` if it's beyond the address space
+    pub fn checked_offset(&self, distance: PicWords) -> Option<PicAddress> {
+        self.0.checked_add(distance.0).map(PicAddress)
     }
 
     pub fn offset(&self, distance: PicWords) -> PicAddress {
-        PicAddress(self.0 + distance.0)
+        self.checked_offset(distance).expect("BUG: PIC address overflow")
+    }
+}
+
+impl Add<PicWords> for PicAddress {
+    type Output = PicAddress;
+
+    fn add(self, distance: PicWords) -> PicAddress {
+        self.offset(distance)
     }
 }
 
@@ -400,6 +470,8 @@ pub struct Control {
     /// Information from PIC flash
     badcore_flash: Mutex<Option<FlashBadcore>>,
     freq_flash: Mutex<Option<FlashFreq>>,
+    /// PIC program to flash when the firmware is wrong, `None` for the embedded/default one
+    pic_program_path: Option<PathBuf>,
 }
 
 impl Control {
@@ -602,6 +674,12 @@ impl Control {
         Ok(self.read(GET_VOLTAGE, 1).await?[0])
     }
 
+    /// Read back voltage set-point the PIC currently holds (see `set_voltage`). It's not a
+    /// measurement: S9 PIC senses neither voltage nor current, so there's nothing else to report.
+    pub async fn read_voltage(&self) -> error::Result<Voltage> {
+        Voltage::from_pic_value(self.get_voltage().await?)
+    }
+
     pub async fn send_heart_beat(&self) -> error::Result<()> {
         self.write(SEND_HEART_BEAT, &[]).await
     }
@@ -639,6 +717,57 @@ impl Control {
         Ok(())
     }
 
+    /// Check that PIC flash holds `program`
+    pub async fn verify_program(&self, program: &firmware::PicProgram) -> error::Result<()> {
+        let in_flash = self.read_flash(program.load_addr, program.prog_size).await?;
+        if in_flash != program.bytes {
+            let first_difference = in_flash
+                .iter()
+                .zip(program.bytes.iter())
+                .position(|(a, b)| a != b)
+                .unwrap_or_else(|| in_flash.len().min(program.bytes.len()));
+            Err(ErrorKind::Power(format!(
+                "PIC program verification failed at byte {:#x}",
+                first_difference
+            )))?
+        }
+        Ok(())
+    }
+
+    /// Load PIC program and read it back, retrying with increasing delay (`attempt * backoff`)
+    /// when either fails so that a glitch on noisy I2C bus isn't fatal. Gives up after
+    /// `attempts` tries.
+    pub async fn program_pic_verified(
+        &self,
+        program: &firmware::PicProgram,
+        attempts: usize,
+        backoff: Duration,
+    ) -> error::Result<()> {
+        assert!(attempts > 0, "BUG: no attempt to program PIC");
+        for attempt in 1..=attempts {
+            let result = match self.program_pic(program).await {
+                Ok(()) => self.verify_program(program).await,
+                Err(e) => Err(e),
+            };
+            let e = match result {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if attempt == attempts {
+                Err(ErrorKind::Power(format!(
+                    "PIC program failed to load after {} attempts: {}",
+                    attempts, e
+                )))?
+            }
+            warn!(
+                "PIC program load attempt {}/{} failed: {}, retrying",
+                attempt, attempts, e
+            );
+            delay_for(backoff * attempt as u32).await;
+        }
+        unreachable!()
+    }
+
     /// Creates a new voltage controller
     pub fn new(backend: Arc<I2cBackend>, hashboard_idx: usize) -> Self {
         Self {
@@ -646,9 +775,16 @@ impl Control {
             current_voltage: Mutex::new(None),
             badcore_flash: Mutex::new(None),
             freq_flash: Mutex::new(None),
+            pic_program_path: None,
         }
     }
 
+    /// Flash PIC program from `path` instead of the embedded/default one
+    pub fn with_pic_program_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.pic_program_path = Some(path.into());
+        self
+    }
+
     async fn reset_and_start_app(&self) -> error::Result<u8> {
         self.reset().await?;
         // Dump PIC flash. This can be done only before jumping to app.
@@ -670,8 +806,9 @@ impl Control {
         // TODO accept multiple
         if version != EXPECTED_VOLTAGE_CTRL_VERSION {
             info!("Bad firmware version! Reloading firmware...");
-            let program = firmware::PicProgram::read(PIC_PROGRAM_PATH)?;
-            self.program_pic(&program).await?;
+            let program = firmware::PicProgram::load(self.pic_program_path.as_deref(), None)?;
+            self.program_pic_verified(&program, PIC_PROGRAM_ATTEMPTS, PIC_PROGRAM_BACKOFF)
+                .await?;
 
             let version = self.reset_and_start_app().await?;
             if version != EXPECTED_VOLTAGE_CTRL_VERSION {
@@ -731,6 +868,8 @@ impl Control {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::async_i2c::test_utils::FakeAsyncI2c;
+    use async_compat::tokio;
 
     #[test]
     fn test_pic_address_words() {
@@ -742,6 +881,47 @@ mod test {
         assert_eq!(PicWords::from_bytes(0x1fffe), PicWords(0xffff));
     }
 
+    #[test]
+    fn test_pic_address_arithmetic() {
+        let load = PicAddress(0x0300);
+        let end = PicAddress(0x0f7f);
+        let size = load.distance_to(end);
+        assert_eq!(size, PicWords(0x0c80));
+        assert_eq!(load + size, PicAddress(0x1000));
+        assert_eq!(load.offset(size), end.offset(PicWords(1)));
+        assert_eq!(load + PicWords(0), load);
+
+        // equal endpoints span one word, reversed ones nothing
+        assert_eq!(load.checked_distance_to(load), Some(PicWords(1)));
+        assert_eq!(end.checked_distance_to(load), None);
+        // whole address space doesn't fit
+        assert_eq!(PicAddress(0).checked_distance_to(PicAddress(0xffff)), None);
+        assert_eq!(
+            PicAddress(1).checked_distance_to(PicAddress(0xffff)),
+            Some(PicWords(0xffff))
+        );
+
+        assert_eq!(PicAddress(0xfff0).checked_offset(PicWords(0xf)), Some(PicAddress(0xffff)));
+        assert_eq!(PicAddress(0xfff0).checked_offset(PicWords(0x10)), None);
+        assert_eq!(PicWords(0x10) + PicWords(0x20), PicWords(0x30));
+        assert_eq!(PicWords(0xffff).checked_add(PicWords(1)), None);
+    }
+
+    #[test]
+    fn test_pic_words_from_bytes() {
+        assert_eq!(PicWords::checked_from_bytes(0), Some(PicWords(0)));
+        assert_eq!(PicWords::checked_from_bytes(0x1900), Some(PicWords(0x0c80)));
+        assert_eq!(PicWords::checked_from_bytes(0x1901), None);
+        assert_eq!(PicWords::checked_from_bytes(0x20000), None);
+        assert_eq!(PicWords::from_bytes(PicWords(0x0c80).to_bytes()), PicWords(0x0c80));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_pic_address_reversed_distance() {
+        PicAddress(0x0200).distance_to(PicAddress(0x0100));
+    }
+
     #[test]
     fn test_get_address() {
         let addr = I2cBackend::get_i2c_address(8);
@@ -777,4 +957,112 @@ mod test {
         // pic=-1
         assert!(Voltage::from_volts(9.443653453490631).is_err());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_fake_i2c_round_trip() {
+        let i2c = FakeAsyncI2c::new();
+        let voltage_ctrl = Control::new(
+            Arc::new(I2cBackend::with_device(Box::new(i2c.clone()))),
+            8,
+        );
+
+        i2c.push_reply(0x57, &[0x03, 0x5c]);
+        assert_eq!(voltage_ctrl.get_version().await.unwrap(), 0x03);
+        assert_eq!(voltage_ctrl.get_voltage().await.unwrap(), 0x5c);
+        // nothing left to read
+        assert!(voltage_ctrl.get_voltage().await.is_err());
+
+        // every command byte goes out in a separate transaction
+        let writes = i2c.take_writes();
+        let command: Vec<_> = writes.iter().map(|(_, bytes)| bytes[0]).take(6).collect();
+        assert!(writes.iter().all(|(address, _)| *address == 0x57));
+        assert_eq!(
+            command,
+            vec![
+                PIC_COMMAND_1,
+                PIC_COMMAND_2,
+                GET_PIC_SOFTWARE_VERSION,
+                PIC_COMMAND_1,
+                PIC_COMMAND_2,
+                GET_VOLTAGE
+            ]
+        );
+    }
+
+    /// Queue reply to PIC read command: reads are done one byte per transaction and only the
+    /// first byte of each transaction is valid
+    fn push_pic_reply(i2c: &FakeAsyncI2c, bytes: &[u8]) {
+        for byte in bytes {
+            i2c.push_reply(0x57, &[*byte]);
+            i2c.push_reply(0x57, &vec![0xee; bytes.len() - 1]);
+        }
+    }
+
+    /// Replies of one PIC program load of `program` followed by read back returning `in_flash`
+    fn push_program_load(i2c: &FakeAsyncI2c, program: &firmware::PicProgram, in_flash: &[u8]) {
+        let start = program.load_addr.0.to_be_bytes();
+        let end = program.load_addr.offset(program.prog_size).0.to_be_bytes();
+        // flash pointer checks of erase and write, pointer after write
+        push_pic_reply(i2c, &start);
+        push_pic_reply(i2c, &start);
+        push_pic_reply(i2c, &end);
+        // read back
+        push_pic_reply(i2c, &start);
+        for block in in_flash.chunks(Control::FLASH_XFER_BLOCK_SIZE_BYTES) {
+            push_pic_reply(i2c, block);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_program_pic_verified() {
+        let i2c = FakeAsyncI2c::new();
+        let voltage_ctrl = Control::new(
+            Arc::new(I2cBackend::with_device(Box::new(i2c.clone()))),
+            8,
+        );
+        let region = firmware::LoadRegion::new(PicAddress(0x0100), PicAddress(0x011f)).unwrap();
+        let bytes = vec![0x3f, 0xff, 0x2a, 0xaa, 0x15, 0x55]
+            .into_iter()
+            .cycle()
+            .take(region.size().to_bytes())
+            .collect::<Vec<u8>>();
+        let program = firmware::PicProgram::from_bytes(bytes, Some(region)).unwrap();
+
+        // first load doesn't stick, the second one does
+        let mut corrupted = program.bytes.clone();
+        corrupted[5] ^= 0x01;
+        push_program_load(&i2c, &program, &corrupted);
+        push_program_load(&i2c, &program, &program.bytes);
+        voltage_ctrl
+            .program_pic_verified(&program, 3, Duration::from_millis(1))
+            .await
+            .expect("retry didn't help");
+        // all replies were used up
+        assert!(voltage_ctrl.get_version().await.is_err());
+
+        // give up after the last attempt
+        push_program_load(&i2c, &program, &corrupted);
+        push_program_load(&i2c, &program, &corrupted);
+        let error = voltage_ctrl
+            .program_pic_verified(&program, 2, Duration::from_millis(1))
+            .await
+            .expect_err("corrupted program accepted")
+            .to_string();
+        assert!(error.contains("after 2 attempts"), "{}", error);
+        assert!(error.contains("byte 0x5"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_read_voltage() {
+        let i2c = FakeAsyncI2c::new();
+        let voltage_ctrl = Control::new(
+            Arc::new(I2cBackend::with_device(Box::new(i2c.clone()))),
+            8,
+        );
+
+        i2c.push_reply(0x57, &[92]);
+        let voltage = voltage_ctrl.read_voltage().await.unwrap();
+        assert_eq!(voltage, Voltage::from_pic_value(92).unwrap());
+        assert!((voltage.as_volts() - 8.9).abs() <= 0.01);
+    }
+}
@@ -1,28 +1,161 @@
 //! PIC firmware loader
 
 use crate::error::{self, ErrorKind};
-use crate::power::{PicAddress, PicWords};
+use crate::power::{PicAddress, PicWords, PIC_PROGRAM_PATH};
 use failure::ResultExt;
 
 use std::convert::AsRef;
-use std::fs::File;
-use std::path::Path;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::u32;
 
-/// Parse line with hex number
-fn parse_line(line: std::io::Result<String>) -> error::Result<u32> {
-    Ok(u32::from_str_radix(&(line?), 16)?)
+/// Parse line with hex number, blank line (there's often one at the end) gives `None`
+fn parse_line(line: &str) -> error::Result<Option<u32>> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(u32::from_str_radix(line, 16)?))
+}
+
+/// Parse program words (one hex number per line) from `reader` into big-endian bytes,
+/// `path` is only used in error messages
+fn parse_program<R: BufRead>(reader: R, path: &Path) -> error::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        let word = parse_line(&line).with_context(|_| {
+            ErrorKind::Power(format!(
+                "cannot parse PIC program {} on line {}: '{}'",
+                path.display(),
+                line_no + 1,
+                line.trim()
+            ))
+        })?;
+        if let Some(word) = word {
+            bytes.push((word >> 8) as u8);
+            bytes.push(word as u8);
+        }
+    }
+    Ok(bytes)
 }
 
-/// Load address and program size are fixed for now
-/// Flash offset
+/// PIC program embedded into the binary at build time, it's flashed when no program path is given
+/// so that there's always a known-good image at hand (ie. in containers without Bitmain files)
+#[cfg(feature = "embedded-pic-firmware")]
+const EMBEDDED_PROGRAM: Option<&[u8]> = Some(include_bytes!(env!("KASOP_PIC_PROGRAM")));
+#[cfg(not(feature = "embedded-pic-firmware"))]
+const EMBEDDED_PROGRAM: Option<&[u8]> = None;
+
+/// Default flash offset
 const PROGRAM_LOAD_ADDRESS: PicAddress = PicAddress(0x0300);
-/// Program size
+/// Default end of program (inclusive)
 const PROGRAM_LOAD_END_ADDRESS: PicAddress = PicAddress(0x0f7f);
 
+/// Region of PIC flash the program is loaded to (both addresses are inclusive)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LoadRegion {
+    pub start: PicAddress,
+    pub end: PicAddress,
+}
+
+impl LoadRegion {
+    pub fn new(start: PicAddress, end: PicAddress) -> error::Result<Self> {
+        if start.checked_distance_to(end).is_none() {
+            Err(ErrorKind::Power(format!(
+                "invalid PIC program load region {:#x}..={:#x}",
+                start.0, end.0
+            )))?
+        }
+        Ok(Self { start, end })
+    }
+
+    /// Size of program that fits exactly into this region
+    pub fn size(&self) -> PicWords {
+        self.start.distance_to(self.end)
+    }
+}
+
+impl Default for LoadRegion {
+    /// Memory map of the PIC used on S9 hashboards
+    fn default() -> Self {
+        Self {
+            start: PROGRAM_LOAD_ADDRESS,
+            end: PROGRAM_LOAD_END_ADDRESS,
+        }
+    }
+}
+
+/// Compute CRC-32 (IEEE 802.3, reflected, as used by zip/gzip) of `bytes`
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Integrity manifest that can accompany the firmware file. It is stored next to the firmware
+/// with `.crc` appended to the file name and contains one line: `<length> <crc32>`, where
+/// length is the number of program bytes (in decimal) and crc32 is CRC-32 of program bytes (in
+/// hex).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub length: usize,
+    pub crc32: u32,
+}
+
+impl Manifest {
+    /// Path of the manifest belonging to firmware on `path`
+    pub fn path_for(path: &Path) -> PathBuf {
+        let mut manifest_path = OsString::from(path.as_os_str());
+        manifest_path.push(".crc");
+        manifest_path.into()
+    }
+
+    pub fn parse(manifest: &str) -> error::Result<Self> {
+        let mut fields = manifest.split_whitespace();
+        let (length, crc32) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(length), Some(crc32), None) => (length, crc32),
+            _ => Err(ErrorKind::Power(format!(
+                "malformed PIC program manifest '{}'",
+                manifest.trim()
+            )))?,
+        };
+        Ok(Self {
+            length: length.parse()?,
+            crc32: u32::from_str_radix(crc32.trim_start_matches("0x"), 16)?,
+        })
+    }
+
+    /// Check that `bytes` match this manifest
+    pub fn verify(&self, bytes: &[u8]) -> error::Result<()> {
+        if bytes.len() != self.length {
+            Err(ErrorKind::Power(format!(
+                "PIC program length mismatch (manifest {:#x}, got {:#x})",
+                self.length,
+                bytes.len()
+            )))?
+        }
+        let crc32 = crc32(bytes);
+        if crc32 != self.crc32 {
+            Err(ErrorKind::Power(format!(
+                "PIC program checksum mismatch (manifest {:#010x}, got {:#010x})",
+                self.crc32, crc32
+            )))?
+        }
+        Ok(())
+    }
+}
+
 /// Program to be loaded to PIC of voltage controller
 #[derive(Clone)]
 pub struct PicProgram {
@@ -33,8 +166,10 @@ pub struct PicProgram {
 
 impl PicProgram {
     /// Construct loadable PIC program from bytes
-    pub fn from_bytes(bytes: Vec<u8>) -> error::Result<Self> {
-        let prog_size = PROGRAM_LOAD_ADDRESS.distance_to(PROGRAM_LOAD_END_ADDRESS);
+    /// The program has to fill `region` exactly, default region is used when `None`
+    pub fn from_bytes(bytes: Vec<u8>, region: Option<LoadRegion>) -> error::Result<Self> {
+        let region = region.unwrap_or_default();
+        let prog_size = region.size();
         if bytes.len() != prog_size.to_bytes() {
             Err(ErrorKind::Power(format!(
                 "wrong size of PIC program (expected {:#x}, got {:#x})",
@@ -43,30 +178,60 @@ impl PicProgram {
             )))?
         }
         Ok(Self {
-            load_addr: PROGRAM_LOAD_ADDRESS,
+            load_addr: region.start,
             prog_size,
             bytes,
         })
     }
 
     /// Parse Bitmain .txt firmware format
-    pub fn read<P: AsRef<Path>>(path: P) -> error::Result<Self> {
+    /// If there's a manifest next to the firmware file (see `Manifest`), the program is verified
+    /// against it so that corrupted image never gets to the PIC.
+    pub fn read<P: AsRef<Path>>(path: P, region: Option<LoadRegion>) -> error::Result<Self> {
         let path = path.as_ref();
         let f = File::open(path)?;
-        let f = BufReader::new(f);
-        let mut bytes = Vec::new();
-        for (line_no, line) in f.lines().enumerate() {
-            let word = parse_line(line).with_context(|_| {
-                ErrorKind::Power(format!(
-                    "cannot parse PIC program {} on line {}",
-                    path.display(),
-                    line_no + 1
-                ))
-            })?;
-            bytes.push((word >> 8) as u8);
-            bytes.push(word as u8);
+        let bytes = parse_program(BufReader::new(f), path)?;
+
+        let manifest_path = Manifest::path_for(path);
+        if manifest_path.exists() {
+            let manifest = fs::read_to_string(&manifest_path)?;
+            Manifest::parse(&manifest)
+                .and_then(|manifest| manifest.verify(&bytes))
+                .with_context(|_| {
+                    ErrorKind::Power(format!(
+                        "PIC program {} failed verification against {}",
+                        path.display(),
+                        manifest_path.display()
+                    ))
+                })?;
+        }
+        Self::from_bytes(bytes, region)
+    }
+
+    /// Parse program embedded into the binary (see `EMBEDDED_PROGRAM`)
+    pub fn embedded(region: Option<LoadRegion>) -> error::Result<Self> {
+        let program = EMBEDDED_PROGRAM.ok_or_else(|| {
+            ErrorKind::Power(
+                "no PIC program embedded (built without embedded-pic-firmware)".to_string(),
+            )
+        })?;
+        Self::parse_embedded(program, region)
+    }
+
+    /// Parse `program` in the format it's embedded in (Bitmain .txt)
+    fn parse_embedded(program: &[u8], region: Option<LoadRegion>) -> error::Result<Self> {
+        let bytes = parse_program(program, Path::new("<embedded>"))?;
+        Self::from_bytes(bytes, region)
+    }
+
+    /// Load program from `path` if given, otherwise the embedded one, or from the default
+    /// `PIC_PROGRAM_PATH` when the binary has none embedded
+    pub fn load(path: Option<&Path>, region: Option<LoadRegion>) -> error::Result<Self> {
+        match path {
+            Some(path) => Self::read(path, region),
+            None if EMBEDDED_PROGRAM.is_some() => Self::embedded(region),
+            None => Self::read(PIC_PROGRAM_PATH, region),
         }
-        Self::from_bytes(bytes)
     }
 }
 
@@ -94,6 +259,103 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_default_load_region() {
+        let program = PicProgram::from_bytes(vec![0; 0x1900], None).expect("bad program size");
+        assert_eq!(program.load_addr, PicAddress(0x0300));
+        assert_eq!(program.prog_size, PicWords(0x0c80));
+    }
+
+    #[test]
+    fn test_custom_load_region() {
+        let region = LoadRegion::new(PicAddress(0x0100), PicAddress(0x01ff)).expect("bad region");
+        assert_eq!(region.size(), region.start.distance_to(region.end));
+
+        let program =
+            PicProgram::from_bytes(vec![0; 0x200], Some(region)).expect("bad program size");
+        assert_eq!(program.load_addr, PicAddress(0x0100));
+        assert_eq!(program.prog_size, PicWords(0x100));
+        assert_eq!(program.prog_size.to_bytes(), program.bytes.len());
+
+        // program sized for the default region doesn't fit
+        assert!(PicProgram::from_bytes(vec![0; 0x1900], Some(region)).is_err());
+        // inverted region
+        assert!(LoadRegion::new(PicAddress(0x0200), PicAddress(0x0100)).is_err());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let mut bytes = vec![0x3f, 0xff, 0x2a, 0xaa, 0x15, 0x55];
+        let manifest = Manifest::parse("6 0xb95e07d4\n").expect("cannot parse manifest");
+        assert_eq!(
+            manifest,
+            Manifest {
+                length: 6,
+                crc32: crc32(&bytes),
+            }
+        );
+        manifest.verify(&bytes).expect("good image rejected");
+
+        // flip one bit
+        bytes[3] ^= 0x01;
+        assert!(manifest.verify(&bytes).is_err());
+        // truncate
+        bytes.truncate(4);
+        assert!(manifest.verify(&bytes).is_err());
+
+        assert!(Manifest::parse("6").is_err());
+        assert!(Manifest::parse("6 zz").is_err());
+        assert_eq!(
+            Manifest::path_for(Path::new("/lib/antminer/hash_s8_app.txt")),
+            PathBuf::from("/lib/antminer/hash_s8_app.txt.crc")
+        );
+    }
+
+    #[test]
+    fn test_parse_program() {
+        let path = Path::new("hash_s8_app.txt");
+        let bytes = parse_program("3fff\n2aaa  \r\n\n1555\n\n".as_bytes(), path)
+            .expect("blank lines and trailing whitespace rejected");
+        assert_eq!(bytes, vec![0x3f, 0xff, 0x2a, 0xaa, 0x15, 0x55]);
+
+        let error = parse_program("3fff\n2a;a \n1555\n".as_bytes(), path)
+            .expect_err("garbage accepted")
+            .to_string();
+        assert!(error.contains("hash_s8_app.txt on line 2"), "{}", error);
+        assert!(error.contains("'2a;a'"), "{}", error);
+    }
+
+    /// Image as it would be embedded from `KASOP_PIC_PROGRAM`, sized for region
+    /// `0x0100..=0x0103`
+    const FIXTURE_PROGRAM: &[u8] = b"3fff\n2aaa\n1555\n0000\n";
+
+    #[test]
+    fn test_embedded_program() {
+        let region = LoadRegion::new(PicAddress(0x0100), PicAddress(0x0103)).expect("bad region");
+        let program = PicProgram::parse_embedded(FIXTURE_PROGRAM, Some(region)).expect("fixture is broken");
+        assert_eq!(program.load_addr, PicAddress(0x0100));
+        assert_eq!(program.bytes, vec![0x3f, 0xff, 0x2a, 0xaa, 0x15, 0x55, 0x00, 0x00]);
+        assert_eq!(program.prog_size.to_bytes(), program.bytes.len());
+        // Fixture doesn't fill the default region
+        assert!(PicProgram::parse_embedded(FIXTURE_PROGRAM, None).is_err());
+
+        match EMBEDDED_PROGRAM {
+            Some(_) => {
+                let program = PicProgram::embedded(None).expect("embedded program is broken");
+                assert_eq!(program.load_addr, PicAddress(0x0300));
+                assert_eq!(program.prog_size.to_bytes(), program.bytes.len());
+                assert_eq!(PicProgram::load(None, None).unwrap().bytes, program.bytes);
+            }
+            None => assert!(PicProgram::embedded(None).is_err()),
+        }
+    }
+
     /// Perform these steps to test we know how to load firmware correctly:
     ///  * load "random bytes" firmware to PIC
     ///  * read back and verify
@@ -108,7 +370,7 @@ mod test {
     async fn test_pic_reload_program() {
         let voltage_ctrl_backend = Arc::new(power::I2cBackend::new(0));
         let voltage_ctrl = power::Control::new(voltage_ctrl_backend, 8);
-        let good_program = power::firmware::PicProgram::read(power::PIC_PROGRAM_PATH)
+        let good_program = power::firmware::PicProgram::read(power::PIC_PROGRAM_PATH, None)
             .expect("program read failed");
 
         // Load garbage program
@@ -0,0 +1,100 @@
+//! Power limiter: lowers chip frequency to keep estimated hashboard power under a limit
+//!
+//! S9 PIC can't measure current, so power is estimated from the voltage read back from the PIC
+//! and from chip frequencies.
+
+use crate::power::Voltage;
+
+/// Power of a hashboard is roughly proportional to the square of voltage and to the sum of chip
+/// frequencies. Calibrated to ~450 W for 63 chips running at 650 MHz on 8.6 V.
+const WATTS_PER_VOLT2_HZ: f64 = 1.486e-10;
+
+/// Frequency is adjusted in multiples of this
+pub const FREQUENCY_STEP: usize = 5_000_000;
+/// The limiter never goes below this frequency
+pub const MIN_FREQUENCY: usize = 100_000_000;
+/// Frequency is restored only when power drops this much (as fraction of limit) under limit
+const HYSTERESIS: f64 = 0.05;
+
+/// Estimate hashboard power in Watts
+pub fn estimate_watts(voltage: Voltage, total_frequency: u64) -> f64 {
+    let volts = voltage.as_volts() as f64;
+    WATTS_PER_VOLT2_HZ * volts * volts * total_frequency as f64
+}
+
+/// What to do with average chip frequency
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    /// Power is within limit, leave frequency as it is
+    Keep,
+    /// Power is over limit, lower frequency to this value
+    Throttle(usize),
+    /// There's room under limit, raise frequency to this value
+    Restore(usize),
+}
+
+/// Power limit of one hashboard
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerLimit {
+    pub watts: f64,
+}
+
+impl PowerLimit {
+    pub fn new(watts: f64) -> Self {
+        Self { watts }
+    }
+
+    /// Decide new average frequency given `estimated_watts` at `avg_frequency`. Frequency never
+    /// goes over `max_frequency` (the frequency the board was configured for).
+    pub fn decide(&self, estimated_watts: f64, avg_frequency: usize, max_frequency: usize) -> Decision {
+        if estimated_watts <= 0.0 || avg_frequency == 0 {
+            return Decision::Keep;
+        }
+        // Power scales linearly with frequency, so find frequency that hits the limit
+        let fitting = (avg_frequency as f64 * self.watts / estimated_watts) as usize;
+        let fitting = (fitting / FREQUENCY_STEP * FREQUENCY_STEP).max(MIN_FREQUENCY).min(max_frequency);
+
+        if estimated_watts > self.watts {
+            if fitting < avg_frequency {
+                return Decision::Throttle(fitting);
+            }
+        } else if estimated_watts < self.watts * (1.0 - HYSTERESIS) && avg_frequency < max_frequency {
+            if fitting > avg_frequency {
+                return Decision::Restore(fitting);
+            }
+        }
+        Decision::Keep
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MHZ: usize = 1_000_000;
+
+    #[test]
+    fn test_estimate_watts() {
+        let voltage = Voltage::from_volts(8.6).unwrap();
+        let watts = estimate_watts(voltage, 63 * 650 * MHZ as u64);
+        assert!((watts - 450.0).abs() < 5.0, "unexpected estimate {}", watts);
+    }
+
+    #[test]
+    fn test_decide() {
+        let limit = PowerLimit::new(400.0);
+
+        // 450 W at 650 MHz -> 577.7 MHz fits the limit, rounded down to 575 MHz
+        assert_eq!(limit.decide(450.0, 650 * MHZ, 650 * MHZ), Decision::Throttle(575 * MHZ));
+        // within limit or within hysteresis band
+        assert_eq!(limit.decide(399.0, 600 * MHZ, 650 * MHZ), Decision::Keep);
+        assert_eq!(limit.decide(390.0, 575 * MHZ, 650 * MHZ), Decision::Keep);
+        // plenty of room, but never over configured frequency
+        assert_eq!(limit.decide(300.0, 575 * MHZ, 650 * MHZ), Decision::Restore(650 * MHZ));
+        assert_eq!(limit.decide(350.0, 560 * MHZ, 650 * MHZ), Decision::Restore(640 * MHZ));
+        assert_eq!(limit.decide(300.0, 650 * MHZ, 650 * MHZ), Decision::Keep);
+        // never under minimal frequency
+        assert_eq!(limit.decide(4000.0, 650 * MHZ, 650 * MHZ), Decision::Throttle(MIN_FREQUENCY));
+        assert_eq!(limit.decide(4000.0, MIN_FREQUENCY, 650 * MHZ), Decision::Keep);
+    }
+}
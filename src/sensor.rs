@@ -17,6 +17,7 @@
 mod tmp42x;
 mod tmp451;
 
+use crate::async_i2c::AsyncI2c;
 use crate::error;
 use crate::i2c;
 
@@ -24,6 +25,7 @@ use async_trait::async_trait;
 use logging::macros::*;
 use lazy_static::lazy_static;
 use std::boxed::Box;
+use std::sync::Arc;
 
 /// Generic sensor
 #[async_trait]
@@ -70,6 +72,40 @@ pub struct Temperature {
     pub remote: Measurement,
 }
 
+/// Name of the sensor when hashboard reports just one
+pub const DEFAULT_SENSOR_NAME: &str = "chip";
+
+/// Readings of all temperature sensors of one hashboard, each with a name (ie. "chip" or
+/// "board")
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Readings {
+    pub sensors: Vec<(String, Temperature)>,
+}
+
+impl Readings {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add(&mut self, name: &str, temperature: Temperature) {
+        self.sensors.push((name.to_string(), temperature));
+    }
+
+    /// Look up reading of sensor `name`
+    pub fn get(&self, name: &str) -> Option<&Temperature> {
+        self.sensors.iter().find(|(sensor_name, _)| sensor_name == name).map(|(_, temperature)| temperature)
+    }
+}
+
+/// Hashboard with a single sensor
+impl From<Temperature> for Readings {
+    fn from(temperature: Temperature) -> Self {
+        Self {
+            sensors: vec![(DEFAULT_SENSOR_NAME.to_string(), temperature)],
+        }
+    }
+}
+
 lazy_static! {
     /// List of all known I2C address where sensors are present
     static ref SENSOR_I2C_ADDRESS: [i2c::Address; 3] = [
@@ -125,8 +161,21 @@ pub async fn probe_i2c_device(
 pub async fn probe_i2c_sensors<T: 'static + i2c::AsyncBus + Clone>(
     i2c_bus: T,
 ) -> error::Result<Option<Box<dyn Sensor>>> {
-    // Go through all known addresses
-    for address in SENSOR_I2C_ADDRESS.iter() {
+    probe_i2c_sensors_at(i2c_bus, None).await
+}
+
+/// Probe for supported sensor at configured `address` only or at all known addresses when it's
+/// `None`
+pub async fn probe_i2c_sensors_at<T: 'static + i2c::AsyncBus + Clone>(
+    i2c_bus: T,
+    address: Option<i2c::Address>,
+) -> error::Result<Option<Box<dyn Sensor>>> {
+    let addresses = match address {
+        Some(address) => vec![address],
+        None => SENSOR_I2C_ADDRESS.to_vec(),
+    };
+    // Go through all candidate addresses
+    for address in addresses.iter() {
         // Construct device at given i2c address
         let i2c_device = Box::new(i2c::Device::new(i2c_bus.clone(), *address));
 
@@ -142,9 +191,55 @@ pub async fn probe_i2c_sensors<T: 'static + i2c::AsyncBus + Clone>(
     Ok(None)
 }
 
+/// Sensors found on one hashboard with their I2C addresses
+pub type FoundSensors = Vec<(i2c::Address, Box<dyn Sensor>)>;
+
+/// Probe for supported sensors at configured `address` only or at all known addresses when it's
+/// `None`. Unlike `probe_i2c_sensors_at`, all sensors found are returned.
+pub async fn probe_all_i2c_sensors_at<T: 'static + i2c::AsyncBus + Clone>(
+    i2c_bus: T,
+    address: Option<i2c::Address>,
+) -> error::Result<FoundSensors> {
+    let addresses = match address {
+        Some(address) => vec![address],
+        None => SENSOR_I2C_ADDRESS.to_vec(),
+    };
+    let mut sensors = Vec::new();
+    for address in addresses {
+        let i2c_device = Box::new(i2c::Device::new(i2c_bus.clone(), address));
+        match probe_i2c_device(i2c_device).await {
+            Ok(Some(sensor)) => sensors.push((address, sensor)),
+            Ok(None) => (),
+            // The sensors already found are good to use
+            Err(e) if !sensors.is_empty() => warn!("{:?}: probing failed: {}", address, e),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(sensors)
+}
+
+/// Name of sensor at `address` in `Readings` of a hashboard with `count` sensors: the only one
+/// keeps `DEFAULT_SENSOR_NAME`, more of them are told apart by address
+pub fn sensor_name(address: i2c::Address, count: usize) -> String {
+    if count == 1 {
+        DEFAULT_SENSOR_NAME.to_string()
+    } else {
+        address.to_string()
+    }
+}
+
+/// Probe for supported sensor on raw I2C master `i2c` (see `probe_i2c_sensors_at`)
+pub async fn probe_async_i2c_sensors(
+    i2c: Arc<dyn AsyncI2c>,
+    address: Option<i2c::Address>,
+) -> error::Result<Option<Box<dyn Sensor>>> {
+    probe_i2c_sensors_at(i2c::AsyncI2cBus::new(i2c), address).await
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::async_i2c::test_utils::FakeAsyncI2c;
     use i2c::test_utils;
     use async_compat::tokio;
 
@@ -172,4 +267,54 @@ mod test {
         assert_eq!(test_probe_address(0x9c, 0x37, 0x21).await, false);
         assert_eq!(test_probe_address(0x84, 0x55, 0x21).await, false);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_probe_all_i2c_sensors() {
+        let bus = i2c::SharedBus::new(test_utils::FakeI2cBus::new(
+            i2c::Address::new(0x98),
+            &[test_utils::InitReg(0xfe, 0x55), test_utils::InitReg(0xff, 0x13)],
+            Some(0),
+            None,
+        ));
+        // Failing to read the other addresses doesn't lose the sensor found
+        let sensors = probe_all_i2c_sensors_at(bus, None).await.unwrap();
+        let addresses = sensors.iter().map(|(address, _)| *address).collect::<Vec<_>>();
+        assert_eq!(addresses, vec![i2c::Address::new(0x98)]);
+
+        assert_eq!(sensor_name(i2c::Address::new(0x98), 1), DEFAULT_SENSOR_NAME);
+        assert_eq!(sensor_name(i2c::Address::new(0x98), 2), "0x98");
+    }
+
+    #[tokio::test]
+    async fn test_sensor_on_async_i2c() {
+        let i2c = FakeAsyncI2c::new();
+        // TMP451 at 0x98 (0x4c in 7-bit): manufacturer and device ID
+        i2c.push_reply(0x4c, &[0x55, 0x13]);
+        let mut sensor = probe_async_i2c_sensors(Arc::new(i2c.clone()), Some(i2c::Address::new(0x98)))
+            .await
+            .unwrap()
+            .expect("sensor not found");
+        assert_eq!(i2c.take_writes(), vec![(0x4c, vec![0xfe]), (0x4c, vec![0xff])]);
+
+        // configuration read back
+        i2c.push_reply(0x4c, &[0x04]);
+        sensor.init().await.unwrap();
+        assert_eq!(
+            i2c.take_writes(),
+            vec![(0x4c, vec![0x09, 0x04]), (0x4c, vec![0x03]), (0x4c, vec![0x11, 0x00])]
+        );
+
+        // status, local, local fraction, remote, remote fraction
+        i2c.push_reply(0x4c, &[0x00, 104, 0x80, 119, 0x00]);
+        assert_eq!(
+            sensor.read_temperature().await.unwrap(),
+            Temperature {
+                local: Measurement::Ok(40.5),
+                remote: Measurement::Ok(55.0),
+            }
+        );
+
+        // nothing at the other addresses
+        assert!(probe_async_i2c_sensors(Arc::new(FakeAsyncI2c::new()), None).await.is_err());
+    }
+}
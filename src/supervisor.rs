@@ -0,0 +1,85 @@
+//! Hashboard supervision: a board that stops producing nonces gets its chips re-initialized a few
+//! times before it is given up on.
+
+/// What to do with hashboard after supervisor check
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SupervisorAction {
+    /// Board produces nonces
+    Keep,
+    /// Board went dark, try re-initializing its chips
+    Reinit,
+    /// Board stayed dark after all re-init attempts, halt it
+    GiveUp,
+}
+
+/// Decides whether hashboard still works based on valid nonce counter progress
+#[derive(Debug, Clone)]
+pub struct BoardSupervisor {
+    /// How many times to re-initialize dark board before giving up
+    max_reinits: usize,
+    /// Re-inits done since the board last produced a nonce
+    reinits: usize,
+    last_valid: Option<usize>,
+    /// Board is judged only after its first nonce, until then it may just have no work
+    armed: bool,
+}
+
+impl BoardSupervisor {
+    pub fn new(max_reinits: usize) -> Self {
+        Self {
+            max_reinits,
+            reinits: 0,
+            last_valid: None,
+            armed: false,
+        }
+    }
+
+    /// Check board with `valid` nonces counted so far. Board that found any nonce since the
+    /// previous check (counter reset counts as a change, too) is alive. So is board that hasn't
+    /// produced any nonce yet.
+    pub fn check(&mut self, valid: usize) -> SupervisorAction {
+        self.armed |= valid > 0;
+        let alive = !self.armed || self.last_valid.map_or(true, |last_valid| last_valid != valid);
+        self.last_valid = Some(valid);
+        if alive {
+            self.reinits = 0;
+            SupervisorAction::Keep
+        } else if self.reinits < self.max_reinits {
+            self.reinits += 1;
+            SupervisorAction::Reinit
+        } else {
+            SupervisorAction::GiveUp
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_board_supervisor() {
+        let mut supervisor = BoardSupervisor::new(2);
+        assert_eq!(supervisor.check(0), SupervisorAction::Keep);
+        assert_eq!(supervisor.check(10), SupervisorAction::Keep);
+        // Board goes dark
+        assert_eq!(supervisor.check(10), SupervisorAction::Reinit);
+        // Re-init helped, attempts start over
+        assert_eq!(supervisor.check(15), SupervisorAction::Keep);
+        assert_eq!(supervisor.check(15), SupervisorAction::Reinit);
+        assert_eq!(supervisor.check(15), SupervisorAction::Reinit);
+        assert_eq!(supervisor.check(15), SupervisorAction::GiveUp);
+        assert_eq!(supervisor.check(15), SupervisorAction::GiveUp);
+
+        // Board without work isn't dark, it's judged after the first nonce (even when counters
+        // are reset later)
+        let mut supervisor = BoardSupervisor::new(1);
+        for _ in 0..5 {
+            assert_eq!(supervisor.check(0), SupervisorAction::Keep);
+        }
+        assert_eq!(supervisor.check(4), SupervisorAction::Keep);
+        assert_eq!(supervisor.check(0), SupervisorAction::Keep);
+        assert_eq!(supervisor.check(0), SupervisorAction::Reinit);
+        assert_eq!(supervisor.check(0), SupervisorAction::GiveUp);
+    }
+}
@@ -1,6 +1,12 @@
 use core::cmp::Ordering;
 use std::fmt;
 
+use crate::Error;
+use num::Float;
+
+/// Target corresponding to difficulty 1 (`0xffff * 2^208`), the same as in Bitcoin
+pub const DIFFICULTY_1_TARGET: Uint256 = Uint256([0, 0, 0, 0x0000_0000_ffff_0000]);
+
 pub fn u256_from_compact_target(bits: u32) -> Uint256 {
     // This is a floating-point "compact" encoding originally used by
     // OpenSSL, which satoshi put into consensus code, so we're stuck
@@ -23,6 +29,15 @@ pub fn u256_from_compact_target(bits: u32) -> Uint256 {
     }
 }
 
+/// Byte order of a hex encoded `Uint256`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HexOrder {
+    /// Most significant byte first (how numbers are usually written)
+    BigEndian,
+    /// Least significant byte first (how hashes are usually serialized)
+    LittleEndian,
+}
+
 /// Little-endian large integer type
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug)]
 pub struct Uint256(pub [u64; 4]);
@@ -59,6 +74,76 @@ impl Uint256 {
         out.chunks_exact_mut(8).zip(self.0).for_each(|(bytes, word)| bytes.copy_from_slice(&word.to_le_bytes()));
         out
     }
+
+    /// Parse hex string (with optional `0x` prefix) of at most 64 digits.
+    /// Big-endian strings are numbers and may be shorter (and odd-length), little-endian strings
+    /// are byte sequences, so they have to consist of whole bytes.
+    pub fn from_hex(hex: &str, order: HexOrder) -> Result<Uint256, Error> {
+        let digits = hex.strip_prefix("0x").unwrap_or(hex);
+        if digits.is_empty() || digits.len() > 64 {
+            return Err(format!("hex target '{}' must have 1 to 64 digits", hex).into());
+        }
+        if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(format!("invalid character '{}' in hex target '{}'", c, hex).into());
+        }
+
+        let mut bytes = [0u8; 32];
+        match order {
+            HexOrder::BigEndian => {
+                let padded = format!("{:0>64}", digits);
+                for (i, byte) in bytes.iter_mut().rev().enumerate() {
+                    *byte = u8::from_str_radix(&padded[2 * i..2 * i + 2], 16)?;
+                }
+            }
+            HexOrder::LittleEndian => {
+                if digits.len() % 2 != 0 {
+                    return Err(format!("odd number of digits in hex target '{}'", hex).into());
+                }
+                for (i, byte) in bytes.iter_mut().take(digits.len() / 2).enumerate() {
+                    *byte = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16)?;
+                }
+            }
+        }
+        Ok(Self::from_le_bytes(bytes))
+    }
+
+    /// Convert (pool) difficulty to target, `DIFFICULTY_1_TARGET / difficulty`
+    pub fn from_difficulty(difficulty: f64) -> Result<Uint256, Error> {
+        if !difficulty.is_finite() || difficulty <= 0.0 {
+            return Err(format!("invalid difficulty {}", difficulty).into());
+        }
+        let (mantissa, exponent, _) = (DIFFICULTY_1_TARGET.to_f64() / difficulty).integer_decode();
+        if exponent >= 0 {
+            if 64 - mantissa.leading_zeros() as usize + exponent as usize > 256 {
+                return Err(format!("target for difficulty {} is too big", difficulty).into());
+            }
+            Ok(Uint256::from_u64(mantissa) << exponent as usize)
+        } else {
+            Ok(Uint256::from_u64(mantissa.checked_shr(-exponent as u32).unwrap_or(0)))
+        }
+    }
+
+    /// Convert target to (pool) difficulty, zero target has infinite difficulty
+    pub fn to_difficulty(&self) -> f64 {
+        DIFFICULTY_1_TARGET.to_f64() / self.to_f64()
+    }
+
+    /// Nearest floating point value
+    fn to_f64(self) -> f64 {
+        self.0.iter().rev().fold(0.0, |acc, word| acc * 2f64.powi(64) + *word as f64)
+    }
+
+    /// Format as 64 hex digits (without prefix)
+    pub fn to_hex(&self, order: HexOrder) -> String {
+        match order {
+            HexOrder::BigEndian => format!("{:x}", self),
+            HexOrder::LittleEndian => self
+                .to_le_bytes()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        }
+    }
 }
 
 impl fmt::LowerHex for Uint256 {
@@ -105,3 +190,68 @@ impl core::ops::Shl<usize> for Uint256 {
         Uint256(ret)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hex_round_trip() {
+        let target = u256_from_compact_target(0x1d00ffff);
+        let be = "00000000ffff0000000000000000000000000000000000000000000000000000";
+        let le = "0000000000000000000000000000000000000000000000000000ffff00000000";
+        assert_eq!(target.to_hex(HexOrder::BigEndian), be);
+        assert_eq!(target.to_hex(HexOrder::LittleEndian), le);
+        assert_eq!(Uint256::from_hex(be, HexOrder::BigEndian).unwrap(), target);
+        assert_eq!(Uint256::from_hex(le, HexOrder::LittleEndian).unwrap(), target);
+
+        // prefix and short strings
+        assert_eq!(
+            Uint256::from_hex("0x1ff", HexOrder::BigEndian).unwrap(),
+            Uint256::from_u64(0x1ff)
+        );
+        assert_eq!(
+            Uint256::from_hex("ff01", HexOrder::LittleEndian).unwrap(),
+            Uint256::from_u64(0x1ff)
+        );
+    }
+
+    #[test]
+    fn test_difficulty() {
+        assert_eq!(DIFFICULTY_1_TARGET, u256_from_compact_target(0x1d00ffff));
+        assert_eq!(Uint256::from_difficulty(1.0).unwrap(), DIFFICULTY_1_TARGET);
+        assert_eq!(DIFFICULTY_1_TARGET.to_difficulty(), 1.0);
+
+        // 0xffff * 2^192
+        let target = Uint256([0, 0, 0, 0x0000_0000_0000_ffff]);
+        assert_eq!(Uint256::from_difficulty(65536.0).unwrap(), target);
+        assert_eq!(target.to_difficulty(), 65536.0);
+
+        // 0xffff * 2^224
+        let target = Uint256([0, 0, 0, 0xffff_0000_0000_0000]);
+        assert_eq!(Uint256::from_difficulty(1.0 / 65536.0).unwrap(), target);
+
+        // fractional difficulty of a typical pool
+        let target = Uint256::from_difficulty(0.5).unwrap();
+        assert_eq!(target, DIFFICULTY_1_TARGET << 1);
+
+        assert!(Uint256::from_difficulty(0.0).is_err());
+        assert!(Uint256::from_difficulty(-1.0).is_err());
+        assert!(Uint256::from_difficulty(f64::NAN).is_err());
+        assert!(Uint256::from_difficulty(f64::INFINITY).is_err());
+        // target wouldn't fit
+        assert!(Uint256::from_difficulty(1e-30).is_err());
+        assert_eq!(Uint256::default().to_difficulty(), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_hex_malformed() {
+        let too_long = "0".repeat(65);
+        assert!(Uint256::from_hex(&too_long, HexOrder::BigEndian).is_err());
+        assert!(Uint256::from_hex("", HexOrder::BigEndian).is_err());
+        assert!(Uint256::from_hex("0x", HexOrder::BigEndian).is_err());
+        assert!(Uint256::from_hex("12g4", HexOrder::BigEndian).is_err());
+        assert!(Uint256::from_hex("+123", HexOrder::BigEndian).is_err());
+        assert!(Uint256::from_hex("123", HexOrder::LittleEndian).is_err());
+    }
+}
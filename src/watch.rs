@@ -7,6 +7,8 @@ use sync::{Arc, AtomicBool, AtomicUsize, Condvar, Mutex, Ordering, RwLock};
 // id is used to check if there's a new value before reading the old value.
 // readers count is so that receivers and senders can know if everyone dropped their channels
 // wait_for_change + notify_change is to allow receivers to wait for new values.
+// Only the latest value is kept, so a receiver that falls behind a burst of values skips straight
+// to the newest one.
 
 struct Shared<T: Clone> {
     value: RwLock<T>,
@@ -54,8 +56,12 @@ impl<T: Clone> Shared<T> {
         self.id.load(Ordering::Acquire)
     }
 
-    fn clone_value(&self) -> T {
-        self.value.read().clone()
+    fn clone_value_with_id(&self) -> (T, usize) {
+        // The ID is incremented under the write lock, so the one read under the read lock belongs to
+        // the value (reading them separately could pair a newer value with an older ID and the value
+        // would be observed twice).
+        let value = self.value.read();
+        (value.clone(), self.id())
     }
 
     fn wake_up_threads(&self) {
@@ -84,6 +90,12 @@ pub struct Sender<T: Clone> {
 }
 
 impl<T: Clone> Sender<T> {
+    /// New receiver that observes only values sent from now on
+    pub fn subscribe(&self) -> Receiver<T> {
+        self.shared.increment_receiver_count();
+        Receiver { shared: Arc::clone(&self.shared), last_observed: self.shared.id() }
+    }
+
     pub fn send(&self, value: T) -> Result<(), ChannelClosed> {
         // if no receiver left, return an error
         if self.shared.receiver_count() == 0 {
@@ -122,31 +134,28 @@ impl<T: Clone> Receiver<T> {
         if !shared.sender_alive() {
             return Err(ChannelClosed(()));
         }
-        let new_id = shared.id();
-        if *last_observed == new_id {
+        if *last_observed == shared.id() {
             return Ok(None);
         }
-        *last_observed = new_id;
-        Ok(Some(shared.clone_value()))
+        let (value, id) = shared.clone_value_with_id();
+        *last_observed = id;
+        Ok(Some(value))
     }
 
     pub fn wait_for_change(&mut self) -> Result<T, ChannelClosed> {
         if let Some(v) = Self::get_changed_internal(&mut self.last_observed, &self.shared)? {
             return Ok(v);
         }
-        let lock = self.shared.wait_for_change.lock();
-        // Check if while acquiring the lock something changed.
-        if let Some(v) = Self::get_changed_internal(&mut self.last_observed, &self.shared)? {
-            return Ok(v);
-        }
-        // wait for a notification of a new value
-        let _lock = self.shared.notify_change.wait(lock);
-        // Recheck if the sender is alive as it might've changed while waiting
-        if !self.shared.sender_alive() {
-            return Err(ChannelClosed(()));
+        let mut lock = self.shared.wait_for_change.lock();
+        loop {
+            // Check if while acquiring the lock (or waiting) something changed, this also rechecks
+            // if the sender is alive as it might've changed while waiting.
+            if let Some(v) = Self::get_changed_internal(&mut self.last_observed, &self.shared)? {
+                return Ok(v);
+            }
+            // wait for a notification of a new value, wake ups can be spurious
+            lock = self.shared.notify_change.wait(lock);
         }
-        self.last_observed = self.shared.id();
-        Ok(self.shared.clone_value())
     }
 }
 
@@ -418,6 +427,49 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_subscribe() {
+        multi_test_runner(
+            || {
+                let (sender, _receiver) = watch::channel("One");
+                let mut late = sender.subscribe();
+                assert_eq!(sender.shared.receiver_count(), 2);
+                assert_eq!(late.get_changed(), Ok(None));
+                sender.send("Two").unwrap();
+                assert_eq!(late.get_changed(), Ok(Some("Two")));
+                drop(late);
+                assert_eq!(sender.shared.receiver_count(), 1);
+            },
+            false,
+        )
+    }
+
+    #[test]
+    fn test_burst_delivers_latest() {
+        multi_test_runner(
+            || {
+                let (sender, mut receiver) = watch::channel(0usize);
+                assert_eq!(receiver.get_changed(), Ok(Some(0)));
+                let handle = thread::spawn(move || {
+                    for value in 1..=100 {
+                        sender.send(value).unwrap();
+                    }
+                    sender
+                });
+                // Values may be skipped, but never go back or repeat and the last one always arrives
+                let mut last = 0;
+                while last != 100 {
+                    let value = receiver.wait_for_change().unwrap();
+                    assert!(value > last, "{} received after {}", value, last);
+                    last = value;
+                }
+                let _sender = handle.join().unwrap();
+                assert_eq!(receiver.get_changed(), Ok(None));
+            },
+            true,
+        )
+    }
+
     #[test]
     fn test_rx_drop_before_waiting() {
         multi_test_runner(
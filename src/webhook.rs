@@ -0,0 +1,274 @@
+//! Webhook notifications (`--webhook-url`)
+//!
+//! Critical events from the event bus (hashboard failed, fans stalled, overheating, throttling,
+//! pool lost) and found blocks are POSTed as JSON to the webhook. Posting is best-effort: failures
+//! are only logged and the same kind of critical event (for the same hashboard) is posted at most
+//! once per interval, so that a flapping condition doesn't flood the receiver. Miner halting on
+//! termination is the last event posted.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde_json::Value as JsonValue;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+
+use crate::events::{self, Event};
+use crate::Error;
+
+/// How long a webhook request may take before it's given up
+pub const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Plain `http://` URL, the miner has no TLS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for WebhookUrl {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None if url.starts_with("https://") => return Err("https is not supported, use http://".into()),
+            None => return Err(format!("{} is not an http:// URL", url)),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| format!("invalid port in {}", url))?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in {}", url));
+        }
+        Ok(Self { host: host.to_string(), port, path: path.to_string() })
+    }
+}
+
+impl fmt::Display for WebhookUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// Whether `event` is worth waking somebody up. The miner mines on one pool/node only, so losing
+/// it means all pools are down.
+pub fn is_critical(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::BoardFailed { .. }
+            | Event::FansStalled { .. }
+            | Event::Overheated { .. }
+            | Event::Throttled { .. }
+            | Event::Disconnected { .. }
+    )
+}
+
+/// JSON body posted for `event` that happened at `timestamp` (RFC 3339)
+pub fn payload(event: &Event, timestamp: &str) -> String {
+    let mut value = serde_json::to_value(event).expect("BUG: event not serializable");
+    if let JsonValue::Object(fields) = &mut value {
+        fields.insert("timestamp".into(), timestamp.into());
+        fields.insert("message".into(), event.to_string().into());
+    }
+    value.to_string()
+}
+
+/// Whether `event` is posted to the webhook
+pub fn is_notified(event: &Event) -> bool {
+    is_critical(event) || matches!(event, Event::BlockFound { .. } | Event::Halted { .. })
+}
+
+/// Lets through one event of each kind (and hashboard) per `interval`, found blocks always pass
+pub struct RateLimiter {
+    interval: Duration,
+    last_sent: HashMap<(&'static str, Option<usize>), Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, last_sent: HashMap::new() }
+    }
+
+    /// Whether `event` happening at `now` may be posted, remembers it if so
+    pub fn allow(&mut self, event: &Event, now: Instant) -> bool {
+        if let Event::BlockFound { .. } = event {
+            return true;
+        }
+        let hashboard_idx = match event {
+            Event::Throttled { hashboard_idx, .. }
+            | Event::ThrottleLifted { hashboard_idx }
+            | Event::BoardFailed { hashboard_idx, .. } => Some(*hashboard_idx),
+            _ => None,
+        };
+        let key = (event.kind(), hashboard_idx);
+        match self.last_sent.get(&key) {
+            Some(last) if now.saturating_duration_since(*last) < self.interval => false,
+            _ => {
+                self.last_sent.insert(key, now);
+                true
+            }
+        }
+    }
+}
+
+/// POST `body` to `url`, anything but 2xx answer is an error
+async fn post(url: &WebhookUrl, body: &str) -> Result<(), Error> {
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        url.path,
+        url.host,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+    // Status line is all we need and it fits into the first read
+    let mut buf = [0u8; 1024];
+    let len = stream.read(&mut buf).await?;
+    let response = String::from_utf8_lossy(&buf[..len]);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(format!("webhook answered {:?}", status_line).into()),
+    }
+}
+
+/// POST `event` to `url`, failures are only logged
+async fn post_event(url: WebhookUrl, event: Event) {
+    let body = payload(&event, &chrono::Utc::now().to_rfc3339());
+    match tokio::time::timeout(POST_TIMEOUT, post(&url, &body)).await {
+        Ok(Ok(())) => debug!("Webhook: posted {}", event.kind()),
+        Ok(Err(e)) => warn!("Webhook: posting {} to {} failed: {}", event.kind(), url, e),
+        Err(_) => warn!("Webhook: posting {} to {} timed out", event.kind(), url),
+    }
+}
+
+/// Task that posts critical events and found blocks to `url`. It runs until the miner halts, the
+/// halt is posted before it ends so that the miner can wait for it.
+pub async fn webhook_task(url: WebhookUrl, min_interval: Duration) {
+    let mut limiter = RateLimiter::new(min_interval);
+    let mut receiver = events::subscribe();
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                debug!("Webhook: lost {} events", count);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if !is_notified(&event) {
+            continue;
+        }
+        if let Event::Halted { .. } = event {
+            post_event(url, event).await;
+            break;
+        }
+        if !limiter.allow(&event, Instant::now()) {
+            debug!("Webhook: not posting {}, posted one recently", event.kind());
+            continue;
+        }
+        // Slow receiver mustn't hold up other events
+        tokio::spawn(post_event(url.clone(), event));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_url() {
+        let url: WebhookUrl = "http://alerts.local:8080/hooks/miner".parse().unwrap();
+        assert_eq!(url, WebhookUrl { host: "alerts.local".into(), port: 8080, path: "/hooks/miner".into() });
+        assert_eq!(url.to_string(), "http://alerts.local:8080/hooks/miner");
+
+        let url: WebhookUrl = "http://10.0.0.1".parse().unwrap();
+        assert_eq!(url, WebhookUrl { host: "10.0.0.1".into(), port: 80, path: "/".into() });
+
+        assert!("https://alerts.local/".parse::<WebhookUrl>().unwrap_err().contains("https"));
+        assert!("alerts.local/hook".parse::<WebhookUrl>().is_err());
+        assert!("http://alerts.local:http/".parse::<WebhookUrl>().is_err());
+        assert!("http://:8080/".parse::<WebhookUrl>().is_err());
+    }
+
+    #[test]
+    fn test_payload() {
+        let event = Event::BoardFailed { hashboard_idx: 6, reason: "no nonces".into() };
+        assert!(is_critical(&event));
+        let body: JsonValue = serde_json::from_str(&payload(&event, "2021-03-04T05:06:07+00:00")).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "event": "board_failed",
+                "timestamp": "2021-03-04T05:06:07+00:00",
+                "hashboard_idx": 6,
+                "reason": "no nonces",
+                "message": "hashboard 6 failed: no nonces",
+            })
+        );
+
+        let body: JsonValue =
+            serde_json::from_str(&payload(&Event::Overheated { temperature: 92.5 }, "2021-03-04T05:06:07+00:00"))
+                .unwrap();
+        assert_eq!(body["event"], "overheated");
+        assert_eq!(body["temperature"], 92.5);
+
+        assert!(!is_critical(&Event::ShareAccepted { job_id: "1".into() }));
+        assert!(!is_notified(&Event::ShareAccepted { job_id: "1".into() }));
+        assert!(is_notified(&Event::BlockFound { block_hash: "ab".into() }));
+        assert!(is_notified(&Event::Halted { error: None }));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(Duration::from_secs(300));
+        let start = Instant::now();
+        let failed = |hashboard_idx| Event::BoardFailed { hashboard_idx, reason: "no nonces".into() };
+        assert!(limiter.allow(&failed(6), start));
+        assert!(!limiter.allow(&failed(6), start + Duration::from_secs(10)));
+        // other hashboard and other kinds have their own limit
+        assert!(limiter.allow(&failed(7), start + Duration::from_secs(10)));
+        assert!(limiter.allow(&Event::FansStalled { fans_missing: 1 }, start + Duration::from_secs(10)));
+        assert!(limiter.allow(&failed(6), start + Duration::from_secs(300)));
+        // every block is worth hearing about
+        let block = Event::BlockFound { block_hash: "ab".into() };
+        assert!(limiter.allow(&block, start));
+        assert!(limiter.allow(&block, start + Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn test_post() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: WebhookUrl = format!("http://{}/hook", listener.local_addr().unwrap()).parse().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"}") {
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        post(&url, r#"{"event":"fans_stalled"}"#).await.unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Length: 24\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"event\":\"fans_stalled\"}"));
+    }
+}